@@ -30,8 +30,9 @@ mod wide;
 use std::ffi::{OsStr, OsString};
 use std::mem;
 use std::os::windows::ffi::OsStringExt;
-use std::ptr;
+use std::ptr::{self, NonNull};
 use std::result;
+use std::slice;
 
 use comedy::com::{create_instance_local_server, CoTaskMem, ComRef, INIT_MTA};
 use comedy::error::{HResult, ResultExt};
@@ -40,16 +41,23 @@ use filetime_win::FileTime;
 use guid_win::Guid;
 use winapi::shared::minwindef::DWORD;
 use winapi::shared::ntdef::{HRESULT, LANGIDFROMLCID, ULONG};
-use winapi::shared::winerror::S_FALSE;
+use winapi::shared::winerror::{E_UNEXPECTED, S_FALSE};
 use winapi::um::bits::{
     IBackgroundCopyError, IBackgroundCopyFile, IBackgroundCopyJob, IBackgroundCopyManager,
     IEnumBackgroundCopyFiles, IEnumBackgroundCopyJobs, BG_JOB_PRIORITY, BG_JOB_PRIORITY_FOREGROUND,
-    BG_JOB_PRIORITY_HIGH, BG_JOB_PRIORITY_LOW, BG_JOB_PRIORITY_NORMAL, BG_JOB_PROXY_USAGE,
-    BG_JOB_PROXY_USAGE_AUTODETECT, BG_JOB_PROXY_USAGE_NO_PROXY, BG_JOB_PROXY_USAGE_PRECONFIG,
-    BG_JOB_STATE_ERROR, BG_JOB_STATE_TRANSIENT_ERROR, BG_JOB_TYPE_DOWNLOAD, BG_NOTIFY_DISABLE,
+    BG_JOB_PRIORITY_HIGH, BG_JOB_PRIORITY_LOW, BG_JOB_PRIORITY_NORMAL,
+    BG_JOB_PROXY_USAGE_AUTODETECT, BG_JOB_PROXY_USAGE_NO_PROXY, BG_JOB_PROXY_USAGE_OVERRIDE,
+    BG_JOB_PROXY_USAGE_PRECONFIG, BG_JOB_STATE_ERROR, BG_JOB_STATE_TRANSIENT_ERROR, BG_JOB_TYPE,
+    BG_JOB_TYPE_DOWNLOAD, BG_JOB_TYPE_UPLOAD, BG_JOB_TYPE_UPLOAD_REPLY, BG_NOTIFY_DISABLE,
     BG_NOTIFY_JOB_ERROR, BG_NOTIFY_JOB_MODIFICATION, BG_NOTIFY_JOB_TRANSFERRED, BG_SIZE_UNKNOWN,
 };
+use winapi::um::bits1_5::{
+    IBackgroundCopyJob2, BG_AUTH_CREDENTIALS, BG_AUTH_SCHEME_BASIC, BG_AUTH_SCHEME_DIGEST,
+    BG_AUTH_SCHEME_NEGOTIATE, BG_AUTH_SCHEME_NTLM, BG_AUTH_TARGET_PROXY, BG_AUTH_TARGET_SERVER,
+};
+use winapi::um::bits2_0::{IBackgroundCopyJob3, BG_FILE_RANGE, BG_LENGTH_TO_EOF};
 use winapi::um::bits2_5::{IBackgroundCopyJobHttpOptions, BG_HTTP_REDIRECT_POLICY_ALLOW_REPORT};
+use winapi::um::bits3_0::{IBackgroundCopyFile3, IBackgroundCopyJob4};
 use winapi::um::bitsmsg::BG_E_NOT_FOUND;
 use winapi::um::unknwnbase::IUnknown;
 use winapi::um::winnls::GetThreadLocale;
@@ -57,15 +65,17 @@ use winapi::um::winnls::GetThreadLocale;
 pub use winapi::um::bits::{BG_ERROR_CONTEXT, BG_JOB_STATE};
 pub use winapi::um::bitsmsg::{BG_S_PARTIAL_COMPLETE, BG_S_UNABLE_TO_DELETE_FILES};
 
+pub use callback::{ErrorCallback, ModificationCallback, TransferredCallback};
 pub use status::{
-    BitsErrorContext, BitsJobError, BitsJobProgress, BitsJobState, BitsJobStatus, BitsJobTimes,
+    BitsErrorContext, BitsFileProgress, BitsJobError, BitsJobProgress, BitsJobState,
+    BitsJobStatus, BitsJobTimes,
 };
 use wide::ToWideNull;
 
 pub use winapi::shared::winerror::E_FAIL;
 
 #[repr(u32)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum BitsJobPriority {
     Foreground = BG_JOB_PRIORITY_FOREGROUND,
     High = BG_JOB_PRIORITY_HIGH,
@@ -74,15 +84,57 @@ pub enum BitsJobPriority {
     Low = BG_JOB_PRIORITY_LOW,
 }
 
-#[repr(u32)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum BitsProxyUsage {
     /// Directly access the network.
-    NoProxy = BG_JOB_PROXY_USAGE_NO_PROXY,
+    NoProxy,
     /// Use Internet Explorer proxy settings. This is the default.
-    Preconfig = BG_JOB_PROXY_USAGE_PRECONFIG,
+    Preconfig,
     /// Attempt to auto-detect the connection's proxy settings.
-    AutoDetect = BG_JOB_PROXY_USAGE_AUTODETECT,
+    AutoDetect,
+    /// Use the first of `proxies` which is available, except for hosts matching `bypass`.
+    ///
+    /// Each proxy is `[scheme=][scheme://]host[:port]`, each bypass entry is a host name which
+    /// may contain `*` wildcards, or `<local>` for any host name without a period.
+    Override {
+        proxies: Vec<String>,
+        bypass: Vec<String>,
+    },
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsJobType {
+    Download = BG_JOB_TYPE_DOWNLOAD,
+    /// Upload a single file.
+    Upload = BG_JOB_TYPE_UPLOAD,
+    /// Upload a single file, and receive a reply from the server.
+    UploadReply = BG_JOB_TYPE_UPLOAD_REPLY,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsAuthTarget {
+    /// The server hosting the file.
+    Server = BG_AUTH_TARGET_SERVER,
+    Proxy = BG_AUTH_TARGET_PROXY,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsAuthScheme {
+    Basic = BG_AUTH_SCHEME_BASIC,
+    Digest = BG_AUTH_SCHEME_DIGEST,
+    Ntlm = BG_AUTH_SCHEME_NTLM,
+    Negotiate = BG_AUTH_SCHEME_NEGOTIATE,
+}
+
+/// A range of bytes in a remote file, for `BitsJob::add_file_with_ranges()`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BitsFileRange {
+    pub offset: u64,
+    /// The number of bytes, or `None` for the rest of the file.
+    pub length: Option<u64>,
 }
 
 type Result<T> = result::Result<T, HResult>;
@@ -125,13 +177,26 @@ impl BackgroundCopyManager {
 
     /// Create a new download job with the given name.
     pub fn create_job(&self, display_name: &OsStr) -> Result<BitsJob> {
+        self.create_job_with_type(display_name, BitsJobType::Download)
+    }
+
+    /// Create a new job of the given type with the given name.
+    ///
+    /// # Compatibility #
+    ///
+    /// Upload jobs are first available in BITS 1.5.
+    pub fn create_job_with_type(
+        &self,
+        display_name: &OsStr,
+        job_type: BitsJobType,
+    ) -> Result<BitsJob> {
         unsafe {
             let mut guid = mem::zeroed();
             Ok(BitsJob(com_call_getter!(
                 |job| self.0,
                 IBackgroundCopyManager::CreateJob(
                     display_name.to_wide_null().as_ptr(),
-                    BG_JOB_TYPE_DOWNLOAD,
+                    job_type as BG_JOB_TYPE,
                     &mut guid,
                     job,
                 )
@@ -174,6 +239,34 @@ impl BackgroundCopyManager {
         }
     }
 
+    /// Get all jobs with the given name.
+    ///
+    /// This only finds jobs owned by the current user.
+    pub fn find_jobs_by_name(&self, match_name: &OsStr) -> Result<Vec<BitsJob>> {
+        let jobs =
+            unsafe { com_call_getter!(|jobs| self.0, IBackgroundCopyManager::EnumJobs(0, jobs))? };
+
+        let mut found = Vec::new();
+        loop {
+            let result = unsafe {
+                com_call_getter!(
+                    |job| jobs,
+                    IEnumBackgroundCopyJobs::Next(1, job, ptr::null_mut())
+                )
+            };
+            match result {
+                Ok(job) => {
+                    if job_name_eq(&job, match_name)? {
+                        found.push(BitsJob(job));
+                    }
+                }
+                // Ran out of jobs to enumerate
+                Err(ref e) if e.code() == S_FALSE => return Ok(found),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Get the job with the given GUID.
     ///
     /// Returns Err if the job was not found.
@@ -254,7 +347,23 @@ impl BitsJob {
         }
     }
 
+    /// Get the type of the job.
+    pub fn job_type(&self) -> Result<BitsJobType> {
+        let mut job_type = 0;
+        unsafe { com_call!(self.0, IBackgroundCopyJob::GetType(&mut job_type)) }?;
+
+        match job_type {
+            BG_JOB_TYPE_DOWNLOAD => Ok(BitsJobType::Download),
+            BG_JOB_TYPE_UPLOAD => Ok(BitsJobType::Upload),
+            BG_JOB_TYPE_UPLOAD_REPLY => Ok(BitsJobType::UploadReply),
+            _ => Err(HResult::new(E_UNEXPECTED)),
+        }
+    }
+
     /// Add a file to the job.
+    ///
+    /// For an upload job `local_file` is the file to upload; an upload job may only have one
+    /// file.
     pub fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
         unsafe {
             com_call!(
@@ -268,6 +377,42 @@ impl BitsJob {
         Ok(())
     }
 
+    /// Add a file to the job, downloading only `ranges` of the remote file.
+    ///
+    /// The ranges are saved one after another in the local file. They must be in order and not
+    /// overlap, and the server must support range requests.
+    ///
+    /// # Compatibility #
+    ///
+    /// First available in Windows Vista.
+    pub fn add_file_with_ranges(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: &[BitsFileRange],
+    ) -> Result<()> {
+        let mut ranges: Vec<BG_FILE_RANGE> = ranges
+            .iter()
+            .map(|range| BG_FILE_RANGE {
+                InitialOffset: range.offset,
+                Length: range.length.unwrap_or(BG_LENGTH_TO_EOF),
+            })
+            .collect();
+
+        unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJob3::AddFileWithRanges(
+                    remote_url.to_wide_null().as_ptr(),
+                    local_file.to_wide_null().as_ptr(),
+                    ranges.len() as DWORD,
+                    ranges.as_mut_ptr(),
+                )
+            )
+        }?;
+        Ok(())
+    }
+
     /// Get the first file in the job.
     ///
     /// This is provided for collecting the redirected remote name of single file jobs.
@@ -284,6 +429,46 @@ impl BitsJob {
         Ok(BitsFile(file))
     }
 
+    /// Iterate over the files in the job, in the order they were added.
+    pub fn files(&self) -> Result<BitsFiles> {
+        unsafe { com_call_getter!(|e| self.0, IBackgroundCopyJob::EnumFiles(e)) }.map(BitsFiles)
+    }
+
+    /// Set the file where the server's reply to an upload-reply job will be saved.
+    ///
+    /// The reply file is available once the job has been completed.
+    pub fn set_reply_file_name(&mut self, reply_file: &OsStr) -> Result<()> {
+        unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJob2::SetReplyFileName(reply_file.to_wide_null().as_ptr())
+            )
+        }?;
+        Ok(())
+    }
+
+    /// Get the server's reply to an upload-reply job.
+    ///
+    /// The reply is only available once the job has been transferred.
+    pub fn get_reply_data(&self) -> Result<Vec<u8>> {
+        let mut buffer = ptr::null_mut();
+        let mut length = 0;
+        unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJob2::GetReplyData(&mut buffer, &mut length)
+            )?;
+
+            Ok(match NonNull::new(buffer) {
+                Some(buffer) => {
+                    let _free = CoTaskMem::new(buffer);
+                    slice::from_raw_parts(buffer.as_ptr(), length as usize).to_vec()
+                }
+                None => Vec::new(),
+            })
+        }
+    }
+
     /// Set the job's description string.
     ///
     /// This is different from the display name set when creating the job.
@@ -303,21 +488,30 @@ impl BitsJob {
     pub fn set_proxy_usage(&mut self, usage: BitsProxyUsage) -> Result<()> {
         use BitsProxyUsage::*;
 
-        match usage {
-            Preconfig | NoProxy | AutoDetect => {
-                unsafe {
-                    com_call!(
-                        self.0,
-                        IBackgroundCopyJob::SetProxySettings(
-                            usage as BG_JOB_PROXY_USAGE,
-                            ptr::null_mut(),
-                            ptr::null_mut(),
-                        )
-                    )
-                }?;
-                Ok(())
-            }
-        }
+        let (usage, proxies, bypass) = match usage {
+            Preconfig => (BG_JOB_PROXY_USAGE_PRECONFIG, None, None),
+            NoProxy => (BG_JOB_PROXY_USAGE_NO_PROXY, None, None),
+            AutoDetect => (BG_JOB_PROXY_USAGE_AUTODETECT, None, None),
+            Override { proxies, bypass } => (
+                BG_JOB_PROXY_USAGE_OVERRIDE,
+                Some(OsString::from(proxies.join(" ")).to_wide_null()),
+                // An empty bypass list is given as null.
+                if bypass.is_empty() {
+                    None
+                } else {
+                    Some(OsString::from(bypass.join(" ")).to_wide_null())
+                },
+            ),
+        };
+        let as_ptr = |list: &Option<Vec<u16>>| list.as_ref().map_or(ptr::null(), |l| l.as_ptr());
+
+        unsafe {
+            com_call!(
+                self.0,
+                IBackgroundCopyJob::SetProxySettings(usage, as_ptr(&proxies), as_ptr(&bypass))
+            )
+        }?;
+        Ok(())
     }
 
     /// Change the job's priority.
@@ -333,11 +527,52 @@ impl BitsJob {
         Ok(())
     }
 
+    /// Get the job's priority.
+    pub fn get_priority(&self) -> Result<BitsJobPriority> {
+        let mut priority = 0;
+        unsafe { com_call!(self.0, IBackgroundCopyJob::GetPriority(&mut priority)) }?;
+
+        match priority {
+            BG_JOB_PRIORITY_FOREGROUND => Ok(BitsJobPriority::Foreground),
+            BG_JOB_PRIORITY_HIGH => Ok(BitsJobPriority::High),
+            BG_JOB_PRIORITY_NORMAL => Ok(BitsJobPriority::Normal),
+            BG_JOB_PRIORITY_LOW => Ok(BitsJobPriority::Low),
+            _ => Err(HResult::new(E_UNEXPECTED)),
+        }
+    }
+
     pub fn set_minimum_retry_delay(&mut self, seconds: ULONG) -> Result<()> {
         unsafe { com_call!(self.0, IBackgroundCopyJob::SetMinimumRetryDelay(seconds)) }?;
         Ok(())
     }
 
+    /// Change how long BITS keeps retrying after a transient error before the job goes into the
+    /// error state, if it makes no progress.
+    ///
+    /// The default is 14 days.
+    pub fn set_no_progress_timeout(&mut self, seconds: ULONG) -> Result<()> {
+        unsafe { com_call!(self.0, IBackgroundCopyJob::SetNoProgressTimeout(seconds)) }?;
+        Ok(())
+    }
+
+    /// Change the longest time BITS may spend transferring the job's files, after which the job
+    /// goes into the error state.
+    ///
+    /// The default is 90 days.
+    ///
+    /// # Compatibility #
+    ///
+    /// First available in Windows Vista.
+    pub fn set_maximum_download_time(&mut self, seconds: ULONG) -> Result<()> {
+        unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJob4::SetMaximumDownloadTime(seconds)
+            )
+        }?;
+        Ok(())
+    }
+
     /// Enable HTTP redirect reporting.
     ///
     /// The default setting is to allow HTTP redirects, but to not report them in any way. With
@@ -359,6 +594,82 @@ impl BitsJob {
         Ok(())
     }
 
+    /// Set custom HTTP headers to be sent with each request of the job.
+    ///
+    /// Each header is `name: value`, terminated by CR LF. This replaces any headers set
+    /// previously.
+    ///
+    /// # Compatibility #
+    ///
+    /// First available in Windows Vista.
+    pub fn set_custom_headers(&mut self, headers: &OsStr) -> Result<()> {
+        unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJobHttpOptions::SetCustomHeaders(headers.to_wide_null().as_ptr())
+            )
+        }?;
+
+        Ok(())
+    }
+
+    /// Set the credentials used to authenticate with `target` using `scheme`.
+    ///
+    /// This replaces any credentials set previously for the same target and scheme.
+    ///
+    /// Only the copy of the password made to pass it to BITS is cleared afterwards, not
+    /// `password`.
+    pub fn set_credentials(
+        &mut self,
+        target: BitsAuthTarget,
+        scheme: BitsAuthScheme,
+        user_name: &OsStr,
+        password: &OsStr,
+    ) -> Result<()> {
+        let mut user_name = user_name.to_wide_null();
+        let mut password = password.to_wide_null();
+
+        let mut credentials: BG_AUTH_CREDENTIALS = unsafe { mem::zeroed() };
+        credentials.Target = target as u32;
+        credentials.Scheme = scheme as u32;
+        unsafe {
+            let basic = credentials.Credentials.Basic_mut();
+            basic.UserName = user_name.as_mut_ptr();
+            basic.Password = password.as_mut_ptr();
+        }
+
+        let result = unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJob2::SetCredentials(&mut credentials)
+            )
+        };
+
+        // Don't leave this copy of the password lying around in freed memory. `password` itself,
+        // and any copies the caller has, are not cleared.
+        for unit in password.iter_mut() {
+            unsafe { ptr::write_volatile(unit, 0) };
+        }
+
+        result?;
+        Ok(())
+    }
+
+    /// Remove the credentials set for `target` and `scheme`, if any.
+    pub fn remove_credentials(
+        &mut self,
+        target: BitsAuthTarget,
+        scheme: BitsAuthScheme,
+    ) -> Result<()> {
+        unsafe {
+            com_call!(
+                self.0.cast()?,
+                IBackgroundCopyJob2::RemoveCredentials(target as u32, scheme as u32)
+            )
+        }?;
+        Ok(())
+    }
+
     /// Resume the job. This must be done at least once to initially enqueue the job.
     pub fn resume(&mut self) -> Result<()> {
         unsafe { com_call!(self.0, IBackgroundCopyJob::Resume()) }?;
@@ -509,11 +820,31 @@ impl BitsJob {
     }
 }
 
+/// An iterator over the files in a job, from [`BitsJob::files()`](struct.BitsJob.html#method.files).
+pub struct BitsFiles(ComRef<IEnumBackgroundCopyFiles>);
+
+impl Iterator for BitsFiles {
+    type Item = Result<BitsFile>;
+
+    fn next(&mut self) -> Option<Result<BitsFile>> {
+        let result = unsafe {
+            com_call_getter!(
+                |file| self.0,
+                IEnumBackgroundCopyFiles::Next(1, file, ptr::null_mut())
+            )
+        };
+        match result {
+            Ok(file) => Some(Ok(BitsFile(file))),
+            // Ran out of files to enumerate
+            Err(ref e) if e.code() == S_FALSE => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
 pub struct BitsFile(ComRef<IBackgroundCopyFile>);
 
 /// A single file in a BITS job.
-///
-/// This is provided for collecting the redirected remote name.
 impl BitsFile {
     /// Get the remote name from which the file is being downloaded.
     ///
@@ -528,6 +859,53 @@ impl BitsFile {
             ))
         }
     }
+
+    /// Get the local path to which the file is being saved.
+    pub fn get_local_name(&self) -> Result<OsString> {
+        unsafe {
+            Ok(OsString::from_wide(
+                com_call_taskmem_getter!(|name| self.0, IBackgroundCopyFile::GetLocalName(name))?
+                    .as_slice_until_null(),
+            ))
+        }
+    }
+
+    /// Get the path of the temporary file where the file is being downloaded, before the job is
+    /// completed.
+    ///
+    /// # Compatibility #
+    ///
+    /// First available in Windows 7.
+    pub fn get_temporary_name(&self) -> Result<OsString> {
+        let file: ComRef<IBackgroundCopyFile3> = self.0.cast()?;
+        unsafe {
+            Ok(OsString::from_wide(
+                com_call_taskmem_getter!(
+                    |name| file,
+                    IBackgroundCopyFile3::GetTemporaryName(name)
+                )?
+                .as_slice_until_null(),
+            ))
+        }
+    }
+
+    /// Get the progress of the file's transfer.
+    pub fn get_progress(&self) -> Result<BitsFileProgress> {
+        let mut progress = unsafe { mem::zeroed() };
+        unsafe {
+            com_call!(self.0, IBackgroundCopyFile::GetProgress(&mut progress))?;
+        }
+
+        Ok(BitsFileProgress {
+            total_bytes: if progress.BytesTotal == BG_SIZE_UNKNOWN {
+                None
+            } else {
+                Some(progress.BytesTotal)
+            },
+            transferred_bytes: progress.BytesTransferred,
+            completed: progress.Completed != 0,
+        })
+    }
 }
 
 #[cfg(test)]
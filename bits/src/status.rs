@@ -109,6 +109,14 @@ pub struct BitsJobProgress {
     pub transferred_files: u32,
 }
 
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "status_serde", derive(Serialize, Deserialize))]
+pub struct BitsFileProgress {
+    pub total_bytes: Option<u64>,
+    pub transferred_bytes: u64,
+    pub completed: bool,
+}
+
 #[derive(Copy, Clone, Debug)]
 #[cfg_attr(feature = "status_serde", derive(Serialize, Deserialize))]
 pub struct BitsJobTimes {
@@ -2,12 +2,13 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+// `#[derive(Fail)]` puts its impls inside a constant.
+#![allow(non_local_definitions)]
+
 extern crate bits_client;
-extern crate comedy;
 //extern crate ctrlc;
 extern crate failure;
 extern crate failure_derive;
-extern crate guid_win;
 
 use std::convert;
 use std::env;
@@ -19,16 +20,17 @@ use std::sync::{Arc, Mutex};
 use failure::{AsFail, Fail};
 
 use bits_client::bits_protocol::HResultMessage;
-use bits_client::{BitsClient, BitsJobState, BitsMonitorClient, BitsProxyUsage, Guid, PipeError};
+use bits_client::{
+    BitsClient, BitsJobPriority, BitsJobState, BitsMonitorClient, BitsProxyUsage, Guid, HResult,
+    PipeError,
+};
 
 #[derive(Debug, Fail)]
 enum MyError {
     #[fail(display = "{}", _0)]
     Msg(String),
     #[fail(display = "HResult")]
-    HResult(#[fail(cause)] comedy::HResult),
-    #[fail(display = "Win32Error")]
-    Win32Error(#[fail(cause)] comedy::Win32Error),
+    HResult(#[fail(cause)] HResult),
     #[fail(display = "PipeError")]
     PipeError(#[fail(cause)] PipeError),
     #[fail(display = "HResultMessage")]
@@ -41,18 +43,12 @@ impl convert::From<PipeError> for MyError {
     }
 }
 
-impl convert::From<comedy::HResult> for MyError {
-    fn from(err: comedy::HResult) -> MyError {
+impl convert::From<HResult> for MyError {
+    fn from(err: HResult) -> MyError {
         MyError::HResult(err)
     }
 }
 
-impl convert::From<comedy::Win32Error> for MyError {
-    fn from(err: comedy::Win32Error) -> MyError {
-        MyError::Win32Error(err)
-    }
-}
-
 impl convert::From<HResultMessage> for MyError {
     fn from(err: HResultMessage) -> MyError {
         MyError::HResultMessage(err)
@@ -61,10 +57,10 @@ impl convert::From<HResultMessage> for MyError {
 
 macro_rules! bail {
     ($e:expr) => {
-        return Err($crate::MyError::Msg($e.to_string()));
+        return Err($crate::MyError::Msg($e.to_string()))
     };
     ($fmt:expr, $($arg:tt)*) => {
-        return Err($crate::MyError::Msg(format!($fmt, $($arg)*)));
+        return Err($crate::MyError::Msg(format!($fmt, $($arg)*)))
     };
 }
 
@@ -83,7 +79,7 @@ pub fn main() {
     }
 }
 
-const EXE_NAME: &'static str = "test_client";
+const EXE_NAME: &str = "test_client";
 
 fn usage() -> String {
     format!(
@@ -105,13 +101,27 @@ fn usage() -> String {
     )
 }
 
+// The BITS service on Windows, the portable engine elsewhere.
+#[cfg(windows)]
+fn new_client() -> std::result::Result<BitsClient, MyError> {
+    Ok(BitsClient::new(
+        OsString::from("bits_client test"),
+        OsString::from("C:\\ProgramData"),
+    )?)
+}
+
+#[cfg(not(windows))]
+fn new_client() -> std::result::Result<BitsClient, MyError> {
+    Ok(BitsClient::new_portable(
+        OsString::from("bits_client test"),
+        env::temp_dir().into_os_string(),
+    )?)
+}
+
 fn entry() -> Result {
     let args: Vec<_> = env::args_os().collect();
 
-    let mut client = BitsClient::new(
-        OsString::from("bits_client test"),
-        OsString::from("C:\\ProgramData"),
-    )?;
+    let mut client = new_client()?;
 
     if args.len() < 2 {
         eprintln!("{}", usage());
@@ -138,7 +148,7 @@ fn entry() -> Result {
         "bits-suspend" if cmd_args.len() == 1 => bits_suspend(&mut client, &cmd_args[0]),
         "bits-resume" if cmd_args.len() == 1 => bits_resume(&mut client, &cmd_args[0]),
         "bits-complete" if cmd_args.len() == 1 => bits_complete(&mut client, &cmd_args[0]),
-        "bits-cancel" if cmd_args.len() >= 1 => {
+        "bits-cancel" if !cmd_args.is_empty() => {
             for guid in cmd_args {
                 bits_cancel(&mut client, guid)?;
             }
@@ -171,7 +181,11 @@ fn bits_start(
             client
                 .lock()
                 .unwrap()
-                .set_update_interval(r.guid.clone(), interval)?
+                .set_update_interval(
+                    r.guid.clone(),
+                    Some(monitor_client.subscription()),
+                    interval,
+                )?
                 .unwrap();
             monitor_loop(client, monitor_client, r.guid.clone(), interval)?;
             Ok(())
@@ -219,7 +233,7 @@ fn monitor_loop(
     let client_for_handler = _client.clone();
     ctrlc::set_handler(move || {
         eprintln!("Ctrl-C!");
-        let _ = client_for_handler.lock().unwrap().stop_update(_guid.clone());
+        let _ = client_for_handler.lock().unwrap().stop_update(_guid.clone(), None);
     })
     .expect("Error setting Ctrl-C handler");
     */
@@ -227,22 +241,22 @@ fn monitor_loop(
     loop {
         let status = monitor_client.get_status(wait_millis * 10)??;
 
-        println!("{:?} {:?}", BitsJobState::from(status.state), status);
+        println!("{:?} {:?}", status.state, status);
 
         //println!("{}", job.get_first_file()?.get_remote_name()?.into_string().unwrap());
         let transfer_completion_time = if let Some(ft) = status.times.transfer_completion {
-            format!("Some({})", ft.to_system_time_utc()?)
+            format!("Some({:?})", ft.to_system_time())
         } else {
             String::from("None")
         };
         println!(
-            "creation: {}, modification: {}, transfer completion: {}",
-            status.times.creation.to_system_time_utc()?,
-            status.times.modification.to_system_time_utc()?,
+            "creation: {:?}, modification: {:?}, transfer completion: {}",
+            status.times.creation.to_system_time(),
+            status.times.modification.to_system_time(),
             transfer_completion_time
         );
 
-        match BitsJobState::from(status.state) {
+        match status.state {
             BitsJobState::Connecting
             | BitsJobState::Transferring
             | BitsJobState::TransientError => {}
@@ -257,16 +271,16 @@ fn monitor_loop(
 }
 
 fn bits_bg(client: &mut BitsClient, guid: &OsStr) -> Result {
-    bits_set_priority(client, guid, false)
+    bits_set_priority(client, guid, BitsJobPriority::Normal)
 }
 
 fn bits_fg(client: &mut BitsClient, guid: &OsStr) -> Result {
-    bits_set_priority(client, guid, true)
+    bits_set_priority(client, guid, BitsJobPriority::Foreground)
 }
 
-fn bits_set_priority(client: &mut BitsClient, guid: &OsStr, foreground: bool) -> Result {
+fn bits_set_priority(client: &mut BitsClient, guid: &OsStr, priority: BitsJobPriority) -> Result {
     let guid = Guid::from_str(&guid.to_string_lossy())?;
-    match client.set_job_priority(guid, foreground)? {
+    match client.set_job_priority(guid, priority)? {
         Ok(()) => Ok(()),
         Err(e) => bail!("error from server {}", e),
     }
@@ -0,0 +1,362 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The backend for the live BITS service, via COM with the `bits` crate.
+//!
+//! The `bits` crate has types of its own for everything in the interface, which are converted
+//! to and from the ones in [`types`](../../types/index.html) here.
+
+use std::ffi::{OsStr, OsString};
+
+use bits::{self, BackgroundCopyManager, BitsJob};
+use comedy;
+use filetime_win;
+use guid_win;
+use winapi::shared::guiddef::GUID;
+
+use types::{
+    BitsAuthScheme, BitsAuthTarget, BitsErrorContext, BitsFileProgress, BitsFileRange,
+    BitsJobError, BitsJobPriority, BitsJobProgress, BitsJobState, BitsJobStatus, BitsJobTimes,
+    BitsJobType, BitsProxyUsage, FileTime, Guid, HResult, HRESULT,
+};
+
+use super::{
+    BackendConnection, BackendFile, BackendJob, ErrorCallback, JobBackend, ModificationCallback,
+    TransferredCallback,
+};
+
+type Result<T> = std::result::Result<T, HResult>;
+
+/// The backend for the live BITS service.
+#[derive(Clone, Debug, Default)]
+pub struct ComBackend;
+
+impl JobBackend for ComBackend {
+    type Connection = BackgroundCopyManager;
+
+    fn connect(&self) -> Result<BackgroundCopyManager> {
+        BackgroundCopyManager::connect().map_err(hresult)
+    }
+}
+
+impl BackendConnection for BackgroundCopyManager {
+    type Job = BitsJob;
+
+    fn create_job_with_type(&self, display_name: &OsStr, job_type: BitsJobType) -> Result<BitsJob> {
+        BackgroundCopyManager::create_job_with_type(self, display_name, to_bits_job_type(job_type))
+            .map_err(hresult)
+    }
+
+    fn get_job_by_guid(&self, guid: &Guid) -> Result<BitsJob> {
+        BackgroundCopyManager::get_job_by_guid(self, &to_guid_win(guid)).map_err(hresult)
+    }
+
+    fn find_job_by_guid_and_name(
+        &self,
+        guid: &Guid,
+        match_name: &OsStr,
+    ) -> Result<Option<BitsJob>> {
+        BackgroundCopyManager::find_job_by_guid_and_name(self, &to_guid_win(guid), match_name)
+            .map_err(hresult)
+    }
+
+    fn find_jobs_by_name(&self, match_name: &OsStr) -> Result<Vec<BitsJob>> {
+        BackgroundCopyManager::find_jobs_by_name(self, match_name).map_err(hresult)
+    }
+
+    fn get_error_description(&self, hr: HRESULT) -> Result<String> {
+        BackgroundCopyManager::get_error_description(self, hr).map_err(hresult)
+    }
+}
+
+impl BackendJob for BitsJob {
+    fn guid(&self) -> Result<Guid> {
+        BitsJob::guid(self).map(from_guid_win).map_err(hresult)
+    }
+
+    fn job_type(&self) -> Result<BitsJobType> {
+        BitsJob::job_type(self)
+            .map(|job_type| match job_type {
+                bits::BitsJobType::Download => BitsJobType::Download,
+                bits::BitsJobType::Upload => BitsJobType::Upload,
+                bits::BitsJobType::UploadReply => BitsJobType::UploadReply,
+            })
+            .map_err(hresult)
+    }
+
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
+        BitsJob::add_file(self, remote_url, local_file).map_err(hresult)
+    }
+
+    fn add_file_with_ranges(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: &[BitsFileRange],
+    ) -> Result<()> {
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|range| bits::BitsFileRange {
+                offset: range.offset,
+                length: range.length,
+            })
+            .collect();
+        BitsJob::add_file_with_ranges(self, remote_url, local_file, &ranges).map_err(hresult)
+    }
+
+    fn get_files(&mut self) -> Result<Vec<BackendFile>> {
+        self.files()
+            .map_err(hresult)?
+            .map(|file| {
+                let file = file.map_err(hresult)?;
+                Ok(BackendFile {
+                    remote_name: file.get_remote_name().map_err(hresult)?,
+                    local_name: file.get_local_name().map_err(hresult)?,
+                    progress: from_bits_file_progress(file.get_progress().map_err(hresult)?),
+                })
+            })
+            .collect()
+    }
+
+    fn get_temporary_names(&mut self) -> Result<Vec<OsString>> {
+        self.files()
+            .map_err(hresult)?
+            .map(|file| {
+                file.and_then(|file| file.get_temporary_name())
+                    .map_err(hresult)
+            })
+            .collect()
+    }
+
+    fn set_proxy_usage(&mut self, usage: BitsProxyUsage) -> Result<()> {
+        let usage = match usage {
+            BitsProxyUsage::NoProxy => bits::BitsProxyUsage::NoProxy,
+            BitsProxyUsage::Preconfig => bits::BitsProxyUsage::Preconfig,
+            BitsProxyUsage::AutoDetect => bits::BitsProxyUsage::AutoDetect,
+            BitsProxyUsage::Override { proxies, bypass } => {
+                bits::BitsProxyUsage::Override { proxies, bypass }
+            }
+        };
+        BitsJob::set_proxy_usage(self, usage).map_err(hresult)
+    }
+
+    fn set_priority(&mut self, priority: BitsJobPriority) -> Result<()> {
+        let priority = match priority {
+            BitsJobPriority::Foreground => bits::BitsJobPriority::Foreground,
+            BitsJobPriority::High => bits::BitsJobPriority::High,
+            BitsJobPriority::Normal => bits::BitsJobPriority::Normal,
+            BitsJobPriority::Low => bits::BitsJobPriority::Low,
+        };
+        BitsJob::set_priority(self, priority).map_err(hresult)
+    }
+
+    fn get_priority(&self) -> Result<BitsJobPriority> {
+        BitsJob::get_priority(self)
+            .map(|priority| match priority {
+                bits::BitsJobPriority::Foreground => BitsJobPriority::Foreground,
+                bits::BitsJobPriority::High => BitsJobPriority::High,
+                bits::BitsJobPriority::Normal => BitsJobPriority::Normal,
+                bits::BitsJobPriority::Low => BitsJobPriority::Low,
+            })
+            .map_err(hresult)
+    }
+
+    fn set_minimum_retry_delay(&mut self, seconds: u32) -> Result<()> {
+        BitsJob::set_minimum_retry_delay(self, seconds).map_err(hresult)
+    }
+
+    fn set_no_progress_timeout(&mut self, seconds: u32) -> Result<()> {
+        BitsJob::set_no_progress_timeout(self, seconds).map_err(hresult)
+    }
+
+    fn set_maximum_download_time(&mut self, seconds: u32) -> Result<()> {
+        BitsJob::set_maximum_download_time(self, seconds).map_err(hresult)
+    }
+
+    fn set_redirect_report(&mut self) -> Result<()> {
+        BitsJob::set_redirect_report(self).map_err(hresult)
+    }
+
+    fn set_custom_headers(&mut self, headers: &OsStr) -> Result<()> {
+        BitsJob::set_custom_headers(self, headers).map_err(hresult)
+    }
+
+    fn set_credentials(
+        &mut self,
+        target: BitsAuthTarget,
+        scheme: BitsAuthScheme,
+        user_name: &OsStr,
+        password: &OsStr,
+    ) -> Result<()> {
+        BitsJob::set_credentials(
+            self,
+            to_bits_auth_target(target),
+            to_bits_auth_scheme(scheme),
+            user_name,
+            password,
+        )
+        .map_err(hresult)
+    }
+
+    fn remove_credentials(&mut self, target: BitsAuthTarget, scheme: BitsAuthScheme) -> Result<()> {
+        BitsJob::remove_credentials(
+            self,
+            to_bits_auth_target(target),
+            to_bits_auth_scheme(scheme),
+        )
+        .map_err(hresult)
+    }
+
+    fn set_reply_file_name(&mut self, reply_file: &OsStr) -> Result<()> {
+        BitsJob::set_reply_file_name(self, reply_file).map_err(hresult)
+    }
+
+    fn get_reply_data(&self) -> Result<Vec<u8>> {
+        BitsJob::get_reply_data(self).map_err(hresult)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        BitsJob::resume(self).map_err(hresult)
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        BitsJob::suspend(self).map_err(hresult)
+    }
+
+    fn complete(&mut self) -> Result<HRESULT> {
+        BitsJob::complete(self).map_err(hresult)
+    }
+
+    fn cancel(&mut self) -> Result<HRESULT> {
+        BitsJob::cancel(self).map_err(hresult)
+    }
+
+    fn register_callbacks(
+        &mut self,
+        transferred_cb: Option<Box<TransferredCallback>>,
+        error_cb: Option<Box<ErrorCallback>>,
+        modification_cb: Option<Box<ModificationCallback>>,
+    ) -> Result<()> {
+        BitsJob::register_callbacks(self, transferred_cb, error_cb, modification_cb)
+            .map_err(hresult)
+    }
+
+    fn get_status(&self) -> Result<BitsJobStatus> {
+        let status = BitsJob::get_status(self).map_err(hresult)?;
+        Ok(BitsJobStatus {
+            state: from_bits_job_state(status.state),
+            progress: BitsJobProgress {
+                total_bytes: status.progress.total_bytes,
+                transferred_bytes: status.progress.transferred_bytes,
+                total_files: status.progress.total_files,
+                transferred_files: status.progress.transferred_files,
+            },
+            error_count: status.error_count,
+            error: status.error.map(|error| BitsJobError {
+                context: from_bits_error_context(error.context),
+                context_str: error.context_str,
+                error: error.error,
+                error_str: error.error_str,
+            }),
+            times: BitsJobTimes {
+                creation: from_file_time_win(status.times.creation),
+                modification: from_file_time_win(status.times.modification),
+                transfer_completion: status.times.transfer_completion.map(from_file_time_win),
+            },
+        })
+    }
+}
+
+fn hresult(e: comedy::HResult) -> HResult {
+    let hr = HResult::new(e.code());
+    match e.get_function() {
+        Some(function) => hr.function(function),
+        None => hr,
+    }
+}
+
+fn to_guid_win(guid: &Guid) -> guid_win::Guid {
+    guid_win::Guid(GUID {
+        Data1: guid.data1,
+        Data2: guid.data2,
+        Data3: guid.data3,
+        Data4: guid.data4,
+    })
+}
+
+fn from_guid_win(guid: guid_win::Guid) -> Guid {
+    Guid {
+        data1: guid.0.Data1,
+        data2: guid.0.Data2,
+        data3: guid.0.Data3,
+        data4: guid.0.Data4,
+    }
+}
+
+fn from_file_time_win(time: filetime_win::FileTime) -> FileTime {
+    FileTime(time.to_u64())
+}
+
+fn from_bits_file_progress(progress: bits::BitsFileProgress) -> BitsFileProgress {
+    BitsFileProgress {
+        total_bytes: progress.total_bytes,
+        transferred_bytes: progress.transferred_bytes,
+        completed: progress.completed,
+    }
+}
+
+fn from_bits_job_state(state: bits::BitsJobState) -> BitsJobState {
+    use bits::BitsJobState::*;
+    match state {
+        Queued => BitsJobState::Queued,
+        Connecting => BitsJobState::Connecting,
+        Transferring => BitsJobState::Transferring,
+        Suspended => BitsJobState::Suspended,
+        Error => BitsJobState::Error,
+        TransientError => BitsJobState::TransientError,
+        Transferred => BitsJobState::Transferred,
+        Acknowledged => BitsJobState::Acknowledged,
+        Cancelled => BitsJobState::Cancelled,
+        Other(state) => BitsJobState::Other(state),
+    }
+}
+
+fn from_bits_error_context(context: bits::BitsErrorContext) -> BitsErrorContext {
+    use bits::BitsErrorContext::*;
+    match context {
+        None => BitsErrorContext::None,
+        Unknown => BitsErrorContext::Unknown,
+        GeneralQueueManager => BitsErrorContext::GeneralQueueManager,
+        QueueManagerNotification => BitsErrorContext::QueueManagerNotification,
+        LocalFile => BitsErrorContext::LocalFile,
+        RemoteFile => BitsErrorContext::RemoteFile,
+        GeneralTransport => BitsErrorContext::GeneralTransport,
+        RemoteApplication => BitsErrorContext::RemoteApplication,
+        Other(context) => BitsErrorContext::Other(context),
+    }
+}
+
+fn to_bits_job_type(job_type: BitsJobType) -> bits::BitsJobType {
+    match job_type {
+        BitsJobType::Download => bits::BitsJobType::Download,
+        BitsJobType::Upload => bits::BitsJobType::Upload,
+        BitsJobType::UploadReply => bits::BitsJobType::UploadReply,
+    }
+}
+
+fn to_bits_auth_target(target: BitsAuthTarget) -> bits::BitsAuthTarget {
+    match target {
+        BitsAuthTarget::Server => bits::BitsAuthTarget::Server,
+        BitsAuthTarget::Proxy => bits::BitsAuthTarget::Proxy,
+    }
+}
+
+fn to_bits_auth_scheme(scheme: BitsAuthScheme) -> bits::BitsAuthScheme {
+    match scheme {
+        BitsAuthScheme::Basic => bits::BitsAuthScheme::Basic,
+        BitsAuthScheme::Digest => bits::BitsAuthScheme::Digest,
+        BitsAuthScheme::Ntlm => bits::BitsAuthScheme::Ntlm,
+        BitsAuthScheme::Negotiate => bits::BitsAuthScheme::Negotiate,
+    }
+}
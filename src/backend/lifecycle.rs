@@ -0,0 +1,176 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The state of a job which is common to the in-process engines, `PortableBits` and
+//! `SimulatedBits`, which follows the lifecycle described in the `backend` module.
+
+use std::panic::{catch_unwind, RefUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use types::{
+    BitsFileProgress, BitsJobError, BitsJobProgress, BitsJobState, BitsJobStatus, BitsJobTimes,
+    FileTime, HRESULT,
+};
+
+use super::{ErrorCallback, ModificationCallback, TransferredCallback};
+
+pub const DEFAULT_RETRY_DELAY_SECS: u32 = 600;
+pub const DEFAULT_NO_PROGRESS_TIMEOUT_SECS: u32 = 14 * 24 * 60 * 60;
+// From FACILITY_HTTP, `BG_E_HTTP_ERROR_xxx` is this with the status code in the low bits.
+pub const HTTP_ERROR_BASE: u32 = 0x8019_0000;
+
+type Callback = dyn Fn() -> Result<(), HRESULT> + RefUnwindSafe + Send + Sync + 'static;
+
+/// Callbacks are collected while an engine's lock is held and run after it is released, so they
+/// can safely call back into the engine.
+#[derive(Default)]
+pub struct Notifications(Vec<Arc<Callback>>);
+
+impl Notifications {
+    pub fn run(self) {
+        for cb in self.0 {
+            let _ = catch_unwind(|| cb());
+        }
+    }
+}
+
+/// The state, error, times and callbacks of one job.
+pub struct Lifecycle {
+    pub state: BitsJobState,
+    pub error_count: u32,
+    pub error: Option<BitsJobError>,
+    pub creation: FileTime,
+    pub modification: FileTime,
+    pub transfer_completion: Option<FileTime>,
+    pub minimum_retry_delay: u32,
+    pub no_progress_timeout: u32,
+    // When the job had its first transient error since it last made progress.
+    stalled_since: Option<Instant>,
+    transferred_cb: Option<Arc<Callback>>,
+    error_cb: Option<Arc<Callback>>,
+    modification_cb: Option<Arc<Callback>>,
+}
+
+impl Lifecycle {
+    /// The lifecycle of a new job, which is `Suspended`.
+    pub fn new() -> Lifecycle {
+        let now = FileTime::now();
+        Lifecycle {
+            state: BitsJobState::Suspended,
+            error_count: 0,
+            error: None,
+            creation: now,
+            modification: now,
+            transfer_completion: None,
+            minimum_retry_delay: DEFAULT_RETRY_DELAY_SECS,
+            no_progress_timeout: DEFAULT_NO_PROGRESS_TIMEOUT_SECS,
+            stalled_since: None,
+            transferred_cb: None,
+            error_cb: None,
+            modification_cb: None,
+        }
+    }
+
+    pub fn register_callbacks(
+        &mut self,
+        transferred_cb: Option<Box<TransferredCallback>>,
+        error_cb: Option<Box<ErrorCallback>>,
+        modification_cb: Option<Box<ModificationCallback>>,
+    ) {
+        self.transferred_cb = transferred_cb.map(Arc::from);
+        self.error_cb = error_cb.map(Arc::from);
+        self.modification_cb = modification_cb.map(Arc::from);
+    }
+
+    /// Record a change to the job, for the modification callback.
+    pub fn modified(&mut self, notifications: &mut Notifications) {
+        self.modification = FileTime::now();
+        if let Some(ref cb) = self.modification_cb {
+            notifications.0.push(cb.clone());
+        }
+    }
+
+    pub fn set_state(&mut self, state: BitsJobState, notifications: &mut Notifications) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+
+        let cb = match state {
+            BitsJobState::Transferred => {
+                self.transfer_completion = Some(FileTime::now());
+                self.transferred_cb.clone()
+            }
+            BitsJobState::Error => self.error_cb.clone(),
+            _ => None,
+        };
+        notifications.0.extend(cb);
+
+        self.modified(notifications);
+    }
+
+    /// Put the job in `TransientError` or `Error`. A transient error is only given up on once the
+    /// job has stalled for the no-progress timeout.
+    pub fn set_error(
+        &mut self,
+        error: BitsJobError,
+        transient: bool,
+        notifications: &mut Notifications,
+    ) {
+        let transient = transient && {
+            let stalled_since = *self.stalled_since.get_or_insert_with(Instant::now);
+            stalled_since.elapsed() < Duration::from_secs(u64::from(self.no_progress_timeout))
+        };
+
+        self.error_count += 1;
+        self.error = Some(error);
+        let state = if transient {
+            BitsJobState::TransientError
+        } else {
+            BitsJobState::Error
+        };
+        self.set_state(state, notifications);
+    }
+
+    /// Record that the job has made progress, so it is no longer stalled.
+    pub fn progressed(&mut self, notifications: &mut Notifications) {
+        self.stalled_since = None;
+        self.modified(notifications);
+    }
+
+    /// The status of the job, with `progress` made from its files.
+    pub fn status<I>(&self, files: I) -> BitsJobStatus
+    where
+        I: IntoIterator<Item = BitsFileProgress>,
+    {
+        BitsJobStatus {
+            state: self.state,
+            progress: job_progress(files),
+            error_count: self.error_count,
+            error: match self.state {
+                BitsJobState::Error | BitsJobState::TransientError => self.error.clone(),
+                _ => None,
+            },
+            times: BitsJobTimes {
+                creation: self.creation,
+                modification: self.modification,
+                transfer_completion: self.transfer_completion,
+            },
+        }
+    }
+}
+
+fn job_progress<I>(files: I) -> BitsJobProgress
+where
+    I: IntoIterator<Item = BitsFileProgress>,
+{
+    let files: Vec<BitsFileProgress> = files.into_iter().collect();
+    BitsJobProgress {
+        total_bytes: files.iter().map(|file| file.total_bytes).sum(),
+        transferred_bytes: files.iter().map(|file| file.transferred_bytes).sum(),
+        total_files: files.len() as u32,
+        transferred_files: files.iter().filter(|file| file.completed).count() as u32,
+    }
+}
@@ -0,0 +1,167 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The operations on BITS that a client depends on.
+//!
+//! `InProcessClient` and `InProcessMonitor` do all of their work through a
+//! [`JobBackend`](trait.JobBackend.html), so they can be run against something other than the
+//! live BITS service. `ComBackend` is the implementation which uses COM via the `bits` crate, it
+//! is only available on Windows. [`PortableBits`](portable/struct.PortableBits.html) is a
+//! transfer engine in plain Rust which works without the service. Tests use `SimulatedBits`, an
+//! in-memory model of the service which serves its files without a network.
+//!
+//! The traits follow the shape of the `bits` crate: a backend is a cheap handle that can be sent
+//! between threads, and it is used to `connect()` to a connection which is only used on the
+//! current thread, like `BackgroundCopyManager`. Connections produce jobs, like `BitsJob`. They
+//! are defined in terms of the crate's own [`types`](../types/index.html), so they are the same
+//! on every platform.
+//!
+//! The in-process engines, `PortableBits` and `SimulatedBits`, follow the documented job
+//! lifecycle:
+//!
+//! * A new job starts out `Suspended`, and `resume()` puts it in the queue (`Queued`).
+//! * While a file is being fetched the job is `Connecting`, then `Transferring` once the
+//!   response has started.
+//! * When all files have been fetched the job is `Transferred`, and the transferred callback
+//!   runs.
+//! * `complete()` moves the temporary files into place and the job is `Acknowledged`,
+//!   `cancel()` deletes them and the job is `Cancelled`; either way the job leaves the queue.
+//! * A transient error puts the job in `TransientError`, and it is retried after the minimum
+//!   retry delay, until no progress has been made for the no-progress timeout. Any other error
+//!   puts it in `Error`, and the error callback runs.
+
+use std::ffi::{OsStr, OsString};
+
+use types::{
+    BitsAuthScheme, BitsAuthTarget, BitsFileProgress, BitsFileRange, BitsJobPriority,
+    BitsJobStatus, BitsJobType, BitsProxyUsage, Guid, HResult, HRESULT,
+};
+
+pub use types::{ErrorCallback, ModificationCallback, TransferredCallback};
+
+#[cfg(windows)]
+mod com;
+mod lifecycle;
+pub mod portable;
+#[cfg(test)]
+pub mod simulated;
+
+#[cfg(windows)]
+pub use self::com::ComBackend;
+pub use self::portable::PortableBits;
+#[cfg(test)]
+pub use self::simulated::SimulatedBits;
+
+/// The backend of clients which don't name one: the BITS service on Windows, and the portable
+/// engine elsewhere.
+#[cfg(windows)]
+pub type DefaultBackend = ComBackend;
+#[cfg(not(windows))]
+pub type DefaultBackend = PortableBits;
+
+type Result<T> = std::result::Result<T, HResult>;
+
+/// A source of connections to BITS, or something that acts like it.
+///
+/// A backend must be cheap to clone, as a clone is held by each monitor.
+pub trait JobBackend: Clone + Send + 'static {
+    type Connection: BackendConnection;
+
+    /// Get access to the service, as with `BackgroundCopyManager::connect()`.
+    fn connect(&self) -> Result<Self::Connection>;
+}
+
+/// A connection to the service, used only on the thread where it was made.
+pub trait BackendConnection {
+    type Job: BackendJob;
+
+    /// Create a new download job with the given name.
+    fn create_job(&self, display_name: &OsStr) -> Result<Self::Job> {
+        self.create_job_with_type(display_name, BitsJobType::Download)
+    }
+
+    /// Create a new job of the given type with the given name.
+    fn create_job_with_type(
+        &self,
+        display_name: &OsStr,
+        job_type: BitsJobType,
+    ) -> Result<Self::Job>;
+
+    /// Get the job with the given GUID, `Err` if it was not found.
+    fn get_job_by_guid(&self, guid: &Guid) -> Result<Self::Job>;
+
+    /// Try to find a job with a given GUID and name.
+    ///
+    /// Returns `Ok(None)` if the job was not found, or if it had the wrong name, as long as there
+    /// was no other error.
+    fn find_job_by_guid_and_name(
+        &self,
+        guid: &Guid,
+        match_name: &OsStr,
+    ) -> Result<Option<Self::Job>>;
+
+    /// Get all jobs with the given name, in no particular order.
+    fn find_jobs_by_name(&self, match_name: &OsStr) -> Result<Vec<Self::Job>>;
+
+    /// Translate an `HRESULT` returned from this connection or its jobs to a description.
+    fn get_error_description(&self, hr: HRESULT) -> Result<String>;
+}
+
+/// The state of a file in a job, as from `bits::BitsFile`.
+#[derive(Clone, Debug)]
+pub struct BackendFile {
+    /// The remote name, updated for redirects.
+    pub remote_name: OsString,
+    pub local_name: OsString,
+    pub progress: BitsFileProgress,
+}
+
+/// A single job, see `bits::BitsJob` for the meaning of each method.
+pub trait BackendJob {
+    fn guid(&self) -> Result<Guid>;
+    fn job_type(&self) -> Result<BitsJobType>;
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()>;
+    fn add_file_with_ranges(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: &[BitsFileRange],
+    ) -> Result<()>;
+    /// Each file in the job, in the order they were added.
+    fn get_files(&mut self) -> Result<Vec<BackendFile>>;
+    /// The temporary file that each file in the job is downloaded to, in the order they were
+    /// added.
+    fn get_temporary_names(&mut self) -> Result<Vec<OsString>>;
+    fn set_proxy_usage(&mut self, usage: BitsProxyUsage) -> Result<()>;
+    fn set_priority(&mut self, priority: BitsJobPriority) -> Result<()>;
+    fn get_priority(&self) -> Result<BitsJobPriority>;
+    fn set_minimum_retry_delay(&mut self, seconds: u32) -> Result<()>;
+    fn set_no_progress_timeout(&mut self, seconds: u32) -> Result<()>;
+    fn set_maximum_download_time(&mut self, seconds: u32) -> Result<()>;
+    fn set_redirect_report(&mut self) -> Result<()>;
+    fn set_custom_headers(&mut self, headers: &OsStr) -> Result<()>;
+    fn set_credentials(
+        &mut self,
+        target: BitsAuthTarget,
+        scheme: BitsAuthScheme,
+        user_name: &OsStr,
+        password: &OsStr,
+    ) -> Result<()>;
+    fn remove_credentials(&mut self, target: BitsAuthTarget, scheme: BitsAuthScheme) -> Result<()>;
+    fn set_reply_file_name(&mut self, reply_file: &OsStr) -> Result<()>;
+    fn get_reply_data(&self) -> Result<Vec<u8>>;
+    fn resume(&mut self) -> Result<()>;
+    fn suspend(&mut self) -> Result<()>;
+    /// Returns the success `HRESULT`, which may be `BG_S_PARTIAL_COMPLETE`.
+    fn complete(&mut self) -> Result<HRESULT>;
+    /// Returns the success `HRESULT`, which may be `BG_S_UNABLE_TO_DELETE_FILES`.
+    fn cancel(&mut self) -> Result<HRESULT>;
+    fn register_callbacks(
+        &mut self,
+        transferred_cb: Option<Box<TransferredCallback>>,
+        error_cb: Option<Box<ErrorCallback>>,
+        modification_cb: Option<Box<ModificationCallback>>,
+    ) -> Result<()>;
+    fn get_status(&self) -> Result<BitsJobStatus>;
+}
@@ -0,0 +1,328 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Just enough HTTP/1.1 for the portable engine: a `GET` with an optional range, or a `POST` of an
+//! uploaded file, the response headers it needs, and chunked response bodies.
+
+use std::io;
+
+pub struct HttpRequest<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+    // Request only the bytes from the first offset to the last, or to the end if there is no
+    // last offset.
+    pub range: Option<(u64, Option<u64>)>,
+    // An `ETag` or `Last-Modified` value from an earlier response, used to ask for a range only
+    // if the file has not changed.
+    pub validator: Option<&'a str>,
+    // The length of a file to be uploaded with `POST`, which is sent after the headers.
+    pub upload_length: Option<u64>,
+    // Extra headers, each terminated by CR LF.
+    pub custom_headers: &'a str,
+}
+
+impl<'a> HttpRequest<'a> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let method = if self.upload_length.is_some() {
+            "POST"
+        } else {
+            "GET"
+        };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n",
+            method, self.path, self.host, self.port
+        );
+        if let Some(length) = self.upload_length {
+            request.push_str(&format!(
+                "Content-Type: application/octet-stream\r\nContent-Length: {}\r\n",
+                length
+            ));
+        }
+        if let Some((first, last)) = self.range {
+            match last {
+                Some(last) => request.push_str(&format!("Range: bytes={}-{}\r\n", first, last)),
+                None => request.push_str(&format!("Range: bytes={}-\r\n", first)),
+            }
+            if let Some(validator) = self.validator {
+                request.push_str(&format!("If-Range: {}\r\n", validator));
+            }
+        }
+        request.push_str(self.custom_headers);
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u32,
+    pub content_length: Option<u64>,
+    pub location: Option<String>,
+    // From `Content-Range: bytes first-last/total`, `total` is `None` if given as `*`.
+    pub content_range: Option<(u64, Option<u64>)>,
+    pub validator: Option<String>,
+    // The body is sent in chunks, and `content_length` does not apply.
+    pub chunked: bool,
+}
+
+pub fn parse_response(headers: &[u8]) -> io::Result<HttpResponse> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+
+    let headers = String::from_utf8_lossy(headers);
+    let mut lines = headers.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let mut response = HttpResponse {
+        status,
+        content_length: None,
+        location: None,
+        content_range: None,
+        validator: None,
+        chunked: false,
+    };
+    let mut last_modified = None;
+
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            response.content_length = Some(value.parse().map_err(|_| invalid())?);
+        } else if name.eq_ignore_ascii_case("Location") {
+            response.location = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("Content-Range") {
+            response.content_range = Some(parse_content_range(value).ok_or_else(invalid)?);
+        } else if name.eq_ignore_ascii_case("ETag") {
+            // Weak tags can't be used with `If-Range`.
+            if !value.starts_with("W/") {
+                response.validator = Some(value.to_owned());
+            }
+        } else if name.eq_ignore_ascii_case("Last-Modified") {
+            last_modified = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // Chunked must be the last coding, and no other coding is understood.
+            let mut codings = value.split(',').map(str::trim);
+            if !codings.all(|coding| coding.eq_ignore_ascii_case("chunked")) {
+                return Err(invalid());
+            }
+            response.chunked = !value.is_empty();
+        }
+    }
+    if response.chunked {
+        response.content_length = None;
+    }
+
+    if response.validator.is_none() {
+        response.validator = last_modified;
+    }
+
+    Ok(response)
+}
+
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let value = value.trim_start_matches("bytes").trim_start();
+    let mut parts = value.splitn(2, '/');
+    let range = parts.next()?;
+    let total = match parts.next()? {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    let first = range.split('-').next()?.parse().ok()?;
+    Some((first, total))
+}
+
+// Decodes a body sent with `Transfer-Encoding: chunked`, as in RFC 7230 section 4.1.
+#[derive(Default)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    // The part of a size or trailer line received so far.
+    line: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(u64),
+    // The CR LF after a chunk's data.
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+impl ChunkedDecoder {
+    // Decode `input`, appending the data to `output`. Anything after the end of the body is
+    // ignored.
+    pub fn decode(&mut self, mut input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid chunked body");
+
+        while !input.is_empty() {
+            match self.state {
+                ChunkState::Data(remaining) => {
+                    let count = remaining.min(input.len() as u64) as usize;
+                    output.extend_from_slice(&input[..count]);
+                    input = &input[count..];
+                    self.state = match remaining - count as u64 {
+                        0 => ChunkState::DataEnd,
+                        remaining => ChunkState::Data(remaining),
+                    };
+                }
+                ChunkState::Done => return Ok(()),
+                _ => {
+                    let line = match self.take_line(&mut input) {
+                        Some(line) => line,
+                        None => continue,
+                    };
+                    self.state = match self.state {
+                        ChunkState::Size => {
+                            // Chunk extensions follow the size, and are ignored.
+                            let line = String::from_utf8_lossy(&line);
+                            let size = line.split(';').next().unwrap_or("").trim();
+                            match u64::from_str_radix(size, 16).map_err(|_| invalid())? {
+                                0 => ChunkState::Trailer,
+                                size => ChunkState::Data(size),
+                            }
+                        }
+                        ChunkState::DataEnd if line.is_empty() => ChunkState::Size,
+                        ChunkState::DataEnd => return Err(invalid()),
+                        // Trailer fields are ignored, up to the empty line which ends the body.
+                        _ if line.is_empty() => ChunkState::Done,
+                        state => state,
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    // Take bytes from `input` up to the end of a line, and return the whole line without its
+    // CR LF once it is complete.
+    fn take_line(&mut self, input: &mut &[u8]) -> Option<Vec<u8>> {
+        match input.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                self.line.extend_from_slice(&input[..end]);
+                *input = &input[end + 1..];
+                if self.line.last() == Some(&b'\r') {
+                    self.line.pop();
+                }
+                Some(self.line.split_off(0))
+            }
+            None => {
+                self.line.extend_from_slice(input);
+                *input = &[];
+                None
+            }
+        }
+    }
+}
+
+// Resolve the `Location` of a redirect against the URL which was requested, as in RFC 3986
+// section 5.2. Only the forms of reference a server is expected to send are handled.
+pub fn resolve_url(base: &str, location: &str) -> String {
+    let scheme_end = location.find("://");
+    if scheme_end.is_some() && scheme_end < location.find('/') {
+        // Already absolute.
+        return location.to_owned();
+    }
+
+    let (scheme, rest) = match base.find("://") {
+        Some(end) => base.split_at(end + 3),
+        None => return location.to_owned(),
+    };
+    if let Some(network_path) = location.strip_prefix("//") {
+        return format!("{}{}", scheme, network_path);
+    }
+
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    if location.starts_with('/') {
+        return format!("{}{}{}", scheme, authority, location);
+    }
+
+    // Relative to the directory of the base path, without its query.
+    let path = path.split('?').next().unwrap_or("");
+    let directory = match path.rfind('/') {
+        Some(slash) => &path[..slash + 1],
+        None => "/",
+    };
+    if location.starts_with('?') {
+        return format!("{}{}{}{}", scheme, authority, path, location);
+    }
+    let mut segments: Vec<&str> = directory[1..].split('/').collect();
+    segments.pop();
+    for segment in location.split('/') {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    if location.ends_with("/.") || location.ends_with("/..") || location == "." || location == ".."
+    {
+        segments.push("");
+    }
+    format!("{}{}/{}", scheme, authority, segments.join("/"))
+}
+
+// Split an `http://host[:port]/path` URL.
+pub fn parse_http_url(url: &str) -> Option<(String, u16, String)> {
+    let rest = url.trim_start_matches("http://");
+    if rest.len() == url.len() || rest.is_empty() {
+        return None;
+    }
+
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) => (&authority[..colon], authority[colon + 1..].parse().ok()?),
+        None => (authority, 80),
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((host.to_owned(), port, path.to_owned()))
+}
+
+// The value of an `Authorization` header for Basic authentication, as in RFC 7617.
+pub fn basic_authorization(user_name: &str, password: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let credentials = format!("{}:{}", user_name, password);
+    let mut encoded = String::from("Basic ");
+    for chunk in credentials.as_bytes().chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
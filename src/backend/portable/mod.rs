@@ -0,0 +1,788 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A background transfer engine written in plain Rust, standing in for the BITS service.
+//!
+//! [`PortableBits`](struct.PortableBits.html) is a [`JobBackend`](../trait.JobBackend.html)
+//! which keeps its own queue of jobs and transfers each on a worker thread with a minimal
+//! HTTP/1.1 client over `std::net`. It is used by
+//! [`BitsClient::new_portable()`](../../enum.BitsClient.html#method.new_portable) where BITS is
+//! not available, and as a simulation of BITS in tests.
+//!
+//! Jobs follow the documented job lifecycle, which is described in the `backend` module. A job is
+//! `Transferring` once the response headers have been received. A server error (5xx) or a
+//! network failure is a transient error; any other HTTP error, or a local file error, is not. A
+//! job which is still transferring after the maximum download time fails with
+//! `BG_E_MAXDOWNLOAD_TIMEOUT`.
+//!
+//! An upload job sends its one file with a single `POST`. An upload-reply job keeps the body of
+//! the response as its reply data, and `complete()` saves it to the reply file.
+//!
+//! A retried file resumes from the end of its temporary file with a `Range` request, guarded by
+//! `If-Range` when the server gave an `ETag` or `Last-Modified`; if the server sends the whole
+//! file instead, the file starts over. A file added with ranges is fetched with one `Range`
+//! request for each, and fails if the server will not send just the range.
+//!
+//! Redirects are followed, including to a relative `Location`. A body may be sent with a
+//! `Content-Length`, in chunks, or up to the end of the connection.
+//!
+//! Only `http://` URLs are supported. Jobs are kept in memory, so they do not outlive the
+//! process.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+use types::hresult::{
+    BG_E_EMPTY, BG_E_INVALID_SERVER_RESPONSE, BG_E_INVALID_STATE, BG_E_MAXDOWNLOAD_TIMEOUT,
+    BG_E_NETWORK_DISCONNECTED, BG_E_NOT_FOUND, BG_E_TOO_LARGE, BG_E_TOO_MANY_FILES,
+    BG_S_PARTIAL_COMPLETE, BG_S_UNABLE_TO_DELETE_FILES, E_ACCESSDENIED, E_FAIL, E_INVALIDARG,
+    E_NOTIMPL, E_OUTOFMEMORY, HRESULT_FROM_WIN32, S_OK,
+};
+use types::{
+    BitsAuthScheme, BitsAuthTarget, BitsErrorContext, BitsFileProgress, BitsFileRange,
+    BitsJobError, BitsJobPriority, BitsJobState, BitsJobStatus, BitsJobType, BitsProxyUsage, Guid,
+    HResult, HRESULT,
+};
+
+use super::lifecycle::{Lifecycle, Notifications, HTTP_ERROR_BASE};
+use super::{
+    BackendConnection, BackendFile, BackendJob, ErrorCallback, JobBackend, ModificationCallback,
+    TransferredCallback,
+};
+
+use self::http::{basic_authorization, parse_http_url};
+use self::transfer::{Transfer, TransferError};
+
+mod http;
+mod transfer;
+
+#[cfg(test)]
+mod tests;
+
+type Result<T> = std::result::Result<T, HResult>;
+
+const DEFAULT_MAXIMUM_DOWNLOAD_TIME_SECS: u32 = 90 * 24 * 60 * 60;
+
+// The Win32 errors for the kinds of `io::Error` which have one.
+const E_FILE_NOT_FOUND: HRESULT = HRESULT_FROM_WIN32(2);
+const E_HANDLE_EOF: HRESULT = HRESULT_FROM_WIN32(38);
+const E_FILE_EXISTS: HRESULT = HRESULT_FROM_WIN32(80);
+
+lazy_static! {
+    static ref GLOBAL: PortableBits = PortableBits::new();
+}
+
+/// A portable transfer engine with the interface of the BITS service.
+///
+/// Each `PortableBits` created with `new()` is an independent service with its own queue;
+/// clones share the queue. [`global()`](#method.global) is shared by the whole process.
+#[derive(Clone)]
+pub struct PortableBits(Arc<Shared>);
+
+struct Shared {
+    state: Mutex<EngineState>,
+    // Notified whenever a job is changed by a client, to wake transfers waiting to retry.
+    changed: Condvar,
+}
+
+struct EngineState {
+    jobs: HashMap<Guid, QueuedJob>,
+}
+
+struct QueuedJob {
+    name: OsString,
+    job_type: BitsJobType,
+    files: Vec<QueuedFile>,
+    lifecycle: Lifecycle,
+    proxy_usage: BitsProxyUsage,
+    priority: BitsJobPriority,
+    maximum_download_time: u32,
+    // When the job first started transferring, counted against the maximum download time.
+    transfer_started: Option<Instant>,
+    redirect_report: bool,
+    // Sent with each request, each header terminated by CR LF.
+    custom_headers: String,
+    // The `Authorization` header value, from Basic credentials for the server. Proxies are never
+    // used, so credentials for them are accepted but not kept.
+    authorization: Option<String>,
+    reply_file_name: Option<PathBuf>,
+    // The body of the response to an upload-reply job, once it has been received.
+    reply_data: Option<Vec<u8>>,
+    // Incremented to stop any ongoing transfer thread.
+    generation: usize,
+}
+
+struct QueuedFile {
+    remote_name: OsString,
+    local_name: PathBuf,
+    temp_name: PathBuf,
+    // Only these parts of the remote file are transferred, one after another, if any are given.
+    ranges: Vec<BitsFileRange>,
+    total_bytes: Option<u64>,
+    transferred_bytes: u64,
+    completed: bool,
+    // Identifies the version of the remote file that was partly transferred.
+    validator: Option<String>,
+}
+
+/// A job in a [`PortableBits`](struct.PortableBits.html) queue.
+pub struct PortableJob {
+    bits: PortableBits,
+    guid: Guid,
+}
+
+impl PortableBits {
+    /// Create a new engine with an empty queue.
+    pub fn new() -> PortableBits {
+        PortableBits(Arc::new(Shared {
+            state: Mutex::new(EngineState {
+                jobs: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+        }))
+    }
+
+    /// Cancel all jobs with the given name, as `BackgroundCopyManager::cancel_jobs_by_name()`.
+    pub fn cancel_jobs_by_name(&self, match_name: &OsStr) {
+        if let Ok(jobs) = self.find_jobs_by_name(match_name) {
+            for mut job in jobs {
+                let _ = job.cancel();
+            }
+        }
+    }
+
+    /// The engine shared by the whole process, like the system's BITS service.
+    pub fn global() -> PortableBits {
+        GLOBAL.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, EngineState> {
+        self.0.state.lock().unwrap()
+    }
+
+    fn job(&self, guid: Guid) -> PortableJob {
+        PortableJob {
+            bits: self.clone(),
+            guid,
+        }
+    }
+}
+
+impl Default for PortableBits {
+    fn default() -> PortableBits {
+        PortableBits::new()
+    }
+}
+
+impl JobBackend for PortableBits {
+    type Connection = PortableBits;
+
+    fn connect(&self) -> Result<PortableBits> {
+        Ok(self.clone())
+    }
+}
+
+impl BackendConnection for PortableBits {
+    type Job = PortableJob;
+
+    fn create_job_with_type(
+        &self,
+        display_name: &OsStr,
+        job_type: BitsJobType,
+    ) -> Result<PortableJob> {
+        let guid = Guid::new_random();
+
+        self.lock().jobs.insert(
+            guid.clone(),
+            QueuedJob {
+                name: display_name.to_os_string(),
+                job_type,
+                files: Vec::new(),
+                lifecycle: Lifecycle::new(),
+                proxy_usage: BitsProxyUsage::Preconfig,
+                priority: BitsJobPriority::Normal,
+                maximum_download_time: DEFAULT_MAXIMUM_DOWNLOAD_TIME_SECS,
+                transfer_started: None,
+                redirect_report: false,
+                custom_headers: String::new(),
+                authorization: None,
+                reply_file_name: None,
+                reply_data: None,
+                generation: 0,
+            },
+        );
+
+        Ok(self.job(guid))
+    }
+
+    fn get_job_by_guid(&self, guid: &Guid) -> Result<PortableJob> {
+        if self.lock().jobs.contains_key(guid) {
+            Ok(self.job(guid.clone()))
+        } else {
+            Err(HResult::new(BG_E_NOT_FOUND))
+        }
+    }
+
+    fn find_job_by_guid_and_name(
+        &self,
+        guid: &Guid,
+        match_name: &OsStr,
+    ) -> Result<Option<PortableJob>> {
+        Ok(match self.lock().jobs.get(guid) {
+            Some(job) if job.name == match_name => Some(self.job(guid.clone())),
+            _ => None,
+        })
+    }
+
+    fn find_jobs_by_name(&self, match_name: &OsStr) -> Result<Vec<PortableJob>> {
+        Ok(self
+            .lock()
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.name == match_name)
+            .map(|(guid, _)| self.job(guid.clone()))
+            .collect())
+    }
+
+    fn get_error_description(&self, hr: HRESULT) -> Result<String> {
+        error_description(hr)
+            .map(String::from)
+            .ok_or_else(|| HResult::new(hr))
+    }
+}
+
+impl PortableJob {
+    fn add(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: Vec<BitsFileRange>,
+    ) -> Result<()> {
+        if remote_url.to_str().and_then(parse_http_url).is_none() {
+            return Err(HResult::new(E_INVALIDARG));
+        }
+
+        let guid = self.guid.clone();
+        self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Transferred
+                | BitsJobState::Acknowledged
+                | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                _ => {}
+            }
+            if job.job_type != BitsJobType::Download && !job.files.is_empty() {
+                return Err(HResult::new(BG_E_TOO_MANY_FILES));
+            }
+
+            // Named for the job, so files of other jobs in the same directory can't collide.
+            let local_name = PathBuf::from(local_file);
+            let guid = guid.to_string();
+            let temp_name = local_name.with_file_name(format!(
+                "BIT{}-{}.tmp",
+                &guid[1..guid.len() - 1],
+                job.files.len()
+            ));
+            job.files.push(QueuedFile {
+                remote_name: remote_url.to_os_string(),
+                local_name,
+                temp_name,
+                ranges,
+                total_bytes: None,
+                transferred_bytes: 0,
+                completed: false,
+                validator: None,
+            });
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    // Run `f` on this job's state, then deliver any notifications it queued.
+    fn with_job<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut QueuedJob, &mut Notifications) -> Result<T>,
+    {
+        let mut notifications = Notifications::default();
+        let result = {
+            let mut state = self.bits.lock();
+            let job = state
+                .jobs
+                .get_mut(&self.guid)
+                .ok_or_else(|| HResult::new(BG_E_NOT_FOUND))?;
+            f(job, &mut notifications)
+        };
+        self.bits.0.changed.notify_all();
+        notifications.run();
+        result
+    }
+
+    // Remove the job from the queue, it may not be found again.
+    fn remove(&self) {
+        self.bits.lock().jobs.remove(&self.guid);
+    }
+}
+
+impl BackendJob for PortableJob {
+    fn guid(&self) -> Result<Guid> {
+        Ok(self.guid.clone())
+    }
+
+    fn job_type(&self) -> Result<BitsJobType> {
+        self.with_job(|job, _| Ok(job.job_type))
+    }
+
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
+        self.add(remote_url, local_file, Vec::new())
+    }
+
+    fn add_file_with_ranges(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: &[BitsFileRange],
+    ) -> Result<()> {
+        if ranges.is_empty() || ranges.iter().any(|range| range.length == Some(0)) {
+            return Err(HResult::new(E_INVALIDARG));
+        }
+        self.add(remote_url, local_file, ranges.to_vec())
+    }
+
+    fn get_files(&mut self) -> Result<Vec<BackendFile>> {
+        self.with_job(|job, _| {
+            Ok(job
+                .files
+                .iter()
+                .map(|file| BackendFile {
+                    remote_name: file.remote_name.clone(),
+                    local_name: file.local_name.clone().into_os_string(),
+                    progress: file.progress(),
+                })
+                .collect())
+        })
+    }
+
+    fn get_temporary_names(&mut self) -> Result<Vec<OsString>> {
+        self.with_job(|job, _| {
+            Ok(job
+                .files
+                .iter()
+                .map(|file| file.temp_name.clone().into_os_string())
+                .collect())
+        })
+    }
+
+    // Transfers always connect directly, so only the settings which may do that are accepted.
+    fn set_proxy_usage(&mut self, usage: BitsProxyUsage) -> Result<()> {
+        if let BitsProxyUsage::Override { .. } = usage {
+            return Err(HResult::new(E_NOTIMPL));
+        }
+
+        self.with_job(|job, notifications| {
+            job.proxy_usage = usage;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_priority(&mut self, priority: BitsJobPriority) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.priority = priority;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn get_priority(&self) -> Result<BitsJobPriority> {
+        self.with_job(|job, _| Ok(job.priority))
+    }
+
+    fn set_minimum_retry_delay(&mut self, seconds: u32) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.minimum_retry_delay = seconds;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_no_progress_timeout(&mut self, seconds: u32) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.no_progress_timeout = seconds;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_maximum_download_time(&mut self, seconds: u32) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.maximum_download_time = seconds;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_redirect_report(&mut self) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.redirect_report = true;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_custom_headers(&mut self, headers: &OsStr) -> Result<()> {
+        let headers = headers.to_str().ok_or_else(|| HResult::new(E_INVALIDARG))?;
+        if !headers.is_empty() && !headers.ends_with("\r\n") {
+            return Err(HResult::new(E_INVALIDARG));
+        }
+
+        self.with_job(|job, notifications| {
+            job.custom_headers = headers.to_owned();
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    // Only Basic authentication is supported.
+    fn set_credentials(
+        &mut self,
+        target: BitsAuthTarget,
+        scheme: BitsAuthScheme,
+        user_name: &OsStr,
+        password: &OsStr,
+    ) -> Result<()> {
+        if scheme != BitsAuthScheme::Basic {
+            return Err(HResult::new(E_NOTIMPL));
+        }
+        let user_name = user_name
+            .to_str()
+            .ok_or_else(|| HResult::new(E_INVALIDARG))?;
+        let password = password
+            .to_str()
+            .ok_or_else(|| HResult::new(E_INVALIDARG))?;
+
+        self.with_job(|job, notifications| {
+            if target == BitsAuthTarget::Server {
+                job.authorization = Some(basic_authorization(user_name, password));
+            }
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn remove_credentials(&mut self, target: BitsAuthTarget, scheme: BitsAuthScheme) -> Result<()> {
+        self.with_job(|job, notifications| {
+            if target == BitsAuthTarget::Server && scheme == BitsAuthScheme::Basic {
+                job.authorization = None;
+            }
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_reply_file_name(&mut self, reply_file: &OsStr) -> Result<()> {
+        self.with_job(|job, notifications| {
+            if job.job_type != BitsJobType::UploadReply {
+                return Err(HResult::new(E_NOTIMPL));
+            }
+            job.reply_file_name = Some(PathBuf::from(reply_file));
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn get_reply_data(&self) -> Result<Vec<u8>> {
+        self.with_job(|job, _| {
+            if job.job_type != BitsJobType::UploadReply {
+                return Err(HResult::new(E_NOTIMPL));
+            }
+            job.reply_data
+                .clone()
+                .ok_or_else(|| HResult::new(BG_E_INVALID_STATE))
+        })
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let generation = self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Transferred
+                | BitsJobState::Acknowledged
+                | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                BitsJobState::Queued | BitsJobState::Connecting | BitsJobState::Transferring => {
+                    // Already active.
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            if job.files.is_empty() {
+                return Err(HResult::new(BG_E_EMPTY));
+            }
+
+            job.generation += 1;
+            job.lifecycle.set_state(BitsJobState::Queued, notifications);
+            Ok(Some(job.generation))
+        })?;
+
+        if let Some(generation) = generation {
+            let transfer = Transfer {
+                bits: self.bits.clone(),
+                guid: self.guid.clone(),
+                generation,
+            };
+            thread::Builder::new()
+                .name(format!("PortableBits transfer {}", self.guid.data1))
+                .spawn(move || transfer.run())
+                .map_err(|_| HResult::new(E_FAIL))?;
+        }
+
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        self.with_job(|job, notifications| match job.lifecycle.state {
+            BitsJobState::Transferred | BitsJobState::Acknowledged | BitsJobState::Cancelled => {
+                Err(HResult::new(BG_E_INVALID_STATE))
+            }
+            BitsJobState::Suspended => Ok(()),
+            _ => {
+                job.generation += 1;
+                job.lifecycle
+                    .set_state(BitsJobState::Suspended, notifications);
+                Ok(())
+            }
+        })
+    }
+
+    // Either every completed file is moved into place and the reply is saved, or nothing is
+    // changed and the job is left as it was.
+    fn complete(&mut self) -> Result<HRESULT> {
+        let result = self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Acknowledged | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                _ => {}
+            }
+
+            // Uploaded files stay where they are, and the reply is saved instead.
+            let moves: Vec<&QueuedFile> = if job.job_type == BitsJobType::Download {
+                job.files.iter().filter(|file| file.completed).collect()
+            } else {
+                Vec::new()
+            };
+            for file in &moves {
+                fs::metadata(&file.temp_name).map_err(|e| HResult::new(hresult_from_io(&e)))?;
+            }
+
+            let mut reply_saved = None;
+            if let (Some(reply_file_name), Some(reply_data)) =
+                (job.reply_file_name.as_ref(), job.reply_data.as_ref())
+            {
+                if let Err(e) = fs::write(reply_file_name, reply_data) {
+                    let _ = fs::remove_file(reply_file_name);
+                    return Err(HResult::new(hresult_from_io(&e)));
+                }
+                reply_saved = Some(reply_file_name);
+            }
+
+            for (moved, file) in moves.iter().enumerate() {
+                if let Err(e) = fs::rename(&file.temp_name, &file.local_name) {
+                    for file in &moves[..moved] {
+                        let _ = fs::rename(&file.local_name, &file.temp_name);
+                    }
+                    if let Some(reply_file_name) = reply_saved {
+                        let _ = fs::remove_file(reply_file_name);
+                    }
+                    return Err(HResult::new(hresult_from_io(&e)));
+                }
+            }
+
+            // Files which were not transferred are abandoned.
+            let mut partial = false;
+            for file in job.files.iter().filter(|file| !file.completed) {
+                partial = true;
+                let _ = fs::remove_file(&file.temp_name);
+            }
+
+            job.generation += 1;
+            job.lifecycle
+                .set_state(BitsJobState::Acknowledged, notifications);
+
+            Ok(if partial { BG_S_PARTIAL_COMPLETE } else { S_OK })
+        })?;
+
+        self.remove();
+        Ok(result)
+    }
+
+    fn cancel(&mut self) -> Result<HRESULT> {
+        let result = self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Acknowledged | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                _ => {}
+            }
+            job.generation += 1;
+
+            let mut unable_to_delete = false;
+            // Uploads never create a temporary file, so there is nothing of theirs to find.
+            for file in &job.files {
+                if let Err(e) = fs::remove_file(&file.temp_name) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        unable_to_delete = true;
+                    }
+                }
+            }
+
+            job.lifecycle
+                .set_state(BitsJobState::Cancelled, notifications);
+
+            Ok(if unable_to_delete {
+                BG_S_UNABLE_TO_DELETE_FILES
+            } else {
+                S_OK
+            })
+        })?;
+
+        self.remove();
+        Ok(result)
+    }
+
+    fn register_callbacks(
+        &mut self,
+        transferred_cb: Option<Box<TransferredCallback>>,
+        error_cb: Option<Box<ErrorCallback>>,
+        modification_cb: Option<Box<ModificationCallback>>,
+    ) -> Result<()> {
+        self.with_job(|job, _| {
+            job.lifecycle
+                .register_callbacks(transferred_cb, error_cb, modification_cb);
+            Ok(())
+        })
+    }
+
+    fn get_status(&self) -> Result<BitsJobStatus> {
+        self.with_job(|job, _| {
+            Ok(job
+                .lifecycle
+                .status(job.files.iter().map(QueuedFile::progress)))
+        })
+    }
+}
+
+impl QueuedJob {
+    // The headers to add to each request, each terminated by CR LF.
+    fn request_headers(&self) -> String {
+        let mut headers = self.custom_headers.clone();
+        if let Some(ref authorization) = self.authorization {
+            headers.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        headers
+    }
+
+    fn set_error(&mut self, error: &TransferError, url: &OsStr, notifications: &mut Notifications) {
+        let job_error = BitsJobError {
+            context: error.context,
+            context_str: context_description(error.context).to_owned(),
+            error: error.hr,
+            error_str: format!(
+                "{} ({})",
+                error_description(error.hr).unwrap_or("Unknown error"),
+                url.to_string_lossy()
+            ),
+        };
+        self.lifecycle
+            .set_error(job_error, error.transient, notifications);
+    }
+}
+
+impl QueuedFile {
+    fn progress(&self) -> BitsFileProgress {
+        BitsFileProgress {
+            total_bytes: self.total_bytes,
+            transferred_bytes: self.transferred_bytes,
+            completed: self.completed,
+        }
+    }
+}
+
+fn hresult_from_io(e: &io::Error) -> HRESULT {
+    // On Windows the OS error is a Win32 error code, elsewhere only the kind of error means the
+    // same thing.
+    #[cfg(windows)]
+    {
+        if let Some(code) = e.raw_os_error() {
+            return HRESULT_FROM_WIN32(code as u32);
+        }
+    }
+
+    match e.kind() {
+        io::ErrorKind::NotFound => E_FILE_NOT_FOUND,
+        io::ErrorKind::PermissionDenied => E_ACCESSDENIED,
+        io::ErrorKind::AlreadyExists => E_FILE_EXISTS,
+        io::ErrorKind::InvalidInput => E_INVALIDARG,
+        io::ErrorKind::OutOfMemory => E_OUTOFMEMORY,
+        io::ErrorKind::UnexpectedEof => E_HANDLE_EOF,
+        _ => E_FAIL,
+    }
+}
+
+fn context_description(context: BitsErrorContext) -> &'static str {
+    match context {
+        BitsErrorContext::GeneralQueueManager => {
+            "An error occurred in the Background Intelligent Transfer Service (BITS) queue manager."
+        }
+        BitsErrorContext::LocalFile => {
+            "The error occurred while the local file was being processed."
+        }
+        BitsErrorContext::RemoteFile => {
+            "The error occurred while the remote file was being processed."
+        }
+        BitsErrorContext::GeneralTransport => {
+            "The error occurred in the transport layer. The client could not connect to the server."
+        }
+        _ => "An unknown error occurred.",
+    }
+}
+
+fn error_description(hr: HRESULT) -> Option<&'static str> {
+    Some(match hr {
+        BG_E_NOT_FOUND => "The requested job was not found.",
+        BG_E_INVALID_STATE => "The requested action is not allowed in the current job state.",
+        BG_E_EMPTY => "There are currently no files attached to this job.",
+        BG_E_NETWORK_DISCONNECTED => "A connection could not be established with the server.",
+        BG_E_INVALID_SERVER_RESPONSE => "The server's response was not valid.",
+        BG_E_TOO_MANY_FILES => "An upload job can only have one file.",
+        BG_E_TOO_LARGE => "The server's reply was too large.",
+        BG_E_MAXDOWNLOAD_TIMEOUT => "The job was not completed within the maximum download time.",
+        BG_S_PARTIAL_COMPLETE => {
+            "Some of the transferred files were deleted because they were \
+                                  incomplete."
+        }
+        BG_S_UNABLE_TO_DELETE_FILES => "Some of the temporary files could not be deleted.",
+        E_FILE_NOT_FOUND => "The system cannot find the file specified.",
+        E_ACCESSDENIED => "Access is denied.",
+        E_FILE_EXISTS => "The file exists.",
+        E_HANDLE_EOF => "Reached the end of the file.",
+        E_OUTOFMEMORY => "Not enough memory resources are available to complete this operation.",
+        E_INVALIDARG => "The parameter is incorrect.",
+        hr if hr as u32 & 0xFFFF_0000 == HTTP_ERROR_BASE => match hr & 0xFFFF {
+            404 => "The requested URL does not exist on the server.",
+            408 => "The server timed out waiting for the request.",
+            500 => "An unexpected condition prevented the server from fulfilling the request.",
+            503 => "The service is temporarily overloaded.",
+            _ => "The server returned an HTTP error status.",
+        },
+        _ => return None,
+    })
+}
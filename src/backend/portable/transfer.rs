@@ -0,0 +1,792 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The worker thread that transfers the files of one job.
+
+use std::cmp;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use types::hresult::{
+    BG_E_INVALID_SERVER_RESPONSE, BG_E_MAXDOWNLOAD_TIMEOUT, BG_E_NETWORK_DISCONNECTED,
+    BG_E_TOO_LARGE, E_INVALIDARG,
+};
+use types::{BitsErrorContext, BitsFileRange, BitsJobState, BitsJobType, Guid, HRESULT};
+
+use super::http::{
+    find_subslice, parse_http_url, parse_response, resolve_url, ChunkedDecoder, HttpRequest,
+    HttpResponse,
+};
+use super::{hresult_from_io, PortableBits, QueuedJob};
+use backend::lifecycle::{Notifications, HTTP_ERROR_BASE};
+
+// How often a blocked transfer checks whether it has been suspended or cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const BUFFER_SIZE: usize = 64 * 1024;
+const MAX_REDIRECTS: u32 = 10;
+// The longest reply kept for an upload-reply job.
+const MAX_REPLY_SIZE: usize = 1 << 20;
+
+pub struct TransferError {
+    pub hr: HRESULT,
+    pub context: BitsErrorContext,
+    pub transient: bool,
+}
+
+impl TransferError {
+    fn local(e: &io::Error) -> TransferError {
+        TransferError {
+            hr: hresult_from_io(e),
+            context: BitsErrorContext::LocalFile,
+            transient: false,
+        }
+    }
+
+    fn network(e: &io::Error) -> TransferError {
+        TransferError {
+            hr: match e.kind() {
+                io::ErrorKind::InvalidData => BG_E_INVALID_SERVER_RESPONSE,
+                _ => BG_E_NETWORK_DISCONNECTED,
+            },
+            context: BitsErrorContext::GeneralTransport,
+            transient: true,
+        }
+    }
+
+    fn http(status: u32) -> TransferError {
+        TransferError {
+            hr: (HTTP_ERROR_BASE | status) as HRESULT,
+            context: BitsErrorContext::RemoteFile,
+            // Server errors and timeouts may go away, other errors will not.
+            transient: status >= 500 || status == 408,
+        }
+    }
+}
+
+// The framing of a response body: a length, chunks, or the rest of the connection.
+enum Body {
+    Length(u64),
+    Chunked(ChunkedDecoder),
+    ToEnd,
+}
+
+impl Body {
+    fn new(response: &HttpResponse) -> Body {
+        match response.content_length {
+            _ if response.chunked => Body::Chunked(ChunkedDecoder::default()),
+            Some(length) => Body::Length(length),
+            None => Body::ToEnd,
+        }
+    }
+
+    // Append the body data in `input` to `output`, ignoring anything after the body.
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        match *self {
+            Body::Length(ref mut remaining) => {
+                let count = cmp::min(*remaining, input.len() as u64) as usize;
+                output.extend_from_slice(&input[..count]);
+                *remaining -= count as u64;
+            }
+            Body::Chunked(ref mut decoder) => decoder.decode(input, output)?,
+            Body::ToEnd => output.extend_from_slice(input),
+        }
+        Ok(())
+    }
+
+    fn is_done(&self) -> bool {
+        match *self {
+            Body::Length(remaining) => remaining == 0,
+            Body::Chunked(ref decoder) => decoder.is_done(),
+            Body::ToEnd => false,
+        }
+    }
+
+    // Check that the body is complete when the connection has closed.
+    fn end(&self) -> io::Result<()> {
+        match *self {
+            Body::ToEnd => Ok(()),
+            _ if self.is_done() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in body",
+            )),
+        }
+    }
+}
+
+// The range to request next of a file with `ranges`, once `transferred` bytes of them have been
+// transferred, or `None` if all of them have been.
+fn next_range(ranges: &[BitsFileRange], mut transferred: u64) -> Option<(u64, Option<u64>)> {
+    for range in ranges {
+        match range.length {
+            Some(length) if transferred >= length => transferred -= length,
+            Some(length) => {
+                return Some((range.offset + transferred, Some(range.offset + length - 1)));
+            }
+            None => return Some((range.offset + transferred, None)),
+        }
+    }
+    None
+}
+
+// The total length of `ranges`, given the total length of the remote file if it is known.
+fn ranges_length(ranges: &[BitsFileRange], file_length: Option<u64>) -> Option<u64> {
+    ranges.iter().try_fold(0u64, |sum, range| {
+        let length = match range.length {
+            Some(length) => length,
+            None => file_length?.checked_sub(range.offset)?,
+        };
+        Some(sum + length)
+    })
+}
+
+// Returned when the transfer was stopped by a change to the job, rather than an error.
+struct Stopped;
+
+enum Step {
+    Continue,
+    Done,
+}
+
+type Fetched = std::result::Result<std::result::Result<(), TransferError>, Stopped>;
+
+// The background thread transferring one job, it stops when the job's `generation` changes.
+pub struct Transfer {
+    pub bits: PortableBits,
+    pub guid: Guid,
+    pub generation: usize,
+}
+
+impl Transfer {
+    pub fn run(self) {
+        loop {
+            match self.step() {
+                Ok(Step::Continue) => {}
+                Ok(Step::Done) | Err(Stopped) => return,
+            }
+        }
+    }
+
+    // Run `f` on the job's state if this transfer is still current.
+    fn with_job<T, F>(&self, f: F) -> std::result::Result<T, Stopped>
+    where
+        F: FnOnce(&mut QueuedJob, &mut Notifications) -> T,
+    {
+        let mut notifications = Notifications::default();
+        let result = {
+            let mut state = self.bits.lock();
+            match state.jobs.get_mut(&self.guid) {
+                Some(job) if job.generation == self.generation => f(job, &mut notifications),
+                _ => return Err(Stopped),
+            }
+        };
+        notifications.run();
+        Ok(result)
+    }
+
+    // Transfer the next incomplete file, or finish the job.
+    fn step(&self) -> std::result::Result<Step, Stopped> {
+        let next = self.with_job(|job, notifications| {
+            let index = match job.files.iter().position(|file| !file.completed) {
+                Some(index) => index,
+                None => {
+                    job.lifecycle
+                        .set_state(BitsJobState::Transferred, notifications);
+                    return None;
+                }
+            };
+
+            let started = *job.transfer_started.get_or_insert_with(Instant::now);
+            let maximum_download_time = u64::from(job.maximum_download_time);
+            if started.elapsed() >= Duration::from_secs(maximum_download_time) {
+                let error = TransferError {
+                    hr: BG_E_MAXDOWNLOAD_TIMEOUT,
+                    context: BitsErrorContext::GeneralQueueManager,
+                    transient: false,
+                };
+                let url = job.files[index].remote_name.clone();
+                job.set_error(&error, &url, notifications);
+                return None;
+            }
+            job.lifecycle
+                .set_state(BitsJobState::Connecting, notifications);
+
+            // An upload is sent from the local file, and always starts over.
+            let upload = job.job_type != BitsJobType::Download;
+            let file = &mut job.files[index];
+            if upload {
+                return Some((index, file.remote_name.clone(), upload, Ok(())));
+            }
+
+            // Pick up where an earlier attempt left off, if the temporary file is intact.
+            let intact = fs::metadata(&file.temp_name)
+                .map(|metadata| metadata.len() == file.transferred_bytes)
+                .unwrap_or(false);
+            let prepared = if file.transferred_bytes > 0 && intact {
+                Ok(())
+            } else {
+                file.transferred_bytes = 0;
+                File::create(&file.temp_name).map(|_| ())
+            };
+            Some((index, file.remote_name.clone(), upload, prepared))
+        })?;
+
+        let (index, url, upload, prepared) = match next {
+            None => return Ok(Step::Done),
+            Some(next) => next,
+        };
+
+        let result = match prepared {
+            Err(e) => Err(TransferError::local(&e)),
+            Ok(()) if upload => self.upload(index, &url)?,
+            Ok(()) => self.fetch(index, &url)?,
+        };
+
+        match result {
+            Ok(()) => Ok(Step::Continue),
+            Err(error) => {
+                let transient = self.with_job(|job, notifications| {
+                    job.set_error(&error, &url, notifications);
+                    job.lifecycle.state == BitsJobState::TransientError
+                })?;
+
+                if !transient {
+                    return Ok(Step::Done);
+                }
+
+                self.wait_for_retry()?;
+                self.with_job(|job, notifications| {
+                    job.lifecycle.set_state(BitsJobState::Queued, notifications)
+                })?;
+                Ok(Step::Continue)
+            }
+        }
+    }
+
+    // Wait for the minimum retry delay, which may be changed while waiting.
+    fn wait_for_retry(&self) -> std::result::Result<(), Stopped> {
+        let failed_at = Instant::now();
+        let mut state = self.bits.lock();
+        loop {
+            let retry_at = match state.jobs.get(&self.guid) {
+                Some(job) if job.generation == self.generation => {
+                    failed_at + Duration::from_secs(u64::from(job.lifecycle.minimum_retry_delay))
+                }
+                _ => return Err(Stopped),
+            };
+
+            let now = Instant::now();
+            if now >= retry_at {
+                return Ok(());
+            }
+            state = self
+                .bits
+                .0
+                .changed
+                .wait_timeout(state, retry_at - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    // Fetch file `index` from `url`, or each of its ranges in turn. What has already been
+    // transferred is not requested again.
+    //
+    // The outer `Result` is `Err` if the transfer was stopped, the inner if the transfer failed.
+    //
+    // `Option::is_none_or()` is too new to use here.
+    #[allow(clippy::unnecessary_map_or)]
+    fn fetch(&self, index: usize, url: &OsStr) -> Fetched {
+        loop {
+            let (transferred, ranges) = self.with_job(|job, _| {
+                let file = &job.files[index];
+                (file.transferred_bytes, file.ranges.clone())
+            })?;
+
+            let range = if ranges.is_empty() {
+                if transferred > 0 {
+                    Some((transferred, None))
+                } else {
+                    None
+                }
+            } else {
+                match next_range(&ranges, transferred) {
+                    Some(range) => Some(range),
+                    None => return self.finish_file(index),
+                }
+            };
+
+            match self.fetch_range(index, url, range, !ranges.is_empty())? {
+                Ok(()) => {}
+                Err(e) => return Ok(Err(e)),
+            }
+
+            // The whole file, or an open-ended range, is finished once its body has been read.
+            if ranges.is_empty() || range.map_or(true, |(_, last)| last.is_none()) {
+                return self.finish_file(index);
+            }
+        }
+    }
+
+    // Request `range` of file `index` from `url`, following redirects, and append the body to
+    // the temporary file. For a file without ranges, a `range` resumes an earlier attempt.
+    fn fetch_range(
+        &self,
+        index: usize,
+        url: &OsStr,
+        range: Option<(u64, Option<u64>)>,
+        ranged: bool,
+    ) -> Fetched {
+        let mut url = url.to_string_lossy().into_owned();
+        let mut redirects = 0;
+        let (validator, custom_headers) =
+            self.with_job(|job, _| (job.files[index].validator.clone(), job.request_headers()))?;
+        let mut range = range;
+
+        loop {
+            let (host, port, path) = match parse_http_url(&url) {
+                Some(parsed) => parsed,
+                None => {
+                    return Ok(Err(TransferError {
+                        hr: E_INVALIDARG,
+                        context: BitsErrorContext::RemoteFile,
+                        transient: false,
+                    }));
+                }
+            };
+
+            let mut stream = match TcpStream::connect((host.as_str(), port)) {
+                Ok(stream) => stream,
+                Err(e) => return Ok(Err(TransferError::network(&e))),
+            };
+
+            let request = HttpRequest {
+                host: &host,
+                port,
+                path: &path,
+                range,
+                validator: validator.as_deref(),
+                upload_length: None,
+                custom_headers: &custom_headers,
+            };
+            if let Err(e) = stream
+                .set_read_timeout(Some(POLL_INTERVAL))
+                .and_then(|()| stream.write_all(&request.to_bytes()))
+            {
+                return Ok(Err(TransferError::network(&e)));
+            }
+
+            let (response, body_start) = match self.read_headers(&mut stream)? {
+                Ok(headers) => headers,
+                Err(e) => return Ok(Err(TransferError::network(&e))),
+            };
+
+            let redirect = match response.location {
+                Some(ref location) if response.status >= 300 && response.status < 400 => {
+                    Some(resolve_url(&url, location))
+                }
+                _ => None,
+            };
+
+            if let Some(location) = redirect {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Ok(Err(TransferError::http(response.status)));
+                }
+                url = location;
+                let redirected = OsString::from(url.as_str());
+                self.with_job(|job, notifications| {
+                    if job.redirect_report {
+                        job.files[index].remote_name = redirected;
+                        job.lifecycle.modified(notifications);
+                    }
+                })?;
+                continue;
+            }
+
+            if response.status == 416 && !ranged && range.is_some() {
+                // The range is no longer valid, start the file over.
+                if let Err(e) = self.restart_file(index)? {
+                    return Ok(Err(TransferError::local(&e)));
+                }
+                range = None;
+                continue;
+            }
+
+            if response.status < 200 || response.status >= 300 {
+                return Ok(Err(TransferError::http(response.status)));
+            }
+
+            let first = range.map_or(0, |(first, _)| first);
+            let total_bytes = match (response.status, response.content_range) {
+                (206, Some((start, total))) if range.is_some() && start == first => {
+                    total.or_else(|| response.content_length.map(|length| first + length))
+                }
+                _ if ranged => {
+                    // The server won't send just the range. If it sent the whole file because
+                    // the file has changed, the ranges can be fetched again from the start.
+                    let changed = first > 0 && validator.is_some();
+                    if let Err(e) = self.restart_file(index)? {
+                        return Ok(Err(TransferError::local(&e)));
+                    }
+                    return Ok(Err(TransferError {
+                        hr: BG_E_INVALID_SERVER_RESPONSE,
+                        context: BitsErrorContext::RemoteFile,
+                        transient: changed,
+                    }));
+                }
+                (206, _) => {
+                    // Not the range that was requested, so it can't be appended; try the whole
+                    // file next time.
+                    if let Err(e) = self.restart_file(index)? {
+                        return Ok(Err(TransferError::local(&e)));
+                    }
+                    return Ok(Err(TransferError::network(&io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected Content-Range",
+                    ))));
+                }
+                _ => {
+                    // The whole file is being sent, from the start.
+                    if range.is_some() {
+                        if let Err(e) = self.restart_file(index)? {
+                            return Ok(Err(TransferError::local(&e)));
+                        }
+                    }
+                    response.content_length
+                }
+            };
+
+            self.with_job(|job, notifications| {
+                let file = &mut job.files[index];
+                file.total_bytes = if ranged {
+                    ranges_length(&file.ranges, total_bytes)
+                } else {
+                    total_bytes
+                };
+                if response.validator.is_some() {
+                    file.validator = response.validator.clone();
+                }
+                job.lifecycle
+                    .set_state(BitsJobState::Transferring, notifications);
+                job.lifecycle.modified(notifications);
+            })?;
+
+            return self.read_body(&mut stream, index, body_start, &response);
+        }
+    }
+
+    // Mark file `index` as completely transferred.
+    fn finish_file(&self, index: usize) -> Fetched {
+        self.with_job(|job, notifications| {
+            let file = &mut job.files[index];
+            file.completed = true;
+            if file.total_bytes.is_none() {
+                file.total_bytes = Some(file.transferred_bytes);
+            }
+            job.lifecycle.modified(notifications);
+        })?;
+
+        Ok(Ok(()))
+    }
+
+    // Upload file `index` to `url` with a single `POST`, keeping the reply if the job is an
+    // upload-reply job. Redirects are not followed.
+    fn upload(&self, index: usize, url: &OsStr) -> Fetched {
+        let url = url.to_string_lossy().into_owned();
+        let (host, port, path) = match parse_http_url(&url) {
+            Some(parsed) => parsed,
+            None => {
+                return Ok(Err(TransferError {
+                    hr: E_INVALIDARG,
+                    context: BitsErrorContext::RemoteFile,
+                    transient: false,
+                }));
+            }
+        };
+
+        let (local_name, wants_reply, custom_headers) = self.with_job(|job, _| {
+            (
+                job.files[index].local_name.clone(),
+                job.job_type == BitsJobType::UploadReply,
+                job.request_headers(),
+            )
+        })?;
+        let (mut file, length) = match File::open(&local_name)
+            .and_then(|file| file.metadata().map(|metadata| (file, metadata.len())))
+        {
+            Ok(opened) => opened,
+            Err(e) => return Ok(Err(TransferError::local(&e))),
+        };
+        self.with_job(|job, notifications| {
+            let file = &mut job.files[index];
+            file.total_bytes = Some(length);
+            file.transferred_bytes = 0;
+            job.lifecycle.modified(notifications);
+        })?;
+
+        let mut stream = match TcpStream::connect((host.as_str(), port)) {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(TransferError::network(&e))),
+        };
+
+        let request = HttpRequest {
+            host: &host,
+            port,
+            path: &path,
+            range: None,
+            validator: None,
+            upload_length: Some(length),
+            custom_headers: &custom_headers,
+        };
+        if let Err(e) = stream
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .and_then(|()| stream.set_write_timeout(Some(POLL_INTERVAL)))
+        {
+            return Ok(Err(TransferError::network(&e)));
+        }
+        if let Err(e) = self.write_all(&mut stream, &request.to_bytes())? {
+            return Ok(Err(TransferError::network(&e)));
+        }
+
+        self.with_job(|job, notifications| {
+            job.lifecycle
+                .set_state(BitsJobState::Transferring, notifications)
+        })?;
+
+        let mut sent = 0u64;
+        let mut chunk = vec![0u8; BUFFER_SIZE];
+        while sent < length {
+            // Never send more than was promised, in case the file has grown.
+            let wanted = cmp::min(chunk.len() as u64, length - sent) as usize;
+            let count = match file.read(&mut chunk[..wanted]) {
+                Ok(0) => {
+                    return Ok(Err(TransferError::local(&io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank during upload",
+                    ))));
+                }
+                Ok(count) => count,
+                Err(e) => return Ok(Err(TransferError::local(&e))),
+            };
+            if let Err(e) = self.write_all(&mut stream, &chunk[..count])? {
+                return Ok(Err(TransferError::network(&e)));
+            }
+            sent += count as u64;
+            self.with_job(|job, notifications| {
+                job.files[index].transferred_bytes = sent;
+                job.lifecycle.progressed(notifications);
+            })?;
+        }
+
+        let (response, body_start) = match self.read_headers(&mut stream)? {
+            Ok(headers) => headers,
+            Err(e) => return Ok(Err(TransferError::network(&e))),
+        };
+        if response.status < 200 || response.status >= 300 {
+            return Ok(Err(TransferError::http(response.status)));
+        }
+
+        let reply = if wants_reply {
+            match self.read_reply(&mut stream, body_start, &response)? {
+                Ok(reply) => Some(reply),
+                Err(e) => return Ok(Err(e)),
+            }
+        } else {
+            None
+        };
+
+        self.with_job(|job, notifications| {
+            job.files[index].completed = true;
+            if reply.is_some() {
+                job.reply_data = reply;
+            }
+            job.lifecycle.modified(notifications);
+        })?;
+
+        Ok(Ok(()))
+    }
+
+    // Read the body of the response to an upload, up to `MAX_REPLY_SIZE`.
+    fn read_reply(
+        &self,
+        stream: &mut TcpStream,
+        body_start: Vec<u8>,
+        response: &HttpResponse,
+    ) -> std::result::Result<std::result::Result<Vec<u8>, TransferError>, Stopped> {
+        let too_large = TransferError {
+            hr: BG_E_TOO_LARGE,
+            context: BitsErrorContext::RemoteFile,
+            transient: false,
+        };
+        if response.content_length > Some(MAX_REPLY_SIZE as u64) {
+            return Ok(Err(too_large));
+        }
+
+        let mut body = Body::new(response);
+        let mut reply = Vec::new();
+        let mut pending = body_start;
+        let mut chunk = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            if let Err(e) = body.decode(&pending, &mut reply) {
+                return Ok(Err(TransferError::network(&e)));
+            }
+            if reply.len() > MAX_REPLY_SIZE {
+                return Ok(Err(too_large));
+            }
+            if body.is_done() {
+                return Ok(Ok(reply));
+            }
+
+            match self.read_some(stream, &mut chunk)? {
+                Ok(0) => {
+                    return Ok(body
+                        .end()
+                        .map(|()| reply)
+                        .map_err(|e| TransferError::network(&e)))
+                }
+                Ok(count) => pending = chunk[..count].to_vec(),
+                Err(e) => return Ok(Err(TransferError::network(&e))),
+            }
+        }
+    }
+
+    // Discard what has been transferred of file `index`.
+    fn restart_file(&self, index: usize) -> std::result::Result<io::Result<()>, Stopped> {
+        self.with_job(|job, _| {
+            let file = &mut job.files[index];
+            file.transferred_bytes = 0;
+            file.validator = None;
+            File::create(&file.temp_name).map(|_| ())
+        })
+    }
+
+    // Read until the end of the response headers. Any body bytes read are also returned.
+    fn read_headers(
+        &self,
+        stream: &mut TcpStream,
+    ) -> std::result::Result<io::Result<(HttpResponse, Vec<u8>)>, Stopped> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let count = match self.read_some(stream, &mut chunk)? {
+                Ok(0) => {
+                    return Ok(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed in headers",
+                    )));
+                }
+                Ok(count) => count,
+                Err(e) => return Ok(Err(e)),
+            };
+            buf.extend_from_slice(&chunk[..count]);
+
+            if let Some(end) = find_subslice(&buf, b"\r\n\r\n") {
+                let body = buf.split_off(end + 4);
+                return Ok(parse_response(&buf).map(|response| (response, body)));
+            }
+        }
+    }
+
+    // Append the response body to the temporary file for file `index`.
+    fn read_body(
+        &self,
+        stream: &mut TcpStream,
+        index: usize,
+        body_start: Vec<u8>,
+        response: &HttpResponse,
+    ) -> Fetched {
+        let mut body = Body::new(response);
+        let mut pending = body_start;
+        let mut chunk = vec![0u8; BUFFER_SIZE];
+
+        loop {
+            let mut data = Vec::new();
+            if let Err(e) = body.decode(&pending, &mut data) {
+                return Ok(Err(TransferError::network(&e)));
+            }
+            if !data.is_empty() {
+                let written = self.with_job(|job, notifications| {
+                    let file = &mut job.files[index];
+                    let result = OpenOptions::new()
+                        .append(true)
+                        .open(&file.temp_name)
+                        .and_then(|mut f| f.write_all(&data));
+                    if result.is_ok() {
+                        file.transferred_bytes += data.len() as u64;
+                        job.lifecycle.progressed(notifications);
+                    }
+                    result
+                })?;
+                if let Err(e) = written {
+                    return Ok(Err(TransferError::local(&e)));
+                }
+            }
+
+            if body.is_done() {
+                return Ok(Ok(()));
+            }
+
+            match self.read_some(stream, &mut chunk)? {
+                // If the body was cut short, what was received is kept for the next attempt.
+                Ok(0) => return Ok(body.end().map_err(|e| TransferError::network(&e))),
+                Ok(count) => pending = chunk[..count].to_vec(),
+                Err(e) => return Ok(Err(TransferError::network(&e))),
+            }
+        }
+    }
+
+    // Write all of `data` to the stream, waking periodically to check whether the transfer should
+    // stop.
+    fn write_all(
+        &self,
+        stream: &mut TcpStream,
+        mut data: &[u8],
+    ) -> std::result::Result<io::Result<()>, Stopped> {
+        while !data.is_empty() {
+            match stream.write(data) {
+                Ok(0) => {
+                    return Ok(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "connection closed in request",
+                    )));
+                }
+                Ok(count) => data = &data[count..],
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::Interrupted =>
+                {
+                    self.with_job(|_, _| ())?;
+                }
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    // Read from the stream, waking periodically to check whether the transfer should stop.
+    fn read_some(
+        &self,
+        stream: &mut TcpStream,
+        buf: &mut [u8],
+    ) -> std::result::Result<io::Result<usize>, Stopped> {
+        loop {
+            match stream.read(buf) {
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::Interrupted =>
+                {
+                    self.with_job(|_, _| ())?;
+                }
+                result => return Ok(result),
+            }
+        }
+    }
+}
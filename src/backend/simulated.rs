@@ -0,0 +1,879 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! An in-memory simulation of the BITS service, for tests.
+//!
+//! [`SimulatedBits`](struct.SimulatedBits.html) is a [`JobBackend`](../trait.JobBackend.html)
+//! which keeps its own queue of jobs like [`PortableBits`](../portable/struct.PortableBits.html),
+//! but never uses the network: the remote files are [`Response`](struct.Response.html)s given to
+//! `serve()`, and a URL which nothing is served at fails as if no server were listening. This
+//! lets tests run without sockets, and without depending on the portable HTTP client.
+//!
+//! Jobs follow the lifecycle described in the `backend` module. A job is `Transferring` for the
+//! delay of each response. A server error (5xx or 408), or a URL with nothing served, is a
+//! transient error; any other error status is not.
+//!
+//! An upload reads its local file, and an upload-reply job keeps the body of the response as
+//! its reply data.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use types::hresult::{
+    BG_E_EMPTY, BG_E_INVALID_STATE, BG_E_NETWORK_DISCONNECTED, BG_E_NOT_FOUND, BG_E_TOO_MANY_FILES,
+    BG_S_PARTIAL_COMPLETE, BG_S_UNABLE_TO_DELETE_FILES, E_FAIL, E_NOTIMPL, S_OK,
+};
+use types::{
+    BitsAuthScheme, BitsAuthTarget, BitsErrorContext, BitsFileProgress, BitsFileRange,
+    BitsJobError, BitsJobPriority, BitsJobState, BitsJobStatus, BitsJobType, BitsProxyUsage, Guid,
+    HResult, HRESULT,
+};
+
+use super::lifecycle::{Lifecycle, Notifications, HTTP_ERROR_BASE};
+use super::{
+    BackendConnection, BackendFile, BackendJob, ErrorCallback, JobBackend, ModificationCallback,
+    TransferredCallback,
+};
+
+type Result<T> = std::result::Result<T, HResult>;
+
+// Used to make GUIDs unique across every simulator in the process.
+static NEXT_JOB_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// What a simulated server sends for a URL.
+#[derive(Clone, Debug)]
+pub struct Response {
+    /// The HTTP status; the body is only sent with a 2xx status.
+    pub status: u32,
+    pub body: Vec<u8>,
+    /// How long the server takes before it sends the body, or the error status.
+    pub delay: Duration,
+}
+
+impl Response {
+    /// A `200 OK` response with `body`, sent straight away.
+    pub fn ok(body: &[u8]) -> Response {
+        Response {
+            status: 200,
+            body: body.to_vec(),
+            delay: Duration::from_millis(0),
+        }
+    }
+
+    /// An error response with the given status, sent straight away.
+    pub fn error(status: u32) -> Response {
+        Response {
+            status,
+            body: Vec::new(),
+            delay: Duration::from_millis(0),
+        }
+    }
+
+    /// The same response after `delay`.
+    pub fn delayed(self, delay: Duration) -> Response {
+        Response { delay, ..self }
+    }
+}
+
+/// A simulated BITS service.
+///
+/// Each `SimulatedBits` created with `new()` is an independent service with its own queue and
+/// its own served URLs; clones share them.
+#[derive(Clone)]
+pub struct SimulatedBits(Arc<Shared>);
+
+struct Shared {
+    state: Mutex<SimulatorState>,
+    // Notified whenever a job is changed by a client, to wake transfers which are waiting.
+    changed: Condvar,
+}
+
+struct SimulatorState {
+    jobs: HashMap<Guid, SimJob>,
+    responses: HashMap<String, Response>,
+}
+
+struct SimJob {
+    name: OsString,
+    job_type: BitsJobType,
+    files: Vec<SimFile>,
+    lifecycle: Lifecycle,
+    priority: BitsJobPriority,
+    reply_file_name: Option<PathBuf>,
+    reply_data: Option<Vec<u8>>,
+    // Incremented to stop any ongoing transfer thread.
+    generation: usize,
+}
+
+struct SimFile {
+    remote_name: OsString,
+    local_name: PathBuf,
+    temp_name: PathBuf,
+    // Only these parts of the remote file are transferred, if given.
+    ranges: Option<Vec<BitsFileRange>>,
+    total_bytes: Option<u64>,
+    transferred_bytes: u64,
+    completed: bool,
+}
+
+/// A job in a [`SimulatedBits`](struct.SimulatedBits.html) queue.
+pub struct SimulatedJob {
+    bits: SimulatedBits,
+    guid: Guid,
+}
+
+impl SimulatedBits {
+    /// Create a new simulated service with an empty queue, and nothing served.
+    pub fn new() -> SimulatedBits {
+        SimulatedBits(Arc::new(Shared {
+            state: Mutex::new(SimulatorState {
+                jobs: HashMap::new(),
+                responses: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+        }))
+    }
+
+    /// Send `response` to each request for `url`, from now on.
+    pub fn serve(&self, url: &str, response: Response) {
+        self.lock().responses.insert(url.to_owned(), response);
+    }
+
+    /// Stop serving `url`, later requests for it fail as if no server were listening.
+    pub fn stop_serving(&self, url: &str) {
+        self.lock().responses.remove(url);
+    }
+
+    /// Cancel all jobs with the given name, as `BackgroundCopyManager::cancel_jobs_by_name()`.
+    pub fn cancel_jobs_by_name(&self, match_name: &OsStr) {
+        if let Ok(jobs) = self.find_jobs_by_name(match_name) {
+            for mut job in jobs {
+                let _ = job.cancel();
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimulatorState> {
+        self.0.state.lock().unwrap()
+    }
+
+    fn job(&self, guid: Guid) -> SimulatedJob {
+        SimulatedJob {
+            bits: self.clone(),
+            guid,
+        }
+    }
+}
+
+impl Default for SimulatedBits {
+    fn default() -> SimulatedBits {
+        SimulatedBits::new()
+    }
+}
+
+impl JobBackend for SimulatedBits {
+    type Connection = SimulatedBits;
+
+    fn connect(&self) -> Result<SimulatedBits> {
+        Ok(self.clone())
+    }
+}
+
+impl BackendConnection for SimulatedBits {
+    type Job = SimulatedJob;
+
+    fn create_job_with_type(
+        &self,
+        display_name: &OsStr,
+        job_type: BitsJobType,
+    ) -> Result<SimulatedJob> {
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+        let guid = Guid {
+            data1: id as u32,
+            data2: 0x5349,
+            data3: 0x4d55,
+            data4: *b"SIMULATE",
+        };
+
+        self.lock().jobs.insert(
+            guid.clone(),
+            SimJob {
+                name: display_name.to_os_string(),
+                job_type,
+                files: Vec::new(),
+                lifecycle: Lifecycle::new(),
+                priority: BitsJobPriority::Normal,
+                reply_file_name: None,
+                reply_data: None,
+                generation: 0,
+            },
+        );
+
+        Ok(self.job(guid))
+    }
+
+    fn get_job_by_guid(&self, guid: &Guid) -> Result<SimulatedJob> {
+        if self.lock().jobs.contains_key(guid) {
+            Ok(self.job(guid.clone()))
+        } else {
+            Err(HResult::new(BG_E_NOT_FOUND))
+        }
+    }
+
+    fn find_job_by_guid_and_name(
+        &self,
+        guid: &Guid,
+        match_name: &OsStr,
+    ) -> Result<Option<SimulatedJob>> {
+        Ok(match self.lock().jobs.get(guid) {
+            Some(job) if job.name == match_name => Some(self.job(guid.clone())),
+            _ => None,
+        })
+    }
+
+    fn find_jobs_by_name(&self, match_name: &OsStr) -> Result<Vec<SimulatedJob>> {
+        Ok(self
+            .lock()
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.name == match_name)
+            .map(|(guid, _)| self.job(guid.clone()))
+            .collect())
+    }
+
+    fn get_error_description(&self, hr: HRESULT) -> Result<String> {
+        Ok(format!("Simulated error {:#010x}", hr))
+    }
+}
+
+impl SimulatedJob {
+    // Run `f` on this job's state, then deliver any notifications it queued.
+    fn with_job<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut SimJob, &mut Notifications) -> Result<T>,
+    {
+        let mut notifications = Notifications::default();
+        let result = {
+            let mut state = self.bits.lock();
+            let job = state
+                .jobs
+                .get_mut(&self.guid)
+                .ok_or_else(|| HResult::new(BG_E_NOT_FOUND))?;
+            f(job, &mut notifications)
+        };
+        self.bits.0.changed.notify_all();
+        notifications.run();
+        result
+    }
+
+    fn add(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: Option<Vec<BitsFileRange>>,
+    ) -> Result<()> {
+        let guid = self.guid.clone();
+        self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Transferred
+                | BitsJobState::Acknowledged
+                | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                _ => {}
+            }
+            if job.job_type != BitsJobType::Download && !job.files.is_empty() {
+                return Err(HResult::new(BG_E_TOO_MANY_FILES));
+            }
+
+            let local_name = PathBuf::from(local_file);
+            let temp_name = local_name.with_file_name(format!(
+                "BIT{:08X}{:02X}.tmp",
+                guid.data1,
+                job.files.len()
+            ));
+            job.files.push(SimFile {
+                remote_name: remote_url.to_os_string(),
+                local_name,
+                temp_name,
+                ranges,
+                total_bytes: None,
+                transferred_bytes: 0,
+                completed: false,
+            });
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    // Remove the job from the queue, it may not be found again.
+    fn remove(&self) {
+        self.bits.lock().jobs.remove(&self.guid);
+    }
+}
+
+impl BackendJob for SimulatedJob {
+    fn guid(&self) -> Result<Guid> {
+        Ok(self.guid.clone())
+    }
+
+    fn job_type(&self) -> Result<BitsJobType> {
+        self.with_job(|job, _| Ok(job.job_type))
+    }
+
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
+        self.add(remote_url, local_file, None)
+    }
+
+    fn add_file_with_ranges(
+        &mut self,
+        remote_url: &OsStr,
+        local_file: &OsStr,
+        ranges: &[BitsFileRange],
+    ) -> Result<()> {
+        self.add(remote_url, local_file, Some(ranges.to_vec()))
+    }
+
+    fn get_files(&mut self) -> Result<Vec<BackendFile>> {
+        self.with_job(|job, _| {
+            Ok(job
+                .files
+                .iter()
+                .map(|file| BackendFile {
+                    remote_name: file.remote_name.clone(),
+                    local_name: file.local_name.clone().into_os_string(),
+                    progress: file.progress(),
+                })
+                .collect())
+        })
+    }
+
+    fn get_temporary_names(&mut self) -> Result<Vec<OsString>> {
+        self.with_job(|job, _| {
+            Ok(job
+                .files
+                .iter()
+                .map(|file| file.temp_name.clone().into_os_string())
+                .collect())
+        })
+    }
+
+    // Nothing is sent over a network, so the network settings are accepted and ignored.
+    fn set_proxy_usage(&mut self, _usage: BitsProxyUsage) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_priority(&mut self, priority: BitsJobPriority) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.priority = priority;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn get_priority(&self) -> Result<BitsJobPriority> {
+        self.with_job(|job, _| Ok(job.priority))
+    }
+
+    fn set_minimum_retry_delay(&mut self, seconds: u32) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.minimum_retry_delay = seconds;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_no_progress_timeout(&mut self, seconds: u32) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.no_progress_timeout = seconds;
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_maximum_download_time(&mut self, _seconds: u32) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_redirect_report(&mut self) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_custom_headers(&mut self, _headers: &OsStr) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_credentials(
+        &mut self,
+        _target: BitsAuthTarget,
+        _scheme: BitsAuthScheme,
+        _user_name: &OsStr,
+        _password: &OsStr,
+    ) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn remove_credentials(
+        &mut self,
+        _target: BitsAuthTarget,
+        _scheme: BitsAuthScheme,
+    ) -> Result<()> {
+        self.with_job(|job, notifications| {
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn set_reply_file_name(&mut self, reply_file: &OsStr) -> Result<()> {
+        self.with_job(|job, notifications| {
+            if job.job_type != BitsJobType::UploadReply {
+                return Err(HResult::new(E_NOTIMPL));
+            }
+            job.reply_file_name = Some(PathBuf::from(reply_file));
+            job.lifecycle.modified(notifications);
+            Ok(())
+        })
+    }
+
+    fn get_reply_data(&self) -> Result<Vec<u8>> {
+        self.with_job(|job, _| {
+            if job.job_type != BitsJobType::UploadReply {
+                return Err(HResult::new(E_NOTIMPL));
+            }
+            job.reply_data
+                .clone()
+                .ok_or_else(|| HResult::new(BG_E_INVALID_STATE))
+        })
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let generation = self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Transferred
+                | BitsJobState::Acknowledged
+                | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                BitsJobState::Queued | BitsJobState::Connecting | BitsJobState::Transferring => {
+                    // Already active.
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            if job.files.is_empty() {
+                return Err(HResult::new(BG_E_EMPTY));
+            }
+
+            job.generation += 1;
+            job.lifecycle.set_state(BitsJobState::Queued, notifications);
+            Ok(Some(job.generation))
+        })?;
+
+        if let Some(generation) = generation {
+            let transfer = Transfer {
+                bits: self.bits.clone(),
+                guid: self.guid.clone(),
+                generation,
+            };
+            thread::Builder::new()
+                .name(format!("SimulatedBits transfer {}", self.guid.data1))
+                .spawn(move || transfer.run())
+                .map_err(|_| HResult::new(E_FAIL))?;
+        }
+
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        self.with_job(|job, notifications| match job.lifecycle.state {
+            BitsJobState::Transferred | BitsJobState::Acknowledged | BitsJobState::Cancelled => {
+                Err(HResult::new(BG_E_INVALID_STATE))
+            }
+            BitsJobState::Suspended => Ok(()),
+            _ => {
+                job.generation += 1;
+                job.lifecycle
+                    .set_state(BitsJobState::Suspended, notifications);
+                Ok(())
+            }
+        })
+    }
+
+    fn complete(&mut self) -> Result<HRESULT> {
+        let result = self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Acknowledged | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                _ => {}
+            }
+            job.generation += 1;
+
+            // Completed files are moved into place, any others are abandoned. Uploaded files
+            // stay where they are, and the reply is saved instead.
+            let mut partial = false;
+            for file in &job.files {
+                if !file.completed {
+                    partial = true;
+                    let _ = fs::remove_file(&file.temp_name);
+                } else if job.job_type == BitsJobType::Download {
+                    fs::rename(&file.temp_name, &file.local_name)
+                        .map_err(|_| HResult::new(E_FAIL))?;
+                }
+            }
+
+            if let (Some(reply_file_name), Some(reply_data)) =
+                (job.reply_file_name.as_ref(), job.reply_data.as_ref())
+            {
+                fs::write(reply_file_name, reply_data).map_err(|_| HResult::new(E_FAIL))?;
+            }
+
+            job.lifecycle
+                .set_state(BitsJobState::Acknowledged, notifications);
+
+            Ok(if partial { BG_S_PARTIAL_COMPLETE } else { S_OK })
+        })?;
+
+        self.remove();
+        Ok(result)
+    }
+
+    fn cancel(&mut self) -> Result<HRESULT> {
+        let result = self.with_job(|job, notifications| {
+            match job.lifecycle.state {
+                BitsJobState::Acknowledged | BitsJobState::Cancelled => {
+                    return Err(HResult::new(BG_E_INVALID_STATE));
+                }
+                _ => {}
+            }
+            job.generation += 1;
+
+            let mut unable_to_delete = false;
+            for file in &job.files {
+                if let Err(e) = fs::remove_file(&file.temp_name) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        unable_to_delete = true;
+                    }
+                }
+            }
+
+            job.lifecycle
+                .set_state(BitsJobState::Cancelled, notifications);
+
+            Ok(if unable_to_delete {
+                BG_S_UNABLE_TO_DELETE_FILES
+            } else {
+                S_OK
+            })
+        })?;
+
+        self.remove();
+        Ok(result)
+    }
+
+    fn register_callbacks(
+        &mut self,
+        transferred_cb: Option<Box<TransferredCallback>>,
+        error_cb: Option<Box<ErrorCallback>>,
+        modification_cb: Option<Box<ModificationCallback>>,
+    ) -> Result<()> {
+        self.with_job(|job, _| {
+            job.lifecycle
+                .register_callbacks(transferred_cb, error_cb, modification_cb);
+            Ok(())
+        })
+    }
+
+    fn get_status(&self) -> Result<BitsJobStatus> {
+        self.with_job(|job, _| {
+            Ok(job
+                .lifecycle
+                .status(job.files.iter().map(SimFile::progress)))
+        })
+    }
+}
+
+impl SimFile {
+    fn progress(&self) -> BitsFileProgress {
+        BitsFileProgress {
+            total_bytes: self.total_bytes,
+            transferred_bytes: self.transferred_bytes,
+            completed: self.completed,
+        }
+    }
+}
+
+struct TransferError {
+    hr: HRESULT,
+    context: BitsErrorContext,
+    transient: bool,
+}
+
+impl TransferError {
+    fn response(response: Option<&Response>) -> Option<TransferError> {
+        Some(match response {
+            // Nothing is listening.
+            None => TransferError {
+                hr: BG_E_NETWORK_DISCONNECTED,
+                context: BitsErrorContext::GeneralTransport,
+                transient: true,
+            },
+            Some(response) if response.status < 200 || response.status >= 300 => TransferError {
+                hr: (HTTP_ERROR_BASE | response.status) as HRESULT,
+                context: BitsErrorContext::RemoteFile,
+                transient: response.status >= 500 || response.status == 408,
+            },
+            Some(_) => return None,
+        })
+    }
+
+    fn local() -> TransferError {
+        TransferError {
+            hr: E_FAIL,
+            context: BitsErrorContext::LocalFile,
+            transient: false,
+        }
+    }
+
+    fn job_error(&self) -> BitsJobError {
+        BitsJobError {
+            context: self.context,
+            context_str: format!("{:?}", self.context),
+            error: self.hr,
+            error_str: format!("Simulated error {:#010x}", self.hr),
+        }
+    }
+}
+
+// Returned when the transfer was stopped by a change to the job, rather than an error.
+struct Stopped;
+
+// The background thread transferring one job, it stops when the job's `generation` changes.
+struct Transfer {
+    bits: SimulatedBits,
+    guid: Guid,
+    generation: usize,
+}
+
+impl Transfer {
+    fn run(self) {
+        let _ = self.transfer();
+    }
+
+    // Run `f` on the job's state if this transfer is still current.
+    fn with_job<T, F>(&self, f: F) -> std::result::Result<T, Stopped>
+    where
+        F: FnOnce(&mut SimJob, &mut Notifications) -> T,
+    {
+        let mut notifications = Notifications::default();
+        let result = {
+            let mut state = self.bits.lock();
+            match state.jobs.get_mut(&self.guid) {
+                Some(job) if job.generation == self.generation => f(job, &mut notifications),
+                _ => return Err(Stopped),
+            }
+        };
+        notifications.run();
+        Ok(result)
+    }
+
+    fn transfer(&self) -> std::result::Result<(), Stopped> {
+        loop {
+            let next = self.with_job(|job, notifications| {
+                match job.files.iter().position(|file| !file.completed) {
+                    Some(index) => {
+                        job.lifecycle
+                            .set_state(BitsJobState::Connecting, notifications);
+                        Some(index)
+                    }
+                    None => {
+                        job.lifecycle
+                            .set_state(BitsJobState::Transferred, notifications);
+                        None
+                    }
+                }
+            })?;
+            let index = match next {
+                Some(index) => index,
+                None => return Ok(()),
+            };
+
+            if let Err(error) = self.transfer_file(index)? {
+                let transient = self.with_job(|job, notifications| {
+                    job.lifecycle
+                        .set_error(error.job_error(), error.transient, notifications);
+                    job.lifecycle.state == BitsJobState::TransientError
+                })?;
+                if !transient {
+                    return Ok(());
+                }
+
+                self.wait_for_retry()?;
+                self.with_job(|job, notifications| {
+                    job.lifecycle.set_state(BitsJobState::Queued, notifications)
+                })?;
+            }
+        }
+    }
+
+    // Transfer file `index` from its served response.
+    fn transfer_file(
+        &self,
+        index: usize,
+    ) -> std::result::Result<std::result::Result<(), TransferError>, Stopped> {
+        let (url, job_type) =
+            self.with_job(|job, _| (job.files[index].remote_name.clone(), job.job_type))?;
+        let response = self
+            .bits
+            .lock()
+            .responses
+            .get(&*url.to_string_lossy())
+            .cloned();
+
+        if let Some(error) = TransferError::response(response.as_ref()) {
+            if let Some(response) = response {
+                self.sleep(response.delay)?;
+            }
+            return Ok(Err(error));
+        }
+        let response = response.unwrap();
+
+        if job_type != BitsJobType::Download {
+            return self.upload(index, response);
+        }
+
+        let body = self.with_job(|job, notifications| {
+            let file = &mut job.files[index];
+            let body = match file.ranges {
+                Some(ref ranges) => select_ranges(&response.body, ranges),
+                None => response.body.clone(),
+            };
+            file.total_bytes = Some(body.len() as u64);
+            job.lifecycle
+                .set_state(BitsJobState::Transferring, notifications);
+            body
+        })?;
+
+        self.sleep(response.delay)?;
+
+        self.with_job(|job, notifications| {
+            let file = &mut job.files[index];
+            if fs::write(&file.temp_name, &body).is_err() {
+                return Err(TransferError::local());
+            }
+            file.transferred_bytes = body.len() as u64;
+            file.completed = true;
+            job.lifecycle.progressed(notifications);
+            Ok(())
+        })
+    }
+
+    // Send the local file of an upload job, keeping the response as the reply.
+    fn upload(
+        &self,
+        index: usize,
+        response: Response,
+    ) -> std::result::Result<std::result::Result<(), TransferError>, Stopped> {
+        let local_name = self.with_job(|job, notifications| {
+            job.lifecycle
+                .set_state(BitsJobState::Transferring, notifications);
+            job.files[index].local_name.clone()
+        })?;
+        let length = match fs::metadata(&local_name) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(Err(TransferError::local())),
+        };
+
+        self.sleep(response.delay)?;
+
+        self.with_job(|job, notifications| {
+            let file = &mut job.files[index];
+            file.total_bytes = Some(length);
+            file.transferred_bytes = length;
+            file.completed = true;
+            if job.job_type == BitsJobType::UploadReply {
+                job.reply_data = Some(response.body);
+            }
+            job.lifecycle.progressed(notifications);
+            Ok(())
+        })
+    }
+
+    // Wait for `duration`, unless the transfer is stopped first.
+    fn sleep(&self, duration: Duration) -> std::result::Result<(), Stopped> {
+        let until = Instant::now() + duration;
+        self.wait_until(|_| until)
+    }
+
+    // Wait for the minimum retry delay, which may be changed while waiting.
+    fn wait_for_retry(&self) -> std::result::Result<(), Stopped> {
+        let failed_at = Instant::now();
+        self.wait_until(|job| {
+            failed_at + Duration::from_secs(u64::from(job.lifecycle.minimum_retry_delay))
+        })
+    }
+
+    // Wait until the time given by `until` for the job, which is checked again whenever the job
+    // changes.
+    fn wait_until<F>(&self, until: F) -> std::result::Result<(), Stopped>
+    where
+        F: Fn(&SimJob) -> Instant,
+    {
+        let mut state = self.bits.lock();
+        loop {
+            let until = match state.jobs.get(&self.guid) {
+                Some(job) if job.generation == self.generation => until(job),
+                _ => return Err(Stopped),
+            };
+
+            let now = Instant::now();
+            if now >= until {
+                return Ok(());
+            }
+            state = self
+                .bits
+                .0
+                .changed
+                .wait_timeout(state, until - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+// The parts of `body` in `ranges`, one after another.
+fn select_ranges(body: &[u8], ranges: &[BitsFileRange]) -> Vec<u8> {
+    let mut selected = Vec::new();
+    for range in ranges {
+        let start = range.offset.min(body.len() as u64) as usize;
+        let end = match range.length {
+            Some(length) => range.offset.saturating_add(length).min(body.len() as u64) as usize,
+            None => body.len(),
+        };
+        selected.extend_from_slice(&body[start..end]);
+    }
+    selected
+}
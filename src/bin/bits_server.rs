@@ -0,0 +1,93 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// A Local Service server for testing, which runs jobs with the portable engine and listens on a
+// Unix socket. Each connection is served on its own thread. Monitor channels are Unix sockets in
+// the same directory as the socket, see `UnixSockets`.
+//
+// Unix only: the crate has no listener for Windows named pipes, so elsewhere this just exits.
+//
+// Usage: bits_server <socket path> <job name> <save path prefix>
+
+extern crate bits_client;
+
+#[cfg(unix)]
+use std::env;
+#[cfg(unix)]
+use std::ffi::OsString;
+use std::process;
+
+#[cfg(unix)]
+fn serve(socket_path: OsString, job_name: OsString, save_path_prefix: OsString) -> ! {
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+
+    use bits_client::backend::PortableBits;
+    use bits_client::server::CommandDispatcher;
+    use bits_client::transport::UnixSockets;
+
+    // Remove a socket left over from a previous run.
+    let _ = fs::remove_file(&socket_path);
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("failed to bind {:?}: {}", socket_path, e);
+            process::exit(1);
+        }
+    };
+
+    let monitor_dir = Path::new(&socket_path)
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf();
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let job_name = job_name.clone();
+        let save_path_prefix = save_path_prefix.clone();
+        let monitor_sockets = UnixSockets::new(monitor_dir.clone());
+        thread::spawn(move || {
+            let result = CommandDispatcher::new(
+                PortableBits::global(),
+                job_name,
+                save_path_prefix,
+                monitor_sockets,
+            )
+            .and_then(|mut dispatcher| dispatcher.serve(&mut stream));
+            if let Err(e) = result {
+                eprintln!("connection failed: {}", e);
+            }
+        });
+    }
+    process::exit(0);
+}
+
+#[cfg(unix)]
+fn main() {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    if args.len() != 3 {
+        eprintln!("usage: bits_server <socket path> <job name> <save path prefix>");
+        process::exit(1);
+    }
+    let mut args = args.into_iter();
+    serve(
+        args.next().unwrap(),
+        args.next().unwrap(),
+        args.next().unwrap(),
+    );
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("bits_server needs Unix sockets, it is not supported on this platform");
+    process::exit(1);
+}
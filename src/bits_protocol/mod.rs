@@ -0,0 +1,782 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Command, response, and status types.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::ops;
+use std::result;
+use std::time::Duration;
+
+use failure::Fail;
+
+use types::{
+    BitsAuthScheme, BitsAuthTarget, BitsErrorContext, BitsFileProgress, BitsFileRange,
+    BitsJobPriority, BitsJobProgress, BitsJobState, BitsJobTimes, BitsProxyUsage, Guid, HRESULT,
+};
+
+pub mod wire;
+
+/// An HRESULT with a descriptive message
+#[derive(Clone, Debug, Fail)]
+pub struct HResultMessage {
+    pub hr: HRESULT,
+    pub message: String,
+}
+
+impl fmt::Display for HResultMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        self.message.fmt(f)
+    }
+}
+
+/// Optional features, which may not be supported by an older Local Service server.
+///
+/// A set of flags, combined with `|`. Flags not known to this version are kept, so they can be
+/// passed along.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Monitors, from `start_job()` and `monitor_job()`.
+    pub const MONITORS: Capabilities = Capabilities(1);
+    /// Jobs with more than one file, and the status of each file in `JobStatus::files`.
+    pub const MULTI_FILE_JOBS: Capabilities = Capabilities(1 << 1);
+    /// `FileStatus::local_name`.
+    pub const FILE_NAMES: Capabilities = Capabilities(1 << 2);
+    /// Upload jobs, and the reply in `JobStatus::reply_data`.
+    pub const UPLOAD: Capabilities = Capabilities(1 << 3);
+    /// Custom HTTP headers on requests.
+    pub const CUSTOM_HEADERS: Capabilities = Capabilities(1 << 4);
+    /// HTTP authentication credentials.
+    pub const CREDENTIALS: Capabilities = Capabilities(1 << 5);
+    /// `BitsProxyUsage::Override`, an explicit proxy list.
+    pub const PROXY_OVERRIDE: Capabilities = Capabilities(1 << 6);
+    /// Choosing a job's priority when it is started, and `JobStatus::priority`.
+    pub const START_PRIORITY: Capabilities = Capabilities(1 << 7);
+    /// Retry and timeout settings, when a job is started and with `update_job_settings()`.
+    pub const JOB_SETTINGS: Capabilities = Capabilities(1 << 8);
+    /// Downloading only some ranges of a file.
+    pub const BYTE_RANGES: Capabilities = Capabilities(1 << 9);
+    /// Checking a downloaded file's size and hash before the job is completed.
+    pub const HASH_VERIFICATION: Capabilities = Capabilities(1 << 10);
+    /// Listing the client's jobs with `list_jobs()`.
+    pub const LIST_JOBS: Capabilities = Capabilities(1 << 11);
+    /// Cancelling all of the client's jobs with `cancel_all_jobs()`.
+    pub const CANCEL_ALL_JOBS: Capabilities = Capabilities(1 << 12);
+    /// Waking monitors when their job is modified, with `set_modification_updates()`.
+    pub const MODIFICATION_UPDATES: Capabilities = Capabilities(1 << 13);
+    /// Changing one of a job's monitors by its `MonitorSubscription`.
+    pub const MONITOR_SUBSCRIPTIONS: Capabilities = Capabilities(1 << 14);
+    /// `JobStatus::bytes_per_second` and `estimated_remaining`.
+    pub const TRANSFER_RATE: Capabilities = Capabilities(1 << 15);
+
+    /// No capabilities.
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    pub fn from_bits(bits: u64) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Whether all of the capabilities in `other` are in `self`.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl ops::BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// The first message in each direction on a Local Service connection, before any `Command`.
+///
+/// Each side sends its own `Hello`, the capabilities of the connection are those in both.
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Describes the sender's build, for diagnostics.
+    pub build: String,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// A `Hello` from this build, offering `capabilities`.
+    pub fn new(capabilities: Capabilities) -> Hello {
+        Hello {
+            protocol_version: wire::PROTOCOL_VERSION,
+            build: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string(),
+            capabilities,
+        }
+    }
+}
+
+/// Options for a job started with
+/// [`BitsClient::start_job_with_options()`](../enum.BitsClient.html#method.start_job_with_options)
+/// or
+/// [`BitsClient::start_job_multi_with_options()`](../enum.BitsClient.html#method.start_job_multi_with_options).
+///
+/// The values of the headers are not shown by `Debug`, they may hold tokens.
+#[derive(Clone, Default)]
+pub struct StartJobOptions {
+    /// Custom HTTP headers, as `(name, value)`.
+    pub headers: Vec<(String, String)>,
+    pub credentials: Option<Credentials>,
+    /// The job's priority, `Foreground` if `None`.
+    pub priority: Option<BitsJobPriority>,
+    /// Retry and timeout settings. The minimum retry delay is 60 seconds if not set.
+    pub settings: JobSettings,
+    /// Only these ranges of each file are downloaded, one after another, if any are given.
+    ///
+    /// They must be in order and not overlap, and only the last may run to the end of the file.
+    pub ranges: Vec<BitsFileRange>,
+    /// What each downloaded file should contain, checked by `complete_job()`, in the order the
+    /// files were given. A file without an entry, or with `None`, is not checked.
+    pub expected: Vec<Option<ExpectedFile>>,
+}
+
+impl fmt::Debug for StartJobOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        f.debug_struct("StartJobOptions")
+            .field("headers", &RedactedHeaders(&self.headers))
+            .field("credentials", &self.credentials)
+            .field("priority", &self.priority)
+            .field("settings", &self.settings)
+            .field("ranges", &self.ranges)
+            .field("expected", &self.expected)
+            .finish()
+    }
+}
+
+// Shows the names of custom headers, but not their values.
+struct RedactedHeaders<'a>(&'a [(String, String)]);
+
+impl<'a> fmt::Debug for RedactedHeaders<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        f.debug_list()
+            .entries(self.0.iter().map(|header| (&header.0, "<redacted>")))
+            .finish()
+    }
+}
+
+/// The expected size and SHA-256 hash of a downloaded file.
+///
+/// When the job is completed, a file which has been transferred is checked before it is moved
+/// into place, and the job is not completed if it doesn't match. This is only done by the client
+/// which started the job, see `BitsClient::start_job_with_options()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExpectedFile {
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Retry and timeout settings of a job, in seconds.
+///
+/// Each setting which is `None` is left as it is, see `bits::BitsJob` for the defaults.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct JobSettings {
+    /// How long to wait before retrying after a transient error.
+    pub minimum_retry_delay_secs: Option<u32>,
+    /// How long to keep retrying without making progress before the job fails.
+    pub no_progress_timeout_secs: Option<u32>,
+    /// How long the job may spend transferring before it fails.
+    pub maximum_download_time_secs: Option<u32>,
+}
+
+/// Credentials to authenticate with a server or proxy.
+///
+/// The password is not shown by `Debug`, but it is not cleared from memory either: it stays in
+/// this `OsString`, in the command sent to the server and in the buffers it is sent and received
+/// in, until they are freed and reused. The portable backend keeps it, encoded, with the job.
+#[derive(Clone)]
+pub struct Credentials {
+    pub target: BitsAuthTarget,
+    pub scheme: BitsAuthScheme,
+    pub user_name: OsString,
+    pub password: OsString,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        f.debug_struct("Credentials")
+            .field("target", &self.target)
+            .field("scheme", &self.scheme)
+            .field("user_name", &self.user_name)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Commands which can be sent to the server.
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub enum Command {
+    StartJob(StartJobCommand),
+    MonitorJob(MonitorJobCommand),
+    SuspendJob(SuspendJobCommand),
+    ResumeJob(ResumeJobCommand),
+    SetJobPriority(SetJobPriorityCommand),
+    SetUpdateInterval(SetUpdateIntervalCommand),
+    StopUpdate(StopUpdateCommand),
+    CompleteJob(CompleteJobCommand),
+    CancelJob(CancelJobCommand),
+    StartJobMulti(StartJobMultiCommand),
+    StartUploadJob(StartUploadJobCommand),
+    UpdateJobSettings(UpdateJobSettingsCommand),
+    ListJobs(ListJobsCommand),
+    CancelAllJobs(CancelAllJobsCommand),
+    SetModificationUpdates(SetModificationUpdatesCommand),
+}
+
+/// Combine a [`Command`](enum.Command.html) with its success and failure result types.
+#[doc(hidden)]
+pub trait CommandType {
+    type Success;
+    type Failure: Fail;
+    fn wrap(command: Self) -> Command;
+}
+
+// Start Job
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct StartJobCommand {
+    pub url: OsString,
+    pub save_path: OsString,
+    pub proxy_usage: BitsProxyUsage,
+    /// Custom HTTP headers, as `(name, value)`.
+    pub headers: Vec<(String, String)>,
+    pub credentials: Option<Credentials>,
+    pub priority: Option<BitsJobPriority>,
+    pub settings: JobSettings,
+    pub ranges: Vec<BitsFileRange>,
+    pub expected: Vec<Option<ExpectedFile>>,
+    pub monitor: Option<MonitorConfig>,
+}
+
+impl fmt::Debug for StartJobCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        f.debug_struct("StartJobCommand")
+            .field("url", &self.url)
+            .field("save_path", &self.save_path)
+            .field("proxy_usage", &self.proxy_usage)
+            .field("headers", &RedactedHeaders(&self.headers))
+            .field("credentials", &self.credentials)
+            .field("priority", &self.priority)
+            .field("settings", &self.settings)
+            .field("ranges", &self.ranges)
+            .field("expected", &self.expected)
+            .field("monitor", &self.monitor)
+            .finish()
+    }
+}
+
+// The subscription is that of the job's monitor, which is stopped if there was no `monitor`.
+impl CommandType for StartJobCommand {
+    type Success = (StartJobSuccess, MonitorSubscription);
+    type Failure = StartJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::StartJob(cmd)
+    }
+}
+
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct MonitorConfig {
+    pub pipe_name: OsString,
+    pub interval_millis: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct StartJobSuccess {
+    pub guid: Guid,
+}
+
+/// Identifies one of the monitors of a job, which may have several at once.
+///
+/// See [`BitsMonitorClient::subscription()`](../enum.BitsMonitorClient.html#method.subscription).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MonitorSubscription(pub u64);
+
+#[derive(Clone, Debug, Fail)]
+pub enum StartJobFailure {
+    #[fail(display = "Argument validation failed: {}", _0)]
+    ArgumentValidation(String),
+    #[fail(display = "Create job: {}", _0)]
+    Create(HResultMessage),
+    #[fail(display = "Add file to job: {}", _0)]
+    AddFile(HResultMessage),
+    #[fail(display = "Apply settings to job: {}", _0)]
+    ApplySettings(HResultMessage),
+    #[fail(display = "Resume job: {}", _0)]
+    Resume(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Start Job Multi
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct StartJobMultiCommand {
+    pub files: Vec<JobFile>,
+    pub proxy_usage: BitsProxyUsage,
+    pub monitor: Option<MonitorConfig>,
+    pub options: StartJobOptions,
+}
+
+impl CommandType for StartJobMultiCommand {
+    type Success = (StartJobSuccess, MonitorSubscription);
+    type Failure = StartJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::StartJobMulti(cmd)
+    }
+}
+
+/// A file to transfer, as for `StartJobCommand`.
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct JobFile {
+    pub url: OsString,
+    pub save_path: OsString,
+}
+
+// Start Upload Job
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct StartUploadJobCommand {
+    pub url: OsString,
+    pub upload_path: OsString,
+    /// An upload-reply job if `Some`, a plain upload job otherwise.
+    pub reply_path: Option<OsString>,
+    pub proxy_usage: BitsProxyUsage,
+    pub monitor: Option<MonitorConfig>,
+}
+
+impl CommandType for StartUploadJobCommand {
+    type Success = (StartJobSuccess, MonitorSubscription);
+    type Failure = StartJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::StartUploadJob(cmd)
+    }
+}
+
+// Monitor Job
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct MonitorJobCommand {
+    pub guid: Guid,
+    pub monitor: MonitorConfig,
+}
+
+impl CommandType for MonitorJobCommand {
+    type Success = MonitorSubscription;
+    type Failure = MonitorJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::MonitorJob(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum MonitorJobFailure {
+    #[fail(display = "Argument validation failed: {}", _0)]
+    ArgumentValidation(String),
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Suspend Job
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct SuspendJobCommand {
+    pub guid: Guid,
+}
+
+impl CommandType for SuspendJobCommand {
+    type Success = ();
+    type Failure = SuspendJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::SuspendJob(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum SuspendJobFailure {
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Suspend job: {}", _0)]
+    SuspendJob(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Resume Job
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct ResumeJobCommand {
+    pub guid: Guid,
+}
+
+impl CommandType for ResumeJobCommand {
+    type Success = ();
+    type Failure = ResumeJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::ResumeJob(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum ResumeJobFailure {
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Resume job: {}", _0)]
+    ResumeJob(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Set Job Priority
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct SetJobPriorityCommand {
+    pub guid: Guid,
+    pub priority: BitsJobPriority,
+}
+
+impl CommandType for SetJobPriorityCommand {
+    type Success = ();
+    type Failure = SetJobPriorityFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::SetJobPriority(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum SetJobPriorityFailure {
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Apply settings to job: {}", _0)]
+    ApplySettings(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Update Job Settings
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct UpdateJobSettingsCommand {
+    pub guid: Guid,
+    pub settings: JobSettings,
+}
+
+impl CommandType for UpdateJobSettingsCommand {
+    type Success = ();
+    type Failure = UpdateJobSettingsFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::UpdateJobSettings(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum UpdateJobSettingsFailure {
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Apply settings to job: {}", _0)]
+    ApplySettings(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Set Update Interval
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct SetUpdateIntervalCommand {
+    pub guid: Guid,
+    pub subscription: Option<MonitorSubscription>,
+    pub interval_millis: u32,
+}
+
+impl CommandType for SetUpdateIntervalCommand {
+    type Success = ();
+    type Failure = SetUpdateIntervalFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::SetUpdateInterval(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum SetUpdateIntervalFailure {
+    #[fail(display = "Argument validation: {}", _0)]
+    ArgumentValidation(String),
+    #[fail(display = "Monitor not found")]
+    NotFound,
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Stop Update
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct StopUpdateCommand {
+    pub guid: Guid,
+    pub subscription: Option<MonitorSubscription>,
+}
+
+impl CommandType for StopUpdateCommand {
+    type Success = ();
+    type Failure = SetUpdateIntervalFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::StopUpdate(cmd)
+    }
+}
+
+/// The shortest interval for reports of modifications, a shorter `min_interval_millis` given
+/// to `set_modification_updates()` is raised to this.
+pub const MIN_MODIFICATION_INTERVAL_MILLIS: u32 = 100;
+
+// Set Modification Updates
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct SetModificationUpdatesCommand {
+    pub guid: Guid,
+    pub subscription: Option<MonitorSubscription>,
+    pub min_interval_millis: Option<u32>,
+}
+
+// Only the monitor's job is looked up, so it shares the failure of `monitor_job()`.
+impl CommandType for SetModificationUpdatesCommand {
+    type Success = ();
+    type Failure = MonitorJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::SetModificationUpdates(cmd)
+    }
+}
+
+// Complete Job
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct CompleteJobCommand {
+    pub guid: Guid,
+}
+
+impl CommandType for CompleteJobCommand {
+    type Success = ();
+    type Failure = CompleteJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::CompleteJob(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum CompleteJobFailure {
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Complete job: {}", _0)]
+    CompleteJob(HResultMessage),
+    #[fail(display = "Job only partially completed")]
+    PartialComplete,
+    /// The downloaded file, named by its local path, is not the expected size or hash. The job
+    /// is left as it was.
+    #[fail(display = "{:?} does not match the expected size and hash", _0)]
+    HashMismatch(OsString),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Cancel Job
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct CancelJobCommand {
+    pub guid: Guid,
+}
+
+impl CommandType for CancelJobCommand {
+    type Success = ();
+    type Failure = CancelJobFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::CancelJob(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum CancelJobFailure {
+    #[fail(display = "Job not found")]
+    NotFound,
+    #[fail(display = "Get job: {}", _0)]
+    GetJob(HResultMessage),
+    #[fail(display = "Cancel job: {}", _0)]
+    CancelJob(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// List Jobs
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct ListJobsCommand {}
+
+impl CommandType for ListJobsCommand {
+    type Success = Vec<(Guid, JobStatus)>;
+    type Failure = ListJobsFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::ListJobs(cmd)
+    }
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum ListJobsFailure {
+    #[fail(display = "List jobs: {}", _0)]
+    ListJobs(HResultMessage),
+    #[fail(display = "Connect to BackgroundCopyManager: {}", _0)]
+    ConnectBcm(HResultMessage),
+    #[fail(display = "BITS error: {}", _0)]
+    OtherBITS(HResultMessage),
+    #[fail(display = "Other failure: {}", _0)]
+    Other(String),
+}
+
+// Cancel All Jobs
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct CancelAllJobsCommand {}
+
+// Only finding the jobs can fail the whole command, so it shares the failure of `list_jobs()`.
+impl CommandType for CancelAllJobsCommand {
+    type Success = CancelAllJobsSuccess;
+    type Failure = ListJobsFailure;
+    fn wrap(cmd: Self) -> Command {
+        Command::CancelAllJobs(cmd)
+    }
+}
+
+/// How a job was cancelled by `cancel_all_jobs()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CancelOutcome {
+    /// The job and its temporary files are gone.
+    Cancelled,
+    /// The job is gone, but some of its temporary files could not be deleted
+    /// (`BG_S_UNABLE_TO_DELETE_FILES`).
+    FilesLeftBehind,
+}
+
+/// The result of `cancel_all_jobs()` for each job found.
+pub type CancelAllJobsSuccess = Vec<(Guid, Result<CancelOutcome, CancelJobFailure>)>;
+
+/// Job status report
+///
+/// This includes a URL which updates with redirect but is otherwise the same as
+/// `bits::status::BitsJobStatus`.
+#[derive(Clone, Debug)]
+pub struct JobStatus {
+    pub state: BitsJobState,
+    pub progress: BitsJobProgress,
+    pub error_count: u32,
+    pub error: Option<JobError>,
+    pub times: BitsJobTimes,
+    /// None means same as last time
+    pub url: Option<OsString>,
+    /// Each file in the job, in the order they were added.
+    pub files: Vec<FileStatus>,
+    /// The server's reply to an upload-reply job, reported once after the job is transferred.
+    ///
+    /// At most [`MAX_REPLY_DATA`](constant.MAX_REPLY_DATA.html) bytes are reported, the whole
+    /// reply is saved to the reply file when the job is completed.
+    pub reply_data: Option<Vec<u8>>,
+    /// `None` from a server which can't report it, without
+    /// [`Capabilities::START_PRIORITY`](struct.Capabilities.html#associatedconstant.START_PRIORITY).
+    pub priority: Option<BitsJobPriority>,
+    /// The transfer rate, averaged over the monitor's reports of the last several seconds.
+    ///
+    /// `None` until a monitor has two reports to compare, and while the job isn't transferring.
+    /// The measurement starts over when the job is suspended or has an error, so the time it
+    /// wasn't transferring doesn't count.
+    pub bytes_per_second: Option<u64>,
+    /// How long the rest of the job should take at `bytes_per_second`, if its size is known.
+    pub estimated_remaining: Option<Duration>,
+}
+
+/// The longest reply reported in a [`JobStatus`](struct.JobStatus.html).
+pub const MAX_REPLY_DATA: usize = 64 * 1024;
+
+/// Status of a single file in a job
+#[derive(Clone, Debug)]
+pub struct FileStatus {
+    /// The remote URL, updated for redirects. None means same as last time
+    pub url: Option<OsString>,
+    /// The local path the file is saved to.
+    pub local_name: OsString,
+    pub progress: BitsFileProgress,
+}
+
+/// Job error report
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "Job error in context {}: {}", context_str, error)]
+pub struct JobError {
+    pub context: BitsErrorContext,
+    pub context_str: String,
+    pub error: HResultMessage,
+}
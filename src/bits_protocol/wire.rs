@@ -0,0 +1,1685 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Binary encoding of commands, results and status reports, for sending them between processes.
+//!
+//! A message is a header followed by the encoded value. The header is the protocol version and
+//! the length of the value, each a little-endian `u32`.
+//!
+//! Each side of a connection first sends a `Hello`, with the `Capabilities` it supports. A field
+//! or variant which came with one of the optional features is only sent if both sides support
+//! it, see the `if` in the listings of fields and variants below. A field which isn't sent is
+//! decoded as its default, a variant which isn't supported fails to decode. So both ends must
+//! encode and decode with the capabilities they share, given to `Writer` and `Reader`.
+//!
+//! Within a value:
+//!
+//! * Integers are little-endian, `bool` is one byte, 0 or 1.
+//! * `String` is a `u32` byte count followed by UTF-8. `OsString` is, on Windows, a `u32` count
+//!   of UTF-16 code units followed by the units, on Unix a `u32` byte count followed by the raw
+//!   bytes, and elsewhere the same as `String`; both ends of a connection run on the same
+//!   machine.
+//! * `Option` is a byte, 0 for `None` or 1 for `Some` followed by the value.
+//! * `Vec` is a `u32` count followed by the elements, a tuple is its elements in order.
+//! * An enum is a one-byte tag followed by the variant's fields, a struct is its fields in
+//!   order.
+//!
+//! The tags are fixed, so new variants must be given new tags. New fields go at the end of a
+//! struct and, like new variants and commands, behind a new capability, so a peer without it is
+//! unaffected.
+
+use std::cmp;
+use std::ffi::OsString;
+use std::result;
+use std::time::Duration;
+
+use failure::Fail;
+
+use super::*;
+use types::FileTime;
+
+// The BITS constants which BITS enums are sent as.
+const BG_JOB_PRIORITY_FOREGROUND: u32 = 0;
+const BG_JOB_PRIORITY_HIGH: u32 = 1;
+const BG_JOB_PRIORITY_NORMAL: u32 = 2;
+const BG_JOB_PRIORITY_LOW: u32 = 3;
+const BG_JOB_PROXY_USAGE_PRECONFIG: u32 = 0;
+const BG_JOB_PROXY_USAGE_NO_PROXY: u32 = 1;
+const BG_JOB_PROXY_USAGE_OVERRIDE: u32 = 2;
+const BG_JOB_PROXY_USAGE_AUTODETECT: u32 = 3;
+const BG_AUTH_TARGET_SERVER: u32 = 1;
+const BG_AUTH_TARGET_PROXY: u32 = 2;
+const BG_AUTH_SCHEME_BASIC: u32 = 1;
+const BG_AUTH_SCHEME_DIGEST: u32 = 2;
+const BG_AUTH_SCHEME_NTLM: u32 = 3;
+const BG_AUTH_SCHEME_NEGOTIATE: u32 = 4;
+
+/// Version of the framing: the header, and the layout of `Hello`.
+///
+/// This is frozen, changes to the encoding of values are negotiated with `Capabilities`
+/// instead. Only a peer with a different framing is rejected.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Size of the header which precedes each message.
+pub const HEADER_SIZE: usize = 8;
+
+/// Longest value accepted by `decode_header()`, so a corrupt header can't cause a huge
+/// allocation, and so the longest that `encode_message()` will produce.
+pub const MAX_MESSAGE_SIZE: u32 = 1 << 20;
+
+#[derive(Clone, Debug, Eq, Fail, PartialEq)]
+pub enum DecodeError {
+    #[fail(display = "Unsupported protocol version {}", _0)]
+    UnsupportedVersion(u32),
+    #[fail(display = "Message length {} does not match header length {}", _0, _1)]
+    LengthMismatch(usize, u32),
+    #[fail(display = "Message length {} is too large", _0)]
+    TooLarge(u32),
+    #[fail(display = "Message ended unexpectedly")]
+    Truncated,
+    #[fail(display = "{} unexpected bytes at end of message", _0)]
+    TrailingBytes(usize),
+    #[fail(display = "Invalid {} tag {}", _0, _1)]
+    InvalidTag(&'static str, u32),
+    #[fail(display = "Invalid string")]
+    InvalidString,
+    #[fail(display = "Invalid duration")]
+    InvalidDuration,
+}
+
+type Result<T> = result::Result<T, DecodeError>;
+
+/// A type which can be written in the wire encoding.
+pub trait Encode {
+    fn encode(&self, buf: &mut Writer);
+}
+
+/// A type which can be read from the wire encoding.
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self>;
+}
+
+/// Writes values for a connection with `capabilities`.
+pub struct Writer {
+    buf: Vec<u8>,
+    capabilities: Capabilities,
+}
+
+impl Writer {
+    pub fn new(capabilities: Capabilities) -> Writer {
+        Writer {
+            buf: Vec::new(),
+            capabilities,
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+/// Reads values from an encoded message, from a connection with `capabilities`.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    capabilities: Capabilities,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8], capabilities: Capabilities) -> Reader<'a> {
+        Reader { buf, capabilities }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(DecodeError::Truncated);
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn take_array<A: Default + AsMut<[u8]>>(&mut self) -> Result<A> {
+        let mut array = A::default();
+        {
+            let dest = array.as_mut();
+            let len = dest.len();
+            dest.copy_from_slice(self.take(len)?);
+        }
+        Ok(array)
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+/// Encode `value` as a complete message, with header, for a connection with `capabilities`.
+///
+/// Fails with `TooLarge` if the value is longer than `MAX_MESSAGE_SIZE`, as the receiver would
+/// reject it.
+pub fn encode_message<T: Encode>(value: &T, capabilities: Capabilities) -> Result<Vec<u8>> {
+    let mut writer = Writer {
+        buf: vec![0u8; HEADER_SIZE],
+        capabilities,
+    };
+    value.encode(&mut writer);
+    let mut buf = writer.into_bytes();
+
+    let len = buf.len() - HEADER_SIZE;
+    if len > MAX_MESSAGE_SIZE as usize {
+        return Err(DecodeError::TooLarge(
+            cmp::min(len, u32::MAX as usize) as u32
+        ));
+    }
+    buf[..4].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    buf[4..HEADER_SIZE].copy_from_slice(&(len as u32).to_le_bytes());
+    Ok(buf)
+}
+
+/// Check a message header, returning the length of the value that follows it.
+pub fn decode_header(header: &[u8; HEADER_SIZE]) -> Result<u32> {
+    let (version, len) = decode_any_header(header)?;
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    Ok(len)
+}
+
+/// Check a message header from a peer with any version, returning the version and the length
+/// of the value that follows it.
+///
+/// For reading a `Hello`, which can be decoded whatever the version, so that a peer with
+/// another framing can be told apart from a corrupt message.
+pub fn decode_any_header(header: &[u8; HEADER_SIZE]) -> Result<(u32, u32)> {
+    let mut reader = Reader::new(header, Capabilities::empty());
+    let version = u32::decode(&mut reader)?;
+    let len = u32::decode(&mut reader)?;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(DecodeError::TooLarge(len));
+    }
+    Ok((version, len))
+}
+
+/// Decode a complete message, with header, from a connection with `capabilities`.
+pub fn decode_message<T: Decode>(message: &[u8], capabilities: Capabilities) -> Result<T> {
+    if message.len() < HEADER_SIZE {
+        return Err(DecodeError::Truncated);
+    }
+    let mut header = [0u8; HEADER_SIZE];
+    header.copy_from_slice(&message[..HEADER_SIZE]);
+    let len = decode_header(&header)?;
+
+    let body = &message[HEADER_SIZE..];
+    if body.len() != len as usize {
+        return Err(DecodeError::LengthMismatch(body.len(), len));
+    }
+    decode_value(body, capabilities)
+}
+
+/// Decode a value without a header, which must use all of `body`, from a connection with
+/// `capabilities`.
+pub fn decode_value<T: Decode>(body: &[u8], capabilities: Capabilities) -> Result<T> {
+    let mut reader = Reader::new(body, capabilities);
+    let value = T::decode(&mut reader)?;
+    if reader.remaining() != 0 {
+        return Err(DecodeError::TrailingBytes(reader.remaining()));
+    }
+    Ok(value)
+}
+
+// Primitives
+
+macro_rules! wire_int {
+    ($ty:ty, $size:expr) => {
+        impl Encode for $ty {
+            fn encode(&self, buf: &mut Writer) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(reader: &mut Reader) -> Result<$ty> {
+                Ok(<$ty>::from_le_bytes(reader.take_array::<[u8; $size]>()?))
+            }
+        }
+    };
+}
+
+wire_int!(u8, 1);
+wire_int!(u16, 2);
+wire_int!(u32, 4);
+wire_int!(i32, 4);
+wire_int!(u64, 8);
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Writer) {
+        (*self as u8).encode(buf);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<bool> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag("bool", u32::from(tag))),
+        }
+    }
+}
+
+impl Encode for () {
+    fn encode(&self, _buf: &mut Writer) {}
+}
+
+impl Decode for () {
+    fn decode(_reader: &mut Reader) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Writer) {
+        (self.len() as u32).encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<String> {
+        let len = u32::decode(reader)? as usize;
+        let bytes = reader.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidString)
+    }
+}
+
+// Paths are sent as UTF-16 so they survive the trip intact on Windows.
+#[cfg(windows)]
+impl Encode for OsString {
+    fn encode(&self, buf: &mut Writer) {
+        use std::os::windows::ffi::OsStrExt;
+        let wide: Vec<u16> = self.encode_wide().collect();
+        (wide.len() as u32).encode(buf);
+        for unit in wide {
+            unit.encode(buf);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Decode for OsString {
+    fn decode(reader: &mut Reader) -> Result<OsString> {
+        use std::os::windows::ffi::OsStringExt;
+        let len = u32::decode(reader)? as usize;
+        let bytes = reader.take(len.checked_mul(2).ok_or(DecodeError::Truncated)?)?;
+        let wide: Vec<u16> = bytes
+            .chunks(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        Ok(OsString::from_wide(&wide))
+    }
+}
+
+// Elsewhere paths are bytes, which need not be UTF-8.
+#[cfg(unix)]
+impl Encode for OsString {
+    fn encode(&self, buf: &mut Writer) {
+        use std::os::unix::ffi::OsStrExt;
+        (self.len() as u32).encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+#[cfg(unix)]
+impl Decode for OsString {
+    fn decode(reader: &mut Reader) -> Result<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+        let len = u32::decode(reader)? as usize;
+        Ok(OsString::from_vec(reader.take(len)?.to_vec()))
+    }
+}
+
+// Anywhere else paths are sent as UTF-8. Any invalid sequence is replaced when it is sent, and
+// rejected when it is received.
+#[cfg(not(any(windows, unix)))]
+impl Encode for OsString {
+    fn encode(&self, buf: &mut Writer) {
+        self.to_string_lossy().into_owned().encode(buf);
+    }
+}
+
+#[cfg(not(any(windows, unix)))]
+impl Decode for OsString {
+    fn decode(reader: &mut Reader) -> Result<OsString> {
+        String::decode(reader).map(OsString::from)
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Writer) {
+        (self.len() as u32).encode(buf);
+        for value in self {
+            value.encode(buf);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Vec<T>> {
+        let len = u32::decode(reader)? as usize;
+        // Don't trust the count for the allocation, a corrupt one could be huge.
+        let mut values = Vec::with_capacity(cmp::min(len, reader.remaining()));
+        for _ in 0..len {
+            values.push(T::decode(reader)?);
+        }
+        Ok(values)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, buf: &mut Writer) {
+        match *self {
+            None => 0u8.encode(buf),
+            Some(ref value) => {
+                1u8.encode(buf);
+                value.encode(buf);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(reader: &mut Reader) -> Result<Option<T>> {
+        match u8::decode(reader)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(reader)?)),
+            tag => Err(DecodeError::InvalidTag("Option", u32::from(tag))),
+        }
+    }
+}
+
+impl<T: Encode, E: Encode> Encode for result::Result<T, E> {
+    fn encode(&self, buf: &mut Writer) {
+        match *self {
+            Ok(ref value) => {
+                0u8.encode(buf);
+                value.encode(buf);
+            }
+            Err(ref error) => {
+                1u8.encode(buf);
+                error.encode(buf);
+            }
+        }
+    }
+}
+
+impl<T: Decode, E: Decode> Decode for result::Result<T, E> {
+    fn decode(reader: &mut Reader) -> Result<result::Result<T, E>> {
+        match u8::decode(reader)? {
+            0 => Ok(Ok(T::decode(reader)?)),
+            1 => Ok(Err(E::decode(reader)?)),
+            tag => Err(DecodeError::InvalidTag("Result", u32::from(tag))),
+        }
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, buf: &mut Writer) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(reader: &mut Reader) -> Result<(A, B)> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+impl Encode for Guid {
+    fn encode(&self, buf: &mut Writer) {
+        self.data1.encode(buf);
+        self.data2.encode(buf);
+        self.data3.encode(buf);
+        buf.extend_from_slice(&self.data4);
+    }
+}
+
+impl Decode for Guid {
+    fn decode(reader: &mut Reader) -> Result<Guid> {
+        Ok(Guid {
+            data1: u32::decode(reader)?,
+            data2: u16::decode(reader)?,
+            data3: u16::decode(reader)?,
+            data4: reader.take_array()?,
+        })
+    }
+}
+
+// A SHA-256 hash, as its bytes.
+impl Encode for [u8; 32] {
+    fn encode(&self, buf: &mut Writer) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Decode for [u8; 32] {
+    fn decode(reader: &mut Reader) -> Result<[u8; 32]> {
+        reader.take_array()
+    }
+}
+
+// `Duration` is its whole seconds, a `u64`, followed by the rest in nanoseconds, a `u32`.
+impl Encode for Duration {
+    fn encode(&self, buf: &mut Writer) {
+        self.as_secs().encode(buf);
+        self.subsec_nanos().encode(buf);
+    }
+}
+
+impl Decode for Duration {
+    fn decode(reader: &mut Reader) -> Result<Duration> {
+        let secs = u64::decode(reader)?;
+        let nanos = u32::decode(reader)?;
+        if nanos >= 1_000_000_000 {
+            return Err(DecodeError::InvalidDuration);
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+// `FileTime` is a `u64`, the same as the low and then the high half of a `FILETIME`.
+impl Encode for FileTime {
+    fn encode(&self, buf: &mut Writer) {
+        self.0.encode(buf);
+    }
+}
+
+impl Decode for FileTime {
+    fn decode(reader: &mut Reader) -> Result<FileTime> {
+        Ok(FileTime(u64::decode(reader)?))
+    }
+}
+
+// BITS enums are sent as their BITS constants, see `types`.
+
+// `Override` is followed by its proxy and bypass lists, and needs `PROXY_OVERRIDE`.
+impl Encode for BitsProxyUsage {
+    fn encode(&self, buf: &mut Writer) {
+        use self::BitsProxyUsage::*;
+        match *self {
+            Preconfig => BG_JOB_PROXY_USAGE_PRECONFIG.encode(buf),
+            NoProxy => BG_JOB_PROXY_USAGE_NO_PROXY.encode(buf),
+            AutoDetect => BG_JOB_PROXY_USAGE_AUTODETECT.encode(buf),
+            Override {
+                ref proxies,
+                ref bypass,
+            } => {
+                BG_JOB_PROXY_USAGE_OVERRIDE.encode(buf);
+                proxies.encode(buf);
+                bypass.encode(buf);
+            }
+        }
+    }
+}
+
+impl Decode for BitsProxyUsage {
+    fn decode(reader: &mut Reader) -> Result<BitsProxyUsage> {
+        match u32::decode(reader)? {
+            BG_JOB_PROXY_USAGE_PRECONFIG => Ok(BitsProxyUsage::Preconfig),
+            BG_JOB_PROXY_USAGE_NO_PROXY => Ok(BitsProxyUsage::NoProxy),
+            BG_JOB_PROXY_USAGE_AUTODETECT => Ok(BitsProxyUsage::AutoDetect),
+            BG_JOB_PROXY_USAGE_OVERRIDE
+                if reader.capabilities().contains(Capabilities::PROXY_OVERRIDE) =>
+            {
+                Ok(BitsProxyUsage::Override {
+                    proxies: Decode::decode(reader)?,
+                    bypass: Decode::decode(reader)?,
+                })
+            }
+            value => Err(DecodeError::InvalidTag("BitsProxyUsage", value)),
+        }
+    }
+}
+
+impl Encode for BitsJobPriority {
+    fn encode(&self, buf: &mut Writer) {
+        (*self as u32).encode(buf);
+    }
+}
+
+impl Decode for BitsJobPriority {
+    fn decode(reader: &mut Reader) -> Result<BitsJobPriority> {
+        match u32::decode(reader)? {
+            BG_JOB_PRIORITY_FOREGROUND => Ok(BitsJobPriority::Foreground),
+            BG_JOB_PRIORITY_HIGH => Ok(BitsJobPriority::High),
+            BG_JOB_PRIORITY_NORMAL => Ok(BitsJobPriority::Normal),
+            BG_JOB_PRIORITY_LOW => Ok(BitsJobPriority::Low),
+            value => Err(DecodeError::InvalidTag("BitsJobPriority", value)),
+        }
+    }
+}
+
+impl Encode for BitsAuthTarget {
+    fn encode(&self, buf: &mut Writer) {
+        (*self as u32).encode(buf);
+    }
+}
+
+impl Decode for BitsAuthTarget {
+    fn decode(reader: &mut Reader) -> Result<BitsAuthTarget> {
+        match u32::decode(reader)? {
+            BG_AUTH_TARGET_SERVER => Ok(BitsAuthTarget::Server),
+            BG_AUTH_TARGET_PROXY => Ok(BitsAuthTarget::Proxy),
+            value => Err(DecodeError::InvalidTag("BitsAuthTarget", value)),
+        }
+    }
+}
+
+impl Encode for BitsAuthScheme {
+    fn encode(&self, buf: &mut Writer) {
+        (*self as u32).encode(buf);
+    }
+}
+
+impl Decode for BitsAuthScheme {
+    fn decode(reader: &mut Reader) -> Result<BitsAuthScheme> {
+        match u32::decode(reader)? {
+            BG_AUTH_SCHEME_BASIC => Ok(BitsAuthScheme::Basic),
+            BG_AUTH_SCHEME_DIGEST => Ok(BitsAuthScheme::Digest),
+            BG_AUTH_SCHEME_NTLM => Ok(BitsAuthScheme::Ntlm),
+            BG_AUTH_SCHEME_NEGOTIATE => Ok(BitsAuthScheme::Negotiate),
+            value => Err(DecodeError::InvalidTag("BitsAuthScheme", value)),
+        }
+    }
+}
+
+impl Encode for BitsJobState {
+    fn encode(&self, buf: &mut Writer) {
+        u32::from(*self).encode(buf);
+    }
+}
+
+impl Decode for BitsJobState {
+    fn decode(reader: &mut Reader) -> Result<BitsJobState> {
+        Ok(BitsJobState::from(u32::decode(reader)?))
+    }
+}
+
+impl Encode for BitsErrorContext {
+    fn encode(&self, buf: &mut Writer) {
+        u32::from(*self).encode(buf);
+    }
+}
+
+impl Decode for BitsErrorContext {
+    fn decode(reader: &mut Reader) -> Result<BitsErrorContext> {
+        Ok(BitsErrorContext::from(u32::decode(reader)?))
+    }
+}
+
+// Structs and enums are listed with their fields in wire order.
+//
+// `field if CAPABILITY` is only sent if the connection has that capability, and is otherwise
+// decoded as its default.
+
+macro_rules! encode_field {
+    ($value:expr, $buf:ident) => {
+        $value.encode($buf)
+    };
+    ($value:expr, $buf:ident, $cap:ident) => {
+        if $buf.capabilities().contains(Capabilities::$cap) {
+            $value.encode($buf)
+        }
+    };
+}
+
+macro_rules! decode_field {
+    ($reader:ident) => {
+        Decode::decode($reader)?
+    };
+    ($reader:ident, $cap:ident) => {
+        if $reader.capabilities().contains(Capabilities::$cap) {
+            Decode::decode($reader)?
+        } else {
+            Default::default()
+        }
+    };
+}
+
+macro_rules! wire_struct {
+    ($ty:ident { $($field:ident $(if $cap:ident)?),* $(,)* }) => {
+        impl Encode for $ty {
+            fn encode(&self, _buf: &mut Writer) {
+                $(encode_field!(self.$field, _buf $(, $cap)?);)*
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(_reader: &mut Reader) -> Result<$ty> {
+                Ok($ty {
+                    $($field: decode_field!(_reader $(, $cap)?),)*
+                })
+            }
+        }
+    };
+}
+
+// Each variant has a fixed tag, and at most one field.
+//
+// `variant if CAPABILITY` only decodes if the connection has that capability, the sender must
+// not send it otherwise.
+macro_rules! wire_enum {
+    ($ty:ident { $($tag:tt => $variant:ident $(($field:ident))* $(if $cap:ident)?,)* }) => {
+        impl Encode for $ty {
+            fn encode(&self, buf: &mut Writer) {
+                match *self {
+                    $($ty::$variant $((ref $field))* => {
+                        ($tag as u8).encode(buf);
+                        $($field.encode(buf);)*
+                    })*
+                }
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(reader: &mut Reader) -> Result<$ty> {
+                match u8::decode(reader)? {
+                    $($tag $(if reader.capabilities().contains(Capabilities::$cap))?
+                        => Ok($ty::$variant $(({
+                        let $field = Decode::decode(reader)?;
+                        $field
+                    }))*),)*
+                    tag => Err(DecodeError::InvalidTag(stringify!($ty), u32::from(tag))),
+                }
+            }
+        }
+    };
+}
+
+impl Encode for Capabilities {
+    fn encode(&self, buf: &mut Writer) {
+        self.bits().encode(buf);
+    }
+}
+
+impl Decode for Capabilities {
+    fn decode(reader: &mut Reader) -> Result<Capabilities> {
+        Ok(Capabilities::from_bits(u64::decode(reader)?))
+    }
+}
+
+// Without `MONITOR_SUBSCRIPTIONS` a subscription isn't sent at all, and is decoded as 0. The
+// client can't pick out one monitor of a job then, so never needs to send one.
+impl Encode for MonitorSubscription {
+    fn encode(&self, buf: &mut Writer) {
+        if buf
+            .capabilities()
+            .contains(Capabilities::MONITOR_SUBSCRIPTIONS)
+        {
+            self.0.encode(buf);
+        }
+    }
+}
+
+impl Decode for MonitorSubscription {
+    fn decode(reader: &mut Reader) -> Result<MonitorSubscription> {
+        if !reader
+            .capabilities()
+            .contains(Capabilities::MONITOR_SUBSCRIPTIONS)
+        {
+            return Ok(MonitorSubscription(0));
+        }
+        Ok(MonitorSubscription(u64::decode(reader)?))
+    }
+}
+
+wire_struct!(HResultMessage { hr, message });
+
+// The layout of `Hello` is fixed, so it can be read from any peer. Any fields which a later
+// framing adds after these are skipped.
+impl Encode for Hello {
+    fn encode(&self, buf: &mut Writer) {
+        self.protocol_version.encode(buf);
+        self.build.encode(buf);
+        self.capabilities.encode(buf);
+    }
+}
+
+impl Decode for Hello {
+    fn decode(reader: &mut Reader) -> Result<Hello> {
+        let hello = Hello {
+            protocol_version: Decode::decode(reader)?,
+            build: Decode::decode(reader)?,
+            capabilities: Decode::decode(reader)?,
+        };
+        let rest = reader.remaining();
+        reader.take(rest)?;
+        Ok(hello)
+    }
+}
+
+wire_enum!(Command {
+    0 => StartJob(command),
+    1 => MonitorJob(command),
+    2 => SuspendJob(command),
+    3 => ResumeJob(command),
+    4 => SetJobPriority(command),
+    5 => SetUpdateInterval(command),
+    6 => CompleteJob(command),
+    7 => CancelJob(command),
+    8 => StopUpdate(command),
+    9 => StartJobMulti(command) if MULTI_FILE_JOBS,
+    10 => StartUploadJob(command) if UPLOAD,
+    11 => UpdateJobSettings(command) if JOB_SETTINGS,
+    12 => ListJobs(command) if LIST_JOBS,
+    13 => CancelAllJobs(command) if CANCEL_ALL_JOBS,
+    14 => SetModificationUpdates(command) if MODIFICATION_UPDATES,
+});
+
+/// Encode a reply to the command in `body`, which could not be decoded, failing with
+/// `message`, or `None` if not even the command's tag is known.
+///
+/// The tags are those of `Command` above, the reply is an `Err` of the command's failure type.
+pub fn encode_command_failure(
+    body: &[u8],
+    message: String,
+    capabilities: Capabilities,
+) -> Option<Vec<u8>> {
+    fn encode<F: Encode>(failure: F, capabilities: Capabilities) -> Option<Vec<u8>> {
+        encode_message::<result::Result<(), F>>(&Err(failure), capabilities).ok()
+    }
+
+    match *body.first()? {
+        0 | 9 | 10 => encode(StartJobFailure::Other(message), capabilities),
+        1 | 14 => encode(MonitorJobFailure::Other(message), capabilities),
+        2 => encode(SuspendJobFailure::Other(message), capabilities),
+        3 => encode(ResumeJobFailure::Other(message), capabilities),
+        4 => encode(SetJobPriorityFailure::Other(message), capabilities),
+        5 | 8 => encode(SetUpdateIntervalFailure::Other(message), capabilities),
+        6 => encode(CompleteJobFailure::Other(message), capabilities),
+        7 => encode(CancelJobFailure::Other(message), capabilities),
+        11 => encode(UpdateJobSettingsFailure::Other(message), capabilities),
+        12 | 13 => encode(ListJobsFailure::Other(message), capabilities),
+        _ => None,
+    }
+}
+
+wire_struct!(StartJobCommand {
+    url,
+    save_path,
+    proxy_usage,
+    monitor,
+    headers if CUSTOM_HEADERS,
+    credentials if CREDENTIALS,
+    priority if START_PRIORITY,
+    settings if JOB_SETTINGS,
+    ranges if BYTE_RANGES,
+    expected if HASH_VERIFICATION,
+});
+wire_struct!(BitsFileRange { offset, length });
+wire_struct!(ExpectedFile { size, sha256 });
+wire_struct!(JobSettings {
+    minimum_retry_delay_secs,
+    no_progress_timeout_secs,
+    maximum_download_time_secs,
+});
+wire_struct!(Credentials {
+    target,
+    scheme,
+    user_name,
+    password,
+});
+wire_struct!(MonitorConfig {
+    pipe_name,
+    interval_millis,
+});
+wire_struct!(StartJobSuccess { guid });
+wire_enum!(StartJobFailure {
+    0 => ArgumentValidation(message),
+    1 => Create(error),
+    2 => AddFile(error),
+    3 => ApplySettings(error),
+    4 => Resume(error),
+    5 => ConnectBcm(error),
+    6 => OtherBITS(error),
+    7 => Other(message),
+});
+
+wire_struct!(StartJobMultiCommand {
+    files,
+    proxy_usage,
+    monitor,
+    options,
+});
+// Each option is only sent with its own capability, like those of `StartJobCommand`.
+wire_struct!(StartJobOptions {
+    headers if CUSTOM_HEADERS,
+    credentials if CREDENTIALS,
+    priority if START_PRIORITY,
+    settings if JOB_SETTINGS,
+    ranges if BYTE_RANGES,
+    expected if HASH_VERIFICATION,
+});
+wire_struct!(JobFile { url, save_path });
+
+wire_struct!(StartUploadJobCommand {
+    url,
+    upload_path,
+    reply_path,
+    proxy_usage,
+    monitor,
+});
+
+wire_struct!(MonitorJobCommand { guid, monitor });
+wire_enum!(MonitorJobFailure {
+    0 => ArgumentValidation(message),
+    1 => NotFound,
+    2 => GetJob(error),
+    3 => ConnectBcm(error),
+    4 => OtherBITS(error),
+    5 => Other(message),
+});
+
+wire_struct!(SuspendJobCommand { guid });
+wire_enum!(SuspendJobFailure {
+    0 => NotFound,
+    1 => GetJob(error),
+    2 => SuspendJob(error),
+    3 => ConnectBcm(error),
+    4 => OtherBITS(error),
+    5 => Other(message),
+});
+
+wire_struct!(ResumeJobCommand { guid });
+wire_enum!(ResumeJobFailure {
+    0 => NotFound,
+    1 => GetJob(error),
+    2 => ResumeJob(error),
+    3 => ConnectBcm(error),
+    4 => OtherBITS(error),
+    5 => Other(message),
+});
+
+wire_struct!(SetJobPriorityCommand { guid, priority });
+wire_enum!(SetJobPriorityFailure {
+    0 => NotFound,
+    1 => GetJob(error),
+    2 => ApplySettings(error),
+    3 => ConnectBcm(error),
+    4 => OtherBITS(error),
+    5 => Other(message),
+});
+
+wire_struct!(UpdateJobSettingsCommand { guid, settings });
+wire_enum!(UpdateJobSettingsFailure {
+    0 => NotFound,
+    1 => GetJob(error),
+    2 => ApplySettings(error),
+    3 => ConnectBcm(error),
+    4 => OtherBITS(error),
+    5 => Other(message),
+});
+
+wire_struct!(SetUpdateIntervalCommand {
+    guid,
+    interval_millis,
+    subscription if MONITOR_SUBSCRIPTIONS,
+});
+wire_enum!(SetUpdateIntervalFailure {
+    0 => ArgumentValidation(message),
+    1 => NotFound,
+    2 => Other(message),
+});
+
+wire_struct!(StopUpdateCommand {
+    guid,
+    subscription if MONITOR_SUBSCRIPTIONS,
+});
+wire_struct!(SetModificationUpdatesCommand {
+    guid,
+    min_interval_millis,
+    subscription if MONITOR_SUBSCRIPTIONS,
+});
+
+wire_struct!(CompleteJobCommand { guid });
+wire_enum!(CompleteJobFailure {
+    0 => NotFound,
+    1 => GetJob(error),
+    2 => CompleteJob(error),
+    3 => PartialComplete,
+    4 => ConnectBcm(error),
+    5 => OtherBITS(error),
+    6 => Other(message),
+    7 => HashMismatch(local_name) if HASH_VERIFICATION,
+});
+
+wire_struct!(CancelJobCommand { guid });
+wire_enum!(CancelJobFailure {
+    0 => NotFound,
+    1 => GetJob(error),
+    2 => CancelJob(error),
+    3 => ConnectBcm(error),
+    4 => OtherBITS(error),
+    5 => Other(message),
+});
+
+wire_struct!(ListJobsCommand {});
+wire_struct!(CancelAllJobsCommand {});
+wire_enum!(CancelOutcome {
+    0 => Cancelled,
+    1 => FilesLeftBehind,
+});
+wire_enum!(ListJobsFailure {
+    0 => ListJobs(error),
+    1 => ConnectBcm(error),
+    2 => OtherBITS(error),
+    3 => Other(message),
+});
+
+wire_struct!(BitsJobProgress {
+    total_bytes,
+    transferred_bytes,
+    total_files,
+    transferred_files,
+});
+wire_struct!(BitsJobTimes {
+    creation,
+    modification,
+    transfer_completion,
+});
+wire_struct!(JobStatus {
+    state,
+    progress,
+    error_count,
+    error,
+    times,
+    url,
+    files if MULTI_FILE_JOBS,
+    reply_data if UPLOAD,
+    priority if START_PRIORITY,
+    bytes_per_second if TRANSFER_RATE,
+    estimated_remaining if TRANSFER_RATE,
+});
+wire_struct!(FileStatus {
+    url,
+    progress,
+    local_name if FILE_NAMES,
+});
+wire_struct!(BitsFileProgress {
+    total_bytes,
+    transferred_bytes,
+    completed,
+});
+wire_struct!(JobError {
+    context,
+    context_str,
+    error,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+
+    // Every capability, so that every field is sent.
+    fn all() -> Capabilities {
+        Capabilities::from_bits(!0)
+    }
+
+    // None of the protocol types implement `PartialEq`, so compare their `Debug` output.
+    fn round_trip<T: Encode + Decode + Debug>(value: T) {
+        let message = encode_message(&value, all()).unwrap();
+        let decoded: T = decode_message(&message, all()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", value));
+        assert_eq!(encode_message(&decoded, all()).unwrap(), message);
+    }
+
+    fn guid() -> Guid {
+        Guid {
+            data1: 0x0123_4567,
+            data2: 0x89ab,
+            data3: 0xcdef,
+            data4: *b"bits_cli",
+        }
+    }
+
+    fn hr_message() -> HResultMessage {
+        HResultMessage {
+            hr: 0x8020_0001u32 as i32,
+            message: "Description of the error".to_owned(),
+        }
+    }
+
+    fn file_time(ticks: u64) -> FileTime {
+        FileTime(ticks)
+    }
+
+    fn monitor() -> MonitorConfig {
+        MonitorConfig {
+            pipe_name: OsString::from("\\\\.\\pipe\\monitor"),
+            interval_millis: 1000,
+        }
+    }
+
+    #[test]
+    fn hello() {
+        round_trip(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            build: "bits_client 0.1.0".to_owned(),
+            capabilities: Capabilities::MONITORS | Capabilities::UPLOAD,
+        });
+
+        // A `Hello` can be read from a peer with another framing, and any fields it has added
+        // are skipped.
+        let mut message = encode_message(&Hello::new(all()), Capabilities::empty()).unwrap();
+        message[0] = PROTOCOL_VERSION as u8 + 1;
+        message[4] += 2;
+        message.extend_from_slice(&[1, 2]);
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&message[..HEADER_SIZE]);
+        assert_eq!(
+            decode_header(&header).unwrap_err(),
+            DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1)
+        );
+        let (version, len) = decode_any_header(&header).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION + 1);
+        assert_eq!(len as usize, message.len() - HEADER_SIZE);
+        let hello: Hello = decode_value(&message[HEADER_SIZE..], Capabilities::empty()).unwrap();
+        assert_eq!(hello.capabilities, all());
+
+        // Capabilities from a newer version are kept.
+        let message = encode_message(&Capabilities::from_bits(1 << 63 | 1), all()).unwrap();
+        let capabilities: Capabilities = decode_message(&message, all()).unwrap();
+        assert!(capabilities.contains(Capabilities::MONITORS));
+        assert_eq!(capabilities.bits(), 1 << 63 | 1);
+    }
+
+    #[test]
+    fn commands() {
+        let command = Command::StartJob(StartJobCommand {
+            url: OsString::from("https://example.com/file"),
+            save_path: OsString::from("dir\\file \u{263A}"),
+            proxy_usage: BitsProxyUsage::AutoDetect,
+            headers: vec![
+                ("Authorization".to_owned(), "Bearer token".to_owned()),
+                ("X-Client-Version".to_owned(), "1.2.3".to_owned()),
+            ],
+            credentials: Some(Credentials {
+                target: BitsAuthTarget::Server,
+                scheme: BitsAuthScheme::Basic,
+                user_name: OsString::from("user"),
+                password: OsString::from("hunter2"),
+            }),
+            priority: Some(BitsJobPriority::Low),
+            settings: JobSettings {
+                minimum_retry_delay_secs: Some(10),
+                no_progress_timeout_secs: None,
+                maximum_download_time_secs: Some(3600),
+            },
+            ranges: vec![
+                BitsFileRange {
+                    offset: 0,
+                    length: Some(512),
+                },
+                BitsFileRange {
+                    offset: 1 << 40,
+                    length: None,
+                },
+            ],
+            expected: vec![Some(ExpectedFile {
+                size: 1 << 20,
+                sha256: *b"0123456789abcdef0123456789abcdef",
+            })],
+            monitor: Some(monitor()),
+        });
+        // The password and the header values are never shown.
+        let debug = format!("{:?}", command);
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("Bearer token"));
+        assert!(!debug.contains("1.2.3"));
+        assert!(debug.contains("X-Client-Version"));
+        // Nor compared by `round_trip()`, but the encoding is.
+        let decoded: Command =
+            decode_message(&encode_message(&command, all()).unwrap(), all()).unwrap();
+        match decoded {
+            Command::StartJob(StartJobCommand {
+                ref headers,
+                credentials: Some(ref credentials),
+                ..
+            }) => {
+                assert_eq!(headers[0].1, "Bearer token");
+                assert_eq!(headers[1].1, "1.2.3");
+                assert_eq!(credentials.password, OsString::from("hunter2"));
+            }
+            ref command => panic!("unexpected command {:?}", command),
+        }
+        round_trip(command);
+        round_trip(Command::StartJob(StartJobCommand {
+            url: OsString::new(),
+            save_path: OsString::new(),
+            proxy_usage: BitsProxyUsage::NoProxy,
+            headers: Vec::new(),
+            credentials: None,
+            priority: None,
+            settings: JobSettings::default(),
+            ranges: Vec::new(),
+            expected: Vec::new(),
+            monitor: None,
+        }));
+        round_trip(Command::StartJob(StartJobCommand {
+            url: OsString::new(),
+            save_path: OsString::new(),
+            proxy_usage: BitsProxyUsage::Override {
+                proxies: vec![
+                    "proxy.example.com:8080".to_owned(),
+                    "http=fallback".to_owned(),
+                ],
+                bypass: vec!["<local>".to_owned(), "*.example.com".to_owned()],
+            },
+            headers: Vec::new(),
+            credentials: None,
+            priority: None,
+            settings: JobSettings::default(),
+            ranges: Vec::new(),
+            expected: Vec::new(),
+            monitor: None,
+        }));
+        round_trip(Command::MonitorJob(MonitorJobCommand {
+            guid: guid(),
+            monitor: monitor(),
+        }));
+        round_trip(Command::SuspendJob(SuspendJobCommand { guid: guid() }));
+        round_trip(Command::ResumeJob(ResumeJobCommand { guid: guid() }));
+        round_trip(Command::SetJobPriority(SetJobPriorityCommand {
+            guid: guid(),
+            priority: BitsJobPriority::High,
+        }));
+        round_trip(Command::UpdateJobSettings(UpdateJobSettingsCommand {
+            guid: guid(),
+            settings: JobSettings {
+                minimum_retry_delay_secs: None,
+                no_progress_timeout_secs: Some(300),
+                maximum_download_time_secs: None,
+            },
+        }));
+        round_trip(Command::SetUpdateInterval(SetUpdateIntervalCommand {
+            guid: guid(),
+            subscription: Some(MonitorSubscription(3)),
+            interval_millis: 250,
+        }));
+        round_trip(Command::StopUpdate(StopUpdateCommand {
+            guid: guid(),
+            subscription: None,
+        }));
+        round_trip(Command::StopUpdate(StopUpdateCommand {
+            guid: guid(),
+            subscription: Some(MonitorSubscription(u64::MAX)),
+        }));
+        round_trip(Command::SetModificationUpdates(
+            SetModificationUpdatesCommand {
+                guid: guid(),
+                subscription: Some(MonitorSubscription(0)),
+                min_interval_millis: Some(100),
+            },
+        ));
+        round_trip(Command::SetModificationUpdates(
+            SetModificationUpdatesCommand {
+                guid: guid(),
+                subscription: None,
+                min_interval_millis: None,
+            },
+        ));
+        round_trip(Command::CompleteJob(CompleteJobCommand { guid: guid() }));
+        round_trip(Command::CancelJob(CancelJobCommand { guid: guid() }));
+        round_trip(Command::ListJobs(ListJobsCommand {}));
+        round_trip(Command::CancelAllJobs(CancelAllJobsCommand {}));
+        round_trip(Command::StartJobMulti(StartJobMultiCommand {
+            files: vec![
+                JobFile {
+                    url: OsString::from("https://example.com/manifest"),
+                    save_path: OsString::from("manifest"),
+                },
+                JobFile {
+                    url: OsString::from("https://example.com/patch"),
+                    save_path: OsString::from("patch"),
+                },
+            ],
+            proxy_usage: BitsProxyUsage::NoProxy,
+            monitor: None,
+            options: StartJobOptions {
+                priority: Some(BitsJobPriority::High),
+                expected: vec![
+                    None,
+                    Some(ExpectedFile {
+                        size: 2,
+                        sha256: [1; 32],
+                    }),
+                ],
+                ..StartJobOptions::default()
+            },
+        }));
+        round_trip(Command::StartUploadJob(StartUploadJobCommand {
+            url: OsString::from("https://example.com/submit"),
+            upload_path: OsString::from("report.dmp"),
+            reply_path: Some(OsString::from("report.reply")),
+            proxy_usage: BitsProxyUsage::Preconfig,
+            monitor: Some(MonitorConfig {
+                pipe_name: OsString::from("monitor"),
+                interval_millis: 1000,
+            }),
+        }));
+    }
+
+    #[test]
+    fn results() {
+        use self::StartJobFailure::*;
+
+        round_trip::<result::Result<_, StartJobFailure>>(Ok((
+            StartJobSuccess { guid: guid() },
+            MonitorSubscription(1),
+        )));
+        round_trip::<result::Result<_, MonitorJobFailure>>(Ok(MonitorSubscription(2)));
+        round_trip::<result::Result<(), CancelJobFailure>>(Ok(()));
+        round_trip::<result::Result<_, ListJobsFailure>>(Ok(vec![
+            (guid(), Ok(CancelOutcome::Cancelled)),
+            (guid(), Ok(CancelOutcome::FilesLeftBehind)),
+            (guid(), Err(CancelJobFailure::CancelJob(hr_message()))),
+        ]));
+
+        for failure in &[
+            ArgumentValidation("bad path".to_owned()),
+            Create(hr_message()),
+            AddFile(hr_message()),
+            ApplySettings(hr_message()),
+            Resume(hr_message()),
+            ConnectBcm(hr_message()),
+            OtherBITS(hr_message()),
+            Other("other".to_owned()),
+        ] {
+            round_trip::<result::Result<(StartJobSuccess, MonitorSubscription), _>>(Err(
+                failure.clone()
+            ));
+        }
+
+        for failure in &[
+            MonitorJobFailure::ArgumentValidation("bad interval".to_owned()),
+            MonitorJobFailure::NotFound,
+            MonitorJobFailure::GetJob(hr_message()),
+            MonitorJobFailure::ConnectBcm(hr_message()),
+            MonitorJobFailure::OtherBITS(hr_message()),
+            MonitorJobFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            SuspendJobFailure::NotFound,
+            SuspendJobFailure::GetJob(hr_message()),
+            SuspendJobFailure::SuspendJob(hr_message()),
+            SuspendJobFailure::ConnectBcm(hr_message()),
+            SuspendJobFailure::OtherBITS(hr_message()),
+            SuspendJobFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            ResumeJobFailure::NotFound,
+            ResumeJobFailure::GetJob(hr_message()),
+            ResumeJobFailure::ResumeJob(hr_message()),
+            ResumeJobFailure::ConnectBcm(hr_message()),
+            ResumeJobFailure::OtherBITS(hr_message()),
+            ResumeJobFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            SetJobPriorityFailure::NotFound,
+            SetJobPriorityFailure::GetJob(hr_message()),
+            SetJobPriorityFailure::ApplySettings(hr_message()),
+            SetJobPriorityFailure::ConnectBcm(hr_message()),
+            SetJobPriorityFailure::OtherBITS(hr_message()),
+            SetJobPriorityFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            UpdateJobSettingsFailure::NotFound,
+            UpdateJobSettingsFailure::GetJob(hr_message()),
+            UpdateJobSettingsFailure::ApplySettings(hr_message()),
+            UpdateJobSettingsFailure::ConnectBcm(hr_message()),
+            UpdateJobSettingsFailure::OtherBITS(hr_message()),
+            UpdateJobSettingsFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            SetUpdateIntervalFailure::ArgumentValidation("bad interval".to_owned()),
+            SetUpdateIntervalFailure::NotFound,
+            SetUpdateIntervalFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            CompleteJobFailure::NotFound,
+            CompleteJobFailure::GetJob(hr_message()),
+            CompleteJobFailure::CompleteJob(hr_message()),
+            CompleteJobFailure::PartialComplete,
+            CompleteJobFailure::HashMismatch(OsString::from("C:\\Temp\\file")),
+            CompleteJobFailure::ConnectBcm(hr_message()),
+            CompleteJobFailure::OtherBITS(hr_message()),
+            CompleteJobFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            ListJobsFailure::ListJobs(hr_message()),
+            ListJobsFailure::ConnectBcm(hr_message()),
+            ListJobsFailure::OtherBITS(hr_message()),
+            ListJobsFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+
+        for failure in &[
+            CancelJobFailure::NotFound,
+            CancelJobFailure::GetJob(hr_message()),
+            CancelJobFailure::CancelJob(hr_message()),
+            CancelJobFailure::ConnectBcm(hr_message()),
+            CancelJobFailure::OtherBITS(hr_message()),
+            CancelJobFailure::Other("other".to_owned()),
+        ] {
+            round_trip(failure.clone());
+        }
+    }
+
+    #[test]
+    fn job_status() {
+        round_trip(JobStatus {
+            state: BitsJobState::TransientError,
+            progress: BitsJobProgress {
+                total_bytes: Some(1 << 40),
+                transferred_bytes: 12345,
+                total_files: 1,
+                transferred_files: 0,
+            },
+            error_count: 2,
+            error: Some(JobError {
+                context: BitsErrorContext::RemoteFile,
+                context_str: "The error occurred while the remote file was being processed."
+                    .to_owned(),
+                error: hr_message(),
+            }),
+            times: BitsJobTimes {
+                creation: file_time(131_000_000_000_000_000),
+                modification: file_time(131_000_000_000_000_001),
+                transfer_completion: None,
+            },
+            url: Some(OsString::from("http://example.com/redirected")),
+            files: vec![
+                FileStatus {
+                    url: Some(OsString::from("http://example.com/redirected")),
+                    local_name: OsString::from("C:\\Temp\\first"),
+                    progress: BitsFileProgress {
+                        total_bytes: Some(1 << 40),
+                        transferred_bytes: 12345,
+                        completed: false,
+                    },
+                },
+                FileStatus {
+                    url: None,
+                    local_name: OsString::from("C:\\Temp\\second"),
+                    progress: BitsFileProgress {
+                        total_bytes: None,
+                        transferred_bytes: 0,
+                        completed: false,
+                    },
+                },
+            ],
+            reply_data: None,
+            priority: Some(BitsJobPriority::Foreground),
+            bytes_per_second: Some(1_000_000),
+            estimated_remaining: Some(Duration::new(1_099_511, 615_432_100)),
+        });
+
+        round_trip(JobStatus {
+            state: BitsJobState::Other(42),
+            progress: BitsJobProgress {
+                total_bytes: None,
+                transferred_bytes: 0,
+                total_files: 0,
+                transferred_files: 0,
+            },
+            error_count: 0,
+            error: None,
+            times: BitsJobTimes {
+                creation: file_time(0),
+                modification: file_time(u64::MAX),
+                transfer_completion: Some(file_time(1)),
+            },
+            url: None,
+            files: Vec::new(),
+            reply_data: Some(b"reply\0data".to_vec()),
+            priority: Some(BitsJobPriority::Low),
+            bytes_per_second: None,
+            estimated_remaining: None,
+        });
+
+        round_trip(BitsErrorContext::Other(99));
+    }
+
+    #[test]
+    fn without_capabilities() {
+        let mut command = StartJobCommand {
+            url: OsString::from("https://example.com/file"),
+            save_path: OsString::from("file"),
+            proxy_usage: BitsProxyUsage::NoProxy,
+            headers: vec![("X-Test".to_owned(), "1".to_owned())],
+            credentials: Some(Credentials {
+                target: BitsAuthTarget::Proxy,
+                scheme: BitsAuthScheme::Ntlm,
+                user_name: OsString::from("user"),
+                password: OsString::from("hunter2"),
+            }),
+            priority: Some(BitsJobPriority::High),
+            settings: JobSettings {
+                minimum_retry_delay_secs: Some(10),
+                no_progress_timeout_secs: None,
+                maximum_download_time_secs: None,
+            },
+            ranges: vec![BitsFileRange {
+                offset: 0,
+                length: None,
+            }],
+            expected: vec![Some(ExpectedFile {
+                size: 1,
+                sha256: [0; 32],
+            })],
+            monitor: Some(monitor()),
+        };
+        let message = encode_message(&command, Capabilities::MONITORS).unwrap();
+        // Only the fields from before any of the optional features are sent...
+        command.headers.clear();
+        command.credentials = None;
+        command.priority = None;
+        command.settings = JobSettings::default();
+        command.ranges.clear();
+        command.expected.clear();
+        let full = encode_message(&command, all()).unwrap();
+        assert_eq!(message[HEADER_SIZE..], full[HEADER_SIZE..message.len()]);
+        // ...and the rest are decoded as their defaults.
+        let decoded: StartJobCommand = decode_message(&message, Capabilities::MONITORS).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", command));
+
+        let status = JobStatus {
+            state: BitsJobState::Transferred,
+            progress: BitsJobProgress {
+                total_bytes: Some(1),
+                transferred_bytes: 1,
+                total_files: 1,
+                transferred_files: 1,
+            },
+            error_count: 0,
+            error: None,
+            times: BitsJobTimes {
+                creation: file_time(1),
+                modification: file_time(2),
+                transfer_completion: Some(file_time(3)),
+            },
+            url: None,
+            files: vec![FileStatus {
+                url: None,
+                local_name: OsString::from("file"),
+                progress: BitsFileProgress {
+                    total_bytes: Some(1),
+                    transferred_bytes: 1,
+                    completed: true,
+                },
+            }],
+            reply_data: Some(b"reply".to_vec()),
+            priority: Some(BitsJobPriority::Low),
+            bytes_per_second: Some(1),
+            estimated_remaining: Some(Duration::from_secs(0)),
+        };
+        let decoded: JobStatus = decode_message(
+            &encode_message(&status, Capabilities::empty()).unwrap(),
+            Capabilities::empty(),
+        )
+        .unwrap();
+        assert!(decoded.files.is_empty());
+        assert!(decoded.reply_data.is_none());
+        assert_eq!(decoded.priority, None);
+        assert_eq!(decoded.bytes_per_second, None);
+        assert_eq!(decoded.estimated_remaining, None);
+
+        // File names need their own capability.
+        let capabilities = Capabilities::MULTI_FILE_JOBS;
+        let decoded: JobStatus = decode_message(
+            &encode_message(&status, capabilities).unwrap(),
+            capabilities,
+        )
+        .unwrap();
+        assert_eq!(decoded.files.len(), 1);
+        assert_eq!(decoded.files[0].local_name, OsString::new());
+
+        // Nor is a subscription, it is decoded as 0.
+        let reply: result::Result<MonitorSubscription, MonitorJobFailure> =
+            Ok(MonitorSubscription(7));
+        let message = encode_message(&reply, Capabilities::empty()).unwrap();
+        assert_eq!(message.len(), HEADER_SIZE + 1);
+        match decode_message(&message, Capabilities::empty()).unwrap() {
+            Ok(MonitorSubscription(0)) => {}
+            result => panic!(
+                "unexpected result {:?}",
+                result as result::Result<_, MonitorJobFailure>
+            ),
+        }
+
+        // Commands and variants from an optional feature only decode with it.
+        let message = encode_message(&Command::ListJobs(ListJobsCommand {}), all()).unwrap();
+        assert_eq!(
+            decode_message::<Command>(&message, Capabilities::MONITORS).unwrap_err(),
+            DecodeError::InvalidTag("Command", 12)
+        );
+        let proxy_usage = BitsProxyUsage::Override {
+            proxies: vec!["proxy".to_owned()],
+            bypass: Vec::new(),
+        };
+        let message = encode_message(&proxy_usage, all()).unwrap();
+        assert_eq!(
+            decode_message::<BitsProxyUsage>(&message, Capabilities::MONITORS).unwrap_err(),
+            DecodeError::InvalidTag("BitsProxyUsage", BG_JOB_PROXY_USAGE_OVERRIDE)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths() {
+        use std::os::unix::ffi::OsStringExt;
+        let path = OsString::from_vec(b"dir/\xff\xfe file".to_vec());
+        let decoded: OsString =
+            decode_message(&encode_message(&path, all()).unwrap(), all()).unwrap();
+        assert_eq!(decoded, path);
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let message = encode_message(
+            &Command::CancelJob(CancelJobCommand { guid: guid() }),
+            all(),
+        )
+        .unwrap();
+
+        let mut wrong_version = message.clone();
+        wrong_version[0] = PROTOCOL_VERSION as u8 + 1;
+        assert_eq!(
+            decode_message::<Command>(&wrong_version, all()).unwrap_err(),
+            DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1)
+        );
+
+        assert_eq!(
+            decode_message::<Command>(&message[..message.len() - 1], all()).unwrap_err(),
+            DecodeError::LengthMismatch(message.len() - HEADER_SIZE - 1, 17)
+        );
+
+        assert_eq!(
+            decode_value::<Command>(&message[HEADER_SIZE..message.len() - 1], all()).unwrap_err(),
+            DecodeError::Truncated
+        );
+
+        let mut wrong_tag = message.clone();
+        wrong_tag[HEADER_SIZE] = 0xff;
+        assert_eq!(
+            decode_message::<Command>(&wrong_tag, all()).unwrap_err(),
+            DecodeError::InvalidTag("Command", 0xff)
+        );
+
+        let mut too_large = message.clone();
+        too_large[7] = 1;
+        assert_eq!(
+            decode_message::<Command>(&too_large, all()).unwrap_err(),
+            DecodeError::TooLarge(0x0100_0011)
+        );
+
+        // Nor will they be sent.
+        let too_long = "x".repeat(MAX_MESSAGE_SIZE as usize);
+        assert_eq!(
+            encode_message(&too_long, all()).unwrap_err(),
+            DecodeError::TooLarge(MAX_MESSAGE_SIZE + 4)
+        );
+
+        let mut extra = encode_message(&true, all()).unwrap();
+        extra[4] += 1;
+        extra.push(0);
+        assert_eq!(
+            decode_message::<bool>(&extra, all()).unwrap_err(),
+            DecodeError::TrailingBytes(1)
+        );
+
+        let mut nanos = Writer::new(all());
+        0u64.encode(&mut nanos);
+        1_000_000_000u32.encode(&mut nanos);
+        assert_eq!(
+            decode_value::<Duration>(&nanos.into_bytes(), all()).unwrap_err(),
+            DecodeError::InvalidDuration
+        );
+    }
+}
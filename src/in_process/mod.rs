@@ -3,25 +3,37 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use std::cmp;
-use std::collections::{hash_map, HashMap};
+use std::collections::HashMap;
 use std::ffi;
+use std::fs;
+use std::io;
+use std::mem;
 use std::path;
 use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::Waker;
 use std::time::{Duration, Instant};
 
-use bits::{
-    BackgroundCopyManager, BitsJob, BitsJobPriority, BitsProxyUsage, BG_S_PARTIAL_COMPLETE, E_FAIL,
-};
-use guid_win::Guid;
+use sha2::{Digest, Sha256};
 
+#[cfg(windows)]
+use backend::ComBackend;
+use backend::{BackendConnection, BackendJob, DefaultBackend, JobBackend, ModificationCallback};
 use bits_protocol::*;
+use types::hresult::{BG_E_NOT_FOUND, BG_S_PARTIAL_COMPLETE, BG_S_UNABLE_TO_DELETE_FILES, E_FAIL};
+use types::{
+    BitsAuthScheme, BitsFileRange, BitsJobPriority, BitsJobState, BitsJobType, BitsProxyUsage,
+    Guid, HResult, HRESULT,
+};
 
+use self::rate::TransferRate;
 use super::Error;
 
+mod rate;
+
 // This is a macro in order to use the NotFound and GetJob variants from whatever enum is in scope.
 macro_rules! get_job {
-    ($bcm:ident, $guid:expr, $name:expr) => {{
-        $bcm = BackgroundCopyManager::connect().map_err(|e| {
+    ($backend:expr, $bcm:ident, $guid:expr, $name:expr) => {{
+        $bcm = $backend.connect().map_err(|e| {
             ConnectBcm(HResultMessage {
                 hr: e.code(),
                 message: e.to_string(),
@@ -33,7 +45,7 @@ macro_rules! get_job {
     }};
 }
 
-fn format_error(bcm: &BackgroundCopyManager, error: comedy::HResult) -> HResultMessage {
+fn format_error<C: BackendConnection>(bcm: &C, error: HResult) -> HResultMessage {
     let bits_description = bcm.get_error_description(error.code()).ok();
 
     HResultMessage {
@@ -46,23 +58,310 @@ fn format_error(bcm: &BackgroundCopyManager, error: comedy::HResult) -> HResultM
     }
 }
 
-// The in-process client uses direct BITS calls via the `bits` crate.
+// The optional features implemented by the in-process client.
+pub fn capabilities() -> Capabilities {
+    Capabilities::MONITORS
+        | Capabilities::MULTI_FILE_JOBS
+        | Capabilities::FILE_NAMES
+        | Capabilities::UPLOAD
+        | Capabilities::CUSTOM_HEADERS
+        | Capabilities::CREDENTIALS
+        | Capabilities::PROXY_OVERRIDE
+        | Capabilities::START_PRIORITY
+        | Capabilities::JOB_SETTINGS
+        | Capabilities::BYTE_RANGES
+        | Capabilities::HASH_VERIFICATION
+        | Capabilities::LIST_JOBS
+        | Capabilities::CANCEL_ALL_JOBS
+        | Capabilities::MODIFICATION_UPDATES
+        | Capabilities::MONITOR_SUBSCRIPTIONS
+        | Capabilities::TRANSFER_RATE
+}
+
+// The minimum retry delay of a new job, unless its `JobSettings` give another.
+const DEFAULT_MINIMUM_RETRY_DELAY_SECS: u32 = 60;
+
+// Apply each of `settings` which is given. The retry delay goes last, as a shorter one may
+// retry straight away, before the other settings would apply to the retry.
+fn apply_job_settings<J: BackendJob>(job: &mut J, settings: &JobSettings) -> Result<(), HResult> {
+    if let Some(seconds) = settings.no_progress_timeout_secs {
+        job.set_no_progress_timeout(seconds)?;
+    }
+    if let Some(seconds) = settings.maximum_download_time_secs {
+        job.set_maximum_download_time(seconds)?;
+    }
+    if let Some(seconds) = settings.minimum_retry_delay_secs {
+        job.set_minimum_retry_delay(seconds)?;
+    }
+    Ok(())
+}
+
+// The longest block of custom headers accepted, as sent: each header is `name: value\r\n`.
+const MAX_CUSTOM_HEADERS_SIZE: usize = 16 * 1024;
+
+// Check `headers` for anything that could change the meaning of the request, such as a line
+// break in a value, and format them for `set_custom_headers()`.
+fn format_custom_headers(headers: &[(String, String)]) -> Result<String, StartJobFailure> {
+    use StartJobFailure::*;
+
+    // A token, as in RFC 7230.
+    fn valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    }
+    // Anything but control characters, other than tab.
+    fn valid_value(value: &str) -> bool {
+        value.chars().all(|c| c == '\t' || !c.is_control())
+    }
+
+    let mut formatted = String::new();
+    for (name, value) in headers {
+        if !valid_name(name) {
+            return Err(ArgumentValidation(format!(
+                "invalid header name {:?}",
+                name
+            )));
+        }
+        if !valid_value(value) {
+            return Err(ArgumentValidation(format!(
+                "invalid value for header {:?}",
+                name
+            )));
+        }
+        formatted.push_str(&format!("{}: {}\r\n", name, value.trim()));
+    }
+
+    if formatted.len() > MAX_CUSTOM_HEADERS_SIZE {
+        return Err(ArgumentValidation(format!(
+            "headers are {} bytes, more than {}",
+            formatted.len(),
+            MAX_CUSTOM_HEADERS_SIZE
+        )));
+    }
+
+    Ok(formatted)
+}
+
+// Check the proxy and bypass lists of `BitsProxyUsage::Override`, whose entries are joined by
+// spaces for BITS.
+fn validate_proxy_usage(proxy_usage: &BitsProxyUsage) -> Result<(), StartJobFailure> {
+    use StartJobFailure::*;
+
+    let (proxies, bypass) = match *proxy_usage {
+        BitsProxyUsage::Override {
+            ref proxies,
+            ref bypass,
+        } => (proxies, bypass),
+        _ => return Ok(()),
+    };
+
+    fn valid_host(host: &str) -> bool {
+        if host.starts_with('[') && host.ends_with(']') {
+            // An IPv6 address.
+            let address = &host[1..host.len() - 1];
+            !address.is_empty()
+                && address
+                    .chars()
+                    .all(|c| c.is_ascii_hexdigit() || ":.".contains(c))
+        } else {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c))
+        }
+    }
+    // `[scheme=][scheme://]host[:port]`
+    fn valid_proxy(proxy: &str) -> bool {
+        let is_scheme = |s: &str| s.eq_ignore_ascii_case("http") || s.eq_ignore_ascii_case("https");
+        let mut rest = proxy;
+        if let Some(equals) = rest.find('=') {
+            if !is_scheme(&rest[..equals]) {
+                return false;
+            }
+            rest = &rest[equals + 1..];
+        }
+        if let Some(separator) = rest.find("://") {
+            if !is_scheme(&rest[..separator]) {
+                return false;
+            }
+            rest = &rest[separator + 3..];
+        }
+        match rest.rfind(':') {
+            Some(colon) if !rest.ends_with(']') => {
+                let port = rest[colon + 1..].parse::<u16>();
+                valid_host(&rest[..colon]) && port.is_ok() && port != Ok(0)
+            }
+            _ => valid_host(rest),
+        }
+    }
+    // A host name with `*` wildcards, or `<local>`.
+    fn valid_bypass(entry: &str) -> bool {
+        entry == "<local>"
+            || (!entry.is_empty()
+                && entry
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._*".contains(c)))
+    }
+
+    if proxies.is_empty() {
+        return Err(ArgumentValidation("empty proxy list".to_string()));
+    }
+    if let Some(proxy) = proxies.iter().find(|proxy| !valid_proxy(proxy)) {
+        return Err(ArgumentValidation(format!("invalid proxy {:?}", proxy)));
+    }
+    if let Some(entry) = bypass.iter().find(|entry| !valid_bypass(entry)) {
+        return Err(ArgumentValidation(format!(
+            "invalid proxy bypass entry {:?}",
+            entry
+        )));
+    }
+    Ok(())
+}
+
+// The messages never include the user name or password, which could end up in a log.
+fn validate_credentials(credentials: &Credentials) -> Result<(), StartJobFailure> {
+    use StartJobFailure::*;
+
+    let has_nul = |s: &ffi::OsStr| s.to_string_lossy().contains('\0');
+    if credentials.user_name.is_empty() {
+        return Err(ArgumentValidation("empty user name".to_string()));
+    }
+    if has_nul(&credentials.user_name) || has_nul(&credentials.password) {
+        return Err(ArgumentValidation(
+            "credentials contain a NUL character".to_string(),
+        ));
+    }
+    // The user name and password are separated by a colon in Basic authentication.
+    if credentials.scheme == BitsAuthScheme::Basic
+        && credentials.user_name.to_string_lossy().contains(':')
+    {
+        return Err(ArgumentValidation(
+            "user name for Basic authentication contains ':'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Check that `ranges` are in order and don't overlap, with only the last running to the end of
+// the file.
+fn validate_ranges(ranges: &[BitsFileRange]) -> Result<(), StartJobFailure> {
+    use StartJobFailure::*;
+
+    // Where the previous range ended, `None` if it runs to the end of the file.
+    let mut previous_end = Some(0);
+    for (i, range) in ranges.iter().enumerate() {
+        let start = match previous_end {
+            Some(end) => end,
+            None => {
+                return Err(ArgumentValidation(format!(
+                    "range {} follows a range to the end of the file",
+                    i
+                )));
+            }
+        };
+        if range.offset < start {
+            return Err(ArgumentValidation(format!(
+                "range {} at offset {} overlaps or precedes the previous range",
+                i, range.offset
+            )));
+        }
+        previous_end = match range.length {
+            Some(0) => return Err(ArgumentValidation(format!("range {} is empty", i))),
+            Some(length) => Some(range.offset.checked_add(length).ok_or_else(|| {
+                ArgumentValidation(format!("range {} ends beyond the largest offset", i))
+            })?),
+            None => None,
+        };
+    }
+    Ok(())
+}
+
+// The status of `job`, with the URL of every file, and no reply data.
+fn job_status<J: BackendJob>(job: &mut J) -> Result<JobStatus, HResult> {
+    let status = job.get_status()?;
+    let files: Vec<FileStatus> = job
+        .get_files()?
+        .into_iter()
+        .map(|file| FileStatus {
+            url: Some(file.remote_name),
+            local_name: file.local_name,
+            progress: file.progress,
+        })
+        .collect();
+
+    Ok(JobStatus {
+        state: status.state,
+        progress: status.progress,
+        error_count: status.error_count,
+        error: status.error.map(|e| JobError {
+            context: e.context,
+            context_str: e.context_str,
+            error: HResultMessage {
+                hr: e.error,
+                message: e.error_str,
+            },
+        }),
+        times: status.times,
+        url: files.first().and_then(|file| file.url.clone()),
+        files,
+        reply_data: None,
+        priority: Some(job.get_priority()?),
+        bytes_per_second: None,
+        estimated_remaining: None,
+    })
+}
+
+// Whether the file at `path` has the expected size and hash.
+fn file_matches(path: &ffi::OsStr, expected: &ExpectedFile) -> io::Result<bool> {
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() != expected.size {
+        return Ok(false);
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.result()[..] == expected.sha256[..])
+}
+
+// The in-process client makes BITS calls directly via a `JobBackend`, by default the `bits` crate.
 // See the corresponding functions in BitsClient.
-pub struct InProcessClient {
+pub struct InProcessClient<B: JobBackend = DefaultBackend> {
+    backend: B,
     job_name: ffi::OsString,
     save_path_prefix: path::PathBuf,
-    monitors: HashMap<Guid, InProcessMonitorControl>,
+    monitors: HashMap<Guid, Arc<Subscribers>>,
+    next_subscription: u64,
+    // The expected contents of the files of each job started with `StartJobOptions::expected`,
+    // in the order of the job's files, until the job is completed or cancelled. These are not
+    // stored with the job, so they are lost with this client.
+    expected_files: HashMap<Guid, Vec<Option<ExpectedFile>>>,
 }
 
-impl InProcessClient {
+#[cfg(windows)]
+impl InProcessClient<ComBackend> {
     pub fn new(
         job_name: ffi::OsString,
         save_path_prefix: ffi::OsString,
-    ) -> Result<InProcessClient, Error> {
+    ) -> Result<InProcessClient<ComBackend>, Error> {
+        InProcessClient::with_backend(ComBackend, job_name, save_path_prefix)
+    }
+}
+
+impl<B: JobBackend> InProcessClient<B> {
+    pub fn with_backend(
+        backend: B,
+        job_name: ffi::OsString,
+        save_path_prefix: ffi::OsString,
+    ) -> Result<InProcessClient<B>, Error> {
         Ok(InProcessClient {
+            backend,
             job_name,
             save_path_prefix: path::PathBuf::from(save_path_prefix),
             monitors: HashMap::new(),
+            next_subscription: 0,
+            expected_files: HashMap::new(),
         })
     }
 
@@ -72,70 +371,239 @@ impl InProcessClient {
         save_path: ffi::OsString,
         proxy_usage: BitsProxyUsage,
         monitor_interval_millis: u32,
-    ) -> Result<(StartJobSuccess, InProcessMonitor), StartJobFailure> {
+    ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure> {
+        self.start_job_with_options(
+            url,
+            save_path,
+            proxy_usage,
+            StartJobOptions::default(),
+            monitor_interval_millis,
+        )
+    }
+
+    pub fn start_job_with_options(
+        &mut self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure> {
+        let full_path = self.validate_save_path(save_path)?;
+
+        self.create_job(
+            BitsJobType::Download,
+            vec![(url, full_path)],
+            None,
+            proxy_usage,
+            options,
+            monitor_interval_millis,
+        )
+    }
+
+    // Verify that `save_path` is under the directory called `save_path_prefix`, returning the
+    // full path.
+    fn validate_save_path(
+        &self,
+        save_path: ffi::OsString,
+    ) -> Result<path::PathBuf, StartJobFailure> {
         use StartJobFailure::*;
 
         let full_path = self.save_path_prefix.join(save_path);
 
-        // Verify that `full_path` is under the directory called `save_path_prefix`.
-        {
-            let canonical_prefix = self.save_path_prefix.canonicalize().map_err(|e| {
-                ArgumentValidation(format!("save_path_prefix.canonicalize(): {}", e))
-            })?;
-            // Full path minus file name, canonicalize() fails with nonexistent files, but the
-            // parent directory ought to exist.
-            let canonical_full_path = full_path
-                .parent()
-                .ok_or_else(|| ArgumentValidation("full_path.parent(): None".into()))?
-                .canonicalize()
-                .map_err(|e| {
-                    ArgumentValidation(format!("full_path.parent().canonicalize(): {}", e))
-                })?;
-
-            if !canonical_full_path.starts_with(&canonical_prefix) {
-                return Err(ArgumentValidation(format!(
-                    "{:?} is not within {:?}",
-                    canonical_full_path, canonical_prefix
-                )));
-            }
+        let canonical_prefix = self
+            .save_path_prefix
+            .canonicalize()
+            .map_err(|e| ArgumentValidation(format!("save_path_prefix.canonicalize(): {}", e)))?;
+        // Full path minus file name, canonicalize() fails with nonexistent files, but the
+        // parent directory ought to exist.
+        let canonical_full_path = full_path
+            .parent()
+            .ok_or_else(|| ArgumentValidation("full_path.parent(): None".into()))?
+            .canonicalize()
+            .map_err(|e| ArgumentValidation(format!("full_path.parent().canonicalize(): {}", e)))?;
+
+        if !canonical_full_path.starts_with(&canonical_prefix) {
+            return Err(ArgumentValidation(format!(
+                "{:?} is not within {:?}",
+                canonical_full_path, canonical_prefix
+            )));
+        }
+
+        Ok(full_path)
+    }
+
+    pub fn start_job_multi(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure> {
+        self.start_job_multi_with_options(
+            files,
+            proxy_usage,
+            StartJobOptions::default(),
+            monitor_interval_millis,
+        )
+    }
+
+    pub fn start_job_multi_with_options(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure> {
+        use StartJobFailure::*;
+
+        if files.is_empty() {
+            return Err(ArgumentValidation("no files".into()));
+        }
+
+        // Validate every path before creating the job.
+        let files = files
+            .into_iter()
+            .map(|(url, save_path)| Ok((url, self.validate_save_path(save_path)?)))
+            .collect::<Result<Vec<_>, StartJobFailure>>()?;
+
+        self.create_job(
+            BitsJobType::Download,
+            files,
+            None,
+            proxy_usage,
+            options,
+            monitor_interval_millis,
+        )
+    }
+
+    pub fn start_upload_job(
+        &mut self,
+        url: ffi::OsString,
+        upload_path: ffi::OsString,
+        reply_path: Option<ffi::OsString>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure> {
+        let upload_path = self.validate_save_path(upload_path)?;
+        let reply_path = match reply_path {
+            Some(reply_path) => Some(self.validate_save_path(reply_path)?),
+            None => None,
+        };
+        let job_type = if reply_path.is_some() {
+            BitsJobType::UploadReply
+        } else {
+            BitsJobType::Upload
+        };
+
+        self.create_job(
+            job_type,
+            vec![(url, upload_path)],
+            reply_path,
+            proxy_usage,
+            StartJobOptions::default(),
+            monitor_interval_millis,
+        )
+    }
+
+    // Create a job with files and a reply path that have already been validated, and start it.
+    // `options` are validated before the job is created.
+    fn create_job(
+        &mut self,
+        job_type: BitsJobType,
+        files: Vec<(ffi::OsString, path::PathBuf)>,
+        reply_path: Option<path::PathBuf>,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure> {
+        use StartJobFailure::*;
+
+        validate_proxy_usage(&proxy_usage)?;
+        let custom_headers = format_custom_headers(&options.headers)?;
+        if let Some(ref credentials) = options.credentials {
+            validate_credentials(credentials)?;
+        }
+        validate_ranges(&options.ranges)?;
+        if options.expected.len() > files.len() {
+            return Err(ArgumentValidation(format!(
+                "{} expected files for {} files",
+                options.expected.len(),
+                files.len()
+            )));
         }
 
         // TODO: Should the job be explicitly cleaned up if this fn can't return success?
         // If the job is dropped before `AddFile` succeeds, I think it automatically gets
         // deleted from the queue. There is only one fallible call after that (`Resume`).
 
-        let bcm = BackgroundCopyManager::connect().map_err(|e| {
+        let bcm = self.backend.connect().map_err(|e| {
             ConnectBcm(HResultMessage {
                 hr: e.code(),
                 message: e.to_string(),
             })
         })?;
         let mut job = bcm
-            .create_job(&self.job_name)
+            .create_job_with_type(&self.job_name, job_type)
             .map_err(|e| Create(format_error(&bcm, e)))?;
 
         let guid = job.guid().map_err(|e| OtherBITS(format_error(&bcm, e)))?;
 
         (|| {
             job.set_proxy_usage(proxy_usage)?;
-            job.set_minimum_retry_delay(60)?;
+            apply_job_settings(
+                &mut job,
+                &JobSettings {
+                    minimum_retry_delay_secs: Some(
+                        options
+                            .settings
+                            .minimum_retry_delay_secs
+                            .unwrap_or(DEFAULT_MINIMUM_RETRY_DELAY_SECS),
+                    ),
+                    ..options.settings
+                },
+            )?;
             job.set_redirect_report()?;
 
-            job.set_priority(BitsJobPriority::Foreground)?;
+            job.set_priority(options.priority.unwrap_or(BitsJobPriority::Foreground))?;
+
+            if let Some(reply_path) = reply_path {
+                job.set_reply_file_name(reply_path.as_os_str())?;
+            }
+            if !custom_headers.is_empty() {
+                job.set_custom_headers(ffi::OsStr::new(&custom_headers))?;
+            }
+            if let Some(ref credentials) = options.credentials {
+                job.set_credentials(
+                    credentials.target,
+                    credentials.scheme,
+                    &credentials.user_name,
+                    &credentials.password,
+                )?;
+            }
 
             Ok(())
         })()
         .map_err(|e| ApplySettings(format_error(&bcm, e)))?;
 
-        let (client, control) = InProcessMonitor::new(&mut job, monitor_interval_millis)
+        let client = self
+            .add_monitor(&mut job, monitor_interval_millis)
             .map_err(|e| OtherBITS(format_error(&bcm, e)))?;
 
-        job.add_file(&url, &full_path.into_os_string())
+        for (url, full_path) in files {
+            let full_path = full_path.into_os_string();
+            if options.ranges.is_empty() {
+                job.add_file(&url, &full_path)
+            } else {
+                job.add_file_with_ranges(&url, &full_path, &options.ranges)
+            }
             .map_err(|e| AddFile(format_error(&bcm, e)))?;
+        }
 
         job.resume().map_err(|e| Resume(format_error(&bcm, e)))?;
 
-        self.monitors.insert(guid.clone(), control);
+        if options.expected.iter().any(Option::is_some) {
+            self.expected_files.insert(guid.clone(), options.expected);
+        }
 
         Ok((StartJobSuccess { guid }, client))
     }
@@ -144,27 +612,46 @@ impl InProcessClient {
         &mut self,
         guid: Guid,
         interval_millis: u32,
-    ) -> Result<InProcessMonitor, MonitorJobFailure> {
+    ) -> Result<InProcessMonitor<B>, MonitorJobFailure> {
         use MonitorJobFailure::*;
 
-        // Stop any preexisting monitor for the same guid.
-        let _ = self.stop_update(guid.clone());
-
         let bcm;
-        let (client, control) =
-            InProcessMonitor::new(&mut get_job!(bcm, &guid, &self.job_name), interval_millis)
-                .map_err(|e| OtherBITS(format_error(&bcm, e)))?;
-
-        self.monitors.insert(guid, control);
+        let mut job = get_job!(self.backend, bcm, &guid, &self.job_name);
+        self.add_monitor(&mut job, interval_millis)
+            .map_err(|e| OtherBITS(format_error(&bcm, e)))
+    }
 
-        Ok(client)
+    // Start another monitor of `job`, alongside any it already has.
+    fn add_monitor<J: BackendJob>(
+        &mut self,
+        job: &mut J,
+        interval_millis: u32,
+    ) -> Result<InProcessMonitor<B>, HResult> {
+        let subscription = MonitorSubscription(self.next_subscription);
+        self.next_subscription += 1;
+
+        let monitor =
+            InProcessMonitor::new(self.backend.clone(), job, subscription, interval_millis)?;
+
+        let subscribers = self
+            .monitors
+            .entry(monitor.guid.clone())
+            .or_default()
+            .clone();
+        subscribers
+            .lock()
+            .unwrap()
+            .insert(subscription, Arc::downgrade(&monitor.vars));
+        register_monitor_callbacks(job, &subscribers)?;
+
+        Ok(monitor)
     }
 
     pub fn suspend_job(&mut self, guid: Guid) -> Result<(), SuspendJobFailure> {
         use SuspendJobFailure::*;
 
         let bcm;
-        get_job!(bcm, &guid, &self.job_name)
+        get_job!(self.backend, bcm, &guid, &self.job_name)
             .suspend()
             .map_err(|e| SuspendJob(format_error(&bcm, e)))?;
 
@@ -175,7 +662,7 @@ impl InProcessClient {
         use ResumeJobFailure::*;
 
         let bcm;
-        get_job!(bcm, &guid, &self.job_name)
+        get_job!(self.backend, bcm, &guid, &self.job_name)
             .resume()
             .map_err(|e| ResumeJob(format_error(&bcm, e)))?;
 
@@ -185,96 +672,260 @@ impl InProcessClient {
     pub fn set_job_priority(
         &mut self,
         guid: Guid,
-        foreground: bool,
+        priority: BitsJobPriority,
     ) -> Result<(), SetJobPriorityFailure> {
         use SetJobPriorityFailure::*;
 
-        let priority = if foreground {
-            BitsJobPriority::Foreground
-        } else {
-            BitsJobPriority::Normal
-        };
-
         let bcm;
-        get_job!(bcm, &guid, &self.job_name)
+        get_job!(self.backend, bcm, &guid, &self.job_name)
             .set_priority(priority)
             .map_err(|e| ApplySettings(format_error(&bcm, e)))?;
 
         Ok(())
     }
 
-    fn get_monitor_control_sender(&mut self, guid: Guid) -> Option<Arc<ControlPair>> {
-        if let hash_map::Entry::Occupied(occ) = self.monitors.entry(guid) {
-            if let Some(sender) = occ.get().0.upgrade() {
-                Some(sender)
-            } else {
-                // Remove dangling Weak
-                occ.remove_entry();
-                None
-            }
+    pub fn update_job_settings(
+        &mut self,
+        guid: Guid,
+        settings: JobSettings,
+    ) -> Result<(), UpdateJobSettingsFailure> {
+        use UpdateJobSettingsFailure::*;
+
+        let bcm;
+        apply_job_settings(
+            &mut get_job!(self.backend, bcm, &guid, &self.job_name),
+            &settings,
+        )
+        .map_err(|e| ApplySettings(format_error(&bcm, e)))?;
+
+        Ok(())
+    }
+
+    // The monitors of job `guid` selected by `subscription`, or all of them if it is `None`.
+    // `Option::is_none_or()` is too new to use here.
+    #[allow(clippy::unnecessary_map_or)]
+    fn monitor_controls(
+        &mut self,
+        guid: &Guid,
+        subscription: Option<MonitorSubscription>,
+    ) -> Vec<Arc<ControlPair>> {
+        if let Some(subscribers) = self.monitors.get(guid) {
+            let mut subscribers = subscribers.lock().unwrap();
+            // Remove dangling Weaks
+            subscribers.retain(|_, control| control.upgrade().is_some());
+            subscribers
+                .iter()
+                .filter(|&(s, _)| subscription.map_or(true, |subscription| subscription == *s))
+                .filter_map(|(_, control)| control.upgrade())
+                .collect()
         } else {
-            None
+            Vec::new()
         }
     }
 
     pub fn set_update_interval(
         &mut self,
         guid: Guid,
+        subscription: Option<MonitorSubscription>,
         interval_millis: u32,
     ) -> Result<(), SetUpdateIntervalFailure> {
         use SetUpdateIntervalFailure::*;
 
-        if let Some(sender) = self.get_monitor_control_sender(guid) {
-            let mut s = sender.1.lock().unwrap();
+        let controls = self.monitor_controls(&guid, subscription);
+        if controls.is_empty() {
+            return Err(NotFound);
+        }
+        for control in controls {
+            let mut s = control.1.lock().unwrap();
             s.interval_millis = interval_millis;
-            sender.0.notify_all();
-            Ok(())
-        } else {
-            Err(NotFound)
+            s.wake();
+            control.0.notify_all();
         }
+        Ok(())
     }
 
-    pub fn stop_update(&mut self, guid: Guid) -> Result<(), SetUpdateIntervalFailure> {
+    pub fn stop_update(
+        &mut self,
+        guid: Guid,
+        subscription: Option<MonitorSubscription>,
+    ) -> Result<(), SetUpdateIntervalFailure> {
         use SetUpdateIntervalFailure::*;
 
-        if let Some(sender) = self.get_monitor_control_sender(guid) {
-            sender.1.lock().unwrap().shutdown = true;
-            sender.0.notify_all();
-            Ok(())
-        } else {
-            Err(NotFound)
+        let controls = self.monitor_controls(&guid, subscription);
+        if controls.is_empty() {
+            return Err(NotFound);
+        }
+        for control in controls {
+            let mut s = control.1.lock().unwrap();
+            s.shutdown = true;
+            s.wake();
+            control.0.notify_all();
+        }
+
+        // Forget the stopped monitors.
+        let remaining = match subscription {
+            Some(subscription) => self.monitors.get(&guid).map_or(0, |subscribers| {
+                let mut subscribers = subscribers.lock().unwrap();
+                subscribers.remove(&subscription);
+                subscribers.len()
+            }),
+            None => 0,
+        };
+        if remaining == 0 {
+            self.monitors.remove(&guid);
+        }
+        Ok(())
+    }
+
+    pub fn set_modification_updates(
+        &mut self,
+        guid: Guid,
+        subscription: Option<MonitorSubscription>,
+        min_interval_millis: Option<u32>,
+    ) -> Result<(), MonitorJobFailure> {
+        use MonitorJobFailure::*;
+
+        let controls = self.monitor_controls(&guid, subscription);
+        if controls.is_empty() {
+            return Err(NotFound);
+        }
+
+        let bcm;
+        let mut job = get_job!(self.backend, bcm, &guid, &self.job_name);
+
+        let min_interval_millis =
+            min_interval_millis.map(|millis| cmp::max(millis, MIN_MODIFICATION_INTERVAL_MILLIS));
+        for control in controls {
+            let mut s = control.1.lock().unwrap();
+            s.modification_interval_millis = min_interval_millis;
+            s.wake();
+            control.0.notify_all();
         }
+
+        // The modification callback is registered only while some monitor needs it.
+        register_monitor_callbacks(&mut job, &self.monitors[&guid])
+            .map_err(|e| OtherBITS(format_error(&bcm, e)))
     }
 
     pub fn complete_job(&mut self, guid: Guid) -> Result<(), CompleteJobFailure> {
         use CompleteJobFailure::*;
 
         let bcm;
-        get_job!(bcm, &guid, &self.job_name)
-            .complete()
+        let mut job = get_job!(self.backend, bcm, &guid, &self.job_name);
+
+        // Check the files before `complete()` moves them into place. Those which haven't been
+        // transferred will be deleted instead.
+        if let Some(expected) = self.expected_files.get(&guid) {
+            let files = job
+                .get_files()
+                .map_err(|e| OtherBITS(format_error(&bcm, e)))?;
+            let temporary_names = job
+                .get_temporary_names()
+                .map_err(|e| OtherBITS(format_error(&bcm, e)))?;
+            for ((file, temporary_name), expected) in
+                files.into_iter().zip(temporary_names).zip(expected)
+            {
+                let expected = match *expected {
+                    Some(ref expected) if file.progress.completed => expected,
+                    _ => continue,
+                };
+                match file_matches(&temporary_name, expected) {
+                    Ok(true) => {}
+                    Ok(false) => return Err(HashMismatch(file.local_name)),
+                    Err(e) => return Err(Other(format!("Read {:?}: {}", temporary_name, e))),
+                }
+            }
+        }
+
+        job.complete()
             .map_err(|e| CompleteJob(format_error(&bcm, e)))
             .and_then(|hr| {
-                if hr == BG_S_PARTIAL_COMPLETE as i32 {
+                self.expected_files.remove(&guid);
+                if hr == BG_S_PARTIAL_COMPLETE {
                     Err(PartialComplete)
                 } else {
                     Ok(())
                 }
             })?;
 
-        let _ = self.stop_update(guid);
+        let _ = self.stop_update(guid, None);
 
         Ok(())
     }
 
+    pub fn list_jobs(&mut self) -> Result<Vec<(Guid, JobStatus)>, ListJobsFailure> {
+        use ListJobsFailure::*;
+
+        let bcm = self.backend.connect().map_err(|e| {
+            ConnectBcm(HResultMessage {
+                hr: e.code(),
+                message: e.to_string(),
+            })
+        })?;
+        let jobs = bcm
+            .find_jobs_by_name(&self.job_name)
+            .map_err(|e| ListJobs(format_error(&bcm, e)))?;
+
+        let mut statuses = Vec::new();
+        for mut job in jobs {
+            let guid = job.guid().map_err(|e| OtherBITS(format_error(&bcm, e)))?;
+            match job_status(&mut job) {
+                Ok(status) => statuses.push((guid, status)),
+                // Completed or cancelled since it was found.
+                Err(ref e) if e.code() == BG_E_NOT_FOUND => {}
+                Err(e) => return Err(OtherBITS(format_error(&bcm, e))),
+            }
+        }
+        Ok(statuses)
+    }
+
+    pub fn cancel_all_jobs(&mut self) -> Result<CancelAllJobsSuccess, ListJobsFailure> {
+        let bcm = self.backend.connect().map_err(|e| {
+            ListJobsFailure::ConnectBcm(HResultMessage {
+                hr: e.code(),
+                message: e.to_string(),
+            })
+        })?;
+        let jobs = bcm
+            .find_jobs_by_name(&self.job_name)
+            .map_err(|e| ListJobsFailure::ListJobs(format_error(&bcm, e)))?;
+
+        let mut results = Vec::new();
+        for mut job in jobs {
+            // A job whose GUID can't be read couldn't be told apart in the results, so it is left
+            // alone.
+            let guid = match job.guid() {
+                Ok(guid) => guid,
+                Err(_) => continue,
+            };
+            let result = match job.cancel() {
+                Ok(hr) => {
+                    let _ = self.stop_update(guid.clone(), None);
+                    self.expected_files.remove(&guid);
+
+                    if hr == BG_S_UNABLE_TO_DELETE_FILES {
+                        Ok(CancelOutcome::FilesLeftBehind)
+                    } else {
+                        Ok(CancelOutcome::Cancelled)
+                    }
+                }
+                Err(e) => Err(CancelJobFailure::CancelJob(format_error(&bcm, e))),
+            };
+            results.push((guid, result));
+        }
+        Ok(results)
+    }
+
     pub fn cancel_job(&mut self, guid: Guid) -> Result<(), CancelJobFailure> {
         use CancelJobFailure::*;
 
         let bcm;
-        get_job!(bcm, &guid, &self.job_name)
+        get_job!(self.backend, bcm, &guid, &self.job_name)
             .cancel()
             .map_err(|e| CancelJob(format_error(&bcm, e)))?;
 
-        let _ = self.stop_update(guid);
+        let _ = self.stop_update(guid.clone(), None);
+        self.expected_files.remove(&guid);
 
         Ok(())
     }
@@ -282,33 +933,134 @@ impl InProcessClient {
 
 // InProcessMonitor can be used on any thread, and `ControlPair` can be synchronously modified to
 // control a blocked `get_status` call from another thread.
-pub struct InProcessMonitor {
+pub struct InProcessMonitor<B: JobBackend = DefaultBackend> {
+    backend: B,
     vars: Arc<ControlPair>,
     guid: Guid,
+    subscription: MonitorSubscription,
     last_status_time: Option<Instant>,
-    last_url: Option<ffi::OsString>,
+    // The URL of each file in the last status.
+    last_urls: Vec<ffi::OsString>,
+    // Set for an upload-reply job until its reply has been reported.
+    reply_pending: bool,
+    rate: TransferRate,
 }
 
 // The `Condvar` is notified when `InProcessMonitorVars` changes.
 type ControlPair = (Condvar, Mutex<InProcessMonitorVars>);
-struct InProcessMonitorControl(Weak<ControlPair>);
 
-// RefUnwindSafe is not impl'd for Condvar but likely should be,
-// see https://github.com/rust-lang/rust/issues/54768
-impl std::panic::RefUnwindSafe for InProcessMonitorControl {}
+// The monitors of one job, by subscription. The job's callbacks notify all of them.
+type Subscribers = Mutex<HashMap<MonitorSubscription, Weak<ControlPair>>>;
 
 struct InProcessMonitorVars {
     interval_millis: u32,
     notified: bool,
     shutdown: bool,
+    // When set, a modification of the job makes a report due this long after the last one,
+    // if that is sooner than the interval. Modifications in between are coalesced.
+    modification_interval_millis: Option<u32>,
+    modified: bool,
+    // Set while an asynchronous monitor is waiting, it is woken along with the `Condvar`.
+    waker: Option<Waker>,
+}
+
+impl InProcessMonitorVars {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    // When the next status report is due, after one at `last_status_time`.
+    fn due(&self, last_status_time: Instant) -> Instant {
+        let due = last_status_time + Duration::from_millis(u64::from(self.interval_millis));
+        match self.modification_interval_millis {
+            Some(millis) if self.modified => cmp::min(
+                due,
+                last_status_time + Duration::from_millis(u64::from(millis)),
+            ),
+            _ => due,
+        }
+    }
+}
+
+// Register the callbacks which notify the monitors of a job. The modification callback is only
+// registered if one of them has asked for modification updates, as BITS calls it often.
+fn register_monitor_callbacks<J: BackendJob>(
+    job: &mut J,
+    subscribers: &Arc<Subscribers>,
+) -> Result<(), HResult> {
+    let transferred_subscribers = subscribers.clone();
+    let transferred_cb =
+        Box::new(move || notify_subscribers(&transferred_subscribers, |vars| vars.notified = true));
+
+    let error_subscribers = subscribers.clone();
+    let error_cb =
+        Box::new(move || notify_subscribers(&error_subscribers, |vars| vars.notified = true));
+
+    let modifications = subscribers
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(Weak::upgrade)
+        .any(|control| {
+            control
+                .1
+                .lock()
+                .unwrap()
+                .modification_interval_millis
+                .is_some()
+        });
+    let modification_cb = if modifications {
+        let modification_subscribers = subscribers.clone();
+        let modification_cb: Box<ModificationCallback> = Box::new(move || {
+            notify_subscribers(&modification_subscribers, |vars| vars.modified = true)
+        });
+        Some(modification_cb)
+    } else {
+        None
+    };
+
+    // Note: These callbacks are never explicitly cleared. They will be freed when the
+    // job is deleted from BITS, and they will be cleared if an attempt is made to call them
+    // when they are no longer valid (e.g. after the process exits). This is done mostly for
+    // simplicity and should be safe.
+
+    job.register_callbacks(Some(transferred_cb), Some(error_cb), modification_cb)
+}
+
+// Apply `notify` to each monitor in `subscribers`, and wake it. Fails if none are left, so that
+// BITS stops calling the callback.
+fn notify_subscribers(
+    subscribers: &Subscribers,
+    notify: fn(&mut InProcessMonitorVars),
+) -> Result<(), HRESULT> {
+    let subscribers = subscribers.lock().map_err(|_| E_FAIL)?;
+    let mut notified = false;
+    for control in subscribers.values().filter_map(Weak::upgrade) {
+        if let Ok(mut vars) = control.1.lock() {
+            notify(&mut vars);
+            vars.wake();
+            control.0.notify_all();
+            notified = true;
+        }
+    }
+    if notified {
+        Ok(())
+    } else {
+        Err(E_FAIL)
+    }
 }
 
-impl InProcessMonitor {
-    fn new(
-        job: &mut BitsJob,
+impl<B: JobBackend> InProcessMonitor<B> {
+    fn new<J: BackendJob>(
+        backend: B,
+        job: &mut J,
+        subscription: MonitorSubscription,
         interval_millis: u32,
-    ) -> Result<(InProcessMonitor, InProcessMonitorControl), comedy::HResult> {
+    ) -> Result<InProcessMonitor<B>, HResult> {
         let guid = job.guid()?;
+        let reply_pending = job.job_type()? == BitsJobType::UploadReply;
 
         let vars = Arc::new((
             Condvar::new(),
@@ -316,50 +1068,26 @@ impl InProcessMonitor {
                 interval_millis,
                 notified: false,
                 shutdown: false,
+                modification_interval_millis: None,
+                modified: false,
+                waker: None,
             }),
         ));
 
-        let transferred_control = InProcessMonitorControl(Arc::downgrade(&vars));
-        let transferred_cb = Box::new(move || {
-            if let Some(control) = transferred_control.0.upgrade() {
-                if let Ok(mut vars) = control.1.lock() {
-                    vars.notified = true;
-                    control.0.notify_all();
-                    return Ok(());
-                }
-            }
-            Err(E_FAIL)
-        });
-
-        let error_control = InProcessMonitorControl(Arc::downgrade(&vars));
-        let error_cb = Box::new(move || {
-            if let Some(control) = error_control.0.upgrade() {
-                if let Ok(mut vars) = control.1.lock() {
-                    vars.notified = true;
-                    control.0.notify_all();
-                    return Ok(());
-                }
-            }
-            Err(E_FAIL)
-        });
-
-        // Note: These callbacks are never explicitly cleared. They will be freed when the
-        // job is deleted from BITS, and they will be cleared if an attempt is made to call them
-        // when they are no longer valid (e.g. after the process exits). This is done mostly for
-        // simplicity and should be safe.
-
-        job.register_callbacks(Some(transferred_cb), Some(error_cb), None)?;
-
-        let control = InProcessMonitorControl(Arc::downgrade(&vars));
-
-        let monitor = InProcessMonitor {
+        Ok(InProcessMonitor {
+            backend,
             guid,
+            subscription,
             vars,
             last_status_time: None,
-            last_url: None,
-        };
+            last_urls: Vec::new(),
+            reply_pending,
+            rate: TransferRate::new(),
+        })
+    }
 
-        Ok((monitor, control))
+    pub fn subscription(&self) -> MonitorSubscription {
+        self.subscription
     }
 
     pub fn get_status(
@@ -390,12 +1118,11 @@ impl InProcessMonitor {
                     return Err(Error::Timeout);
                 }
 
-                // Get the interval every pass through the loop, in case it has changed.
-                let interval = Duration::from_millis(u64::from(s.interval_millis));
-
+                // Get the due time every pass through the loop, in case the interval has changed
+                // or the job has been modified.
                 let wait_until = self
                     .last_status_time
-                    .map(|last_status_time| cmp::min(last_status_time + interval, timeout_end));
+                    .map(|last_status_time| cmp::min(s.due(last_status_time), timeout_end));
 
                 if s.notified {
                     // Notified, exit loop to get status.
@@ -428,58 +1155,105 @@ impl InProcessMonitor {
 
                 // Mutex re-acquired, loop.
             }
+
+            // Any modification is covered by this report.
+            s.modified = false;
         }
 
         // No error yet, start getting status now.
-        self.last_status_time = Some(Instant::now());
+        Ok(self.report_status())
+    }
 
-        let bcm = match BackgroundCopyManager::connect() {
+    // The non-blocking counterpart of the wait in `get_status`: `Ok(None)` if a status report
+    // is due now, otherwise `Ok(Some(deadline))` when it will be due, unless `waker` is woken
+    // sooner.
+    #[cfg(feature = "stream")]
+    pub fn poll_due(&mut self, waker: &Waker) -> Result<Option<Instant>, Error> {
+        let mut s = self.vars.1.lock().unwrap();
+
+        if s.shutdown {
+            return Err(Error::NotConnected);
+        }
+
+        let due = self
+            .last_status_time
+            .map(|last_status_time| s.due(last_status_time));
+        match due {
+            Some(due) if !s.notified && due > Instant::now() => {
+                s.waker = Some(waker.clone());
+                Ok(Some(due))
+            }
+            _ => {
+                s.notified = false;
+                s.modified = false;
+                Ok(None)
+            }
+        }
+    }
+
+    // Get the status now. Any error disconnects the monitor.
+    pub fn report_status(&mut self) -> Result<JobStatus, HResultMessage> {
+        let now = Instant::now();
+        self.last_status_time = Some(now);
+
+        let bcm = match self.backend.connect() {
             Ok(bcm) => bcm,
             Err(e) => {
                 // On any error, disconnect.
                 self.vars.1.lock().unwrap().shutdown = true;
 
                 // Errors below can use the BCM to do `format_error()`, but this one just gets the
-                // basic `comedy::HResult` treatment.
-                return Ok(Err(HResultMessage {
+                // basic `HResult` treatment.
+                return Err(HResultMessage {
                     hr: e.code(),
                     message: format!("{}", e),
-                }));
+                });
             }
         };
 
-        Ok((|| {
+        (|| {
             let mut job = bcm.get_job_by_guid(&self.guid)?;
+            let mut status = job_status(&mut job)?;
+
+            // URLs are only reported when they have changed.
+            let last_urls = mem::replace(
+                &mut self.last_urls,
+                status
+                    .files
+                    .iter()
+                    .filter_map(|file| file.url.clone())
+                    .collect(),
+            );
+            for (i, file) in status.files.iter_mut().enumerate() {
+                if last_urls.get(i) == file.url.as_ref() {
+                    file.url = None;
+                }
+            }
+            status.url = status.files.first().and_then(|file| file.url.clone());
 
-            let status = job.get_status()?;
-            let url = job.get_first_file()?.get_remote_name()?;
-
-            Ok(JobStatus {
-                state: status.state,
-                progress: status.progress,
-                error_count: status.error_count,
-                error: status.error.map(|e| JobError {
-                    context: e.context,
-                    context_str: e.context_str,
-                    error: HResultMessage {
-                        hr: e.error,
-                        message: e.error_str,
-                    },
-                }),
-                times: status.times,
-                url: if self.last_url.is_some() && *self.last_url.as_ref().unwrap() == url {
-                    None
-                } else {
-                    self.last_url = Some(url);
-                    self.last_url.clone()
-                },
-            })
+            if self.reply_pending && status.state == BitsJobState::Transferred {
+                let mut reply_data = job.get_reply_data()?;
+                reply_data.truncate(MAX_REPLY_DATA);
+                self.reply_pending = false;
+                status.reply_data = Some(reply_data);
+            }
+
+            self.rate.add_sample(
+                now,
+                status.state,
+                status.error_count,
+                status.progress.transferred_bytes,
+            );
+            status.bytes_per_second = self.rate.bytes_per_second();
+            status.estimated_remaining = self.rate.estimated_remaining(status.progress.total_bytes);
+
+            Ok(status)
         })()
         .map_err(|e| {
             // On any error, disconnect.
             self.vars.1.lock().unwrap().shutdown = true;
             format_error(&bcm, e)
-        }))
+        })
     }
 }
 
@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The transfer rate of a job, from the `transferred_bytes` of its monitor's status reports.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use types::BitsJobState;
+
+// How far back the rate is averaged. The oldest sample within it is compared with one just
+// before it, so a monitor with a longer interval still has two samples to compare.
+const WINDOW: Duration = Duration::from_secs(10);
+
+// A sliding window of `(Instant, transferred_bytes)` samples of a job.
+//
+// Only the time the job spends transferring is measured: the window starts over whenever the
+// job is suspended, has an error, or otherwise stops transferring.
+#[derive(Debug, Default)]
+pub struct TransferRate {
+    samples: VecDeque<(Instant, u64)>,
+    // The error count of the last sample, it may have changed without an error state having been
+    // sampled.
+    error_count: u32,
+}
+
+impl TransferRate {
+    pub fn new() -> TransferRate {
+        Default::default()
+    }
+
+    pub fn add_sample(
+        &mut self,
+        time: Instant,
+        state: BitsJobState,
+        error_count: u32,
+        transferred_bytes: u64,
+    ) {
+        let restarted = match self.samples.back() {
+            Some(&(last_time, last_bytes)) => time < last_time || transferred_bytes < last_bytes,
+            None => false,
+        };
+        if restarted || error_count != self.error_count {
+            self.samples.clear();
+        }
+        self.error_count = error_count;
+
+        match state {
+            BitsJobState::Queued | BitsJobState::Connecting | BitsJobState::Transferring => {}
+            _ => {
+                self.samples.clear();
+                return;
+            }
+        }
+
+        self.samples.push_back((time, transferred_bytes));
+        while self.samples.len() > 2 && time.duration_since(self.samples[1].0) >= WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    // The average rate over the window, or `None` without two samples to compare.
+    pub fn bytes_per_second(&self) -> Option<u64> {
+        let (first_time, first_bytes) = *self.samples.front()?;
+        let (last_time, last_bytes) = *self.samples.back()?;
+        let elapsed = last_time.duration_since(first_time).as_nanos();
+        if elapsed == 0 {
+            return None;
+        }
+        Some((u128::from(last_bytes - first_bytes) * 1_000_000_000 / elapsed) as u64)
+    }
+
+    // How long the rest of `total_bytes` should take at the current rate, or `None` if the total
+    // is unknown or nothing is being transferred.
+    pub fn estimated_remaining(&self, total_bytes: Option<u64>) -> Option<Duration> {
+        let &(_, transferred_bytes) = self.samples.back()?;
+        let remaining = total_bytes?.saturating_sub(transferred_bytes);
+        let rate = self.bytes_per_second()?;
+        if remaining == 0 {
+            return Some(Duration::from_secs(0));
+        }
+        if rate == 0 {
+            return None;
+        }
+        let nanos = u128::from(remaining % rate) * 1_000_000_000 / u128::from(rate);
+        Some(Duration::new(remaining / rate, nanos as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TransferRate;
+    use types::BitsJobState;
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn steady_rate() {
+        let start = Instant::now();
+        let mut rate = TransferRate::new();
+
+        rate.add_sample(start, BitsJobState::Transferring, 0, 0);
+        assert_eq!(rate.bytes_per_second(), None);
+        assert_eq!(rate.estimated_remaining(Some(10_000)), None);
+
+        rate.add_sample(at(start, 500), BitsJobState::Transferring, 0, 500);
+        rate.add_sample(at(start, 1000), BitsJobState::Transferring, 0, 1000);
+        assert_eq!(rate.bytes_per_second(), Some(1000));
+        assert_eq!(
+            rate.estimated_remaining(Some(10_000)),
+            Some(Duration::from_secs(9))
+        );
+        assert_eq!(
+            rate.estimated_remaining(Some(1500)),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(rate.estimated_remaining(None), None);
+        assert_eq!(
+            rate.estimated_remaining(Some(1000)),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn window_slides() {
+        let start = Instant::now();
+        let mut rate = TransferRate::new();
+
+        // Fast at first, then slow.
+        rate.add_sample(start, BitsJobState::Transferring, 0, 0);
+        rate.add_sample(at(start, 10_000), BitsJobState::Transferring, 0, 100_000);
+        for i in 1..=20 {
+            rate.add_sample(
+                at(start, 10_000 + i * 1000),
+                BitsJobState::Transferring,
+                0,
+                100_000 + i * 100,
+            );
+        }
+        // Only the last 10 seconds count.
+        assert_eq!(rate.bytes_per_second(), Some(100));
+    }
+
+    #[test]
+    fn long_interval() {
+        let start = Instant::now();
+        let mut rate = TransferRate::new();
+
+        // Reports further apart than the window are still compared.
+        rate.add_sample(start, BitsJobState::Transferring, 0, 0);
+        rate.add_sample(at(start, 60_000), BitsJobState::Transferring, 0, 60_000);
+        rate.add_sample(at(start, 120_000), BitsJobState::Transferring, 0, 180_000);
+        assert_eq!(rate.bytes_per_second(), Some(2000));
+    }
+
+    #[test]
+    fn suspend_and_resume() {
+        let start = Instant::now();
+        let mut rate = TransferRate::new();
+
+        rate.add_sample(start, BitsJobState::Transferring, 0, 0);
+        rate.add_sample(at(start, 1000), BitsJobState::Transferring, 0, 1000);
+        assert_eq!(rate.bytes_per_second(), Some(1000));
+
+        rate.add_sample(at(start, 2000), BitsJobState::Suspended, 0, 2000);
+        assert_eq!(rate.bytes_per_second(), None);
+        assert_eq!(rate.estimated_remaining(Some(10_000)), None);
+
+        // The time spent suspended doesn't count against the rate.
+        rate.add_sample(at(start, 60_000), BitsJobState::Queued, 0, 2000);
+        assert_eq!(rate.bytes_per_second(), None);
+        rate.add_sample(at(start, 61_000), BitsJobState::Transferring, 0, 2500);
+        assert_eq!(rate.bytes_per_second(), Some(500));
+    }
+
+    #[test]
+    fn errors() {
+        let start = Instant::now();
+        let mut rate = TransferRate::new();
+
+        rate.add_sample(start, BitsJobState::Transferring, 0, 0);
+        rate.add_sample(at(start, 1000), BitsJobState::Transferring, 0, 1000);
+        rate.add_sample(at(start, 2000), BitsJobState::TransientError, 1, 1000);
+        assert_eq!(rate.bytes_per_second(), None);
+        rate.add_sample(at(start, 30_000), BitsJobState::Transferring, 1, 1000);
+        rate.add_sample(at(start, 31_000), BitsJobState::Transferring, 1, 4000);
+        assert_eq!(rate.bytes_per_second(), Some(3000));
+
+        // An error between reports, which was retried before the next one.
+        rate.add_sample(at(start, 60_000), BitsJobState::Transferring, 2, 5000);
+        assert_eq!(rate.bytes_per_second(), None);
+        rate.add_sample(at(start, 61_000), BitsJobState::Transferring, 2, 6000);
+        assert_eq!(rate.bytes_per_second(), Some(1000));
+
+        // The transfer started over.
+        rate.add_sample(at(start, 62_000), BitsJobState::Transferring, 2, 0);
+        assert_eq!(rate.bytes_per_second(), None);
+        rate.add_sample(at(start, 63_000), BitsJobState::Transferring, 2, 500);
+        assert_eq!(rate.bytes_per_second(), Some(500));
+    }
+
+    #[test]
+    fn stalled() {
+        let start = Instant::now();
+        let mut rate = TransferRate::new();
+
+        rate.add_sample(start, BitsJobState::Connecting, 0, 0);
+        rate.add_sample(at(start, 1000), BitsJobState::Connecting, 0, 0);
+        assert_eq!(rate.bytes_per_second(), Some(0));
+        assert_eq!(rate.estimated_remaining(Some(1000)), None);
+    }
+}
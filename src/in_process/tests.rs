@@ -2,7 +2,9 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-// These are full integration tests that use the BITS service.
+// These are full integration tests that use the BITS service. Each test is run against the BITS
+// service, on Windows, against the portable engine with a local HTTP server, and against the
+// in-memory simulator, which needs no network at all.
 
 // TODO
 // It may make sense to restrict how many tests can run at once. BITS is only supposed to support
@@ -10,23 +12,27 @@
 // exceed that.
 
 #![cfg(test)]
+#[cfg(windows)]
 extern crate bits;
 extern crate lazy_static;
 extern crate rand;
 extern crate regex;
 extern crate tempdir;
 
+use std::cell::RefCell;
 use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::panic;
+use std::path;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
+#[cfg(windows)]
+use self::bits::BackgroundCopyManager;
 use self::{
-    bits::BackgroundCopyManager,
     lazy_static::lazy_static,
     rand::{thread_rng, Rng},
     regex::bytes::Regex,
@@ -34,8 +40,14 @@ use self::{
 };
 use super::{
     super::{BitsJobState, Error},
-    BitsProxyUsage, InProcessClient, StartJobSuccess,
+    BitsProxyUsage, CancelOutcome, CompleteJobFailure, ExpectedFile, InProcessClient, JobBackend,
+    SetUpdateIntervalFailure, StartJobOptions, StartJobSuccess, MIN_MODIFICATION_INTERVAL_MILLIS,
 };
+use backend::simulated::Response;
+#[cfg(windows)]
+use backend::ComBackend;
+use backend::{PortableBits, SimulatedBits};
+use sha2::{Digest, Sha256};
 
 static SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1];
 
@@ -49,15 +61,98 @@ fn format_job_name(name: &str) -> OsString {
 
 fn format_dir_prefix(tmp_dir: &TempDir) -> OsString {
     let mut dir = tmp_dir.path().to_path_buf().into_os_string();
-    dir.push("\\");
+    dir.push(path::MAIN_SEPARATOR.to_string());
     dir
 }
 
-fn cancel_jobs(name: &OsStr) {
-    BackgroundCopyManager::connect()
-        .unwrap()
-        .cancel_jobs_by_name(name)
-        .unwrap();
+// A backend that the tests can run against, with a server that it can transfer from.
+trait TestBackend: JobBackend {
+    type Server: TestServer;
+
+    fn cancel_jobs(&self, name: &OsStr);
+    fn serve(&self, name: &'static str, responses: HttpServerResponses) -> Self::Server;
+}
+
+// A server which sends `HttpServerResponses::body` for any URL, except the special error URLs.
+trait TestServer {
+    fn format_url(&self, name: &str) -> OsString;
+    fn shutdown(&mut self);
+}
+
+#[cfg(windows)]
+impl TestBackend for ComBackend {
+    type Server = MockHttpServerHandle;
+
+    fn cancel_jobs(&self, name: &OsStr) {
+        BackgroundCopyManager::connect()
+            .unwrap()
+            .cancel_jobs_by_name(name)
+            .unwrap();
+    }
+
+    fn serve(&self, name: &'static str, responses: HttpServerResponses) -> MockHttpServerHandle {
+        mock_http_server(name, responses)
+    }
+}
+
+impl TestBackend for PortableBits {
+    type Server = MockHttpServerHandle;
+
+    fn cancel_jobs(&self, name: &OsStr) {
+        self.cancel_jobs_by_name(name);
+    }
+
+    fn serve(&self, name: &'static str, responses: HttpServerResponses) -> MockHttpServerHandle {
+        mock_http_server(name, responses)
+    }
+}
+
+impl TestBackend for SimulatedBits {
+    type Server = SimulatedServer;
+
+    fn cancel_jobs(&self, name: &OsStr) {
+        self.cancel_jobs_by_name(name);
+    }
+
+    fn serve(&self, name: &'static str, responses: HttpServerResponses) -> SimulatedServer {
+        SimulatedServer {
+            bits: self.clone(),
+            name,
+            responses,
+            urls: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+// Serves the simulator each URL that is asked for, as the mock HTTP server would.
+struct SimulatedServer {
+    bits: SimulatedBits,
+    name: &'static str,
+    responses: HttpServerResponses,
+    urls: RefCell<Vec<String>>,
+}
+
+impl TestServer for SimulatedServer {
+    fn format_url(&self, name: &str) -> OsString {
+        let url = format!("http://{}.simulated/{}", self.name, name);
+        let response = match name {
+            "error_404" => Response::error(404),
+            "error_500" => Response::error(500),
+            _ => Response::ok(&self.responses.body),
+        };
+        self.bits.serve(
+            &url,
+            response.delayed(Duration::from_millis(self.responses.delay)),
+        );
+        self.urls.borrow_mut().push(url.clone());
+        url.into()
+    }
+
+    fn shutdown(&mut self) {
+        for url in self.urls.borrow_mut().drain(..) {
+            self.bits.stop_serving(&url);
+        }
+    }
 }
 
 struct HttpServerResponses {
@@ -72,14 +167,14 @@ struct MockHttpServerHandle {
     shutdown: Arc<(Mutex<bool>, Condvar)>,
 }
 
-impl MockHttpServerHandle {
+impl TestServer for MockHttpServerHandle {
     fn shutdown(&mut self) {
         if self.join.is_none() {
             return;
         }
 
         {
-            let &(ref lock, ref cvar) = &*self.shutdown;
+            let (lock, cvar) = &*self.shutdown;
             let mut shutdown = lock.lock().unwrap();
 
             if !*shutdown {
@@ -171,6 +266,8 @@ fn mock_http_server(name: &'static str, responses: HttpServerResponses) -> MockH
                     .set_read_timeout(Some(Duration::from_millis(10_000)))
                     .unwrap();
                 let mut s = Vec::new();
+                // One byte at a time, to read no further than the end of the request.
+                #[allow(clippy::unbuffered_bytes)]
                 for b in Read::by_ref(&mut socket).bytes() {
                     if b.is_err() {
                         eprintln!("read error {:?}", b);
@@ -246,33 +343,57 @@ fn mock_http_server(name: &'static str, responses: HttpServerResponses) -> MockH
     }
 }
 
-// Test wrapper to ensure jobs are canceled, set up name strings
+// Test wrapper to ensure jobs are canceled, set up name strings, and run the test with each
+// backend.
 macro_rules! test {
-    (fn $name:ident($param:ident : &str, $tmpdir:ident : &TempDir) $body:block) => {
-        #[test]
-        fn $name() {
-            let $param = stringify!($name);
-            let $tmpdir = &TempDir::new($param).unwrap();
+    (fn $name:ident($param:ident : &str, $tmpdir:ident : &TempDir, $backend:ident : &B)
+        $body:block) => {
+        mod $name {
+            use super::*;
+
+            fn run<B: TestBackend>($param: &'static str, $tmpdir: &TempDir, $backend: &B) $body
 
-            let result = panic::catch_unwind(|| $body);
+            fn run_with_backend<B: TestBackend>(backend: B) {
+                let name = stringify!($name);
+                let tmp_dir = &TempDir::new(name).unwrap();
 
-            cancel_jobs(&format_job_name($param));
+                let result =
+                    panic::catch_unwind(panic::AssertUnwindSafe(|| run(name, tmp_dir, &backend)));
 
-            if let Err(e) = result {
-                panic::resume_unwind(e);
+                backend.cancel_jobs(&format_job_name(name));
+
+                if let Err(e) = result {
+                    panic::resume_unwind(e);
+                }
+            }
+
+            #[cfg(windows)]
+            #[test]
+            fn com() {
+                run_with_backend(ComBackend);
+            }
+
+            #[test]
+            fn portable() {
+                run_with_backend(PortableBits::new());
+            }
+
+            #[test]
+            fn simulated() {
+                run_with_backend(SimulatedBits::new());
             }
         }
     };
 }
 
 test! {
-    fn start_monitor_and_cancel(name: &str, tmp_dir: &TempDir) {
-        let mut server = mock_http_server(name, HttpServerResponses {
+    fn start_monitor_and_cancel(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
             body: name.to_owned().into_boxed_str().into_boxed_bytes(),
             delay: 10_000,
         });
 
-        let mut client = InProcessClient::new(format_job_name(name), tmp_dir.path().into()).unwrap();
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), tmp_dir.path().into()).unwrap();
 
         let interval = 10_000;
         let timeout = 10_000;
@@ -311,15 +432,15 @@ test! {
 }
 
 test! {
-    fn start_monitor_and_complete(name: &str, tmp_dir: &TempDir) {
+    fn start_monitor_and_complete(name: &str, tmp_dir: &TempDir, backend: &B) {
         let file_path = tmp_dir.path().join(name);
 
-        let mut server = mock_http_server(name, HttpServerResponses {
+        let mut server = backend.serve(name, HttpServerResponses {
             body: name.to_owned().into_boxed_str().into_boxed_bytes(),
             delay: 500,
         });
 
-        let mut client = InProcessClient::new(format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
 
         let interval = 100;
         let timeout = 10_000;
@@ -340,7 +461,7 @@ test! {
                         panic!("monitor failed before completion {:?}", e);
                     }
                 }
-                Ok(Ok(status)) => match BitsJobState::from(status.state) {
+                Ok(Ok(status)) => match status.state {
                     BitsJobState::Queued | BitsJobState::Connecting
                         | BitsJobState::Transferring => {
                             //eprintln!("{:?}", BitsJobState::from(status.state));
@@ -351,10 +472,10 @@ test! {
                         completed = true;
                     }
                     _ => {
-                        panic!(format!("{:?}", status));
+                        panic!("{:?}", status);
                     }
                 }
-                Ok(Err(e)) => panic!(format!("{:?}", e)),
+                Ok(Err(e)) => panic!("{:?}", e),
             }
 
             // Timeout to prevent waiting forever
@@ -382,13 +503,13 @@ test! {
 }
 
 test! {
-    fn async_transferred_notification(name: &str, tmp_dir: &TempDir) {
-        let mut server = mock_http_server(name, HttpServerResponses {
+    fn async_transferred_notification(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
             body: name.to_owned().into_boxed_str().into_boxed_bytes(),
             delay: 250,
         });
 
-        let mut client = InProcessClient::new(format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
 
         let interval = 60_000;
         let timeout = 10_000;
@@ -432,13 +553,13 @@ test! {
 }
 
 test! {
-    fn change_interval(name: &str, tmp_dir: &TempDir) {
-        let mut server = mock_http_server(name, HttpServerResponses {
+    fn change_interval(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
             body: name.to_owned().into_boxed_str().into_boxed_bytes(),
             delay: 1000,
         });
 
-        let mut client = InProcessClient::new(format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
 
         let interval = 60_000;
         let timeout = 10_000;
@@ -452,7 +573,7 @@ test! {
         let _join = thread::Builder::new()
             .spawn(move || {
                 thread::sleep(Duration::from_millis(250));
-                client.set_update_interval(guid, 500).unwrap();
+                client.set_update_interval(guid, None, 500).unwrap();
             });
 
         // First immediate report
@@ -471,13 +592,13 @@ test! {
 }
 
 test! {
-    fn async_error_notification(name: &str, tmp_dir: &TempDir) {
-        let mut server = mock_http_server(name, HttpServerResponses {
+    fn async_error_notification(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
             body: name.to_owned().into_boxed_str().into_boxed_bytes(),
             delay: 100,
         });
 
-        let mut client = InProcessClient::new(format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
 
         let interval = 60_000;
         let timeout = 10_000;
@@ -504,13 +625,13 @@ test! {
 }
 
 test! {
-    fn transient_error(name: &str, tmp_dir: &TempDir) {
-        let mut server = mock_http_server(name, HttpServerResponses {
+    fn transient_error(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
             body: name.to_owned().into_boxed_str().into_boxed_bytes(),
             delay: 100,
         });
 
-        let mut client = InProcessClient::new(format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
 
         let interval = 1_000;
         let timeout = 10_000;
@@ -535,3 +656,271 @@ test! {
         // job will be cancelled by macro
     }
 }
+
+test! {
+    fn suspend_and_resume(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
+            body: name.to_owned().into_boxed_str().into_boxed_bytes(),
+            delay: 500,
+        });
+
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+
+        let interval = 100;
+        let timeout = 10_000;
+
+        let (StartJobSuccess { guid }, mut monitor) =
+            client.start_job(server.format_url(name), name.into(), BitsProxyUsage::Preconfig, interval).unwrap();
+
+        client.suspend_job(guid.clone()).unwrap();
+
+        let status = monitor.get_status(timeout).expect("should initially be ok").unwrap();
+        assert_eq!(status.state, BitsJobState::Suspended);
+
+        client.resume_job(guid.clone()).unwrap();
+
+        // Get status reports until transfer finishes (~500ms)
+        let start = Instant::now();
+        loop {
+            let status = monitor.get_status(timeout).expect("should get status update").unwrap();
+            match status.state {
+                BitsJobState::Queued | BitsJobState::Connecting | BitsJobState::Transferring => {}
+                BitsJobState::Transferred => break,
+                _ => panic!("{:?}", status),
+            }
+            assert!(start.elapsed() < Duration::from_millis(60_000));
+        }
+
+        assert_eq!(monitor.get_status(timeout).unwrap().unwrap().progress.transferred_files, 1);
+
+        server.shutdown();
+
+        // job will be cancelled by macro
+    }
+}
+
+test! {
+    fn modification_updates(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
+            body: name.to_owned().into_boxed_str().into_boxed_bytes(),
+            delay: 10_000,
+        });
+
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+
+        let interval = 60_000;
+        let timeout = 30_000;
+        let min_interval = 500;
+
+        let (StartJobSuccess { guid }, mut monitor) =
+            client.start_job(server.format_url(name), name.into(), BitsProxyUsage::Preconfig, interval).unwrap();
+
+        client.set_modification_updates(guid.clone(), None, Some(min_interval)).unwrap();
+
+        monitor.get_status(timeout).expect("should initially be ok").unwrap();
+        let start = Instant::now();
+
+        // Suspending the job modifies it, which is reported after the minimum interval instead of
+        // the monitor interval.
+        client.suspend_job(guid.clone()).unwrap();
+        let status = monitor.get_status(timeout).expect("should get status update").unwrap();
+        assert_eq!(status.state, BitsJobState::Suspended);
+        assert!(start.elapsed() >= Duration::from_millis(u64::from(min_interval) - 50));
+        assert!(start.elapsed() < Duration::from_millis(u64::from(interval)));
+
+        // A shorter minimum interval than the floor is raised to it.
+        client.set_modification_updates(guid.clone(), None, Some(0)).unwrap();
+        let start = Instant::now();
+        client.resume_job(guid.clone()).unwrap();
+        monitor.get_status(timeout).expect("should get status update").unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(u64::from(MIN_MODIFICATION_INTERVAL_MILLIS) - 50));
+
+        server.shutdown();
+
+        // job will be cancelled by macro
+    }
+}
+
+test! {
+    fn multiple_monitors(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
+            body: name.to_owned().into_boxed_str().into_boxed_bytes(),
+            delay: 10_000,
+        });
+
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+
+        let interval = 60_000;
+        let timeout = 10_000;
+
+        let (StartJobSuccess { guid }, mut first) =
+            client.start_job(server.format_url(name), name.into(), BitsProxyUsage::Preconfig, interval).unwrap();
+        let mut second = client.monitor_job(guid.clone(), interval).unwrap();
+        assert_ne!(first.subscription(), second.subscription());
+
+        first.get_status(timeout).expect("should initially be ok").unwrap();
+        second.get_status(timeout).expect("should initially be ok").unwrap();
+
+        // Stopping one monitor leaves the other running.
+        client.stop_update(guid.clone(), Some(first.subscription())).unwrap();
+        match first.get_status(timeout) {
+            Err(Error::NotConnected) => {}
+            r => panic!("unexpected result from get_status() {:?}", r),
+        }
+
+        let start = Instant::now();
+        client.set_update_interval(guid.clone(), Some(second.subscription()), 100).unwrap();
+        second.get_status(timeout).expect("should get status update").unwrap();
+        assert!(start.elapsed() < Duration::from_millis(u64::from(timeout)));
+
+        // The stopped monitor can't be changed any more.
+        match client.set_update_interval(guid.clone(), Some(first.subscription()), 100) {
+            Err(SetUpdateIntervalFailure::NotFound) => {}
+            r => panic!("unexpected result from set_update_interval() {:?}", r),
+        }
+
+        client.stop_update(guid.clone(), None).unwrap();
+        match second.get_status(timeout) {
+            Err(Error::NotConnected) => {}
+            r => panic!("unexpected result from get_status() {:?}", r),
+        }
+
+        server.shutdown();
+
+        // job will be cancelled by macro
+    }
+}
+
+test! {
+    fn cancel_all_jobs(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
+            body: name.to_owned().into_boxed_str().into_boxed_bytes(),
+            delay: 10_000,
+        });
+
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+
+        let interval = 10_000;
+        let timeout = 10_000;
+
+        let (StartJobSuccess { guid: first }, mut monitor) =
+            client.start_job(server.format_url(name), format!("{}_0", name).into(), BitsProxyUsage::Preconfig, interval).unwrap();
+        let (StartJobSuccess { guid: second }, _) =
+            client.start_job(server.format_url(name), format!("{}_1", name).into(), BitsProxyUsage::Preconfig, interval).unwrap();
+
+        monitor.get_status(timeout).expect("should initially be ok").unwrap();
+
+        let mut results = client.cancel_all_jobs().unwrap();
+        assert_eq!(results.len(), 2);
+        results.sort_by_key(|(guid, _)| *guid != first);
+        assert_eq!(results[0].0, first);
+        assert_eq!(results[1].0, second);
+        for (_, result) in &results {
+            match *result {
+                Ok(CancelOutcome::Cancelled) => {}
+                ref r => panic!("unexpected result from cancel_all_jobs() {:?}", r),
+            }
+        }
+
+        // The monitor is stopped along with its job.
+        match monitor.get_status(timeout) {
+            Err(Error::NotConnected) => {}
+            r => panic!("unexpected result from get_status() {:?}", r),
+        }
+
+        assert!(client.cancel_all_jobs().unwrap().is_empty());
+
+        server.shutdown();
+    }
+}
+
+test! {
+    fn expected_hash(name: &str, tmp_dir: &TempDir, backend: &B) {
+        let mut server = backend.serve(name, HttpServerResponses {
+            body: name.to_owned().into_boxed_str().into_boxed_bytes(),
+            delay: 100,
+        });
+
+        let mut client = InProcessClient::with_backend(backend.clone(), format_job_name(name), format_dir_prefix(tmp_dir)).unwrap();
+
+        let interval = 100;
+        let timeout = 10_000;
+
+        let mut sha256 = [0u8; 32];
+        sha256.copy_from_slice(&Sha256::digest(name.as_bytes()));
+        let expected = ExpectedFile { size: name.len() as u64, sha256 };
+        let mut wrong_hash = expected;
+        wrong_hash.sha256[0] ^= 1;
+        let wrong_size = ExpectedFile { size: expected.size + 1, ..expected };
+
+        // The file is only moved into place if it matches.
+        for (i, &(expected, matches)) in [(wrong_hash, false), (wrong_size, false), (expected, true)].iter().enumerate() {
+            let save_path = format!("{}_{}", name, i);
+            let file_path = tmp_dir.path().join(&save_path);
+            let options = StartJobOptions { expected: vec![Some(expected)], ..StartJobOptions::default() };
+
+            let (StartJobSuccess { guid }, mut monitor) =
+                client.start_job_with_options(server.format_url(name), save_path.into(), BitsProxyUsage::Preconfig, options, interval).unwrap();
+
+            let start = Instant::now();
+            loop {
+                let status = monitor.get_status(timeout).expect("should get status update").unwrap();
+                match status.state {
+                    BitsJobState::Queued | BitsJobState::Connecting | BitsJobState::Transferring => {}
+                    BitsJobState::Transferred => break,
+                    _ => panic!("{:?}", status),
+                }
+                assert!(start.elapsed() < Duration::from_millis(60_000));
+            }
+
+            match client.complete_job(guid.clone()) {
+                Ok(()) if matches => {
+                    assert_eq!(fs::read(&file_path).unwrap(), name.as_bytes());
+                }
+                Err(CompleteJobFailure::HashMismatch(ref local_name)) if !matches => {
+                    assert_eq!(local_name, file_path.as_os_str());
+                    assert!(!file_path.exists());
+                    // The job is left as it was, to be cancelled.
+                    client.cancel_job(guid).unwrap();
+                }
+                r => panic!("unexpected result from complete_job() {:?}", r),
+            }
+        }
+
+        // Each file of a job is checked against its own expected file, or not at all without
+        // one.
+        for (i, expected) in [vec![None, Some(expected)], vec![Some(expected), Some(wrong_hash)]].iter().enumerate() {
+            let save_paths = [format!("{}_multi_{}_0", name, i), format!("{}_multi_{}_1", name, i)];
+            let files = save_paths.iter().map(|save_path| (server.format_url(name), save_path.into())).collect();
+            let options = StartJobOptions { expected: expected.clone(), ..StartJobOptions::default() };
+
+            let (StartJobSuccess { guid }, mut monitor) =
+                client.start_job_multi_with_options(files, BitsProxyUsage::Preconfig, options, interval).unwrap();
+
+            let start = Instant::now();
+            loop {
+                let status = monitor.get_status(timeout).expect("should get status update").unwrap();
+                match status.state {
+                    BitsJobState::Queued | BitsJobState::Connecting | BitsJobState::Transferring => {}
+                    BitsJobState::Transferred => break,
+                    _ => panic!("{:?}", status),
+                }
+                assert!(start.elapsed() < Duration::from_millis(60_000));
+            }
+
+            let second_path = tmp_dir.path().join(&save_paths[1]);
+            match client.complete_job(guid.clone()) {
+                Ok(()) if i == 0 => {
+                    assert_eq!(fs::read(&second_path).unwrap(), name.as_bytes());
+                }
+                Err(CompleteJobFailure::HashMismatch(ref local_name)) if i == 1 => {
+                    assert_eq!(local_name, second_path.as_os_str());
+                    client.cancel_job(guid).unwrap();
+                }
+                r => panic!("unexpected result from complete_job() {:?}", r),
+            }
+        }
+
+        server.shutdown();
+    }
+}
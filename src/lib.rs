@@ -5,7 +5,8 @@
 //! An interface for managing and monitoring BITS jobs.
 //!
 //! BITS is a Windows service for performing downloads in the background, independent from an
-//! application, usually via HTTP/HTTPS.
+//! application, usually via HTTP/HTTPS. Where BITS is not available, the same interface is
+//! provided by a portable engine running in the current process.
 //!
 //! [`BitsClient`](enum.BitsClient.html) is the main interface, used to issue commands.
 //!
@@ -15,30 +16,67 @@
 //! Microsoft's documentation for BITS can be found at
 //! <https://docs.microsoft.com/en-us/windows/desktop/Bits/background-intelligent-transfer-service-portal>
 
+// `#[derive(Fail)]` puts its impls inside a constant.
+#![allow(non_local_definitions)]
+
+#[cfg(windows)]
 extern crate bits;
+#[cfg(windows)]
 extern crate comedy;
 extern crate failure;
 extern crate failure_derive;
+#[cfg(windows)]
+extern crate filetime_win;
+#[cfg(feature = "stream")]
+extern crate futures;
+#[cfg(feature = "stream")]
+extern crate futures_timer;
+#[cfg(windows)]
 extern crate guid_win;
+#[macro_use]
+extern crate lazy_static;
+extern crate sha2;
+#[cfg(windows)]
+extern crate winapi;
 
+pub mod backend;
 pub mod bits_protocol;
+pub mod server;
+pub mod transport;
+pub mod types;
 
 mod in_process;
+mod local_service;
+#[cfg(feature = "stream")]
+mod stream;
+mod wait;
 
 use std::convert;
 use std::ffi;
 
+#[cfg(windows)]
+use backend::ComBackend;
+use backend::{DefaultBackend, JobBackend, PortableBits};
+use bits_protocol::wire::DecodeError;
 use bits_protocol::*;
 use failure::Fail;
+use transport::{MonitorConnector, Transport};
 
-pub use bits::status::{BitsErrorContext, BitsJobState, BitsJobTimes};
-pub use bits::{BitsJobProgress, BitsJobStatus, BitsProxyUsage};
-pub use bits_protocol::{JobError, JobStatus};
-pub use comedy::HResult;
-pub use guid_win::Guid;
+pub use bits_protocol::{
+    CancelAllJobsSuccess, CancelOutcome, Capabilities, Credentials, ExpectedFile, FileStatus,
+    JobError, JobSettings, JobStatus, MonitorSubscription, StartJobOptions, MAX_REPLY_DATA,
+    MIN_MODIFICATION_INTERVAL_MILLIS,
+};
+#[cfg(feature = "stream")]
+pub use stream::BitsMonitorStream;
+pub use types::{
+    BitsAuthScheme, BitsAuthTarget, BitsErrorContext, BitsFileProgress, BitsFileRange,
+    BitsJobPriority, BitsJobProgress, BitsJobState, BitsJobStatus, BitsJobTimes, BitsProxyUsage,
+    FileTime, Guid, HResult,
+};
+pub use wait::{WaitForJobFailure, WaitOptions, WaitOutcome};
 
-// These errors would come from a Local Service client but are mostly unused currently.
-// PipeError properly lives in the crate that deals with named pipes, but it isn't in use now.
+/// Errors communicating with the server, from a Local Service client.
 #[derive(Clone, Debug, Eq, Fail, PartialEq)]
 pub enum PipeError {
     #[fail(display = "Pipe is not connected")]
@@ -49,6 +87,8 @@ pub enum PipeError {
     WriteCount(usize, u32),
     #[fail(display = "Windows API error")]
     Api(#[fail(cause)] HResult),
+    #[fail(display = "Invalid message")]
+    Protocol(#[fail(cause)] DecodeError),
 }
 
 impl convert::From<HResult> for PipeError {
@@ -57,55 +97,151 @@ impl convert::From<HResult> for PipeError {
     }
 }
 
+impl convert::From<DecodeError> for PipeError {
+    fn from(err: DecodeError) -> PipeError {
+        PipeError::Protocol(err)
+    }
+}
+
 pub use PipeError as Error;
 
 /// A client for interacting with BITS.
 ///
 /// Methods on `BitsClient` return a `Result<Result<_, XyzFailure>, Error>`. The outer `Result`
 /// is `Err` if there was a communication error in sending the associated command or receiving
-/// its response. Currently this is always `Ok` as all clients are in-process. The inner
-/// `Result` is `Err` if there was an error executing the command.
+/// its response, which is only possible with a Local Service client. The inner `Result` is `Err`
+/// if there was an error executing the command.
 ///
 /// A single `BitsClient` can be used with multiple BITS jobs simultaneously; generally a job
 /// is not bound tightly to a client.
 ///
 /// A `BitsClient` tracks all [`BitsMonitorClient`s](enum.BitsMonitorClient.html) that it started
-/// with `start_job()` or `monitor_job()`, so that the monitor can be stopped or modified.
-pub enum BitsClient {
-    // The `InProcess` variant does all BITS calls directly.
+/// with `start_job()` or `monitor_job()`, so that the monitors can be stopped or modified, each
+/// by its [`MonitorSubscription`](struct.MonitorSubscription.html) or all of a job's at once.
+///
+/// The type parameter selects the [`JobBackend`](backend/trait.JobBackend.html) used by an
+/// in-process client, normally the live BITS service on Windows and the portable engine
+/// elsewhere.
+pub enum BitsClient<B: JobBackend = DefaultBackend> {
+    // The `InProcess` variant does all BITS calls directly, with the BITS service on Windows or
+    // any other backend.
     #[doc(hidden)]
-    InProcess(in_process::InProcessClient),
-    // Space is reserved here for the LocalService variant, which will work through an external
-    // process running as Local Service.
+    InProcess(in_process::InProcessClient<B>),
+    // The `Portable` variant does the transfers itself, with the engine in `backend::portable`.
+    #[doc(hidden)]
+    Portable(in_process::InProcessClient<PortableBits>),
+    // The `LocalService` variant sends commands to a server in an external process, which may
+    // run as Local Service.
+    #[doc(hidden)]
+    LocalService(local_service::LocalServiceClient),
 }
 
-use BitsClient::InProcess;
+use BitsClient::{InProcess, LocalService, Portable};
+
+#[cfg(windows)]
+impl BitsClient<ComBackend> {
+    /// Create an in-process `BitsClient` which uses the BITS service, only on Windows.
+    ///
+    /// `job_name` and `save_path_prefix` are as for [`with_backend()`](#method.with_backend).
+    pub fn new(
+        job_name: ffi::OsString,
+        save_path_prefix: ffi::OsString,
+    ) -> Result<BitsClient<ComBackend>, Error> {
+        Ok(InProcess(in_process::InProcessClient::new(
+            job_name,
+            save_path_prefix,
+        )?))
+    }
+}
 
 impl BitsClient {
-    /// Create an in-process `BitsClient`.
+    /// Create a `BitsClient` which transfers jobs itself, without the BITS service.
+    ///
+    /// Jobs are run by a pure-Rust engine on background threads in this process, and are shared
+    /// with any other portable `BitsClient` in the process. They are lost when the process
+    /// exits. Only `http://` URLs can be downloaded.
+    ///
+    /// `job_name` and `save_path_prefix` are as for [`with_backend()`](#method.with_backend).
+    pub fn new_portable(
+        job_name: ffi::OsString,
+        save_path_prefix: ffi::OsString,
+    ) -> Result<BitsClient, Error> {
+        Ok(Portable(in_process::InProcessClient::with_backend(
+            PortableBits::global(),
+            job_name,
+            save_path_prefix,
+        )?))
+    }
+
+    /// Create a `BitsClient` which sends commands over `transport` to a server in another
+    /// process, so that jobs can be run as a different user.
+    ///
+    /// If a reply is not received within `timeout_millis` milliseconds the command fails with
+    /// `Err(Error::Timeout)`. After any `Err(Error)` the client is disconnected, and further
+    /// commands fail with `Err(Error::NotConnected)`.
+    ///
+    /// Each monitor started by `start_job()` or `monitor_job()` receives its status reports on a
+    /// channel of its own, which is connected to with `monitor_connector`.
+    ///
+    /// The client and server first exchange versions and [`Capabilities`](#method.capabilities),
+    /// this fails if the server doesn't reply within `timeout_millis` or uses a different
+    /// protocol version.
+    pub fn new_local_service<T, M>(
+        transport: T,
+        monitor_connector: M,
+        timeout_millis: u32,
+    ) -> Result<BitsClient, Error>
+    where
+        T: Transport + 'static,
+        M: MonitorConnector + 'static,
+    {
+        Ok(LocalService(local_service::LocalServiceClient::connect(
+            Box::new(transport),
+            Box::new(monitor_connector),
+            timeout_millis,
+        )?))
+    }
+}
+
+impl<B: JobBackend> BitsClient<B> {
+    /// Create an in-process `BitsClient` which uses `backend` instead of the BITS service.
     ///
     /// `job_name` will be used when creating jobs, and this `BitsClient` can only be used to
     /// manipulate jobs with that name.
     ///
     /// `save_path_prefix` will be prepended to the local `save_path` given to `start_job()`, it
     /// must name an existing directory.
-    pub fn new(
+    pub fn with_backend(
+        backend: B,
         job_name: ffi::OsString,
         save_path_prefix: ffi::OsString,
-    ) -> Result<BitsClient, Error> {
-        Ok(InProcess(in_process::InProcessClient::new(
+    ) -> Result<BitsClient<B>, Error> {
+        Ok(InProcess(in_process::InProcessClient::with_backend(
+            backend,
             job_name,
             save_path_prefix,
         )?))
     }
 
+    /// The optional features which can be used with this client.
+    ///
+    /// For a Local Service client these are the features supported by both the client and the
+    /// server, methods which need any others fail with an `Other` failure.
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            InProcess(_) | Portable(_) => in_process::capabilities(),
+            LocalService(client) => client.capabilities(),
+        }
+    }
+
     /// Start a job to download a single file at `url` to local path `save_path` (relative to the
     /// `save_path_prefix` given when constructing the `BitsClient`).
     ///
     /// `save_path_prefix` combined with `save_path` must name a file (existing or not) in an
     /// existing directory, which must be under the directory named by `save_path_prefix`.
     ///
-    /// `proxy_usage` determines what proxy will be used.
+    /// `proxy_usage` determines what proxy will be used. The lists of `BitsProxyUsage::Override`
+    /// are checked, failing with `StartJobFailure::ArgumentValidation` if an entry is malformed.
     ///
     /// When a successful result `Ok(result)` is returned, `result.0.guid` is the id for the
     /// new job, and `result.1` is a monitor client that can be polled for periodic updates,
@@ -116,11 +252,170 @@ impl BitsClient {
         save_path: ffi::OsString,
         proxy_usage: BitsProxyUsage,
         monitor_interval_millis: u32,
-    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient), StartJobFailure>, Error> {
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient<B>), StartJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client
                 .start_job(url, save_path, proxy_usage, monitor_interval_millis)
                 .map(|(success, monitor)| (success, BitsMonitorClient::InProcess(monitor)))),
+            Portable(client) => Ok(client
+                .start_job(url, save_path, proxy_usage, monitor_interval_millis)
+                .map(|(success, monitor)| (success, BitsMonitorClient::Portable(monitor)))),
+            LocalService(client) => Ok(client
+                .start_job(url, save_path, proxy_usage, monitor_interval_millis)?
+                .map(|(success, monitor)| (success, BitsMonitorClient::LocalService(monitor)))),
+        }
+    }
+
+    /// Start a job to download a single file, as with [`start_job()`](#method.start_job), with
+    /// the settings in `options`.
+    ///
+    /// Each of `options.headers` is a `(name, value)` pair. The headers are rejected with
+    /// `StartJobFailure::ArgumentValidation` if a name is not a valid HTTP token, if a value
+    /// contains a line break or other control character, or if they are too large altogether.
+    ///
+    /// `options.credentials` are used if the server or proxy asks for authentication. The user
+    /// name must not be empty, and for Basic authentication it must not contain a `:`.
+    ///
+    /// If `options.expected` has an entry for the file, [`complete_job()`](#method.complete_job) on
+    /// this client checks the downloaded file against it. It is not stored with the job, only kept
+    /// in memory by this client, or by its server for as long as a Local Service client is
+    /// connected. The job can still be completed without the check by another client, or by this
+    /// one after it reconnects.
+    pub fn start_job_with_options(
+        &mut self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient<B>), StartJobFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client
+                .start_job_with_options(
+                    url,
+                    save_path,
+                    proxy_usage,
+                    options,
+                    monitor_interval_millis,
+                )
+                .map(|(success, monitor)| (success, BitsMonitorClient::InProcess(monitor)))),
+            Portable(client) => Ok(client
+                .start_job_with_options(
+                    url,
+                    save_path,
+                    proxy_usage,
+                    options,
+                    monitor_interval_millis,
+                )
+                .map(|(success, monitor)| (success, BitsMonitorClient::Portable(monitor)))),
+            LocalService(client) => Ok(client
+                .start_job_with_options(
+                    url,
+                    save_path,
+                    proxy_usage,
+                    options,
+                    monitor_interval_millis,
+                )?
+                .map(|(success, monitor)| (success, BitsMonitorClient::LocalService(monitor)))),
+        }
+    }
+
+    /// Start a job to download several files as one, so that they complete together.
+    ///
+    /// `files` is a list of `(url, save_path)` pairs, each as for
+    /// [`start_job()`](#method.start_job); every `save_path` is checked before the job is
+    /// created. The returned monitor reports the progress of each file in
+    /// [`JobStatus::files`](struct.JobStatus.html#structfield.files).
+    pub fn start_job_multi(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient<B>), StartJobFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client
+                .start_job_multi(files, proxy_usage, monitor_interval_millis)
+                .map(|(success, monitor)| (success, BitsMonitorClient::InProcess(monitor)))),
+            Portable(client) => Ok(client
+                .start_job_multi(files, proxy_usage, monitor_interval_millis)
+                .map(|(success, monitor)| (success, BitsMonitorClient::Portable(monitor)))),
+            LocalService(client) => Ok(client
+                .start_job_multi(files, proxy_usage, monitor_interval_millis)?
+                .map(|(success, monitor)| (success, BitsMonitorClient::LocalService(monitor)))),
+        }
+    }
+
+    /// Start a job to download several files as one, as with
+    /// [`start_job_multi()`](#method.start_job_multi), with the settings in `options`, which are
+    /// as for [`start_job_with_options()`](#method.start_job_with_options).
+    ///
+    /// `options.ranges` are downloaded from each file. Each of `options.expected` is checked
+    /// against the file in the same place in `files`, there must not be more of them than files.
+    pub fn start_job_multi_with_options(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient<B>), StartJobFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client
+                .start_job_multi_with_options(files, proxy_usage, options, monitor_interval_millis)
+                .map(|(success, monitor)| (success, BitsMonitorClient::InProcess(monitor)))),
+            Portable(client) => Ok(client
+                .start_job_multi_with_options(files, proxy_usage, options, monitor_interval_millis)
+                .map(|(success, monitor)| (success, BitsMonitorClient::Portable(monitor)))),
+            LocalService(client) => Ok(client
+                .start_job_multi_with_options(files, proxy_usage, options, monitor_interval_millis)?
+                .map(|(success, monitor)| (success, BitsMonitorClient::LocalService(monitor)))),
+        }
+    }
+
+    /// Start a job to upload the file at `upload_path`, relative to the `save_path_prefix`, to
+    /// `url`.
+    ///
+    /// If `reply_path` is given the job is an upload-reply job: the server's reply is saved to
+    /// `reply_path`, also relative to the `save_path_prefix`, when the job is completed, and it
+    /// is reported in the [`JobStatus::reply_data`](struct.JobStatus.html#structfield.reply_data)
+    /// of the first status after the job is transferred.
+    ///
+    /// Otherwise as for [`start_job()`](#method.start_job).
+    pub fn start_upload_job(
+        &mut self,
+        url: ffi::OsString,
+        upload_path: ffi::OsString,
+        reply_path: Option<ffi::OsString>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, BitsMonitorClient<B>), StartJobFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client
+                .start_upload_job(
+                    url,
+                    upload_path,
+                    reply_path,
+                    proxy_usage,
+                    monitor_interval_millis,
+                )
+                .map(|(success, monitor)| (success, BitsMonitorClient::InProcess(monitor)))),
+            Portable(client) => Ok(client
+                .start_upload_job(
+                    url,
+                    upload_path,
+                    reply_path,
+                    proxy_usage,
+                    monitor_interval_millis,
+                )
+                .map(|(success, monitor)| (success, BitsMonitorClient::Portable(monitor)))),
+            LocalService(client) => Ok(client
+                .start_upload_job(
+                    url,
+                    upload_path,
+                    reply_path,
+                    proxy_usage,
+                    monitor_interval_millis,
+                )?
+                .map(|(success, monitor)| (success, BitsMonitorClient::LocalService(monitor)))),
         }
     }
 
@@ -129,17 +424,24 @@ impl BitsClient {
     ///
     /// The returned `Ok(monitor)` is a monitor client to be polled for periodic updates.
     ///
-    /// There can only be one ongoing `BitsMonitorClient` for each job associated with a given
-    /// `BitsClient`. If a monitor client already exists for the specified job, it will be stopped.
+    /// Any other monitors of the job keep running alongside the new one, each at its own
+    /// interval. Use the new monitor's [`subscription()`](enum.BitsMonitorClient.html#method.subscription)
+    /// to change or stop it alone.
     pub fn monitor_job(
         &mut self,
         guid: Guid,
         interval_millis: u32,
-    ) -> Result<Result<BitsMonitorClient, MonitorJobFailure>, Error> {
+    ) -> Result<Result<BitsMonitorClient<B>, MonitorJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client
                 .monitor_job(guid, interval_millis)
                 .map(BitsMonitorClient::InProcess)),
+            Portable(client) => Ok(client
+                .monitor_job(guid, interval_millis)
+                .map(BitsMonitorClient::Portable)),
+            LocalService(client) => Ok(client
+                .monitor_job(guid, interval_millis)?
+                .map(BitsMonitorClient::LocalService)),
         }
     }
 
@@ -147,6 +449,8 @@ impl BitsClient {
     pub fn suspend_job(&mut self, guid: Guid) -> Result<Result<(), SuspendJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.suspend_job(guid)),
+            Portable(client) => Ok(client.suspend_job(guid)),
+            LocalService(client) => client.suspend_job(guid),
         }
     }
 
@@ -154,53 +458,117 @@ impl BitsClient {
     pub fn resume_job(&mut self, guid: Guid) -> Result<Result<(), ResumeJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.resume_job(guid)),
+            Portable(client) => Ok(client.resume_job(guid)),
+            LocalService(client) => client.resume_job(guid),
         }
     }
 
     /// Set the priority of job `guid`.
     ///
-    /// `foreground == true` will set the priority to `BG_JOB_PRIORITY_FOREGROUND`,
-    /// `false` will use the default `BG_JOB_PRIORITY_NORMAL`.
     /// See the Microsoft documentation for `BG_JOB_PRIORITY` for details.
     ///
-    /// A job created by `start_job()` will be foreground priority, by default.
+    /// A job created by `start_job()` will be foreground priority, by default. The current
+    /// priority is reported in [`JobStatus::priority`](struct.JobStatus.html#structfield.priority).
     pub fn set_job_priority(
         &mut self,
         guid: Guid,
-        foreground: bool,
+        priority: BitsJobPriority,
     ) -> Result<Result<(), SetJobPriorityFailure>, Error> {
         match self {
-            InProcess(client) => Ok(client.set_job_priority(guid, foreground)),
+            InProcess(client) => Ok(client.set_job_priority(guid, priority)),
+            Portable(client) => Ok(client.set_job_priority(guid, priority)),
+            LocalService(client) => client.set_job_priority(guid, priority),
+        }
+    }
+
+    /// Change the retry and timeout settings of job `guid`.
+    ///
+    /// Settings which are `None` are left as they are. See the Microsoft documentation for
+    /// `IBackgroundCopyJob::SetMinimumRetryDelay`, `SetNoProgressTimeout` and
+    /// `IBackgroundCopyJob4::SetMaximumDownloadTime` for details.
+    pub fn update_job_settings(
+        &mut self,
+        guid: Guid,
+        settings: JobSettings,
+    ) -> Result<Result<(), UpdateJobSettingsFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client.update_job_settings(guid, settings)),
+            Portable(client) => Ok(client.update_job_settings(guid, settings)),
+            LocalService(client) => client.update_job_settings(guid, settings),
         }
     }
 
-    /// Change the update interval for an ongoing monitor of job `guid`.
+    /// Change the update interval for the ongoing monitor `subscription` of job `guid`, or for
+    /// all of the job's monitors if it is `None`.
     pub fn set_update_interval(
         &mut self,
         guid: Guid,
+        subscription: Option<MonitorSubscription>,
         interval_millis: u32,
     ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
         match self {
-            InProcess(client) => Ok(client.set_update_interval(guid, interval_millis)),
+            InProcess(client) => {
+                Ok(client.set_update_interval(guid, subscription, interval_millis))
+            }
+            Portable(client) => Ok(client.set_update_interval(guid, subscription, interval_millis)),
+            LocalService(client) => client.set_update_interval(guid, subscription, interval_millis),
         }
     }
 
-    /// Stop any ongoing monitor for job `guid`.
+    /// Stop the ongoing monitor `subscription` of job `guid`, or all of the job's monitors if it
+    /// is `None`. Other monitors of the job are unaffected.
     pub fn stop_update(
         &mut self,
         guid: Guid,
+        subscription: Option<MonitorSubscription>,
     ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
         match self {
-            InProcess(client) => Ok(client.stop_update(guid)),
+            InProcess(client) => Ok(client.stop_update(guid, subscription)),
+            Portable(client) => Ok(client.stop_update(guid, subscription)),
+            LocalService(client) => client.stop_update(guid, subscription),
+        }
+    }
+
+    /// Also wake the ongoing monitor `subscription` of job `guid`, or all of the job's monitors
+    /// if it is `None`, when the job is modified, e.g. its state changes, it makes progress, or
+    /// another process suspends or resumes it, instead of only when it is transferred or fails.
+    ///
+    /// Modifications are coalesced: a report is made at most once per `min_interval_millis`
+    /// milliseconds for them, and otherwise at the monitor's usual interval. An interval below
+    /// [`MIN_MODIFICATION_INTERVAL_MILLIS`](constant.MIN_MODIFICATION_INTERVAL_MILLIS.html) is
+    /// raised to it. `None` turns this off again. It is off for a new monitor.
+    pub fn set_modification_updates(
+        &mut self,
+        guid: Guid,
+        subscription: Option<MonitorSubscription>,
+        min_interval_millis: Option<u32>,
+    ) -> Result<Result<(), MonitorJobFailure>, Error> {
+        match self {
+            InProcess(client) => {
+                Ok(client.set_modification_updates(guid, subscription, min_interval_millis))
+            }
+            Portable(client) => {
+                Ok(client.set_modification_updates(guid, subscription, min_interval_millis))
+            }
+            LocalService(client) => {
+                client.set_modification_updates(guid, subscription, min_interval_millis)
+            }
         }
     }
 
     /// Complete the job `guid`.
     ///
     /// This also stops any ongoing monitor for the job.
+    ///
+    /// If the job was started by this client with [`ExpectedFile`s](struct.ExpectedFile.html),
+    /// each of its files which has been transferred is checked first against its own; if one
+    /// doesn't match, this fails with `CompleteJobFailure::HashMismatch` and the job is neither
+    /// completed nor cancelled.
     pub fn complete_job(&mut self, guid: Guid) -> Result<Result<(), CompleteJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.complete_job(guid)),
+            Portable(client) => Ok(client.complete_job(guid)),
+            LocalService(client) => client.complete_job(guid),
         }
     }
 
@@ -210,20 +578,71 @@ impl BitsClient {
     pub fn cancel_job(&mut self, guid: Guid) -> Result<Result<(), CancelJobFailure>, Error> {
         match self {
             InProcess(client) => Ok(client.cancel_job(guid)),
+            Portable(client) => Ok(client.cancel_job(guid)),
+            LocalService(client) => client.cancel_job(guid),
+        }
+    }
+
+    /// List the jobs with this client's `job_name`, with the current status of each.
+    ///
+    /// This is intended for finding jobs which are still in BITS after the process that started
+    /// them has exited, e.g. after a crash, so they can be monitored, completed or cancelled. The
+    /// statuses report every file's URL, and no reply data.
+    pub fn list_jobs(&mut self) -> Result<Result<Vec<(Guid, JobStatus)>, ListJobsFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client.list_jobs()),
+            Portable(client) => Ok(client.list_jobs()),
+            LocalService(client) => client.list_jobs(),
+        }
+    }
+
+    /// Cancel all jobs with this client's `job_name`.
+    ///
+    /// Each job found gets its own result: `CancelOutcome::FilesLeftBehind` if BITS could not
+    /// delete all of its temporary files, and `Err` if it could not be cancelled. A job whose
+    /// GUID can't be read is skipped. Any ongoing monitor of a cancelled job is stopped.
+    /// `Err(ListJobsFailure)` means the jobs could not be found at all.
+    pub fn cancel_all_jobs(
+        &mut self,
+    ) -> Result<Result<CancelAllJobsSuccess, ListJobsFailure>, Error> {
+        match self {
+            InProcess(client) => Ok(client.cancel_all_jobs()),
+            Portable(client) => Ok(client.cancel_all_jobs()),
+            LocalService(client) => client.cancel_all_jobs(),
         }
     }
+
+    /// Monitor job `guid` until it is transferred, fails, or is cancelled or completed by
+    /// someone else, and return how it ended along with its last status.
+    ///
+    /// With `options.complete`, a transferred job is also completed, and the status is the one
+    /// from just before that. With `options.no_progress_timeout_millis`, this gives up once the
+    /// job has transferred nothing for that long; otherwise it blocks for as long as the job
+    /// takes, including while it is suspended.
+    ///
+    /// The job gets a monitor of its own for this, any other monitors keep running.
+    pub fn wait_for_job(
+        &mut self,
+        guid: Guid,
+        options: WaitOptions,
+    ) -> Result<Result<(WaitOutcome, JobStatus), WaitForJobFailure>, Error> {
+        wait::wait_for_job(self, guid, options)
+    }
 }
 
 /// The client side of a monitor for a BITS job.
 ///
 /// It is intended to be used by calling `get_status` in a loop to receive notifications about
 /// the status of a job. Because `get_status` blocks, it is recommended to run this loop on its
-/// own thread.
-pub enum BitsMonitorClient {
-    InProcess(in_process::InProcessMonitor),
+/// own thread, or, with the `stream` feature, to use [`into_stream()`](#method.into_stream)
+/// instead.
+pub enum BitsMonitorClient<B: JobBackend = DefaultBackend> {
+    InProcess(in_process::InProcessMonitor<B>),
+    Portable(in_process::InProcessMonitor<PortableBits>),
+    LocalService(local_service::LocalServiceMonitor),
 }
 
-impl BitsMonitorClient {
+impl<B: JobBackend> BitsMonitorClient<B> {
     /// `get_status` will return a result approximately every `monitor_interval_millis`
     /// milliseconds, but in case a result isn't available within `timeout_millis` milliseconds
     /// this will return `Err(Error::Timeout)`. Any `Err` returned, including timeout, indicates
@@ -243,6 +662,29 @@ impl BitsMonitorClient {
     ) -> Result<Result<JobStatus, HResultMessage>, Error> {
         match self {
             BitsMonitorClient::InProcess(client) => client.get_status(timeout_millis),
+            BitsMonitorClient::Portable(client) => client.get_status(timeout_millis),
+            BitsMonitorClient::LocalService(client) => client.get_status(timeout_millis),
+        }
+    }
+
+    /// The handle of this monitor, to change or stop it alone with `BitsClient`'s
+    /// `set_update_interval()`, `stop_update()` or `set_modification_updates()`.
+    pub fn subscription(&self) -> MonitorSubscription {
+        match self {
+            BitsMonitorClient::InProcess(client) => client.subscription(),
+            BitsMonitorClient::Portable(client) => client.subscription(),
+            BitsMonitorClient::LocalService(client) => client.subscription(),
         }
     }
+
+    /// Turn this monitor into an asynchronous `Stream` of the results `get_status` would return
+    /// in `Ok`, which ends when the monitor is stopped.
+    ///
+    /// An in-process monitor is woken by its job's notifications and by a timer for the
+    /// interval, so it never blocks a thread. A Local Service monitor still waits for its
+    /// reports on a thread of its own.
+    #[cfg(feature = "stream")]
+    pub fn into_stream(self) -> BitsMonitorStream<B> {
+        BitsMonitorStream::new(self)
+    }
 }
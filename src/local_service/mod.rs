@@ -0,0 +1,574 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::cmp;
+use std::ffi;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bits_protocol::wire::{
+    decode_any_header, decode_value, encode_message, Decode, DecodeError, HEADER_SIZE,
+    PROTOCOL_VERSION,
+};
+use bits_protocol::*;
+use transport::{MonitorConnector, Transport};
+use types::{BitsJobPriority, BitsProxyUsage, Guid};
+
+use super::Error;
+
+// How long the body of a message may take to arrive once its header has, however little of the
+// caller's timeout is left.
+const MIN_BODY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// What each capability allows, for the failure when the server doesn't support it.
+const CAPABILITY_DESCRIPTIONS: &[(Capabilities, &str)] = &[
+    (Capabilities::MONITORS, "monitors"),
+    (Capabilities::MULTI_FILE_JOBS, "multi-file jobs"),
+    (Capabilities::FILE_NAMES, "file names in status reports"),
+    (Capabilities::UPLOAD, "upload jobs"),
+    (Capabilities::CUSTOM_HEADERS, "custom headers"),
+    (Capabilities::CREDENTIALS, "credentials"),
+    (Capabilities::PROXY_OVERRIDE, "proxy lists"),
+    (
+        Capabilities::START_PRIORITY,
+        "choosing the priority of a new job",
+    ),
+    (Capabilities::JOB_SETTINGS, "job settings"),
+    (Capabilities::BYTE_RANGES, "byte ranges"),
+    (Capabilities::HASH_VERIFICATION, "hash verification"),
+    (Capabilities::LIST_JOBS, "listing jobs"),
+    (Capabilities::CANCEL_ALL_JOBS, "cancelling all jobs"),
+    (Capabilities::MODIFICATION_UPDATES, "modification updates"),
+    (
+        Capabilities::MONITOR_SUBSCRIPTIONS,
+        "changing one monitor of a job",
+    ),
+    (Capabilities::TRANSFER_RATE, "transfer rates"),
+];
+
+// Distinguishes the monitor channels of this process.
+static NEXT_MONITOR_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn monitor_pipe_name() -> ffi::OsString {
+    ffi::OsString::from(format!(
+        "bits-monitor-{}-{}",
+        process::id(),
+        NEXT_MONITOR_ID.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+// The optional features that the Local Service client can use, if the server supports them.
+fn client_capabilities() -> Capabilities {
+    Capabilities::MONITORS
+        | Capabilities::MULTI_FILE_JOBS
+        | Capabilities::FILE_NAMES
+        | Capabilities::UPLOAD
+        | Capabilities::CUSTOM_HEADERS
+        | Capabilities::CREDENTIALS
+        | Capabilities::PROXY_OVERRIDE
+        | Capabilities::START_PRIORITY
+        | Capabilities::JOB_SETTINGS
+        | Capabilities::BYTE_RANGES
+        | Capabilities::HASH_VERIFICATION
+        | Capabilities::LIST_JOBS
+        | Capabilities::CANCEL_ALL_JOBS
+        | Capabilities::MODIFICATION_UPDATES
+        | Capabilities::MONITOR_SUBSCRIPTIONS
+        | Capabilities::TRANSFER_RATE
+}
+
+// The capabilities needed to send `proxy_usage`.
+fn proxy_capabilities(proxy_usage: &BitsProxyUsage) -> Capabilities {
+    match *proxy_usage {
+        BitsProxyUsage::Override { .. } => Capabilities::PROXY_OVERRIDE,
+        _ => Capabilities::empty(),
+    }
+}
+
+// The capabilities needed to send `options`, each is only sent with its own.
+fn options_capabilities(options: &StartJobOptions) -> Capabilities {
+    let mut required = Capabilities::empty();
+    if !options.headers.is_empty() {
+        required = required | Capabilities::CUSTOM_HEADERS;
+    }
+    if options.credentials.is_some() {
+        required = required | Capabilities::CREDENTIALS;
+    }
+    if options.priority.is_some() {
+        required = required | Capabilities::START_PRIORITY;
+    }
+    if options.settings != JobSettings::default() {
+        required = required | Capabilities::JOB_SETTINGS;
+    }
+    if !options.ranges.is_empty() {
+        required = required | Capabilities::BYTE_RANGES;
+    }
+    if options.expected.iter().any(Option::is_some) {
+        required = required | Capabilities::HASH_VERIFICATION;
+    }
+    required
+}
+
+// The capabilities needed to change only the monitor `subscription`, rather than all of a job's.
+fn subscription_capabilities(subscription: Option<MonitorSubscription>) -> Capabilities {
+    match subscription {
+        Some(_) => Capabilities::MONITOR_SUBSCRIPTIONS,
+        None => Capabilities::empty(),
+    }
+}
+
+fn send_message(transport: &mut dyn Transport, message: &[u8]) -> Result<(), Error> {
+    let written = transport.send(message)?;
+    if written != message.len() {
+        return Err(Error::WriteCount(message.len(), written as u32));
+    }
+    Ok(())
+}
+
+// Read one message from `transport`, decoding it with `capabilities`. The header and body
+// together must arrive within `timeout`, though once the header has arrived the body is always
+// given `MIN_BODY_TIMEOUT`.
+fn recv_message<T: Decode>(
+    transport: &mut dyn Transport,
+    timeout: Duration,
+    capabilities: Capabilities,
+) -> Result<T, Error> {
+    let (version, body) = recv_body(transport, timeout)?;
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version).into());
+    }
+    Ok(decode_value(&body, capabilities)?)
+}
+
+// Read the body of one message from a peer with any version, as for `recv_message()`, returning
+// the version with it.
+fn recv_body(transport: &mut dyn Transport, timeout: Duration) -> Result<(u32, Vec<u8>), Error> {
+    let deadline = Instant::now() + timeout;
+
+    let mut header = [0u8; HEADER_SIZE];
+    transport.recv(&mut header, timeout)?;
+    let (version, len) = decode_any_header(&header)?;
+
+    // Giving up part way through would leave the rest of the message to be misread as the next.
+    let mut body = vec![0u8; len as usize];
+    let remaining = cmp::max(
+        deadline.saturating_duration_since(Instant::now()),
+        MIN_BODY_TIMEOUT,
+    );
+    transport.recv(&mut body, remaining)?;
+    Ok((version, body))
+}
+
+// The Local Service client sends commands to a server in another process, which makes the BITS
+// calls, usually as a different user.
+// See the corresponding functions in BitsClient.
+pub struct LocalServiceClient {
+    transport: Box<dyn Transport>,
+    monitor_connector: Box<dyn MonitorConnector>,
+    timeout: Duration,
+    // Supported by both the client and the server.
+    capabilities: Capabilities,
+    // Once a command has failed the stream may be out of step with the server, so no more
+    // commands are sent.
+    connected: bool,
+}
+
+impl LocalServiceClient {
+    // Exchange `Hello`s with the server. Only a server with another framing is rejected, any
+    // other difference is in the capabilities.
+    pub fn connect(
+        mut transport: Box<dyn Transport>,
+        monitor_connector: Box<dyn MonitorConnector>,
+        timeout_millis: u32,
+    ) -> Result<LocalServiceClient, Error> {
+        let timeout = Duration::from_millis(u64::from(timeout_millis));
+
+        let hello = Hello::new(client_capabilities());
+        send_message(
+            &mut *transport,
+            &encode_message(&hello, Capabilities::empty())?,
+        )?;
+        let (_, body) = recv_body(&mut *transport, timeout)?;
+        let hello: Hello = decode_value(&body, Capabilities::empty())?;
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(hello.protocol_version).into());
+        }
+
+        Ok(LocalServiceClient {
+            transport,
+            monitor_connector,
+            timeout,
+            capabilities: hello.capabilities & client_capabilities(),
+            connected: true,
+        })
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // Fail with `failure`, naming what is missing, unless all of `required` is supported.
+    fn require_capability<F>(
+        &self,
+        required: Capabilities,
+        failure: fn(String) -> F,
+    ) -> Result<(), F> {
+        if self.capabilities.contains(required) {
+            return Ok(());
+        }
+        let missing: Vec<&str> = CAPABILITY_DESCRIPTIONS
+            .iter()
+            .filter(|&&(capability, _)| {
+                required.contains(capability) && !self.capabilities.contains(capability)
+            })
+            .map(|&(_, description)| description)
+            .collect();
+        Err(failure(format!(
+            "Not supported by the server: {}",
+            missing.join(", ")
+        )))
+    }
+
+    // Send a command and wait for the reply.
+    fn send<C>(&mut self, cmd: C) -> Result<Result<C::Success, C::Failure>, Error>
+    where
+        C: CommandType,
+        C::Success: Decode,
+        C::Failure: Decode,
+    {
+        if !self.connected {
+            return Err(Error::NotConnected);
+        }
+
+        // A command too long to send fails without disturbing the connection.
+        let message = encode_message(&C::wrap(cmd), self.capabilities)?;
+        let result = self.exchange(&message);
+        if result.is_err() {
+            self.connected = false;
+        }
+        result
+    }
+
+    fn exchange<S: Decode, F: Decode>(&mut self, message: &[u8]) -> Result<Result<S, F>, Error> {
+        send_message(&mut *self.transport, message)?;
+        recv_message(&mut *self.transport, self.timeout, self.capabilities)
+    }
+
+    // The server has created the monitor's channel by the time it replies, so connect to it
+    // now. If that fails, the error is reported by the monitor's first `get_status()`.
+    fn connect_monitor(
+        &mut self,
+        pipe_name: &ffi::OsStr,
+        subscription: MonitorSubscription,
+    ) -> LocalServiceMonitor {
+        LocalServiceMonitor {
+            transport: self.monitor_connector.connect(pipe_name),
+            capabilities: self.capabilities,
+            subscription,
+        }
+    }
+
+    pub fn start_job(
+        &mut self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        self.start_job_with_options(
+            url,
+            save_path,
+            proxy_usage,
+            StartJobOptions::default(),
+            monitor_interval_millis,
+        )
+    }
+
+    pub fn start_job_with_options(
+        &mut self,
+        url: ffi::OsString,
+        save_path: ffi::OsString,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        let required = Capabilities::MONITORS
+            | proxy_capabilities(&proxy_usage)
+            | options_capabilities(&options);
+        if let Err(failure) = self.require_capability(required, StartJobFailure::Other) {
+            return Ok(Err(failure));
+        }
+
+        let StartJobOptions {
+            headers,
+            credentials,
+            priority,
+            settings,
+            ranges,
+            expected,
+        } = options;
+        let pipe_name = monitor_pipe_name();
+        let result = self.send(StartJobCommand {
+            url,
+            save_path,
+            proxy_usage,
+            headers,
+            credentials,
+            priority,
+            settings,
+            ranges,
+            expected,
+            monitor: Some(MonitorConfig {
+                pipe_name: pipe_name.clone(),
+                interval_millis: monitor_interval_millis,
+            }),
+        })?;
+        Ok(result.map(|(success, subscription)| {
+            (success, self.connect_monitor(&pipe_name, subscription))
+        }))
+    }
+
+    pub fn start_job_multi(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        self.start_job_multi_with_options(
+            files,
+            proxy_usage,
+            StartJobOptions::default(),
+            monitor_interval_millis,
+        )
+    }
+
+    pub fn start_job_multi_with_options(
+        &mut self,
+        files: Vec<(ffi::OsString, ffi::OsString)>,
+        proxy_usage: BitsProxyUsage,
+        options: StartJobOptions,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        let required = Capabilities::MONITORS
+            | Capabilities::MULTI_FILE_JOBS
+            | proxy_capabilities(&proxy_usage)
+            | options_capabilities(&options);
+        if let Err(failure) = self.require_capability(required, StartJobFailure::Other) {
+            return Ok(Err(failure));
+        }
+
+        let pipe_name = monitor_pipe_name();
+        let result = self.send(StartJobMultiCommand {
+            files: files
+                .into_iter()
+                .map(|(url, save_path)| JobFile { url, save_path })
+                .collect(),
+            proxy_usage,
+            monitor: Some(MonitorConfig {
+                pipe_name: pipe_name.clone(),
+                interval_millis: monitor_interval_millis,
+            }),
+            options,
+        })?;
+        Ok(result.map(|(success, subscription)| {
+            (success, self.connect_monitor(&pipe_name, subscription))
+        }))
+    }
+
+    pub fn start_upload_job(
+        &mut self,
+        url: ffi::OsString,
+        upload_path: ffi::OsString,
+        reply_path: Option<ffi::OsString>,
+        proxy_usage: BitsProxyUsage,
+        monitor_interval_millis: u32,
+    ) -> Result<Result<(StartJobSuccess, LocalServiceMonitor), StartJobFailure>, Error> {
+        let required =
+            Capabilities::UPLOAD | Capabilities::MONITORS | proxy_capabilities(&proxy_usage);
+        if let Err(failure) = self.require_capability(required, StartJobFailure::Other) {
+            return Ok(Err(failure));
+        }
+
+        let pipe_name = monitor_pipe_name();
+        let result = self.send(StartUploadJobCommand {
+            url,
+            upload_path,
+            reply_path,
+            proxy_usage,
+            monitor: Some(MonitorConfig {
+                pipe_name: pipe_name.clone(),
+                interval_millis: monitor_interval_millis,
+            }),
+        })?;
+        Ok(result.map(|(success, subscription)| {
+            (success, self.connect_monitor(&pipe_name, subscription))
+        }))
+    }
+
+    pub fn monitor_job(
+        &mut self,
+        guid: Guid,
+        interval_millis: u32,
+    ) -> Result<Result<LocalServiceMonitor, MonitorJobFailure>, Error> {
+        if let Err(failure) =
+            self.require_capability(Capabilities::MONITORS, MonitorJobFailure::Other)
+        {
+            return Ok(Err(failure));
+        }
+
+        let pipe_name = monitor_pipe_name();
+        let result = self.send(MonitorJobCommand {
+            guid,
+            monitor: MonitorConfig {
+                pipe_name: pipe_name.clone(),
+                interval_millis,
+            },
+        })?;
+        Ok(result.map(|subscription| self.connect_monitor(&pipe_name, subscription)))
+    }
+
+    pub fn suspend_job(&mut self, guid: Guid) -> Result<Result<(), SuspendJobFailure>, Error> {
+        self.send(SuspendJobCommand { guid })
+    }
+
+    pub fn resume_job(&mut self, guid: Guid) -> Result<Result<(), ResumeJobFailure>, Error> {
+        self.send(ResumeJobCommand { guid })
+    }
+
+    pub fn set_job_priority(
+        &mut self,
+        guid: Guid,
+        priority: BitsJobPriority,
+    ) -> Result<Result<(), SetJobPriorityFailure>, Error> {
+        self.send(SetJobPriorityCommand { guid, priority })
+    }
+
+    pub fn update_job_settings(
+        &mut self,
+        guid: Guid,
+        settings: JobSettings,
+    ) -> Result<Result<(), UpdateJobSettingsFailure>, Error> {
+        if let Err(failure) =
+            self.require_capability(Capabilities::JOB_SETTINGS, UpdateJobSettingsFailure::Other)
+        {
+            return Ok(Err(failure));
+        }
+        self.send(UpdateJobSettingsCommand { guid, settings })
+    }
+
+    pub fn set_update_interval(
+        &mut self,
+        guid: Guid,
+        subscription: Option<MonitorSubscription>,
+        interval_millis: u32,
+    ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
+        let required = subscription_capabilities(subscription);
+        if let Err(failure) = self.require_capability(required, SetUpdateIntervalFailure::Other) {
+            return Ok(Err(failure));
+        }
+        self.send(SetUpdateIntervalCommand {
+            guid,
+            subscription,
+            interval_millis,
+        })
+    }
+
+    pub fn stop_update(
+        &mut self,
+        guid: Guid,
+        subscription: Option<MonitorSubscription>,
+    ) -> Result<Result<(), SetUpdateIntervalFailure>, Error> {
+        let required = subscription_capabilities(subscription);
+        if let Err(failure) = self.require_capability(required, SetUpdateIntervalFailure::Other) {
+            return Ok(Err(failure));
+        }
+        self.send(StopUpdateCommand { guid, subscription })
+    }
+
+    pub fn set_modification_updates(
+        &mut self,
+        guid: Guid,
+        subscription: Option<MonitorSubscription>,
+        min_interval_millis: Option<u32>,
+    ) -> Result<Result<(), MonitorJobFailure>, Error> {
+        let required = Capabilities::MODIFICATION_UPDATES | subscription_capabilities(subscription);
+        if let Err(failure) = self.require_capability(required, MonitorJobFailure::Other) {
+            return Ok(Err(failure));
+        }
+        self.send(SetModificationUpdatesCommand {
+            guid,
+            subscription,
+            min_interval_millis,
+        })
+    }
+
+    pub fn complete_job(&mut self, guid: Guid) -> Result<Result<(), CompleteJobFailure>, Error> {
+        self.send(CompleteJobCommand { guid })
+    }
+
+    pub fn cancel_job(&mut self, guid: Guid) -> Result<Result<(), CancelJobFailure>, Error> {
+        self.send(CancelJobCommand { guid })
+    }
+
+    pub fn list_jobs(&mut self) -> Result<Result<Vec<(Guid, JobStatus)>, ListJobsFailure>, Error> {
+        if let Err(failure) =
+            self.require_capability(Capabilities::LIST_JOBS, ListJobsFailure::Other)
+        {
+            return Ok(Err(failure));
+        }
+        self.send(ListJobsCommand {})
+    }
+
+    pub fn cancel_all_jobs(
+        &mut self,
+    ) -> Result<Result<CancelAllJobsSuccess, ListJobsFailure>, Error> {
+        if let Err(failure) =
+            self.require_capability(Capabilities::CANCEL_ALL_JOBS, ListJobsFailure::Other)
+        {
+            return Ok(Err(failure));
+        }
+        self.send(CancelAllJobsCommand {})
+    }
+}
+
+// Receives the status reports that the server streams over a monitor's own channel. The server
+// closes the channel when the monitor is stopped, including by `complete_job()` or
+// `cancel_job()`.
+pub struct LocalServiceMonitor {
+    // Once any `Err` has been returned, this is `Err(Error::NotConnected)`.
+    transport: Result<Box<dyn Transport>, Error>,
+    capabilities: Capabilities,
+    subscription: MonitorSubscription,
+}
+
+impl LocalServiceMonitor {
+    pub fn subscription(&self) -> MonitorSubscription {
+        self.subscription
+    }
+
+    pub fn get_status(
+        &mut self,
+        timeout_millis: u32,
+    ) -> Result<Result<JobStatus, HResultMessage>, Error> {
+        let result = match self.transport {
+            Ok(ref mut transport) => recv_message(
+                &mut **transport,
+                Duration::from_millis(u64::from(timeout_millis)),
+                self.capabilities,
+            ),
+            Err(ref e) => Err(e.clone()),
+        };
+
+        // Both an error and an error status stop the monitor.
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) | Err(_) => self.transport = Err(Error::NotConnected),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,608 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Tests of the Local Service client against a fake server, which replies to each command with
+// whatever the test's handler returns.
+
+#![cfg(test)]
+
+use std::ffi::OsString;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::super::{
+    BitsAuthScheme, BitsAuthTarget, BitsClient, BitsFileRange, BitsJobPriority, BitsJobProgress,
+    BitsJobState, BitsJobTimes, BitsProxyUsage, Error, FileTime, Guid,
+};
+use super::client_capabilities;
+use bits_protocol::wire::{
+    decode_header, decode_value, encode_message, DecodeError, HEADER_SIZE, PROTOCOL_VERSION,
+};
+use bits_protocol::*;
+use transport::{memory_pair, MemoryPipes, MemoryTransport, MonitorListener, Transport};
+
+const TIMEOUT_MILLIS: u32 = 1_000;
+
+fn test_guid() -> Guid {
+    Guid {
+        data1: 0x1234_5678,
+        data2: 0x9abc,
+        data3: 0xdef0,
+        data4: *b"LocalSvc",
+    }
+}
+
+// Reply to the client's `Hello` with `hello`, then serve commands until the client disconnects,
+// returning the commands received. `handler` returns the encoded reply, or `None` to not reply.
+fn fake_server_with_hello<T, F>(
+    mut transport: T,
+    hello: Hello,
+    mut handler: F,
+) -> JoinHandle<Vec<Command>>
+where
+    T: Transport + 'static,
+    F: FnMut(&Command) -> Option<Vec<u8>> + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut received = Vec::new();
+        let timeout = Duration::from_secs(10);
+
+        let mut header = [0u8; HEADER_SIZE];
+        if let Err(Error::NotConnected) = transport.recv(&mut header, timeout) {
+            return received;
+        }
+        let mut body = vec![0u8; decode_header(&header).unwrap() as usize];
+        transport.recv(&mut body, timeout).unwrap();
+        let client_hello: Hello = decode_value(&body, Capabilities::empty()).unwrap();
+        let capabilities = client_hello.capabilities & hello.capabilities;
+        transport
+            .send(&encode_message(&hello, Capabilities::empty()).unwrap())
+            .unwrap();
+
+        loop {
+            let mut header = [0u8; HEADER_SIZE];
+            if let Err(Error::NotConnected) = transport.recv(&mut header, timeout) {
+                return received;
+            }
+            let mut body = vec![0u8; decode_header(&header).unwrap() as usize];
+            transport.recv(&mut body, timeout).unwrap();
+            let command = decode_value(&body, capabilities).unwrap();
+
+            if let Some(reply) = handler(&command) {
+                if transport.send(&reply).is_err() {
+                    return received;
+                }
+            }
+            received.push(command);
+        }
+    })
+}
+
+fn fake_server<T, F>(transport: T, handler: F) -> JoinHandle<Vec<Command>>
+where
+    T: Transport + 'static,
+    F: FnMut(&Command) -> Option<Vec<u8>> + Send + 'static,
+{
+    fake_server_with_hello(transport, Hello::new(client_capabilities()), handler)
+}
+
+fn test_status() -> JobStatus {
+    JobStatus {
+        state: BitsJobState::Transferring,
+        progress: BitsJobProgress {
+            total_bytes: Some(100),
+            transferred_bytes: 10,
+            total_files: 1,
+            transferred_files: 0,
+        },
+        error_count: 0,
+        error: None,
+        times: BitsJobTimes {
+            creation: FileTime(0),
+            modification: FileTime(0),
+            transfer_completion: None,
+        },
+        url: None,
+        files: Vec::new(),
+        reply_data: None,
+        priority: Some(BitsJobPriority::Foreground),
+        bytes_per_second: Some(5),
+        estimated_remaining: Some(Duration::from_secs(18)),
+    }
+}
+
+fn ok_reply() -> Option<Vec<u8>> {
+    Some(encode_message::<Result<(), CompleteJobFailure>>(&Ok(()), client_capabilities()).unwrap())
+}
+
+#[test]
+fn commands_and_replies() {
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server(server_end, |command| match *command {
+        Command::CompleteJob(_) => Some(
+            encode_message::<Result<(), _>>(
+                &Err(CompleteJobFailure::PartialComplete),
+                client_capabilities(),
+            )
+            .unwrap(),
+        ),
+        Command::CancelJob(_) => Some(
+            encode_message::<Result<(), _>>(
+                &Err(CancelJobFailure::GetJob(HResultMessage {
+                    hr: -1,
+                    message: "failed".to_string(),
+                })),
+                client_capabilities(),
+            )
+            .unwrap(),
+        ),
+        _ => ok_reply(),
+    });
+
+    let mut client =
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).unwrap();
+    let guid = test_guid();
+
+    assert!(client.suspend_job(guid.clone()).unwrap().is_ok());
+    assert!(client.resume_job(guid.clone()).unwrap().is_ok());
+    assert!(client
+        .set_job_priority(guid.clone(), BitsJobPriority::Low)
+        .unwrap()
+        .is_ok());
+    assert!(client
+        .set_update_interval(guid.clone(), None, 500)
+        .unwrap()
+        .is_ok());
+    assert!(client
+        .stop_update(guid.clone(), Some(MonitorSubscription(3)))
+        .unwrap()
+        .is_ok());
+    match client.complete_job(guid.clone()).unwrap() {
+        Err(CompleteJobFailure::PartialComplete) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    match client.cancel_job(guid.clone()).unwrap() {
+        Err(CancelJobFailure::GetJob(HResultMessage { hr: -1, .. })) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    drop(client);
+    let received = server.join().unwrap();
+    assert_eq!(received.len(), 7);
+    match received[3] {
+        Command::SetUpdateInterval(SetUpdateIntervalCommand {
+            ref guid,
+            subscription: None,
+            interval_millis: 500,
+        }) => assert_eq!(guid, &test_guid()),
+        ref command => panic!("unexpected command {:?}", command),
+    }
+    match received[4] {
+        Command::StopUpdate(StopUpdateCommand {
+            subscription: Some(MonitorSubscription(3)),
+            ..
+        }) => {}
+        ref command => panic!("unexpected command {:?}", command),
+    }
+}
+
+#[test]
+fn slow_body() {
+    let (mut client_end, mut server_end) = memory_pair();
+    let message = encode_message(&7u8, client_capabilities()).unwrap();
+    server_end.send(&message[..HEADER_SIZE]).unwrap();
+    let sender = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        server_end.send(&message[HEADER_SIZE..]).unwrap();
+        server_end
+    });
+
+    // The header is already there, but the body isn't, and the timeout is up.
+    let value: u8 = super::recv_message(
+        &mut client_end,
+        Duration::from_millis(0),
+        client_capabilities(),
+    )
+    .unwrap();
+    assert_eq!(value, 7);
+
+    sender.join().unwrap();
+}
+
+#[test]
+fn monitor() {
+    let pipes = MemoryPipes::new();
+    let mut server_pipes = pipes.clone();
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server(server_end, move |command| match *command {
+        Command::StartJob(StartJobCommand {
+            monitor: Some(ref monitor),
+            ..
+        }) => {
+            // Queue up a status and an error for the monitor, then close its channel.
+            let mut channel = server_pipes.listen(&monitor.pipe_name).unwrap();
+            channel
+                .send(
+                    &encode_message::<Result<_, HResultMessage>>(
+                        &Ok(test_status()),
+                        client_capabilities(),
+                    )
+                    .unwrap(),
+                )
+                .unwrap();
+            channel
+                .send(
+                    &encode_message::<Result<JobStatus, _>>(
+                        &Err(HResultMessage {
+                            hr: -1,
+                            message: "failed".to_string(),
+                        }),
+                        client_capabilities(),
+                    )
+                    .unwrap(),
+                )
+                .unwrap();
+            Some(
+                encode_message::<Result<_, StartJobFailure>>(
+                    &Ok((
+                        StartJobSuccess { guid: test_guid() },
+                        MonitorSubscription(5),
+                    )),
+                    client_capabilities(),
+                )
+                .unwrap(),
+            )
+        }
+        _ => None,
+    });
+
+    let mut client = BitsClient::new_local_service(client_end, pipes, TIMEOUT_MILLIS).unwrap();
+    let (success, mut monitor) = client
+        .start_job(
+            "http://example.com/file".into(),
+            "file".into(),
+            BitsProxyUsage::Preconfig,
+            1000,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(success.guid, test_guid());
+    assert_eq!(monitor.subscription(), MonitorSubscription(5));
+
+    let status = monitor.get_status(TIMEOUT_MILLIS).unwrap().unwrap();
+    assert_eq!(status.state, BitsJobState::Transferring);
+    assert_eq!(status.bytes_per_second, Some(5));
+    assert_eq!(status.estimated_remaining, Some(Duration::from_secs(18)));
+    match monitor.get_status(TIMEOUT_MILLIS).unwrap() {
+        Err(HResultMessage { hr: -1, .. }) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // The monitor stops after an error.
+    assert_eq!(
+        monitor.get_status(TIMEOUT_MILLIS).unwrap_err(),
+        Error::NotConnected
+    );
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn monitor_not_created() {
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server(server_end, |_| {
+        Some(
+            encode_message::<Result<_, MonitorJobFailure>>(
+                &Ok(MonitorSubscription(0)),
+                client_capabilities(),
+            )
+            .unwrap(),
+        )
+    });
+
+    // The server claims success without creating the channel.
+    let mut client =
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).unwrap();
+    let mut monitor = client.monitor_job(test_guid(), 1000).unwrap().unwrap();
+    assert_eq!(
+        monitor.get_status(TIMEOUT_MILLIS).unwrap_err(),
+        Error::NotConnected
+    );
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn timeout_disconnects() {
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server(server_end, |_| None);
+
+    let mut client = BitsClient::new_local_service(client_end, MemoryPipes::new(), 100).unwrap();
+    assert_eq!(client.suspend_job(test_guid()).unwrap_err(), Error::Timeout);
+    // A late reply could be mistaken for the reply to the next command, so give up.
+    assert_eq!(
+        client.resume_job(test_guid()).unwrap_err(),
+        Error::NotConnected
+    );
+
+    drop(client);
+    assert_eq!(server.join().unwrap().len(), 1);
+}
+
+#[test]
+fn server_gone() {
+    let (client_end, server_end) = memory_pair();
+    drop(server_end);
+
+    assert_eq!(
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).err(),
+        Some(Error::NotConnected)
+    );
+}
+
+#[test]
+fn short_write() {
+    // Writes the `Hello` reply into `replies`, then reports that it wrote one byte less than
+    // requested.
+    struct ShortWrite {
+        replies: MemoryTransport,
+        writes: usize,
+    }
+
+    impl Transport for ShortWrite {
+        fn send(&mut self, data: &[u8]) -> Result<usize, Error> {
+            self.writes += 1;
+            if self.writes == 1 {
+                Ok(data.len())
+            } else {
+                Ok(data.len() - 1)
+            }
+        }
+
+        fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), Error> {
+            self.replies.recv(buf, timeout)
+        }
+    }
+
+    let (replies, mut server_end) = memory_pair();
+    server_end
+        .send(&encode_message(&Hello::new(Capabilities::MONITORS), client_capabilities()).unwrap())
+        .unwrap();
+
+    let expected_len = encode_message(
+        &Command::SuspendJob(SuspendJobCommand { guid: test_guid() }),
+        client_capabilities(),
+    )
+    .unwrap()
+    .len();
+
+    let mut client = BitsClient::new_local_service(
+        ShortWrite { replies, writes: 0 },
+        MemoryPipes::new(),
+        TIMEOUT_MILLIS,
+    )
+    .unwrap();
+    assert_eq!(
+        client.suspend_job(test_guid()).unwrap_err(),
+        Error::WriteCount(expected_len, expected_len as u32 - 1)
+    );
+}
+
+#[test]
+fn handshake() {
+    // An older server, without monitors.
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server_with_hello(server_end, Hello::new(Capabilities::empty()), |_| {
+        ok_reply()
+    });
+
+    let mut client =
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).unwrap();
+    assert_eq!(client.capabilities(), Capabilities::empty());
+    match client.monitor_job(test_guid(), 1000).unwrap() {
+        Err(MonitorJobFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+    // Nor multi-file jobs.
+    match client
+        .start_job_multi(
+            vec![(OsString::from("url"), OsString::from("file"))],
+            BitsProxyUsage::Preconfig,
+            1000,
+        )
+        .unwrap()
+    {
+        Err(StartJobFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+    // Nor uploads.
+    match client
+        .start_upload_job(
+            OsString::from("url"),
+            OsString::from("file"),
+            None,
+            BitsProxyUsage::Preconfig,
+            1000,
+        )
+        .unwrap()
+    {
+        Err(StartJobFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+    // Nor custom headers, credentials, choosing the priority, job settings, byte ranges, or
+    // hash verification.
+    let credentials = Credentials {
+        target: BitsAuthTarget::Server,
+        scheme: BitsAuthScheme::Basic,
+        user_name: OsString::from("user"),
+        password: OsString::from("password"),
+    };
+    for options in &[
+        StartJobOptions {
+            headers: vec![("X-Test".to_string(), "1".to_string())],
+            ..StartJobOptions::default()
+        },
+        StartJobOptions {
+            credentials: Some(credentials),
+            ..StartJobOptions::default()
+        },
+        StartJobOptions {
+            priority: Some(BitsJobPriority::Normal),
+            ..StartJobOptions::default()
+        },
+        StartJobOptions {
+            settings: JobSettings {
+                minimum_retry_delay_secs: Some(10),
+                ..JobSettings::default()
+            },
+            ..StartJobOptions::default()
+        },
+        StartJobOptions {
+            ranges: vec![BitsFileRange {
+                offset: 0,
+                length: None,
+            }],
+            ..StartJobOptions::default()
+        },
+        StartJobOptions {
+            expected: vec![Some(ExpectedFile {
+                size: 0,
+                sha256: [0; 32],
+            })],
+            ..StartJobOptions::default()
+        },
+    ] {
+        match client
+            .start_job_with_options(
+                OsString::from("url"),
+                OsString::from("file"),
+                BitsProxyUsage::Preconfig,
+                options.clone(),
+                1000,
+            )
+            .unwrap()
+        {
+            Err(StartJobFailure::Other(_)) => {}
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+    match client
+        .update_job_settings(test_guid(), JobSettings::default())
+        .unwrap()
+    {
+        Err(UpdateJobSettingsFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // Nor listing or cancelling all jobs.
+    match client.list_jobs().unwrap() {
+        Err(ListJobsFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    match client.cancel_all_jobs().unwrap() {
+        Err(ListJobsFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // Nor modification updates.
+    match client
+        .set_modification_updates(test_guid(), None, Some(100))
+        .unwrap()
+    {
+        Err(MonitorJobFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // Nor proxy overrides, or addressing a particular monitor.
+    match client
+        .start_job(
+            OsString::from("url"),
+            OsString::from("file"),
+            BitsProxyUsage::Override {
+                proxies: vec!["proxy".to_string()],
+                bypass: Vec::new(),
+            },
+            1000,
+        )
+        .unwrap()
+    {
+        Err(StartJobFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+    match client
+        .set_update_interval(test_guid(), Some(MonitorSubscription(1)), 100)
+        .unwrap()
+    {
+        Err(SetUpdateIntervalFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // Other commands still work, and leave out what the server doesn't know.
+    assert!(client.suspend_job(test_guid()).unwrap().is_ok());
+    assert!(client
+        .set_update_interval(test_guid(), None, 100)
+        .unwrap()
+        .is_ok());
+
+    drop(client);
+    // The unsupported commands were never sent.
+    assert_eq!(server.join().unwrap().len(), 2);
+
+    // A newer server, with capabilities unknown to the client.
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server_with_hello(
+        server_end,
+        Hello::new(Capabilities::from_bits(1 << 63) | Capabilities::MONITORS),
+        |_| ok_reply(),
+    );
+    let client =
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).unwrap();
+    assert_eq!(client.capabilities(), Capabilities::MONITORS);
+    drop(client);
+    server.join().unwrap();
+
+    // A server with a different protocol version.
+    let (client_end, server_end) = memory_pair();
+    let mut hello = Hello::new(Capabilities::MONITORS);
+    hello.protocol_version += 1;
+    let server = fake_server_with_hello(server_end, hello, |_| ok_reply());
+    assert_eq!(
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).err(),
+        Some(Error::Protocol(DecodeError::UnsupportedVersion(
+            PROTOCOL_VERSION + 1
+        )))
+    );
+    server.join().unwrap();
+}
+
+#[test]
+fn invalid_reply() {
+    let (client_end, server_end) = memory_pair();
+    let server = fake_server(server_end, |_| {
+        Some(encode_message(&7u8, client_capabilities()).unwrap())
+    });
+
+    let mut client =
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).unwrap();
+    assert_eq!(
+        client.suspend_job(test_guid()).unwrap_err(),
+        Error::Protocol(DecodeError::InvalidTag("Result", 7))
+    );
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket() {
+    use std::os::unix::net::UnixStream;
+
+    let (client_end, server_end) = UnixStream::pair().unwrap();
+    let server = fake_server(server_end, |_| ok_reply());
+
+    let mut client =
+        BitsClient::new_local_service(client_end, MemoryPipes::new(), TIMEOUT_MILLIS).unwrap();
+    assert!(client.suspend_job(test_guid()).unwrap().is_ok());
+
+    drop(client);
+    assert_eq!(server.join().unwrap().len(), 1);
+}
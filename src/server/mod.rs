@@ -0,0 +1,425 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The server side of the Local Service client.
+//!
+//! A [`CommandDispatcher`](struct.CommandDispatcher.html) executes the commands sent by a
+//! [`BitsClient::new_local_service()`](../enum.BitsClient.html#method.new_local_service) client.
+//! It works just like the in-process client, on behalf of the remote one: it can only
+//! manipulate jobs with its own `job_name`, and only saves files under its own
+//! `save_path_prefix`.
+//!
+//! A connection starts with each side sending a `Hello`, with its protocol version and
+//! `Capabilities`.
+//!
+//! Each monitor streams its status reports over the channel named by its `MonitorConfig`, from a
+//! thread of its own, until it is stopped by `StopUpdate`, `CompleteJob` or `CancelJob`.
+
+use std::cmp;
+use std::ffi;
+use std::thread;
+use std::time::Duration;
+
+use backend::{DefaultBackend, JobBackend};
+use bits_protocol::wire::{
+    decode_any_header, decode_value, encode_command_failure, encode_message, DecodeError, Encode,
+    Writer, HEADER_SIZE, PROTOCOL_VERSION,
+};
+use bits_protocol::*;
+use in_process::{self, InProcessClient, InProcessMonitor};
+use transport::{MonitorListener, Transport};
+use types::hresult::E_FAIL;
+use types::Guid;
+
+use super::Error;
+
+// How long a connection may be idle between commands.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+// How long the rest of a command may take to arrive once its header has been received.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The reply to a [`Command`](../bits_protocol/enum.Command.html), with the result type for
+/// that command.
+#[derive(Debug)]
+pub enum Reply {
+    StartJob(Result<(StartJobSuccess, MonitorSubscription), StartJobFailure>),
+    MonitorJob(Result<MonitorSubscription, MonitorJobFailure>),
+    SuspendJob(Result<(), SuspendJobFailure>),
+    ResumeJob(Result<(), ResumeJobFailure>),
+    SetJobPriority(Result<(), SetJobPriorityFailure>),
+    SetUpdateInterval(Result<(), SetUpdateIntervalFailure>),
+    StopUpdate(Result<(), SetUpdateIntervalFailure>),
+    CompleteJob(Result<(), CompleteJobFailure>),
+    CancelJob(Result<(), CancelJobFailure>),
+    StartJobMulti(Result<(StartJobSuccess, MonitorSubscription), StartJobFailure>),
+    StartUploadJob(Result<(StartJobSuccess, MonitorSubscription), StartJobFailure>),
+    UpdateJobSettings(Result<(), UpdateJobSettingsFailure>),
+    ListJobs(Result<Vec<(Guid, JobStatus)>, ListJobsFailure>),
+    CancelAllJobs(Result<CancelAllJobsSuccess, ListJobsFailure>),
+    SetModificationUpdates(Result<(), MonitorJobFailure>),
+}
+
+// The client knows which command it sent, so only the result goes on the wire.
+impl Encode for Reply {
+    fn encode(&self, buf: &mut Writer) {
+        use self::Reply::*;
+        match *self {
+            StartJob(ref result) => result.encode(buf),
+            MonitorJob(ref result) => result.encode(buf),
+            SuspendJob(ref result) => result.encode(buf),
+            ResumeJob(ref result) => result.encode(buf),
+            SetJobPriority(ref result) => result.encode(buf),
+            SetUpdateInterval(ref result) => result.encode(buf),
+            StopUpdate(ref result) => result.encode(buf),
+            CompleteJob(ref result) => result.encode(buf),
+            CancelJob(ref result) => result.encode(buf),
+            StartJobMulti(ref result) => result.encode(buf),
+            StartUploadJob(ref result) => result.encode(buf),
+            UpdateJobSettings(ref result) => result.encode(buf),
+            ListJobs(ref result) => result.encode(buf),
+            CancelAllJobs(ref result) => result.encode(buf),
+            SetModificationUpdates(ref result) => result.encode(buf),
+        }
+    }
+}
+
+impl Reply {
+    // A failure with `message`, in place of this reply.
+    fn into_failure(self, message: String) -> Reply {
+        use self::Reply::*;
+        match self {
+            StartJob(_) => StartJob(Err(StartJobFailure::Other(message))),
+            MonitorJob(_) => MonitorJob(Err(MonitorJobFailure::Other(message))),
+            SuspendJob(_) => SuspendJob(Err(SuspendJobFailure::Other(message))),
+            ResumeJob(_) => ResumeJob(Err(ResumeJobFailure::Other(message))),
+            SetJobPriority(_) => SetJobPriority(Err(SetJobPriorityFailure::Other(message))),
+            SetUpdateInterval(_) => {
+                SetUpdateInterval(Err(SetUpdateIntervalFailure::Other(message)))
+            }
+            StopUpdate(_) => StopUpdate(Err(SetUpdateIntervalFailure::Other(message))),
+            CompleteJob(_) => CompleteJob(Err(CompleteJobFailure::Other(message))),
+            CancelJob(_) => CancelJob(Err(CancelJobFailure::Other(message))),
+            StartJobMulti(_) => StartJobMulti(Err(StartJobFailure::Other(message))),
+            StartUploadJob(_) => StartUploadJob(Err(StartJobFailure::Other(message))),
+            UpdateJobSettings(_) => {
+                UpdateJobSettings(Err(UpdateJobSettingsFailure::Other(message)))
+            }
+            ListJobs(_) => ListJobs(Err(ListJobsFailure::Other(message))),
+            CancelAllJobs(_) => CancelAllJobs(Err(ListJobsFailure::Other(message))),
+            SetModificationUpdates(_) => {
+                SetModificationUpdates(Err(MonitorJobFailure::Other(message)))
+            }
+        }
+    }
+}
+
+// Stream status reports from `monitor` over `channel`, encoded with `capabilities`, until the
+// monitor is stopped, it reports an error, or the client goes away.
+fn stream_monitor<B: JobBackend>(
+    mut monitor: InProcessMonitor<B>,
+    mut channel: Box<dyn Transport>,
+    capabilities: Capabilities,
+) {
+    // No timeout is needed, the monitor is stopped by `stop_update()`, `complete_job()` or
+    // `cancel_job()`.
+    while let Ok(status) = monitor.get_status(u32::MAX) {
+        let mut last = status.is_err();
+        let message = match encode_message(&status, capabilities) {
+            Ok(message) => message,
+            // Too long for the client to accept, so report an error in its place.
+            Err(e) => {
+                last = true;
+                let status: Result<JobStatus, _> = Err(HResultMessage {
+                    hr: E_FAIL,
+                    message: format!("The status report is too large to send: {}", e),
+                });
+                match encode_message(&status, capabilities) {
+                    Ok(message) => message,
+                    Err(_) => return,
+                }
+            }
+        };
+        match channel.send(&message) {
+            Ok(written) if written == message.len() => {}
+            _ => return,
+        }
+        if last {
+            return;
+        }
+    }
+}
+
+/// Executes commands from a Local Service client.
+pub struct CommandDispatcher<B: JobBackend = DefaultBackend> {
+    client: InProcessClient<B>,
+    monitor_listener: Box<dyn MonitorListener>,
+    // Supported by both the client and the server, once `serve()` has exchanged `Hello`s.
+    capabilities: Capabilities,
+}
+
+impl<B: JobBackend> CommandDispatcher<B> {
+    /// Create a `CommandDispatcher` which runs jobs with `backend`.
+    ///
+    /// `job_name` and `save_path_prefix` are as for
+    /// [`BitsClient::with_backend()`](../enum.BitsClient.html#method.with_backend).
+    ///
+    /// Monitor channels are created with `monitor_listener`.
+    pub fn new<M: MonitorListener + 'static>(
+        backend: B,
+        job_name: ffi::OsString,
+        save_path_prefix: ffi::OsString,
+        monitor_listener: M,
+    ) -> Result<CommandDispatcher<B>, Error> {
+        Ok(CommandDispatcher {
+            client: InProcessClient::with_backend(backend, job_name, save_path_prefix)?,
+            monitor_listener: Box::new(monitor_listener),
+            capabilities: in_process::capabilities(),
+        })
+    }
+
+    /// Execute `command`.
+    pub fn dispatch(&mut self, command: Command) -> Reply {
+        match command {
+            Command::StartJob(StartJobCommand {
+                url,
+                save_path,
+                proxy_usage,
+                headers,
+                credentials,
+                priority,
+                settings,
+                ranges,
+                expected,
+                monitor,
+            }) => Reply::StartJob(self.start_job(monitor, |client, interval_millis| {
+                let options = StartJobOptions {
+                    headers,
+                    credentials,
+                    priority,
+                    settings,
+                    ranges,
+                    expected,
+                };
+                client.start_job_with_options(url, save_path, proxy_usage, options, interval_millis)
+            })),
+            Command::StartJobMulti(StartJobMultiCommand {
+                files,
+                proxy_usage,
+                monitor,
+                options,
+            }) => Reply::StartJobMulti(self.start_job(monitor, |client, interval_millis| {
+                let files = files
+                    .into_iter()
+                    .map(|file| (file.url, file.save_path))
+                    .collect();
+                client.start_job_multi_with_options(files, proxy_usage, options, interval_millis)
+            })),
+            Command::StartUploadJob(StartUploadJobCommand {
+                url,
+                upload_path,
+                reply_path,
+                proxy_usage,
+                monitor,
+            }) => Reply::StartUploadJob(self.start_job(monitor, |client, interval_millis| {
+                client.start_upload_job(url, upload_path, reply_path, proxy_usage, interval_millis)
+            })),
+            Command::MonitorJob(cmd) => Reply::MonitorJob(self.monitor_job(cmd)),
+            Command::SuspendJob(cmd) => Reply::SuspendJob(self.client.suspend_job(cmd.guid)),
+            Command::ResumeJob(cmd) => Reply::ResumeJob(self.client.resume_job(cmd.guid)),
+            Command::SetJobPriority(cmd) => {
+                Reply::SetJobPriority(self.client.set_job_priority(cmd.guid, cmd.priority))
+            }
+            Command::UpdateJobSettings(cmd) => {
+                Reply::UpdateJobSettings(self.client.update_job_settings(cmd.guid, cmd.settings))
+            }
+            Command::SetUpdateInterval(cmd) => Reply::SetUpdateInterval(
+                self.client
+                    .set_update_interval(cmd.guid, cmd.subscription, cmd.interval_millis),
+            ),
+            Command::StopUpdate(cmd) => {
+                Reply::StopUpdate(self.client.stop_update(cmd.guid, cmd.subscription))
+            }
+            // A Local Service client may send any interval, so it is raised to the floor as soon
+            // as it is received.
+            Command::SetModificationUpdates(cmd) => Reply::SetModificationUpdates(
+                self.client.set_modification_updates(
+                    cmd.guid,
+                    cmd.subscription,
+                    cmd.min_interval_millis
+                        .map(|millis| cmp::max(millis, MIN_MODIFICATION_INTERVAL_MILLIS)),
+                ),
+            ),
+            Command::CompleteJob(cmd) => Reply::CompleteJob(self.complete_job(cmd.guid)),
+            Command::CancelJob(cmd) => Reply::CancelJob(self.client.cancel_job(cmd.guid)),
+            Command::ListJobs(_) => Reply::ListJobs(self.client.list_jobs()),
+            Command::CancelAllJobs(_) => Reply::CancelAllJobs(self.client.cancel_all_jobs()),
+        }
+    }
+
+    // Start a job with `start`, passing it the monitor interval, and stream its status over the
+    // channel given by `monitor`, if any.
+    fn start_job<F>(
+        &mut self,
+        monitor: Option<MonitorConfig>,
+        start: F,
+    ) -> Result<(StartJobSuccess, MonitorSubscription), StartJobFailure>
+    where
+        F: FnOnce(
+            &mut InProcessClient<B>,
+            u32,
+        ) -> Result<(StartJobSuccess, InProcessMonitor<B>), StartJobFailure>,
+    {
+        let channel = match monitor {
+            Some(ref config) => Some(
+                self.monitor_listener
+                    .listen(&config.pipe_name)
+                    .map_err(|e| StartJobFailure::Other(monitor_channel_error(&e)))?,
+            ),
+            None => None,
+        };
+        let interval_millis = monitor
+            .as_ref()
+            .map_or(u32::MAX, |config| config.interval_millis);
+
+        let (success, job_monitor) = start(&mut self.client, interval_millis)?;
+        let subscription = job_monitor.subscription();
+
+        match channel {
+            Some(channel) => {
+                let capabilities = self.capabilities;
+                thread::spawn(move || stream_monitor(job_monitor, channel, capabilities));
+            }
+            None => {
+                let _ = self
+                    .client
+                    .stop_update(success.guid.clone(), Some(subscription));
+            }
+        }
+        Ok((success, subscription))
+    }
+
+    fn monitor_job(
+        &mut self,
+        cmd: MonitorJobCommand,
+    ) -> Result<MonitorSubscription, MonitorJobFailure> {
+        let channel = self
+            .monitor_listener
+            .listen(&cmd.monitor.pipe_name)
+            .map_err(|e| MonitorJobFailure::Other(monitor_channel_error(&e)))?;
+
+        let job_monitor = self
+            .client
+            .monitor_job(cmd.guid, cmd.monitor.interval_millis)?;
+        let subscription = job_monitor.subscription();
+
+        let capabilities = self.capabilities;
+        thread::spawn(move || stream_monitor(job_monitor, channel, capabilities));
+        Ok(subscription)
+    }
+
+    // A client without `HASH_VERIFICATION` can't decode `HashMismatch`, which it can only get
+    // for a job that another client started.
+    fn complete_job(&mut self, guid: Guid) -> Result<(), CompleteJobFailure> {
+        match self.client.complete_job(guid) {
+            Err(CompleteJobFailure::HashMismatch(ref local_name))
+                if !self.capabilities.contains(Capabilities::HASH_VERIFICATION) =>
+            {
+                Err(CompleteJobFailure::Other(format!(
+                    "{:?} does not have the expected size and hash",
+                    local_name
+                )))
+            }
+            result => result,
+        }
+    }
+
+    /// Exchange `Hello`s with a client on `transport`, then execute the commands it sends and
+    /// send back the replies, until the client disconnects.
+    ///
+    /// Only a client with another framing is rejected, after it has been sent the server's
+    /// `Hello` so it can tell why. Otherwise the optional features used are those that both
+    /// support.
+    ///
+    /// Returns `Ok(())` when the client disconnects, or `Err` if the connection failed or a
+    /// message could not be decoded. A command which could not be decoded is first answered
+    /// with an `Other` failure, so the client learns why.
+    pub fn serve<T: Transport + ?Sized>(&mut self, transport: &mut T) -> Result<(), Error> {
+        let hello: Hello = match recv_body(transport)? {
+            Some((_, body)) => decode_value(&body, Capabilities::empty())?,
+            None => return Ok(()),
+        };
+        send_message(
+            transport,
+            &Hello::new(in_process::capabilities()),
+            Capabilities::empty(),
+        )?;
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(hello.protocol_version).into());
+        }
+        self.capabilities = hello.capabilities & in_process::capabilities();
+
+        while let Some((version, body)) = recv_body(transport)? {
+            if version != PROTOCOL_VERSION {
+                return Err(DecodeError::UnsupportedVersion(version).into());
+            }
+            let command = match decode_value(&body, self.capabilities) {
+                Ok(command) => command,
+                Err(e) => {
+                    // Tell the client why, if it can tell which command this was, before giving
+                    // up on the connection.
+                    let message = format!("The server could not decode the command: {}", e);
+                    if let Some(reply) = encode_command_failure(&body, message, self.capabilities) {
+                        let _ = transport.send(&reply);
+                    }
+                    return Err(e.into());
+                }
+            };
+            let reply = self.dispatch(command);
+            match send_message(transport, &reply, self.capabilities) {
+                // Nothing was sent, and the client would reject a reply this long, so send a
+                // failure in its place.
+                Err(Error::Protocol(e @ DecodeError::TooLarge(_))) => {
+                    let message = format!("The reply is too large to send: {}", e);
+                    send_message(transport, &reply.into_failure(message), self.capabilities)?;
+                }
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Receive the body of a message, still to be decoded, with the version of its header, or `None`
+// if the client has disconnected.
+fn recv_body<T: Transport + ?Sized>(transport: &mut T) -> Result<Option<(u32, Vec<u8>)>, Error> {
+    let mut header = [0u8; HEADER_SIZE];
+    match transport.recv(&mut header, IDLE_TIMEOUT) {
+        Err(Error::NotConnected) => return Ok(None),
+        result => result?,
+    }
+    let (version, len) = decode_any_header(&header)?;
+    let mut body = vec![0u8; len as usize];
+    transport.recv(&mut body, MESSAGE_TIMEOUT)?;
+    Ok(Some((version, body)))
+}
+
+fn send_message<T: Transport + ?Sized, V: Encode>(
+    transport: &mut T,
+    value: &V,
+    capabilities: Capabilities,
+) -> Result<(), Error> {
+    let message = encode_message(value, capabilities)?;
+    let written = transport.send(&message)?;
+    if written != message.len() {
+        return Err(Error::WriteCount(message.len(), written as u32));
+    }
+    Ok(())
+}
+
+fn monitor_channel_error(e: &Error) -> String {
+    format!("Failed to create the monitor channel: {}", e)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,826 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Tests of the command dispatcher, run with the simulated service.
+
+#![cfg(test)]
+extern crate tempdir;
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::tempdir::TempDir;
+use super::{CommandDispatcher, Reply};
+use backend::simulated::Response;
+use backend::{BackendConnection, BackendJob, SimulatedBits};
+use bits_protocol::wire::{decode_header, decode_value, encode_message, DecodeError, HEADER_SIZE};
+use bits_protocol::*;
+use transport::{memory_pair, MemoryPipes, MemoryTransport, Transport};
+use {
+    BitsAuthScheme, BitsAuthTarget, BitsClient, BitsFileRange, BitsJobPriority, BitsJobState,
+    BitsProxyUsage, Error, Guid,
+};
+
+const JOB_NAME: &str = "CommandDispatcher Test";
+// Nothing is served here, the jobs in these tests are never expected to transfer.
+const URL: &str = "http://unserved.simulated/file";
+
+fn dispatcher(
+    bits: &SimulatedBits,
+    tmp_dir: &TempDir,
+    pipes: &MemoryPipes,
+) -> CommandDispatcher<SimulatedBits> {
+    CommandDispatcher::new(
+        bits.clone(),
+        OsString::from(JOB_NAME),
+        tmp_dir.path().as_os_str().to_os_string(),
+        pipes.clone(),
+    )
+    .unwrap()
+}
+
+fn start_job_command(save_path: &str) -> Command {
+    Command::StartJob(StartJobCommand {
+        url: OsString::from(URL),
+        save_path: OsString::from(save_path),
+        proxy_usage: BitsProxyUsage::Preconfig,
+        headers: Vec::new(),
+        credentials: None,
+        priority: None,
+        settings: JobSettings::default(),
+        ranges: Vec::new(),
+        expected: Vec::new(),
+        monitor: None,
+    })
+}
+
+#[test]
+fn start_job() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &MemoryPipes::new());
+
+    let guid = match dispatcher.dispatch(start_job_command("file")) {
+        Reply::StartJob(Ok((StartJobSuccess { guid }, _))) => guid,
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+
+    let job = bits
+        .find_job_by_guid_and_name(&guid, OsStr::new(JOB_NAME))
+        .unwrap();
+    assert!(job.is_some());
+
+    match dispatcher.dispatch(Command::CancelJob(CancelJobCommand { guid })) {
+        Reply::CancelJob(Ok(())) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn start_job_validation() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+
+    let mut command = start_job_command("../outside");
+    if let Command::StartJob(ref mut cmd) = command {
+        cmd.monitor = Some(MonitorConfig {
+            pipe_name: OsString::from("monitor"),
+            interval_millis: 1000,
+        });
+    }
+    match dispatcher.dispatch(command) {
+        Reply::StartJob(Err(StartJobFailure::ArgumentValidation(_))) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn start_job_headers() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &MemoryPipes::new());
+
+    let header = |name: &str, value: &str| (name.to_string(), value.to_string());
+    let start = |dispatcher: &mut CommandDispatcher<_>, headers| {
+        let mut command = start_job_command("file");
+        if let Command::StartJob(ref mut cmd) = command {
+            cmd.headers = headers;
+        }
+        dispatcher.dispatch(command)
+    };
+
+    for headers in &[
+        vec![header("X-Test", "1\r\nX-Injected: 2")],
+        vec![header("X-Test", "1\n")],
+        vec![header("X-Test: 1\r\nX-Injected", "2")],
+        vec![header("", "1")],
+        vec![header("X Test", "1")],
+        vec![header("X-Test", &"x".repeat(16 * 1024))],
+    ] {
+        match start(&mut dispatcher, headers.clone()) {
+            Reply::StartJob(Err(StartJobFailure::ArgumentValidation(_))) => {}
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    let guid = match start(
+        &mut dispatcher,
+        vec![
+            header("X-Test", "1"),
+            header("Authorization", "Bearer\tabc"),
+        ],
+    ) {
+        Reply::StartJob(Ok((StartJobSuccess { guid }, _))) => guid,
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    match dispatcher.dispatch(Command::CancelJob(CancelJobCommand { guid })) {
+        Reply::CancelJob(Ok(())) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn start_job_credentials() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &MemoryPipes::new());
+
+    let password = "hunter2";
+    for &(scheme, user_name) in &[
+        (BitsAuthScheme::Basic, ""),
+        (BitsAuthScheme::Basic, "user:name"),
+        (BitsAuthScheme::Ntlm, "user\0name"),
+    ] {
+        let mut command = start_job_command("file");
+        if let Command::StartJob(ref mut cmd) = command {
+            cmd.credentials = Some(Credentials {
+                target: BitsAuthTarget::Server,
+                scheme,
+                user_name: OsString::from(user_name),
+                password: OsString::from(password),
+            });
+        }
+        match dispatcher.dispatch(command) {
+            Reply::StartJob(Err(StartJobFailure::ArgumentValidation(ref message))) => {
+                assert!(!message.contains(password));
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+}
+
+#[test]
+fn start_job_proxy_override() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &MemoryPipes::new());
+
+    let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let mut start = |proxies: &[&str], bypass: &[&str]| {
+        let mut command = start_job_command("file");
+        if let Command::StartJob(ref mut cmd) = command {
+            cmd.proxy_usage = BitsProxyUsage::Override {
+                proxies: strings(proxies),
+                bypass: strings(bypass),
+            };
+        }
+        dispatcher.dispatch(command)
+    };
+
+    for &(proxies, bypass) in &[
+        (&[][..], &[][..]),
+        (&["proxy.example.com:8080 other"][..], &[][..]),
+        (&["proxy.example.com:0"][..], &[][..]),
+        (&["proxy.example.com:port"][..], &[][..]),
+        (&["ftp=proxy.example.com"][..], &[][..]),
+        (&["socks://proxy.example.com"][..], &[][..]),
+        (&["::1"][..], &[][..]),
+        (&[""][..], &[][..]),
+        (&["proxy"][..], &["*.example.com;other"][..]),
+        (&["proxy"][..], &[""][..]),
+    ] {
+        match start(proxies, bypass) {
+            Reply::StartJob(Err(StartJobFailure::ArgumentValidation(_))) => {}
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    // Valid lists are passed to the service.
+    match start(
+        &[
+            "proxy.example.com:8080",
+            "https=https://secure.example.com",
+            "[::1]:3128",
+            "[::1]",
+        ],
+        &["<local>", "*.example.com", "10.0.0.*"],
+    ) {
+        Reply::StartJob(Ok(_)) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn start_job_ranges() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &MemoryPipes::new());
+
+    let range = |offset, length| BitsFileRange { offset, length };
+    let mut start = |ranges: &[BitsFileRange]| {
+        let mut command = start_job_command("file");
+        if let Command::StartJob(ref mut cmd) = command {
+            cmd.ranges = ranges.to_vec();
+        }
+        dispatcher.dispatch(command)
+    };
+
+    for ranges in &[
+        &[range(0, Some(0))][..],
+        &[range(100, Some(10)), range(0, Some(10))][..],
+        &[range(0, Some(10)), range(5, Some(10))][..],
+        &[range(0, None), range(100, Some(10))][..],
+        &[range(u64::MAX - 1, Some(2))][..],
+    ] {
+        match start(ranges) {
+            Reply::StartJob(Err(StartJobFailure::ArgumentValidation(_))) => {}
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    // Valid ranges are passed to the service.
+    match start(&[range(0, Some(10)), range(10, Some(5)), range(1 << 20, None)]) {
+        Reply::StartJob(Ok(_)) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn start_job_multi() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+
+    let job_file = |url: &str, save_path: &str| JobFile {
+        url: OsString::from(url),
+        save_path: OsString::from(save_path),
+    };
+
+    // Every path is validated before the job is created.
+    let command = Command::StartJobMulti(StartJobMultiCommand {
+        files: vec![job_file(URL, "first"), job_file(URL, "../outside")],
+        proxy_usage: BitsProxyUsage::Preconfig,
+        monitor: None,
+        options: StartJobOptions::default(),
+    });
+    match dispatcher.dispatch(command) {
+        Reply::StartJobMulti(Err(StartJobFailure::ArgumentValidation(_))) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    // There is no file for the second expected file.
+    let command = Command::StartJobMulti(StartJobMultiCommand {
+        files: vec![job_file(URL, "first")],
+        proxy_usage: BitsProxyUsage::Preconfig,
+        monitor: None,
+        options: StartJobOptions {
+            expected: vec![None, None],
+            ..StartJobOptions::default()
+        },
+    });
+    match dispatcher.dispatch(command) {
+        Reply::StartJobMulti(Err(StartJobFailure::ArgumentValidation(_))) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    let command = Command::StartJobMulti(StartJobMultiCommand {
+        files: Vec::new(),
+        proxy_usage: BitsProxyUsage::Preconfig,
+        monitor: None,
+        options: StartJobOptions::default(),
+    });
+    match dispatcher.dispatch(command) {
+        Reply::StartJobMulti(Err(StartJobFailure::ArgumentValidation(_))) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    let (client_end, mut server_end) = memory_pair();
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+    let mut client = BitsClient::new_local_service(client_end, pipes, 10_000).unwrap();
+    let other_url = "http://unserved.simulated/other";
+    let (success, mut monitor) = client
+        .start_job_multi(
+            vec![
+                (OsString::from(URL), OsString::from("first")),
+                (OsString::from(other_url), OsString::from("second")),
+            ],
+            BitsProxyUsage::Preconfig,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+
+    let status = monitor.get_status(1_000).unwrap().unwrap();
+    assert_eq!(status.progress.total_files, 2);
+    assert_eq!(status.url, Some(OsString::from(URL)));
+    let urls: Vec<_> = status.files.iter().map(|file| file.url.clone()).collect();
+    assert_eq!(
+        urls,
+        vec![Some(OsString::from(URL)), Some(OsString::from(other_url))]
+    );
+    let local_names: Vec<_> = status
+        .files
+        .iter()
+        .map(|file| file.local_name.clone())
+        .collect();
+    assert_eq!(
+        local_names,
+        vec![
+            tmp_dir.path().join("first").into_os_string(),
+            tmp_dir.path().join("second").into_os_string(),
+        ]
+    );
+
+    let job = bits
+        .find_job_by_guid_and_name(&success.guid, OsStr::new(JOB_NAME))
+        .unwrap();
+    assert!(job.is_some());
+
+    assert!(client.cancel_job(success.guid).unwrap().is_ok());
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn start_upload_job() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    fs::write(tmp_dir.path().join("upload"), b"uploaded data").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+
+    // Both the uploaded file and the reply must be under the prefix.
+    for &(upload_path, reply_path) in &[("../upload", None), ("upload", Some("../reply"))] {
+        let command = Command::StartUploadJob(StartUploadJobCommand {
+            url: OsString::from(URL),
+            upload_path: OsString::from(upload_path),
+            reply_path: reply_path.map(OsString::from),
+            proxy_usage: BitsProxyUsage::Preconfig,
+            monitor: None,
+        });
+        match dispatcher.dispatch(command) {
+            Reply::StartUploadJob(Err(StartJobFailure::ArgumentValidation(_))) => {}
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    // A server which replies to the upload.
+    let url = "http://upload.simulated/submit";
+    bits.serve(url, Response::ok(b"received"));
+
+    let (client_end, mut server_end) = memory_pair();
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+    let mut client = BitsClient::new_local_service(client_end, pipes, 10_000).unwrap();
+    let (success, mut monitor) = client
+        .start_upload_job(
+            OsString::from(url),
+            OsString::from("upload"),
+            Some(OsString::from("reply")),
+            BitsProxyUsage::Preconfig,
+            10,
+        )
+        .unwrap()
+        .unwrap();
+
+    // The reply is reported once, when the job is transferred.
+    let reply_data = loop {
+        let status = monitor.get_status(10_000).unwrap().unwrap();
+        if status.state == BitsJobState::Transferred {
+            break status.reply_data;
+        }
+        assert!(status.reply_data.is_none());
+    };
+    assert_eq!(reply_data, Some(b"received".to_vec()));
+    let status = monitor.get_status(10_000).unwrap().unwrap();
+    assert!(status.reply_data.is_none());
+
+    assert!(client.complete_job(success.guid).unwrap().is_ok());
+    assert_eq!(fs::read(tmp_dir.path().join("reply")).unwrap(), b"received");
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn client_and_server() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+
+    // One job the server may manipulate, and one it may not.
+    let mut job = bits.create_job(OsStr::new(JOB_NAME)).unwrap();
+    job.add_file(OsStr::new(URL), tmp_dir.path().join("file").as_os_str())
+        .unwrap();
+    let guid = job.guid().unwrap();
+    let other_guid = bits
+        .create_job(OsStr::new("Some other job"))
+        .unwrap()
+        .guid()
+        .unwrap();
+
+    let pipes = MemoryPipes::new();
+    let (client_end, mut server_end) = memory_pair();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+
+    let mut client = BitsClient::new_local_service(client_end, pipes, 10_000).unwrap();
+    assert_eq!(
+        client.capabilities(),
+        Capabilities::MONITORS
+            | Capabilities::MULTI_FILE_JOBS
+            | Capabilities::FILE_NAMES
+            | Capabilities::UPLOAD
+            | Capabilities::CUSTOM_HEADERS
+            | Capabilities::CREDENTIALS
+            | Capabilities::PROXY_OVERRIDE
+            | Capabilities::START_PRIORITY
+            | Capabilities::JOB_SETTINGS
+            | Capabilities::BYTE_RANGES
+            | Capabilities::HASH_VERIFICATION
+            | Capabilities::LIST_JOBS
+            | Capabilities::CANCEL_ALL_JOBS
+            | Capabilities::MODIFICATION_UPDATES
+            | Capabilities::MONITOR_SUBSCRIPTIONS
+            | Capabilities::TRANSFER_RATE
+    );
+
+    assert!(client.resume_job(guid.clone()).unwrap().is_ok());
+    assert!(client.suspend_job(guid.clone()).unwrap().is_ok());
+    match client.suspend_job(other_guid).unwrap() {
+        Err(SuspendJobFailure::NotFound) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    match client.stop_update(guid.clone(), None).unwrap() {
+        Err(SetUpdateIntervalFailure::NotFound) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    // Only the server's own job is listed.
+    let jobs = client.list_jobs().unwrap().unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].0, guid);
+    assert_eq!(jobs[0].1.state, BitsJobState::Suspended);
+    assert_eq!(jobs[0].1.url.as_deref(), Some(OsStr::new(URL)));
+
+    assert!(client.cancel_job(guid.clone()).unwrap().is_ok());
+    match client.cancel_job(guid).unwrap() {
+        Err(CancelJobFailure::NotFound) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert!(client.list_jobs().unwrap().unwrap().is_empty());
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn invalid_command() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let (mut client_end, mut server_end) = memory_pair();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+
+    let timeout = Duration::from_secs(10);
+    let recv = |transport: &mut MemoryTransport| {
+        let mut header = [0u8; HEADER_SIZE];
+        transport.recv(&mut header, timeout).unwrap();
+        let mut body = vec![0u8; decode_header(&header).unwrap() as usize];
+        transport.recv(&mut body, timeout).unwrap();
+        body
+    };
+
+    client_end
+        .send(&encode_message(&Hello::new(Capabilities::empty()), Capabilities::empty()).unwrap())
+        .unwrap();
+    let _: Hello = decode_value(&recv(&mut client_end), Capabilities::empty()).unwrap();
+
+    // A `SuspendJob` with only part of its GUID.
+    let mut command = encode_message(
+        &Command::SuspendJob(SuspendJobCommand {
+            guid: Guid::new_random(),
+        }),
+        Capabilities::empty(),
+    )
+    .unwrap();
+    command.truncate(command.len() - 4);
+    command[4] -= 4;
+    client_end.send(&command).unwrap();
+
+    let reply = recv(&mut client_end);
+    match decode_value::<Result<(), SuspendJobFailure>>(&reply, Capabilities::empty()).unwrap() {
+        Err(SuspendJobFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    match server.join().unwrap() {
+        Err(Error::Protocol(DecodeError::Truncated)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+#[test]
+fn reply_too_large() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+
+    // Together the jobs' URLs are longer than a message may be.
+    let url = format!("{}?{}", URL, "x".repeat(200_000));
+    for i in 0..4 {
+        let mut job = bits.create_job(OsStr::new(JOB_NAME)).unwrap();
+        let save_path = tmp_dir.path().join(format!("file{}", i));
+        job.add_file(OsStr::new(&url), save_path.as_os_str())
+            .unwrap();
+    }
+
+    let pipes = MemoryPipes::new();
+    let (client_end, mut server_end) = memory_pair();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+
+    let mut client = BitsClient::new_local_service(client_end, pipes, 10_000).unwrap();
+    match client.list_jobs().unwrap() {
+        Err(ListJobsFailure::Other(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // The connection is still usable.
+    assert_eq!(client.cancel_all_jobs().unwrap().unwrap().len(), 4);
+    assert!(client.list_jobs().unwrap().unwrap().is_empty());
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn priority() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let (client_end, mut server_end) = memory_pair();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+    let mut client = BitsClient::new_local_service(client_end, pipes, 10_000).unwrap();
+
+    let job_priority = |guid| bits.get_job_by_guid(guid).unwrap().get_priority().unwrap();
+
+    // Jobs are in the foreground unless another priority is chosen.
+    let (success, mut monitor) = client
+        .start_job(
+            OsString::from(URL),
+            OsString::from("first"),
+            BitsProxyUsage::Preconfig,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+    let status = monitor.get_status(1_000).unwrap().unwrap();
+    assert_eq!(status.priority, Some(BitsJobPriority::Foreground));
+    assert!(client.cancel_job(success.guid).unwrap().is_ok());
+
+    let options = StartJobOptions {
+        priority: Some(BitsJobPriority::Low),
+        ..StartJobOptions::default()
+    };
+    let (success, mut monitor) = client
+        .start_job_with_options(
+            OsString::from(URL),
+            OsString::from("second"),
+            BitsProxyUsage::Preconfig,
+            options,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+    let guid = success.guid;
+    let status = monitor.get_status(1_000).unwrap().unwrap();
+    assert_eq!(status.priority, Some(BitsJobPriority::Low));
+    assert_eq!(job_priority(&guid), BitsJobPriority::Low);
+
+    assert!(client
+        .set_job_priority(guid.clone(), BitsJobPriority::High)
+        .unwrap()
+        .is_ok());
+    assert_eq!(job_priority(&guid), BitsJobPriority::High);
+
+    assert!(client.cancel_job(guid).unwrap().is_ok());
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn job_settings() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let (client_end, mut server_end) = memory_pair();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+    let mut client = BitsClient::new_local_service(client_end, pipes, 10_000).unwrap();
+
+    // Nothing is listening at `URL`, so each attempt fails with a transient error.
+    let wait_for_state = |guid: &Guid, state| {
+        let timeout = Instant::now() + Duration::from_secs(10);
+        loop {
+            let status = bits.get_job_by_guid(guid).unwrap().get_status().unwrap();
+            if status.state == state {
+                return status;
+            }
+            assert!(Instant::now() < timeout, "timed out in {:?}", status.state);
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    // With no time allowed without progress, the first transient error is final.
+    let options = StartJobOptions {
+        settings: JobSettings {
+            no_progress_timeout_secs: Some(0),
+            ..JobSettings::default()
+        },
+        ..StartJobOptions::default()
+    };
+    let (success, _monitor) = client
+        .start_job_with_options(
+            OsString::from(URL),
+            OsString::from("first"),
+            BitsProxyUsage::Preconfig,
+            options,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+    let status = wait_for_state(&success.guid, BitsJobState::Error);
+    assert_eq!(status.error_count, 1);
+    assert!(client.cancel_job(success.guid).unwrap().is_ok());
+
+    // Otherwise the job waits a minute to retry, until its settings are changed.
+    let (success, _monitor) = client
+        .start_job(
+            OsString::from(URL),
+            OsString::from("second"),
+            BitsProxyUsage::Preconfig,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+    let guid = success.guid;
+    wait_for_state(&guid, BitsJobState::TransientError);
+    let settings = JobSettings {
+        minimum_retry_delay_secs: Some(0),
+        no_progress_timeout_secs: Some(0),
+        maximum_download_time_secs: None,
+    };
+    assert!(client
+        .update_job_settings(guid.clone(), settings)
+        .unwrap()
+        .is_ok());
+    let status = wait_for_state(&guid, BitsJobState::Error);
+    assert_eq!(status.error_count, 2);
+
+    assert!(client.cancel_job(guid.clone()).unwrap().is_ok());
+    match client.update_job_settings(guid, settings).unwrap() {
+        Err(UpdateJobSettingsFailure::NotFound) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn monitor() {
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let pipes = MemoryPipes::new();
+    let (client_end, mut server_end) = memory_pair();
+    let mut dispatcher = dispatcher(&bits, &tmp_dir, &pipes);
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+
+    let mut client = BitsClient::new_local_service(client_end, pipes.clone(), 10_000).unwrap();
+    let (success, mut monitor) = client
+        .start_job(
+            OsString::from(URL),
+            OsString::from("file"),
+            BitsProxyUsage::Preconfig,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+    let guid = success.guid;
+
+    // The first status is sent immediately, the next not for a minute.
+    monitor.get_status(1_000).unwrap().unwrap();
+    assert!(client
+        .set_update_interval(guid.clone(), Some(monitor.subscription()), 10)
+        .unwrap()
+        .is_ok());
+    // The new interval is used for the next status.
+    monitor.get_status(1_000).unwrap().unwrap();
+
+    // The job can be monitored again, alongside the first monitor.
+    let mut second_monitor = client.monitor_job(guid.clone(), 60_000).unwrap().unwrap();
+    assert_ne!(second_monitor.subscription(), monitor.subscription());
+    second_monitor.get_status(1_000).unwrap().unwrap();
+    monitor.get_status(1_000).unwrap().unwrap();
+
+    // Stopping the first monitor leaves the second one running.
+    assert!(client
+        .stop_update(guid.clone(), Some(monitor.subscription()))
+        .unwrap()
+        .is_ok());
+    loop {
+        // Statuses may have been sent in the meantime.
+        if let Err(e) = monitor.get_status(1_000) {
+            assert_eq!(e, Error::NotConnected);
+            break;
+        }
+    }
+    assert!(client
+        .set_update_interval(guid.clone(), Some(second_monitor.subscription()), 10)
+        .unwrap()
+        .is_ok());
+    second_monitor.get_status(1_000).unwrap().unwrap();
+
+    // Cancelling the job closes its monitor.
+    assert!(client.cancel_job(guid).unwrap().is_ok());
+    assert_eq!(
+        second_monitor.get_status(1_000).unwrap_err(),
+        Error::NotConnected
+    );
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_sockets() {
+    use std::os::unix::net::UnixStream;
+    use transport::UnixSockets;
+
+    let tmp_dir = TempDir::new("CommandDispatcher").unwrap();
+    let bits = SimulatedBits::new();
+    let (client_end, mut server_end) = UnixStream::pair().unwrap();
+    let mut dispatcher = CommandDispatcher::new(
+        bits,
+        OsString::from(JOB_NAME),
+        tmp_dir.path().as_os_str().to_os_string(),
+        UnixSockets::new(tmp_dir.path()),
+    )
+    .unwrap();
+    let server = thread::spawn(move || dispatcher.serve(&mut server_end));
+
+    let mut client =
+        BitsClient::new_local_service(client_end, UnixSockets::new(tmp_dir.path()), 10_000)
+            .unwrap();
+    let (success, mut monitor) = client
+        .start_job(
+            OsString::from(URL),
+            OsString::from("file"),
+            BitsProxyUsage::Preconfig,
+            60_000,
+        )
+        .unwrap()
+        .unwrap();
+    monitor.get_status(1_000).unwrap().unwrap();
+
+    assert!(client.cancel_job(success.guid).unwrap().is_ok());
+    assert_eq!(monitor.get_status(1_000).unwrap_err(), Error::NotConnected);
+
+    // A monitor channel must be named within the directory.
+    let mut command = start_job_command("file");
+    if let Command::StartJob(ref mut cmd) = command {
+        cmd.monitor = Some(MonitorConfig {
+            pipe_name: OsString::from("../monitor"),
+            interval_millis: 1000,
+        });
+    }
+    let mut dispatcher = CommandDispatcher::new(
+        SimulatedBits::new(),
+        OsString::from(JOB_NAME),
+        tmp_dir.path().as_os_str().to_os_string(),
+        UnixSockets::new(tmp_dir.path()),
+    )
+    .unwrap();
+    match dispatcher.dispatch(command) {
+        Reply::StartJob(Err(StartJobFailure::Other(_))) => {}
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
@@ -0,0 +1,198 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! An asynchronous `Stream` of a monitor's status reports.
+//!
+//! An in-process monitor is polled without blocking: it is woken by the same notifications that
+//! wake a blocked `get_status()`, and by a timer when the next report is due, so one thread can
+//! watch many jobs. A Local Service monitor can only receive its reports by blocking, so it gets
+//! a thread of its own which forwards them to the stream.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::Stream;
+use futures_timer::Delay;
+
+use backend::{DefaultBackend, JobBackend, PortableBits};
+use bits_protocol::{HResultMessage, JobStatus};
+use in_process::InProcessMonitor;
+use local_service::LocalServiceMonitor;
+
+use super::BitsMonitorClient;
+
+/// The status reports of a [`BitsMonitorClient`](enum.BitsMonitorClient.html), as a
+/// `futures::Stream`.
+///
+/// Each item is what `get_status()` would have returned in `Ok`. The stream ends when the
+/// monitor is stopped, including after it yields an `Err`.
+///
+/// Created by [`BitsMonitorClient::into_stream()`](enum.BitsMonitorClient.html#method.into_stream).
+pub struct BitsMonitorStream<B: JobBackend = DefaultBackend>(StreamInner<B>);
+
+enum StreamInner<B: JobBackend> {
+    InProcess(InProcessStream<B>),
+    Portable(InProcessStream<PortableBits>),
+    LocalService(mpsc::UnboundedReceiver<Result<JobStatus, HResultMessage>>),
+}
+
+// No field is ever pinned, `Delay` and the receiver are `Unpin` themselves.
+impl<B: JobBackend> Unpin for BitsMonitorStream<B> {}
+
+impl<B: JobBackend> BitsMonitorStream<B> {
+    pub(crate) fn new(monitor: BitsMonitorClient<B>) -> BitsMonitorStream<B> {
+        BitsMonitorStream(match monitor {
+            BitsMonitorClient::InProcess(monitor) => {
+                StreamInner::InProcess(InProcessStream::new(monitor))
+            }
+            BitsMonitorClient::Portable(monitor) => {
+                StreamInner::Portable(InProcessStream::new(monitor))
+            }
+            BitsMonitorClient::LocalService(monitor) => {
+                StreamInner::LocalService(forward_local_service(monitor))
+            }
+        })
+    }
+}
+
+impl<B: JobBackend> Stream for BitsMonitorStream<B> {
+    type Item = Result<JobStatus, HResultMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match self.get_mut().0 {
+            StreamInner::InProcess(ref mut stream) => stream.poll_next(cx),
+            StreamInner::Portable(ref mut stream) => stream.poll_next(cx),
+            StreamInner::LocalService(ref mut receiver) => Pin::new(receiver).poll_next(cx),
+        }
+    }
+}
+
+struct InProcessStream<B: JobBackend> {
+    monitor: InProcessMonitor<B>,
+    // The timer for the next report, and when it is due.
+    timer: Option<(Instant, Delay)>,
+}
+
+impl<B: JobBackend> InProcessStream<B> {
+    fn new(monitor: InProcessMonitor<B>) -> InProcessStream<B> {
+        InProcessStream {
+            monitor,
+            timer: None,
+        }
+    }
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Result<JobStatus, HResultMessage>>> {
+        loop {
+            let due = match self.monitor.poll_due(cx.waker()) {
+                Err(_) => return Poll::Ready(None),
+                Ok(None) => {
+                    self.timer = None;
+                    return Poll::Ready(Some(self.monitor.report_status()));
+                }
+                Ok(Some(due)) => due,
+            };
+
+            // The deadline moves if the interval is changed.
+            if self.timer.as_ref().map(|&(timer_due, _)| timer_due) != Some(due) {
+                let now = Instant::now();
+                let delay = if due > now {
+                    due - now
+                } else {
+                    Duration::from_millis(0)
+                };
+                self.timer = Some((due, Delay::new(delay)));
+            }
+
+            match Pin::new(&mut self.timer.as_mut().unwrap().1).poll(cx) {
+                // The report is due now, check again.
+                Poll::Ready(()) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+// Forward the reports of `monitor` from a thread of its own, until the monitor is stopped or
+// the stream is dropped.
+fn forward_local_service(
+    mut monitor: LocalServiceMonitor,
+) -> mpsc::UnboundedReceiver<Result<JobStatus, HResultMessage>> {
+    let (sender, receiver) = mpsc::unbounded();
+    thread::spawn(move || {
+        while let Ok(status) = monitor.get_status(u32::MAX) {
+            let last = status.is_err();
+            if sender.unbounded_send(status).is_err() || last {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::ffi::OsString;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use self::tempdir::TempDir;
+    use futures::executor::block_on_stream;
+
+    use backend::SimulatedBits;
+    use {BitsClient, BitsJobState, BitsProxyUsage};
+
+    #[test]
+    fn in_process() {
+        let tmp_dir = TempDir::new("BitsMonitorStream").unwrap();
+        let mut client = BitsClient::with_backend(
+            SimulatedBits::new(),
+            OsString::from("BitsMonitorStream Test"),
+            tmp_dir.path().as_os_str().to_os_string(),
+        )
+        .unwrap();
+
+        // Nothing is served here, the job is never expected to transfer.
+        let (success, monitor) = client
+            .start_job(
+                OsString::from("http://unserved.simulated/file"),
+                OsString::from("file"),
+                BitsProxyUsage::Preconfig,
+                100,
+            )
+            .unwrap()
+            .unwrap();
+        let guid = success.guid;
+        client.suspend_job(guid.clone()).unwrap().unwrap();
+
+        let mut stream = block_on_stream(monitor.into_stream());
+
+        // The first report is immediate, the next one waits for the interval.
+        let start = Instant::now();
+        let status = stream.next().unwrap().unwrap();
+        assert_eq!(status.state, BitsJobState::Suspended);
+        stream.next().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Stopping the monitor wakes the stream without waiting for the interval.
+        client
+            .set_update_interval(guid.clone(), None, 60_000)
+            .unwrap()
+            .unwrap();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            client.cancel_job(guid).unwrap().unwrap();
+        });
+        let start = Instant::now();
+        assert!(stream.next().is_none());
+        assert!(start.elapsed() < Duration::from_millis(30_000));
+
+        canceller.join().unwrap();
+    }
+}
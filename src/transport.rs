@@ -0,0 +1,438 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Byte transports connecting a Local Service client to its server.
+//!
+//! A [`Transport`](trait.Transport.html) carries the messages encoded by
+//! [`bits_protocol::wire`](../bits_protocol/wire/index.html). In production this is a Windows
+//! named pipe ([`NamedPipe`](struct.NamedPipe.html)); `TcpStream`, `UnixStream` and the
+//! in-memory [`memory_pair()`](fn.memory_pair.html) can be used elsewhere, e.g. in tests.
+//!
+//! Only the client's end of a named pipe is provided here, the server creating the pipes is
+//! left to the application. `UnixSockets` provides both ends of the monitor channels on Unix.
+//!
+//! Errors are reported as [`PipeError`](../enum.PipeError.html): `NotConnected` if the other end
+//! has gone away, `Timeout` if a read took too long.
+//!
+//! Each monitor streams its status over a channel of its own, named by
+//! [`MonitorConfig::pipe_name`](../bits_protocol/struct.MonitorConfig.html). The server creates
+//! the channel with a [`MonitorListener`](trait.MonitorListener.html) before replying to the
+//! command that started the monitor, then the client connects to it with a
+//! [`MonitorConnector`](trait.MonitorConnector.html).
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::PipeError;
+use types::hresult::{E_FAIL, HRESULT_FROM_WIN32};
+use types::HResult;
+
+#[cfg(windows)]
+pub use self::named_pipe::{NamedPipe, NamedPipes};
+#[cfg(unix)]
+pub use self::unix_socket::UnixSockets;
+
+/// A reliable, ordered byte stream to the other side.
+pub trait Transport: Send {
+    /// Write `data`, returning the number of bytes that were written.
+    fn send(&mut self, data: &[u8]) -> Result<usize, PipeError>;
+
+    /// Fill `buf`, failing with `PipeError::Timeout` if it takes longer than `timeout`.
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), PipeError>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&mut self, data: &[u8]) -> Result<usize, PipeError> {
+        (**self).send(data)
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), PipeError> {
+        (**self).recv(buf, timeout)
+    }
+}
+
+/// Creates monitor channels, on the server side.
+pub trait MonitorListener: Send {
+    /// Create the channel `pipe_name` and return the server's end of it. The client may not
+    /// have connected yet.
+    fn listen(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError>;
+}
+
+/// Connects to monitor channels, on the client side.
+pub trait MonitorConnector: Send {
+    /// Connect to the channel `pipe_name`, which the server has already created.
+    fn connect(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError>;
+}
+
+fn pipe_error(e: &io::Error) -> PipeError {
+    use std::io::ErrorKind::*;
+    match e.kind() {
+        WouldBlock | TimedOut => PipeError::Timeout,
+        UnexpectedEof | BrokenPipe | ConnectionAborted | ConnectionReset | NotConnected => {
+            PipeError::NotConnected
+        }
+        _ => PipeError::Api(match e.raw_os_error() {
+            Some(code) if cfg!(windows) => HResult::new(HRESULT_FROM_WIN32(code as u32)),
+            _ => HResult::new(E_FAIL),
+        }),
+    }
+}
+
+fn write_stream<W: Write>(stream: &mut W, data: &[u8]) -> Result<usize, PipeError> {
+    let mut written = 0;
+    while written < data.len() {
+        match stream.write(&data[written..]) {
+            Ok(0) => break,
+            Ok(count) => written += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(pipe_error(&e)),
+        }
+    }
+    Ok(written)
+}
+
+// Implement `Transport` for a socket type with `set_read_timeout()`.
+macro_rules! socket_transport {
+    ($ty:ty) => {
+        impl Transport for $ty {
+            fn send(&mut self, data: &[u8]) -> Result<usize, PipeError> {
+                write_stream(self, data)
+            }
+
+            fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), PipeError> {
+                let deadline = Instant::now() + timeout;
+                let mut filled = 0;
+                while filled < buf.len() {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(PipeError::Timeout);
+                    }
+                    self.set_read_timeout(Some(deadline - now))
+                        .map_err(|e| pipe_error(&e))?;
+                    match self.read(&mut buf[filled..]) {
+                        Ok(0) => return Err(PipeError::NotConnected),
+                        Ok(count) => filled += count,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(pipe_error(&e)),
+                    }
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+socket_transport!(TcpStream);
+#[cfg(unix)]
+socket_transport!(UnixStream);
+
+/// One end of an in-memory transport, see [`memory_pair()`](fn.memory_pair.html).
+pub struct MemoryTransport {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+/// Create a connected pair of in-memory transports, what is sent on one is received on the
+/// other. Dropping one end disconnects the other.
+pub fn memory_pair() -> (MemoryTransport, MemoryTransport) {
+    let (a_sender, b_receiver) = mpsc::channel();
+    let (b_sender, a_receiver) = mpsc::channel();
+    (
+        MemoryTransport {
+            sender: a_sender,
+            receiver: a_receiver,
+            pending: VecDeque::new(),
+        },
+        MemoryTransport {
+            sender: b_sender,
+            receiver: b_receiver,
+            pending: VecDeque::new(),
+        },
+    )
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, data: &[u8]) -> Result<usize, PipeError> {
+        self.sender
+            .send(data.to_vec())
+            .map_err(|_| PipeError::NotConnected)?;
+        Ok(data.len())
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), PipeError> {
+        let deadline = Instant::now() + timeout;
+        while self.pending.len() < buf.len() {
+            let now = Instant::now();
+            let wait = if now < deadline {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            match self.receiver.recv_timeout(wait) {
+                Ok(data) => self.pending.extend(data),
+                Err(RecvTimeoutError::Timeout) => return Err(PipeError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(PipeError::NotConnected),
+            }
+        }
+        let len = buf.len();
+        for (dest, byte) in buf.iter_mut().zip(self.pending.drain(..len)) {
+            *dest = byte;
+        }
+        Ok(())
+    }
+}
+
+/// In-memory monitor channels, for a client and server in the same process.
+///
+/// Clones share the same channels, so one can be given to each side.
+#[derive(Clone, Default)]
+pub struct MemoryPipes(Arc<Mutex<HashMap<OsString, MemoryTransport>>>);
+
+impl MemoryPipes {
+    pub fn new() -> MemoryPipes {
+        MemoryPipes::default()
+    }
+}
+
+impl MonitorListener for MemoryPipes {
+    fn listen(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError> {
+        let (client_end, server_end) = memory_pair();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(pipe_name.to_os_string(), client_end);
+        Ok(Box::new(server_end))
+    }
+}
+
+impl MonitorConnector for MemoryPipes {
+    fn connect(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError> {
+        match self.0.lock().unwrap().remove(pipe_name) {
+            Some(client_end) => Ok(Box::new(client_end)),
+            None => Err(PipeError::NotConnected),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix_socket {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Component, Path, PathBuf};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{pipe_error, MonitorConnector, MonitorListener, Transport};
+    use types::hresult::E_INVALIDARG;
+    use types::HResult;
+    use PipeError;
+
+    // How long the server waits for the client to connect to a new channel.
+    const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+    // How often the server checks whether the client has connected.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Monitor channels as Unix sockets, named `pipe_name` in a directory shared by the client
+    /// and server.
+    #[derive(Clone, Debug)]
+    pub struct UnixSockets {
+        dir: PathBuf,
+    }
+
+    impl UnixSockets {
+        pub fn new<P: Into<PathBuf>>(dir: P) -> UnixSockets {
+            UnixSockets { dir: dir.into() }
+        }
+
+        // The name comes from the other side, so it must not lead out of the directory.
+        fn socket_path(&self, pipe_name: &OsStr) -> Result<PathBuf, PipeError> {
+            let mut components = Path::new(pipe_name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) => Ok(self.dir.join(name)),
+                _ => Err(PipeError::Api(HResult::new(E_INVALIDARG))),
+            }
+        }
+    }
+
+    impl MonitorListener for UnixSockets {
+        fn listen(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError> {
+            let path = self.socket_path(pipe_name)?;
+            let listener = UnixListener::bind(&path).map_err(|e| pipe_error(&e))?;
+            listener.set_nonblocking(true).map_err(|e| pipe_error(&e))?;
+            Ok(Box::new(PendingSocket {
+                path,
+                listener: Some(listener),
+                stream: None,
+            }))
+        }
+    }
+
+    impl MonitorConnector for UnixSockets {
+        fn connect(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError> {
+            let path = self.socket_path(pipe_name)?;
+            UnixStream::connect(path)
+                .map(|stream| Box::new(stream) as Box<dyn Transport>)
+                .map_err(|e| pipe_error(&e))
+        }
+    }
+
+    // The server's end of a channel, which accepts the client's connection when first used.
+    struct PendingSocket {
+        path: PathBuf,
+        listener: Option<UnixListener>,
+        stream: Option<UnixStream>,
+    }
+
+    impl PendingSocket {
+        fn stream(&mut self) -> Result<&mut UnixStream, PipeError> {
+            if self.stream.is_none() {
+                let listener = self.listener.take().ok_or(PipeError::NotConnected)?;
+                let deadline = Instant::now() + ACCEPT_TIMEOUT;
+                let stream = loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => break stream,
+                        Err(ref e)
+                            if e.kind() == io::ErrorKind::WouldBlock
+                                && Instant::now() < deadline =>
+                        {
+                            thread::sleep(POLL_INTERVAL)
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Err(PipeError::Timeout)
+                        }
+                        Err(e) => return Err(pipe_error(&e)),
+                    }
+                };
+                stream.set_nonblocking(false).map_err(|e| pipe_error(&e))?;
+                let _ = fs::remove_file(&self.path);
+                self.stream = Some(stream);
+            }
+            Ok(self.stream.as_mut().unwrap())
+        }
+    }
+
+    impl Transport for PendingSocket {
+        fn send(&mut self, data: &[u8]) -> Result<usize, PipeError> {
+            self.stream()?.send(data)
+        }
+
+        fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), PipeError> {
+            self.stream()?.recv(buf, timeout)
+        }
+    }
+
+    impl Drop for PendingSocket {
+        fn drop(&mut self) {
+            if self.listener.is_some() {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod named_pipe {
+    use std::cmp;
+    use std::ffi::{OsStr, OsString};
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::namedpipeapi::PeekNamedPipe;
+
+    use super::{pipe_error, write_stream, MonitorConnector, Transport};
+    use PipeError;
+
+    // How often a read checks whether data has arrived.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// The client end of a Windows named pipe.
+    pub struct NamedPipe(File);
+
+    impl NamedPipe {
+        /// Connect to the pipe named `\\.\pipe\<name>`.
+        pub fn connect(name: &OsStr) -> Result<NamedPipe, PipeError> {
+            let mut path = OsString::from(r"\\.\pipe\");
+            path.push(name);
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map(NamedPipe)
+                .map_err(|e| pipe_error(&e))
+        }
+
+        // The number of bytes that can be read without blocking.
+        fn available(&self) -> Result<usize, PipeError> {
+            let mut available: DWORD = 0;
+            let ok = unsafe {
+                PeekNamedPipe(
+                    self.0.as_raw_handle() as *mut _,
+                    ptr::null_mut(),
+                    0,
+                    ptr::null_mut(),
+                    &mut available,
+                    ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(pipe_error(&io::Error::last_os_error()));
+            }
+            Ok(available as usize)
+        }
+    }
+
+    /// Connects to monitor channels as Windows named pipes, `\\.\pipe\<pipe_name>`.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct NamedPipes;
+
+    impl MonitorConnector for NamedPipes {
+        fn connect(&mut self, pipe_name: &OsStr) -> Result<Box<dyn Transport>, PipeError> {
+            Ok(Box::new(NamedPipe::connect(pipe_name)?))
+        }
+    }
+
+    impl Transport for NamedPipe {
+        fn send(&mut self, data: &[u8]) -> Result<usize, PipeError> {
+            write_stream(&mut self.0, data)
+        }
+
+        // Pipe reads can't time out without overlapped I/O, so only read what is available.
+        fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<(), PipeError> {
+            let deadline = Instant::now() + timeout;
+            let mut filled = 0;
+            while filled < buf.len() {
+                let available = self.available()?;
+                if available == 0 {
+                    if Instant::now() >= deadline {
+                        return Err(PipeError::Timeout);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                let end = filled + cmp::min(available, buf.len() - filled);
+                match self.0.read(&mut buf[filled..end]) {
+                    Ok(0) => return Err(PipeError::NotConnected),
+                    Ok(count) => filled += count,
+                    Err(e) => return Err(pipe_error(&e)),
+                }
+            }
+            Ok(())
+        }
+    }
+}
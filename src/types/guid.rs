@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use super::hresult::{HResult, E_INVALIDARG};
+
+/// A GUID, with the fields of a Windows `GUID`.
+///
+/// `Display` writes the registry format, `{XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX}`, and
+/// `FromStr` reads it, with or without the braces.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    /// A new random GUID, of version 4 as in RFC 4122.
+    ///
+    /// The random bits come from the keys that the standard library gets from the OS for each
+    /// `HashMap`, so they are unpredictable without another dependency.
+    pub fn new_random() -> Guid {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let random = || {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+            if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                hasher.write_u128(elapsed.as_nanos());
+            }
+            hasher.finish()
+        };
+        let (high, low) = (random(), random());
+
+        let mut data4 = low.to_be_bytes();
+        // The variant, RFC 4122.
+        data4[0] = (data4[0] & 0x3f) | 0x80;
+        Guid {
+            data1: (high >> 32) as u32,
+            data2: (high >> 16) as u16,
+            // The version, 4.
+            data3: (high as u16 & 0x0fff) | 0x4000,
+            data4,
+        }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            self.data1, self.data2, self.data3, self.data4[0], self.data4[1]
+        )?;
+        for byte in &self.data4[2..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Fails with `E_INVALIDARG` if the string is not a GUID.
+impl FromStr for Guid {
+    type Err = HResult;
+
+    fn from_str(s: &str) -> Result<Guid, HResult> {
+        let invalid = || HResult::new(E_INVALIDARG);
+        let s = if s.starts_with('{') && s.ends_with('}') {
+            &s[1..s.len() - 1]
+        } else {
+            s
+        };
+
+        let groups: Vec<&str> = s.split('-').collect();
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        if lengths != [8, 4, 4, 4, 12]
+            || !groups
+                .iter()
+                .all(|group| group.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            return Err(invalid());
+        }
+
+        let mut data4 = [0u8; 8];
+        let tail = format!("{}{}", groups[3], groups[4]);
+        for (i, byte) in data4.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&tail[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Guid {
+            data1: u32::from_str_radix(groups[0], 16).map_err(|_| invalid())?,
+            data2: u16::from_str_radix(groups[1], 16).map_err(|_| invalid())?,
+            data3: u16::from_str_radix(groups[2], 16).map_err(|_| invalid())?,
+            data4,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Guid;
+
+    #[test]
+    fn round_trip() {
+        let guid = Guid {
+            data1: 0x0123_4567,
+            data2: 0x89ab,
+            data3: 0xcdef,
+            data4: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+        };
+        let s = "{01234567-89AB-CDEF-0123-456789ABCDEF}";
+        assert_eq!(guid.to_string(), s);
+        assert_eq!(s.parse::<Guid>().unwrap(), guid);
+        assert_eq!(s[1..s.len() - 1].parse::<Guid>().unwrap(), guid);
+        assert_eq!(s.to_lowercase().parse::<Guid>().unwrap(), guid);
+
+        assert!("".parse::<Guid>().is_err());
+        assert!("{01234567-89AB-CDEF-0123-456789ABCDEF"
+            .parse::<Guid>()
+            .is_err());
+        assert!("01234567-89AB-CDEF-0123-456789ABCDEG"
+            .parse::<Guid>()
+            .is_err());
+        assert!("0123456-789AB-CDEF-0123-456789ABCDEF"
+            .parse::<Guid>()
+            .is_err());
+    }
+}
@@ -0,0 +1,85 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! `HRESULT` error codes, and the ones used by BITS and its backends.
+
+use std::fmt;
+
+use failure::Fail;
+
+/// A Windows error code, negative for an error.
+#[allow(non_camel_case_types)]
+pub type HRESULT = i32;
+
+pub const S_OK: HRESULT = 0;
+pub const E_NOTIMPL: HRESULT = 0x8000_4001_u32 as HRESULT;
+pub const E_FAIL: HRESULT = 0x8000_4005_u32 as HRESULT;
+pub const E_ACCESSDENIED: HRESULT = 0x8007_0005_u32 as HRESULT;
+pub const E_OUTOFMEMORY: HRESULT = 0x8007_000E_u32 as HRESULT;
+pub const E_INVALIDARG: HRESULT = 0x8007_0057_u32 as HRESULT;
+
+pub const BG_E_NOT_FOUND: HRESULT = 0x8020_0001_u32 as HRESULT;
+pub const BG_E_INVALID_STATE: HRESULT = 0x8020_0002_u32 as HRESULT;
+pub const BG_E_EMPTY: HRESULT = 0x8020_0003_u32 as HRESULT;
+pub const BG_E_NETWORK_DISCONNECTED: HRESULT = 0x8020_0010_u32 as HRESULT;
+pub const BG_S_PARTIAL_COMPLETE: HRESULT = 0x0020_0017;
+pub const BG_S_UNABLE_TO_DELETE_FILES: HRESULT = 0x0020_001A;
+pub const BG_E_INVALID_SERVER_RESPONSE: HRESULT = 0x8020_001B_u32 as HRESULT;
+pub const BG_E_TOO_MANY_FILES: HRESULT = 0x8020_001C_u32 as HRESULT;
+pub const BG_E_TOO_LARGE: HRESULT = 0x8020_0020_u32 as HRESULT;
+pub const BG_E_MAXDOWNLOAD_TIMEOUT: HRESULT = 0x8020_0054_u32 as HRESULT;
+
+/// The `HRESULT` of a Win32 error code, as the `HRESULT_FROM_WIN32` macro.
+#[allow(non_snake_case)]
+pub const fn HRESULT_FROM_WIN32(code: u32) -> HRESULT {
+    if code as HRESULT <= 0 {
+        code as HRESULT
+    } else {
+        ((code & 0xFFFF) | 0x8007_0000) as HRESULT
+    }
+}
+
+/// An error `HRESULT`, optionally with the name of the failing function.
+#[derive(Clone, Debug, Eq, Fail, PartialEq)]
+pub struct HResult {
+    code: HRESULT,
+    function: Option<&'static str>,
+}
+
+impl HResult {
+    /// Create from an `HRESULT`.
+    pub fn new(hr: HRESULT) -> HResult {
+        HResult {
+            code: hr,
+            function: None,
+        }
+    }
+
+    /// Get the `HRESULT`.
+    pub fn code(&self) -> HRESULT {
+        self.code
+    }
+
+    /// Add the name of the failing function to the error.
+    pub fn function(self, function: &'static str) -> HResult {
+        HResult {
+            function: Some(function),
+            ..self
+        }
+    }
+
+    /// Get the name of the failing function, if known.
+    pub fn get_function(&self) -> Option<&'static str> {
+        self.function
+    }
+}
+
+impl fmt::Display for HResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(function) = self.function {
+            write!(f, "{} error: ", function)?;
+        }
+        write!(f, "HRESULT {:#010x}", self.code)
+    }
+}
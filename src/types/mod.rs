@@ -0,0 +1,265 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The types of BITS jobs and their status, owned by this crate so they are available on every
+//! platform.
+//!
+//! They follow the types of the `bits`, `comedy`, `guid_win` and `filetime_win` crates, which
+//! are only built on Windows; the [`ComBackend`](../backend/struct.ComBackend.html) converts
+//! between the two. Values which BITS defines as constants, such as the job states, keep the
+//! values of those constants.
+
+use std::panic::RefUnwindSafe;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod hresult;
+
+mod guid;
+
+pub use self::guid::Guid;
+pub use self::hresult::{HResult, HRESULT};
+
+/// The type of a notification callback.
+///
+/// The callbacks must be `Fn()` to be called arbitrarily many times, `RefUnwindSafe` to have a
+/// panic unwind safely caught, `Send`, `Sync` and `'static` to run on any thread at any time.
+pub type TransferredCallback =
+    dyn Fn() -> Result<(), HRESULT> + RefUnwindSafe + Send + Sync + 'static;
+pub type ErrorCallback = dyn Fn() -> Result<(), HRESULT> + RefUnwindSafe + Send + Sync + 'static;
+pub type ModificationCallback =
+    dyn Fn() -> Result<(), HRESULT> + RefUnwindSafe + Send + Sync + 'static;
+
+/// A point in time, as a count of 100ns intervals since 1601-01-01 UTC like a Windows
+/// `FILETIME`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FileTime(pub u64);
+
+// Seconds from 1601-01-01 to 1970-01-01.
+const EPOCH_DIFFERENCE_SECS: u64 = 11_644_473_600;
+
+impl FileTime {
+    /// The current time.
+    pub fn now() -> FileTime {
+        let since_unix_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        FileTime(
+            (since_unix_epoch.as_secs() + EPOCH_DIFFERENCE_SECS) * 10_000_000
+                + u64::from(since_unix_epoch.subsec_nanos() / 100),
+        )
+    }
+
+    pub fn to_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The time as a `SystemTime`, saturating at the Unix epoch.
+    pub fn to_system_time(self) -> SystemTime {
+        let since_unix_epoch = self.0.saturating_sub(EPOCH_DIFFERENCE_SECS * 10_000_000);
+        UNIX_EPOCH
+            + Duration::from_secs(since_unix_epoch / 10_000_000)
+            + Duration::from_nanos(since_unix_epoch % 10_000_000 * 100)
+    }
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsJobPriority {
+    Foreground = 0,
+    High = 1,
+    /// Default
+    Normal = 2,
+    Low = 3,
+}
+
+#[derive(Clone, Debug)]
+pub enum BitsProxyUsage {
+    /// Directly access the network.
+    NoProxy,
+    /// Use Internet Explorer proxy settings. This is the default.
+    Preconfig,
+    /// Attempt to auto-detect the connection's proxy settings.
+    AutoDetect,
+    /// Use the first of `proxies` which is available, except for hosts matching `bypass`.
+    ///
+    /// Each proxy is `[scheme=][scheme://]host[:port]`, each bypass entry is a host name which
+    /// may contain `*` wildcards, or `<local>` for any host name without a period.
+    Override {
+        proxies: Vec<String>,
+        bypass: Vec<String>,
+    },
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsJobType {
+    Download = 0,
+    /// Upload a single file.
+    Upload = 1,
+    /// Upload a single file, and receive a reply from the server.
+    UploadReply = 2,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsAuthTarget {
+    /// The server hosting the file.
+    Server = 1,
+    Proxy = 2,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsAuthScheme {
+    Basic = 1,
+    Digest = 2,
+    Ntlm = 3,
+    Negotiate = 4,
+}
+
+/// A range of bytes in a remote file, for `BackendJob::add_file_with_ranges()`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BitsFileRange {
+    pub offset: u64,
+    /// The number of bytes, or `None` for the rest of the file.
+    pub length: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BitsJobStatus {
+    pub state: BitsJobState,
+    pub progress: BitsJobProgress,
+    pub error_count: u32,
+    pub error: Option<BitsJobError>,
+    pub times: BitsJobTimes,
+}
+
+#[derive(Clone, Debug)]
+pub struct BitsJobError {
+    pub context: BitsErrorContext,
+    pub context_str: String,
+    pub error: HRESULT,
+    pub error_str: String,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsErrorContext {
+    None,
+    Unknown,
+    GeneralQueueManager,
+    QueueManagerNotification,
+    LocalFile,
+    RemoteFile,
+    GeneralTransport,
+    RemoteApplication,
+    /// No other values are documented
+    Other(u32),
+}
+
+impl From<u32> for BitsErrorContext {
+    fn from(ec: u32) -> BitsErrorContext {
+        use self::BitsErrorContext::*;
+        match ec {
+            0 => None,
+            1 => Unknown,
+            2 => GeneralQueueManager,
+            3 => QueueManagerNotification,
+            4 => LocalFile,
+            5 => RemoteFile,
+            6 => GeneralTransport,
+            7 => RemoteApplication,
+            ec => Other(ec),
+        }
+    }
+}
+
+impl From<BitsErrorContext> for u32 {
+    fn from(ec: BitsErrorContext) -> u32 {
+        use self::BitsErrorContext::*;
+        match ec {
+            None => 0,
+            Unknown => 1,
+            GeneralQueueManager => 2,
+            QueueManagerNotification => 3,
+            LocalFile => 4,
+            RemoteFile => 5,
+            GeneralTransport => 6,
+            RemoteApplication => 7,
+            Other(ec) => ec,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitsJobState {
+    Queued,
+    Connecting,
+    Transferring,
+    Suspended,
+    Error,
+    TransientError,
+    Transferred,
+    Acknowledged,
+    Cancelled,
+    /// No other values are documented
+    Other(u32),
+}
+
+impl From<u32> for BitsJobState {
+    fn from(s: u32) -> BitsJobState {
+        use self::BitsJobState::*;
+        match s {
+            0 => Queued,
+            1 => Connecting,
+            2 => Transferring,
+            3 => Suspended,
+            4 => Error,
+            5 => TransientError,
+            6 => Transferred,
+            7 => Acknowledged,
+            8 => Cancelled,
+            s => Other(s),
+        }
+    }
+}
+
+impl From<BitsJobState> for u32 {
+    fn from(s: BitsJobState) -> u32 {
+        use self::BitsJobState::*;
+        match s {
+            Queued => 0,
+            Connecting => 1,
+            Transferring => 2,
+            Suspended => 3,
+            Error => 4,
+            TransientError => 5,
+            Transferred => 6,
+            Acknowledged => 7,
+            Cancelled => 8,
+            Other(s) => s,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BitsJobProgress {
+    pub total_bytes: Option<u64>,
+    pub transferred_bytes: u64,
+    pub total_files: u32,
+    pub transferred_files: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BitsFileProgress {
+    pub total_bytes: Option<u64>,
+    pub transferred_bytes: u64,
+    pub completed: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BitsJobTimes {
+    pub creation: FileTime,
+    pub modification: FileTime,
+    pub transfer_completion: Option<FileTime>,
+}
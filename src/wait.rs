@@ -0,0 +1,370 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Waiting for a job to finish, with a monitor of its own.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+
+use backend::JobBackend;
+use bits_protocol::{CompleteJobFailure, HResultMessage, JobStatus, MonitorJobFailure};
+
+use super::{BitsClient, BitsJobState, BitsMonitorClient, Error, Guid};
+use types::hresult::BG_E_NOT_FOUND;
+
+/// Options for [`BitsClient::wait_for_job()`](enum.BitsClient.html#method.wait_for_job).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WaitOptions {
+    /// How often the job's status is checked, 1 second by default.
+    pub interval_millis: u32,
+    /// Complete the job once it is transferred.
+    pub complete: bool,
+    /// Give up once the job has transferred nothing for this long, e.g. while it is suspended
+    /// or waiting to retry after a transient error. By default, wait for as long as it takes.
+    pub no_progress_timeout_millis: Option<u32>,
+}
+
+impl Default for WaitOptions {
+    fn default() -> WaitOptions {
+        WaitOptions {
+            interval_millis: 1000,
+            complete: false,
+            no_progress_timeout_millis: None,
+        }
+    }
+}
+
+/// How a job ended, from [`BitsClient::wait_for_job()`](enum.BitsClient.html#method.wait_for_job).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WaitOutcome {
+    /// The job was transferred, it is ready for `complete_job()`.
+    Transferred,
+    /// The job was transferred and completed with `WaitOptions::complete`, its files are in
+    /// place.
+    Completed,
+    /// The job failed, as described by `JobStatus::error`. It can still be resumed or
+    /// cancelled.
+    Error,
+    /// The job was reported as cancelled.
+    Cancelled,
+    /// The job was reported as completed.
+    Acknowledged,
+    /// The job is gone, it was completed or cancelled by someone else. The status is the last
+    /// one reported before it went, which doesn't tell which.
+    Gone,
+    /// The job made no progress within `WaitOptions::no_progress_timeout_millis`.
+    NoProgress,
+}
+
+#[derive(Clone, Debug, Fail)]
+pub enum WaitForJobFailure {
+    #[fail(display = "Monitor job: {}", _0)]
+    MonitorJob(MonitorJobFailure),
+    #[fail(display = "Get status: {}", _0)]
+    GetStatus(HResultMessage),
+    #[fail(display = "Complete job: {}", _0)]
+    CompleteJob(CompleteJobFailure),
+}
+
+pub(crate) fn wait_for_job<B: JobBackend>(
+    client: &mut BitsClient<B>,
+    guid: Guid,
+    options: WaitOptions,
+) -> Result<Result<(WaitOutcome, JobStatus), WaitForJobFailure>, Error> {
+    // Reports must come at least as often as the deadline is to be checked.
+    let interval_millis = options
+        .no_progress_timeout_millis
+        .map_or(options.interval_millis, |millis| {
+            cmp::min(millis, options.interval_millis)
+        });
+
+    let mut monitor = match client.monitor_job(guid.clone(), interval_millis)? {
+        Ok(monitor) => monitor,
+        Err(e) => return Ok(Err(WaitForJobFailure::MonitorJob(e))),
+    };
+    let result = wait(&mut monitor, options.no_progress_timeout_millis);
+
+    // Only this monitor is stopped, any others of the job keep running. It is already gone if
+    // the job is.
+    let stopped = client.stop_update(guid.clone(), Some(monitor.subscription()));
+
+    match (result, stopped) {
+        // A Local Service monitor is also disconnected if the server is, which says nothing
+        // about the job.
+        (Ok(Ok((WaitOutcome::Gone, _))), Err(e)) => Err(e),
+        (Ok(Ok((WaitOutcome::Transferred, status))), _) if options.complete => {
+            Ok(match client.complete_job(guid)? {
+                Ok(()) => Ok((WaitOutcome::Completed, status)),
+                Err(e) => Err(WaitForJobFailure::CompleteJob(e)),
+            })
+        }
+        (result, _) => result,
+    }
+}
+
+// Read status reports from `monitor` until the job reaches a state to be acted on, is gone, or
+// has made no progress for `no_progress_timeout_millis`.
+fn wait<B: JobBackend>(
+    monitor: &mut BitsMonitorClient<B>,
+    no_progress_timeout_millis: Option<u32>,
+) -> Result<Result<(WaitOutcome, JobStatus), WaitForJobFailure>, Error> {
+    let no_progress_timeout =
+        no_progress_timeout_millis.map(|millis| Duration::from_millis(u64::from(millis)));
+    let mut last_bytes = None;
+    let mut last_progress_time = Instant::now();
+    let mut last_status = None;
+
+    loop {
+        // However long the monitor's interval is, wait for its next report until the job has
+        // gone without progress for too long.
+        let timeout_millis = match no_progress_timeout {
+            Some(timeout) => {
+                let remaining =
+                    (last_progress_time + timeout).saturating_duration_since(Instant::now());
+                // Rounded up, so as not to give up early. `u128::div_ceil()` is too new to use
+                // here.
+                #[allow(clippy::manual_div_ceil)]
+                let millis = (remaining.as_micros() + 999) / 1000;
+                cmp::min(millis, u128::from(u32::MAX)) as u32
+            }
+            None => u32::MAX,
+        };
+
+        let status = match (monitor.get_status(timeout_millis), last_status.take()) {
+            (Ok(Ok(status)), _) => status,
+            (Err(Error::Timeout), Some(status)) => {
+                return Ok(Ok((WaitOutcome::NoProgress, status)));
+            }
+            // The job is gone: removed by another client, which the monitor finds when it
+            // reports, or by this one, which stops the job's monitors.
+            (Ok(Err(ref e)), Some(status)) if e.hr == BG_E_NOT_FOUND => {
+                return Ok(Ok((WaitOutcome::Gone, status)));
+            }
+            (Err(Error::NotConnected), Some(status)) => {
+                return Ok(Ok((WaitOutcome::Gone, status)));
+            }
+            (Ok(Err(e)), _) => return Ok(Err(WaitForJobFailure::GetStatus(e))),
+            (Err(e), _) => return Err(e),
+        };
+
+        let outcome = match status.state {
+            BitsJobState::Transferred => WaitOutcome::Transferred,
+            BitsJobState::Error => WaitOutcome::Error,
+            BitsJobState::Cancelled => WaitOutcome::Cancelled,
+            BitsJobState::Acknowledged => WaitOutcome::Acknowledged,
+            _ => {
+                let now = Instant::now();
+                if last_bytes != Some(status.progress.transferred_bytes) {
+                    last_bytes = Some(status.progress.transferred_bytes);
+                    last_progress_time = now;
+                }
+                match no_progress_timeout {
+                    Some(timeout) if now.duration_since(last_progress_time) >= timeout => {
+                        WaitOutcome::NoProgress
+                    }
+                    _ => {
+                        last_status = Some(status);
+                        continue;
+                    }
+                }
+            }
+        };
+        return Ok(Ok((outcome, status)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::ffi::OsString;
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use self::tempdir::TempDir;
+
+    use super::{WaitOptions, WaitOutcome};
+    use backend::simulated::Response;
+    use backend::SimulatedBits;
+    use {BitsClient, BitsJobState, BitsProxyUsage, JobSettings};
+
+    fn client(bits: &SimulatedBits, tmp_dir: &TempDir) -> BitsClient<SimulatedBits> {
+        BitsClient::with_backend(
+            bits.clone(),
+            OsString::from("wait_for_job Test"),
+            tmp_dir.path().as_os_str().to_os_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn complete() {
+        let tmp_dir = TempDir::new("wait_for_job").unwrap();
+        let bits = SimulatedBits::new();
+        let mut client = client(&bits, &tmp_dir);
+
+        let url = "http://server.simulated/file";
+        bits.serve(url, Response::ok(b"body"));
+
+        let (success, _) = client
+            .start_job(
+                OsString::from(url),
+                OsString::from("file"),
+                BitsProxyUsage::Preconfig,
+                u32::MAX,
+            )
+            .unwrap()
+            .unwrap();
+        let options = WaitOptions {
+            interval_millis: 100,
+            complete: true,
+            ..WaitOptions::default()
+        };
+        let (outcome, status) = client.wait_for_job(success.guid, options).unwrap().unwrap();
+        assert_eq!(outcome, WaitOutcome::Completed);
+        assert_eq!(status.state, BitsJobState::Transferred);
+        assert_eq!(status.progress.transferred_bytes, 4);
+        assert_eq!(fs::read(tmp_dir.path().join("file")).unwrap(), b"body");
+    }
+
+    #[test]
+    fn no_progress_and_error() {
+        let tmp_dir = TempDir::new("wait_for_job").unwrap();
+        let mut client = client(&SimulatedBits::new(), &tmp_dir);
+
+        // Nothing is served here, the job is never expected to transfer.
+        let (success, _) = client
+            .start_job(
+                OsString::from("http://unserved.simulated/file"),
+                OsString::from("file"),
+                BitsProxyUsage::Preconfig,
+                u32::MAX,
+            )
+            .unwrap()
+            .unwrap();
+        let guid = success.guid;
+
+        // The job retries after a minute, so it gives up first.
+        let options = WaitOptions {
+            interval_millis: 60_000,
+            no_progress_timeout_millis: Some(500),
+            ..WaitOptions::default()
+        };
+        let start = Instant::now();
+        let (outcome, status) = client.wait_for_job(guid.clone(), options).unwrap().unwrap();
+        assert_eq!(outcome, WaitOutcome::NoProgress);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(status.progress.transferred_bytes, 0);
+
+        // Without retries, the job fails.
+        let settings = JobSettings {
+            minimum_retry_delay_secs: Some(0),
+            no_progress_timeout_secs: Some(0),
+            maximum_download_time_secs: None,
+        };
+        client
+            .update_job_settings(guid.clone(), settings)
+            .unwrap()
+            .unwrap();
+        let options = WaitOptions {
+            interval_millis: 100,
+            complete: true,
+            ..WaitOptions::default()
+        };
+        let (outcome, status) = client.wait_for_job(guid.clone(), options).unwrap().unwrap();
+        assert_eq!(outcome, WaitOutcome::Error);
+        assert!(status.error.is_some());
+
+        client.cancel_job(guid).unwrap().unwrap();
+    }
+
+    #[test]
+    fn cancelled_and_completed_elsewhere() {
+        let tmp_dir = TempDir::new("wait_for_job").unwrap();
+        let bits = SimulatedBits::new();
+        let mut client = client(&bits, &tmp_dir);
+
+        let url = "http://server.simulated/file";
+        bits.serve(url, Response::ok(b"body"));
+
+        // The second file is never served, so the job keeps retrying it.
+        let (success, _) = client
+            .start_job_multi(
+                vec![
+                    (OsString::from(url), OsString::from("file")),
+                    (
+                        OsString::from("http://unserved.simulated/file"),
+                        OsString::from("unserved"),
+                    ),
+                ],
+                BitsProxyUsage::Preconfig,
+                u32::MAX,
+            )
+            .unwrap()
+            .unwrap();
+        let guid = success.guid;
+
+        // Completing the job moves the file which was transferred into place.
+        let other = {
+            let bits = bits.clone();
+            let tmp_dir = tmp_dir.path().to_owned();
+            let guid = guid.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(500));
+                let mut other = BitsClient::with_backend(
+                    bits,
+                    OsString::from("wait_for_job Test"),
+                    tmp_dir.into_os_string(),
+                )
+                .unwrap();
+                other.complete_job(guid).unwrap()
+            })
+        };
+        let options = WaitOptions {
+            interval_millis: 100,
+            ..WaitOptions::default()
+        };
+        let (outcome, status) = client.wait_for_job(guid, options).unwrap().unwrap();
+        assert!(other.join().unwrap().is_err());
+        assert_eq!(outcome, WaitOutcome::Gone);
+        assert_eq!(status.state, BitsJobState::TransientError);
+        assert_eq!(status.progress.transferred_files, 1);
+        assert_eq!(fs::read(tmp_dir.path().join("file")).unwrap(), b"body");
+
+        // Cancelling it leaves nothing in place.
+        let (success, _) = client
+            .start_job(
+                OsString::from("http://unserved.simulated/file"),
+                OsString::from("cancelled"),
+                BitsProxyUsage::Preconfig,
+                u32::MAX,
+            )
+            .unwrap()
+            .unwrap();
+        let guid = success.guid;
+        let other = {
+            let bits = bits.clone();
+            let tmp_dir = tmp_dir.path().to_owned();
+            let guid = guid.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(500));
+                let mut other = BitsClient::with_backend(
+                    bits,
+                    OsString::from("wait_for_job Test"),
+                    tmp_dir.into_os_string(),
+                )
+                .unwrap();
+                other.cancel_job(guid).unwrap().unwrap();
+            })
+        };
+        let (outcome, status) = client.wait_for_job(guid, options).unwrap().unwrap();
+        other.join().unwrap();
+        assert_eq!(outcome, WaitOutcome::Gone);
+        assert_eq!(status.state, BitsJobState::TransientError);
+        assert!(!tmp_dir.path().join("cancelled").exists());
+    }
+}